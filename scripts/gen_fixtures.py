@@ -0,0 +1,1482 @@
+#!/usr/bin/env python3
+"""Generate the patch fixtures under tests/fixtures.
+
+The fixtures are produced by encoders written here from the published formats, independently
+of the decoders in src/, so that the tests exercise patches the crate did not make itself.
+
+    scripts/gen_fixtures.py [bsdiff] [puffin] [lz4diff] [zucchini] [zipsig]
+
+The lz4diff fixture compresses blocks with the system liblz4 through ctypes. The zucchini
+fixture builds its ELF inputs with gcc when they are missing. The zipsig fixture signs with
+openssl, which also makes its certificates when they are missing.
+"""
+
+import bisect
+import bz2
+import ctypes
+import ctypes.util
+import hashlib
+import io
+import random
+import struct
+import subprocess
+import sys
+import zipfile
+import zlib
+from pathlib import Path
+
+FIXTURES = Path(__file__).resolve().parent.parent / "tests" / "fixtures"
+
+
+# bsdiff, following the reference bsdiff 4.3 by Colin Percival.
+
+
+def offtout(value):
+    """bsdiff's sign-magnitude little-endian 64-bit integer."""
+    if value < 0:
+        return struct.pack("<Q", -value | (1 << 63))
+    return struct.pack("<Q", value)
+
+
+def suffix_array(data):
+    """Sorted suffixes of `data`, including the empty one, as bsdiff's qsufsort builds them."""
+    return sorted(range(len(data) + 1), key=lambda i: data[i:])
+
+
+def match_len(old, old_pos, new, new_pos):
+    length = 0
+    while (
+        old_pos + length < len(old)
+        and new_pos + length < len(new)
+        and old[old_pos + length] == new[new_pos + length]
+    ):
+        length += 1
+    return length
+
+
+def search(sa, old, new, new_pos, start, end):
+    """Longest match of `new[new_pos:]` among the suffixes `sa[start..=end]` of `old`."""
+    while end - start >= 2:
+        middle = start + (end - start) // 2
+        length = min(len(old) - sa[middle], len(new) - new_pos)
+        if old[sa[middle] : sa[middle] + length] < new[new_pos : new_pos + length]:
+            start = middle
+        else:
+            end = middle
+    x = match_len(old, sa[start], new, new_pos)
+    y = match_len(old, sa[end], new, new_pos)
+    return (x, sa[start]) if x > y else (y, sa[end])
+
+
+def bsdiff(old, new):
+    """Control entries and the diff and extra streams turning `old` into `new`."""
+    sa = suffix_array(old)
+    controls, diff, extra = [], bytearray(), bytearray()
+    scan = length = pos = 0
+    last_scan = last_pos = last_offset = 0
+    while scan < len(new):
+        old_score = 0
+        scan += length
+        scsc = scan
+        while scan < len(new):
+            length, pos = search(sa, old, new, scan, 0, len(old))
+            while scsc < scan + length:
+                if scsc + last_offset < len(old) and old[scsc + last_offset] == new[scsc]:
+                    old_score += 1
+                scsc += 1
+            if (length == old_score and length != 0) or length > old_score + 8:
+                break
+            if scan + last_offset < len(old) and old[scan + last_offset] == new[scan]:
+                old_score -= 1
+            scan += 1
+
+        if length == old_score and scan != len(new):
+            continue
+
+        # Extend the previous match forwards and this one backwards.
+        s = best = len_f = i = 0
+        while last_scan + i < scan and last_pos + i < len(old):
+            if old[last_pos + i] == new[last_scan + i]:
+                s += 1
+            i += 1
+            if s * 2 - i > best * 2 - len_f:
+                best, len_f = s, i
+        len_b = 0
+        if scan < len(new):
+            s = best = 0
+            i = 1
+            while scan >= last_scan + i and pos >= i:
+                if old[pos - i] == new[scan - i]:
+                    s += 1
+                if s * 2 - i > best * 2 - len_b:
+                    best, len_b = s, i
+                i += 1
+        if last_scan + len_f > scan - len_b:
+            overlap = last_scan + len_f - (scan - len_b)
+            s = best = len_s = 0
+            for i in range(overlap):
+                if new[last_scan + len_f - overlap + i] == old[last_pos + len_f - overlap + i]:
+                    s += 1
+                if new[scan - len_b + i] == old[pos - len_b + i]:
+                    s -= 1
+                if s > best:
+                    best, len_s = s, i + 1
+            len_f += len_s - overlap
+            len_b -= len_s
+
+        diff += bytes((new[last_scan + i] - old[last_pos + i]) & 0xFF for i in range(len_f))
+        extra_len = (scan - len_b) - (last_scan + len_f)
+        extra += new[last_scan + len_f : last_scan + len_f + extra_len]
+        controls.append((len_f, extra_len, (pos - len_b) - (last_pos + len_f)))
+        last_scan, last_pos, last_offset = scan - len_b, pos - len_b, pos - scan
+    return controls, bytes(diff), bytes(extra)
+
+
+def bspatch(old, controls, diff, extra):
+    """Apply a patch the way the reference bspatch does, to check the encoder."""
+    new, old_pos, diff_pos, extra_pos = bytearray(), 0, 0, 0
+    for add, copy, seek in controls:
+        for i in range(add):
+            old_byte = old[old_pos + i] if 0 <= old_pos + i < len(old) else 0
+            new.append((diff[diff_pos + i] + old_byte) & 0xFF)
+        diff_pos += add
+        old_pos += add
+        new += extra[extra_pos : extra_pos + copy]
+        extra_pos += copy
+        old_pos += seek
+    return bytes(new)
+
+
+def bsdiff_patch(old, new, magic, compress):
+    """A patch in the `BSDIFF40` or `BSDF2` container, each stream compressed with `compress`."""
+    controls, diff, extra = bsdiff(old, new)
+    assert bspatch(old, controls, diff, extra) == new
+    ctrl = b"".join(offtout(x) + offtout(y) + offtout(z) for x, y, z in controls)
+    ctrl, diff, extra = compress(ctrl), compress(diff), compress(extra)
+    header = magic + offtout(len(ctrl)) + offtout(len(diff)) + offtout(len(new))
+    return header + ctrl + diff + extra
+
+
+def executable(rng, size, shift=0):
+    """Code-like data with little-endian pointers into itself, moved by `shift` in new versions."""
+    data = bytearray()
+    while len(data) < size:
+        kind = rng.randrange(4)
+        if kind == 0:
+            data += struct.pack("<I", 0x10000 + rng.randrange(size) + shift)
+        elif kind == 1:
+            data += rng.choice([b"\x55\x48\x89\xe5", b"\xc3", b"\x90\x90", b"\x48\x83\xec\x20"])
+        else:
+            data += bytes(rng.randrange(256) for _ in range(rng.randrange(1, 12)))
+    return bytes(data[:size])
+
+
+def gen_bsdiff():
+    old = executable(random.Random(4), 12 * 1024)
+    new = bytearray(executable(random.Random(4), 12 * 1024, shift=0x40))
+    new[1024:1024] = b"inserted in the new version " * 3
+    new[6000:6200] = b""
+    new[9000:9016] = b"patched bytes!!!"
+    new += b"appended tail"
+    new = bytes(new)
+
+    out = FIXTURES / "bsdiff"
+    out.mkdir(parents=True, exist_ok=True)
+    (out / "old.bin").write_bytes(old)
+    (out / "new.bin").write_bytes(new)
+    # The reference bsdiff compresses every stream with bzip2.
+    (out / "patch.bsdiff40").write_bytes(bsdiff_patch(old, new, b"BSDIFF40", bz2.compress))
+    # BSDF2 names the compression of each stream after its magic: 1 is bzip2.
+    (out / "patch.bsdf2").write_bytes(bsdiff_patch(old, new, b"BSDF2\x01\x01\x01", bz2.compress))
+    print("bsdiff: new.bin sha256", hashlib.sha256(new).hexdigest())
+
+
+# puffin, following the puff format and patch container of AOSP's external/puffin.
+
+LENGTH_BASES = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83,
+                99, 115, 131, 163, 195, 227, 258]
+LENGTH_EXTRA = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5,
+                5, 0]
+DISTANCE_BASES = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769,
+                  1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577]
+DISTANCE_EXTRA = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11,
+                  11, 12, 12, 13, 13]
+CODE_LENGTH_ORDER = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15]
+
+
+class Bits:
+    """Deflate's least-significant-bit-first reader over `data`, starting at bit `position`."""
+
+    def __init__(self, data, position):
+        self.data, self.position = data, position
+
+    def read(self, count):
+        value = 0
+        for i in range(count):
+            byte = self.data[self.position // 8]
+            value |= ((byte >> (self.position % 8)) & 1) << i
+            self.position += 1
+        return value
+
+
+def huffman(lengths):
+    """Map of (length, code) to symbol for a canonical Huffman code (RFC 1951, 3.2.2)."""
+    codes, code = {}, 0
+    for length in range(1, 16):
+        for symbol, symbol_length in enumerate(lengths):
+            if symbol_length == length:
+                codes[(length, code)] = symbol
+                code += 1
+        code <<= 1
+    return codes
+
+
+def decode(bits, codes):
+    code = 0
+    for length in range(1, 16):
+        code = (code << 1) | bits.read(1)
+        if (length, code) in codes:
+            return codes[(length, code)]
+    raise ValueError("invalid Huffman code")
+
+
+class Puffs:
+    """Writes puff items, merging literals into runs."""
+
+    def __init__(self):
+        self.out, self.literals = bytearray(), bytearray()
+
+    def flush(self):
+        for start in range(0, len(self.literals), (1 << 16) + 127):
+            run = self.literals[start : start + (1 << 16) + 127]
+            if len(run) <= 127:
+                self.out.append(len(run) - 1)
+            else:
+                self.out += bytes([127]) + struct.pack(">H", len(run) - 128)
+            self.out += run
+        self.literals = bytearray()
+
+    def metadata(self, metadata):
+        self.flush()
+        self.out += struct.pack(">H", len(metadata) - 1) + bytes(metadata)
+
+    def length(self, length):
+        self.flush()
+        if length < 130:
+            self.out.append(0x80 | (length - 3))
+        else:
+            self.out += bytes([0xFF, length - 130])
+
+    def len_dist(self, length, distance):
+        self.length(length)
+        self.out += struct.pack(">H", distance - 1)
+
+    def end_of_block(self):
+        self.length(259)
+
+
+def puff_deflate(data, offset):
+    """Puff the deflate stream at bit `offset` of `data`, returning the puff and its end bit."""
+    bits, puffs, out = Bits(data, offset), Puffs(), bytearray()
+    fixed = (huffman([8] * 144 + [9] * 112 + [7] * 24 + [8] * 8), huffman([5] * 30))
+    final = 0
+    while not final:
+        final, kind = bits.read(1), bits.read(2)
+        header = (final << 7) | (kind << 5)
+        if kind == 0:
+            skip = (8 - bits.position % 8) % 8
+            skipped = bits.read(skip)
+            length, nlength = bits.read(16), bits.read(16)
+            assert length ^ nlength == 0xFFFF
+            puffs.metadata([header | skipped])
+            stored = bytes(bits.read(8) for _ in range(length))
+            puffs.literals += stored
+            out += stored
+            puffs.end_of_block()
+            continue
+        if kind == 1:
+            puffs.metadata([header])
+            lit_len, distance = fixed
+        else:
+            metadata = [header]
+            num_lit_len, num_distance, num_codes = bits.read(5), bits.read(5), bits.read(4)
+            metadata += [num_lit_len, num_distance, num_codes]
+            code_lengths = [0] * 19
+            for i in range(num_codes + 4):
+                code_lengths[CODE_LENGTH_ORDER[i]] = bits.read(3)
+                if i % 2 == 0:
+                    metadata.append(code_lengths[CODE_LENGTH_ORDER[i]] << 4)
+                else:
+                    metadata[-1] |= code_lengths[CODE_LENGTH_ORDER[i]]
+            code_huffman = huffman(code_lengths)
+            lengths = []
+            while len(lengths) < num_lit_len + 257 + num_distance + 1:
+                symbol = decode(bits, code_huffman)
+                if symbol < 16:
+                    metadata.append(symbol)
+                    lengths.append(symbol)
+                elif symbol == 16:
+                    extra = bits.read(2)
+                    metadata.append(16 + extra)
+                    lengths += [lengths[-1]] * (3 + extra)
+                elif symbol == 17:
+                    extra = bits.read(3)
+                    metadata.append(20 + extra)
+                    lengths += [0] * (3 + extra)
+                else:
+                    extra = bits.read(7)
+                    metadata.append(28 + extra)
+                    lengths += [0] * (11 + extra)
+            puffs.metadata(metadata)
+            lit_len = huffman(lengths[: num_lit_len + 257])
+            distance = huffman(lengths[num_lit_len + 257 :])
+        while True:
+            symbol = decode(bits, lit_len)
+            if symbol < 256:
+                puffs.literals.append(symbol)
+                out.append(symbol)
+            elif symbol == 256:
+                puffs.end_of_block()
+                break
+            else:
+                index = symbol - 257
+                length = LENGTH_BASES[index] + bits.read(LENGTH_EXTRA[index])
+                index = decode(bits, distance)
+                dist = DISTANCE_BASES[index] + bits.read(DISTANCE_EXTRA[index])
+                puffs.len_dist(length, dist)
+                for _ in range(length):
+                    out.append(out[-dist])
+    puffs.flush()
+    assert bytes(out) == zlib.decompress(data[offset // 8 :], -15)
+    return bytes(puffs.out), bits.position
+
+
+def puff_stream(data, deflate_offsets):
+    """Puff `data` with deflate streams at the byte-aligned `deflate_offsets`, returning the
+    puffed data and the `StreamInfo` fields. Other bits are kept as one puff byte per source
+    byte, shifted down when a deflate ends inside it."""
+    out, deflates, puffs, position = bytearray(), [], [], 0
+    for offset in deflate_offsets:
+        out += data[position // 8 : offset // 8]
+        puff, end = puff_deflate(data, offset)
+        deflates.append((offset, end - offset))
+        puffs.append((len(out), len(puff)))
+        out += puff
+        position = end
+        if position % 8:
+            out.append(data[position // 8] >> (position % 8))
+            position += 8 - position % 8
+    out += data[position // 8 :]
+    return bytes(out), deflates, puffs
+
+
+def varint(value):
+    out = bytearray()
+    while value >= 0x80:
+        out.append(value & 0x7F | 0x80)
+        value >>= 7
+    return bytes(out + bytes([value]))
+
+
+def field(number, value):
+    """A protobuf field: varint for integers, length-delimited for bytes."""
+    if isinstance(value, int):
+        return varint(number << 3) + varint(value)
+    return varint(number << 3 | 2) + varint(len(value)) + value
+
+
+def stream_info(deflates, puffs, puff_length):
+    extents = lambda number, extents: b"".join(
+        field(number, field(1, offset) + field(2, length)) for offset, length in extents
+    )
+    return extents(1, deflates) + extents(2, puffs) + field(3, puff_length)
+
+
+def deflate(data, level):
+    compressor = zlib.compressobj(level, zlib.DEFLATED, -15)
+    return compressor.compress(data) + compressor.flush()
+
+
+def archive(files):
+    """A container of raw bytes around deflate streams, returning it and the stream offsets."""
+    data, offsets = bytearray(), []
+    for name, contents, level in files:
+        data += b"FILE" + name + struct.pack("<I", len(contents))
+        offsets.append(len(data) * 8)
+        data += deflate(contents, level)
+    data += b"END"
+    return bytes(data), offsets
+
+
+def text(rng, words, count):
+    return " ".join(rng.choice(words) for _ in range(count)).encode()
+
+
+def brotli_stored(data):
+    """A brotli stream (RFC 7932) holding `data` in uncompressed meta-blocks of up to 64 KiB."""
+    out, bits, count = bytearray(), 0, 1  # WBITS = 16, a single 0 bit.
+
+    def flush():
+        nonlocal bits, count
+        out.extend((bits >> (8 * i)) & 0xFF for i in range((count + 7) // 8))
+        bits, count = 0, 0
+
+    for start in range(0, len(data), 1 << 16):
+        chunk = data[start : start + (1 << 16)]
+        # ISLAST = 0, MNIBBLES = 4, MLEN - 1 and ISUNCOMPRESSED = 1, then byte-aligned data.
+        bits |= ((len(chunk) - 1) << 3 | 1 << 19) << count
+        count += 20
+        flush()
+        out += chunk
+    # ISLAST = 1 and ISLASTEMPTY = 1.
+    bits |= 0b11 << count
+    count += 2
+    flush()
+    return bytes(out)
+
+
+def gen_puffin():
+    words = "the puffin patch moves deflate streams into a byte oriented form".split()
+    rng = random.Random(1)
+    manifest = text(rng, words, 1500)
+    binary = executable(random.Random(2), 4096)
+    small = b"short stream, compressed with fixed Huffman codes"
+    src, src_offsets = archive(
+        [(b"manifest", manifest, 6), (b"binary", binary, 0), (b"small", small, 9)]
+    )
+    rng = random.Random(3)
+    manifest = manifest[:3000] + text(rng, words, 200) + manifest[3500:]
+    binary = executable(random.Random(2), 4096, shift=0x20)
+    dst, dst_offsets = archive(
+        [(b"manifest", manifest, 9), (b"binary", binary, 0), (b"small", small[::-1], 9)]
+    )
+
+    puffed_src, src_deflates, src_puffs = puff_stream(src, src_offsets)
+    puffed_dst, dst_deflates, dst_puffs = puff_stream(dst, dst_offsets)
+    header = (
+        field(1, 1)
+        + field(2, stream_info(src_deflates, src_puffs, len(puffed_src)))
+        + field(3, stream_info(dst_deflates, dst_puffs, len(puffed_dst)))
+    )
+    # The puffed streams are diffed with bsdiff into a BSDF2 patch with brotli streams, as puffin
+    # does, though stored rather than compressed.
+    raw_patch = bsdiff_patch(puffed_src, puffed_dst, b"BSDF2\x02\x02\x02", brotli_stored)
+    patch = b"PUF1" + struct.pack(">I", len(header)) + header + raw_patch
+
+    out = FIXTURES / "puffin"
+    out.mkdir(parents=True, exist_ok=True)
+    (out / "source.bin").write_bytes(src)
+    (out / "target.bin").write_bytes(dst)
+    (out / "patch.puffin").write_bytes(patch)
+    print("puffin: target.bin sha256", hashlib.sha256(dst).hexdigest())
+
+
+# lz4diff, following AOSP's update_engine lz4diff patches.
+
+
+def liblz4():
+    lib = ctypes.CDLL(ctypes.util.find_library("lz4") or "liblz4.so.1")
+    lib.LZ4_versionString.restype = ctypes.c_char_p
+    return lib
+
+
+def lz4_compress(lib, data, target_size, level):
+    """Compress all of `data` into at most `target_size` bytes, with LZ4HC unless `level` is 0,
+    or return `None` if it does not fit."""
+    out = ctypes.create_string_buffer(target_size)
+    src_size = ctypes.c_int(len(data))
+    if level == 0:
+        written = lib.LZ4_compress_destSize(data, out, ctypes.byref(src_size), target_size)
+    else:
+        state = ctypes.create_string_buffer(lib.LZ4_sizeofStateHC())
+        written = lib.LZ4_compress_HC_destSize(
+            state, data, out, ctypes.byref(src_size), target_size, level
+        )
+    if written <= 0 or src_size.value != len(data):
+        return None
+    return out.raw[:written]
+
+
+def lz4_length(length):
+    """The bytes extending a token's 4-bit length field past 15."""
+    out = bytearray()
+    if length >= 15:
+        length -= 15
+        while length >= 255:
+            out.append(255)
+            length -= 255
+        out.append(length)
+    return bytes(out)
+
+
+def lz4_greedy(data):
+    """An LZ4 block from a plain greedy matcher, standing in for a compressor other than liblz4.
+    Like the format requires, the last 5 bytes are literals and no match starts in the last
+    12 bytes."""
+    out, last_seen, anchor, position = bytearray(), {}, 0, 0
+    while position + 12 <= len(data):
+        key = data[position : position + 4]
+        candidate = last_seen.get(key)
+        last_seen[key] = position
+        if candidate is None or position - candidate > 0xFFFF:
+            position += 1
+            continue
+        length = 4
+        while position + length < len(data) - 5 and data[candidate + length] == data[position + length]:
+            length += 1
+        literals = data[anchor:position]
+        out.append(min(len(literals), 15) << 4 | min(length - 4, 15))
+        out += lz4_length(len(literals)) + literals
+        out += struct.pack("<H", position - candidate) + lz4_length(length - 4)
+        position += length
+        anchor = position
+    literals = data[anchor:]
+    out.append(min(len(literals), 15) << 4)
+    return bytes(out + lz4_length(len(literals)) + literals)
+
+
+def lz4_blocks(lib, data, algo, level, block_size, encoders=None):
+    """EROFS-style blocks of `data`: each `block_size` cluster is compressed into half its size,
+    right-aligned after zero padding, or stored as is when it does not fit. `encoders` replaces
+    liblz4 for some blocks. Returns the blob and the `CompressionInfo` fields."""
+    encoders = encoders or {}
+    target_size = block_size // 2
+    blob, blocks = bytearray(), []
+    for index, offset in enumerate(range(0, len(data), block_size)):
+        chunk = data[offset : offset + block_size]
+        recompressed = lz4_compress(lib, chunk, target_size, level)
+        if recompressed is None:
+            blob += chunk
+            blocks.append((offset, len(chunk), len(chunk), b"", b""))
+            continue
+        compressed = encoders[index](chunk) if index in encoders else recompressed
+        assert len(compressed) <= target_size
+        compressed = bytes(target_size - len(compressed)) + compressed
+        recompressed = bytes(target_size - len(recompressed)) + recompressed
+        blob += compressed
+        # The patch records the hash of what liblz4 makes of the block, and a postfix patch
+        # when the actual block differs from it.
+        postfix = b""
+        if recompressed != compressed:
+            postfix = bsdiff_patch(recompressed, compressed, b"BSDF2\x01\x01\x01", bz2.compress)
+        sha256 = hashlib.sha256(recompressed).digest()
+        blocks.append((offset, len(chunk), target_size, sha256, postfix))
+    info = b""
+    for offset, length, compressed_length, sha256, postfix in blocks:
+        block = field(1, offset) + field(2, length) + field(3, compressed_length)
+        if sha256:
+            block += field(4, sha256)
+        if postfix:
+            block += field(5, postfix)
+        info += field(1, block)
+    info += field(2, 1)
+    info += field(3, field(1, algo) + (field(2, level) if level else b""))
+    return bytes(blob), info
+
+
+def gen_lz4diff():
+    lib = liblz4()
+    block_size = 4096
+    words = "an erofs image compresses files into lz4 blocks".split()
+    old = text(random.Random(5), words, 3000)[: 4 * block_size]
+    rng = random.Random(6)
+    old += bytes(rng.randrange(256) for _ in range(block_size))
+    new = bytearray(old)
+    new[5000:5000] = b"new data in the second cluster " * 4
+    new[9000:9200] = text(random.Random(7), words, 60)[:200]
+    new = bytes(new[: 5 * block_size])
+
+    # The source is compressed with LZ4 and the target with LZ4HC, except for its second block,
+    # so the patch needs a postfix patch for it.
+    src, src_info = lz4_blocks(lib, old, 1, 0, block_size)
+    dst, dst_info = lz4_blocks(lib, new, 2, 9, block_size, {1: lz4_greedy})
+
+    # The inner patch type is bsdiff, the default, so it is left out.
+    header = field(1, src_info) + field(2, dst_info)
+    inner_patch = bsdiff_patch(old, new, b"BSDF2\x01\x01\x01", bz2.compress)
+    patch = b"LZ4DIFF" + struct.pack(">II", 1, len(header)) + header + inner_patch
+
+    out = FIXTURES / "lz4diff"
+    out.mkdir(parents=True, exist_ok=True)
+    (out / "source.bin").write_bytes(src)
+    (out / "target.bin").write_bytes(dst)
+    (out / "patch.lz4diff").write_bytes(patch)
+    print("lz4diff: target.bin sha256", hashlib.sha256(dst).hexdigest())
+
+
+# Zucchini, following Chromium's zucchini: references of an x86-64 ELF shared object, found the
+# way its ELF disassembler does, and of a DEX file, recorded as it is assembled, with
+# equivalences from a greedy matcher.
+
+OFFSET_BOUND = 0x7FFFFFFF
+ELF_X64 = 4
+RELOC, ABS32, REL32 = 0, 1, 2
+# Reference groups as (type, pool, width).
+ELF_X64_GROUPS = [(RELOC, 0, 8), (ABS32, 1, 8), (REL32, 2, 4)]
+
+
+class Translator:
+    """Offset-RVA translation of non-overlapping units; dangling RVAs get fake offsets."""
+
+    def __init__(self, units):
+        self.units = sorted(units, key=lambda unit: unit[2])
+        self.fake_begin = max(offset + size for offset, size, _, _ in self.units)
+
+    def rva_to_offset(self, rva):
+        for offset, offset_size, unit_rva, rva_size in self.units:
+            if unit_rva <= rva < unit_rva + rva_size:
+                delta = rva - unit_rva
+                return offset + delta if delta < offset_size else self.fake_begin + rva
+        return None
+
+    def offset_to_rva(self, offset):
+        if offset >= self.fake_begin:
+            rva = offset - self.fake_begin
+            dangling = any(
+                unit_rva + offset_size <= rva < unit_rva + rva_size
+                for _, offset_size, unit_rva, rva_size in self.units
+            )
+            return rva if dangling else None
+        for unit_offset, offset_size, unit_rva, _ in self.units:
+            if unit_offset <= offset < unit_offset + offset_size:
+                return offset - unit_offset + unit_rva
+        return None
+
+
+def elf64_sections(data):
+    shoff, = struct.unpack_from("<Q", data, 40)
+    shnum, = struct.unpack_from("<H", data, 60)
+    keys = ("type", "flags", "addr", "offset", "size", "link", "info", "align", "entsize")
+    return [
+        dict(zip(keys, struct.unpack_from("<IQQQQIIQQ", data, shoff + 64 * index + 4)))
+        for index in range(shnum)
+    ]
+
+
+def rel32_x64(data, lo, hi):
+    """Candidate rel32 operands in data[lo:hi]: (cursor, operand location, may leave section)."""
+    cursor = lo
+    while cursor < hi:
+        code = data[cursor:hi]
+        found = None
+        if len(code) >= 5 and code[0] in (0xE8, 0xE9):
+            found = (1, False)
+        elif len(code) >= 6 and code[0] == 0x0F and code[1] & 0xF0 == 0x80:
+            found = (2, False)
+        elif len(code) >= 6 and (
+            (code[0] == 0xFF and code[1] in (0x15, 0x25))
+            or (code[0] in (0x89, 0x8B, 0x8D) and code[1] & 0xC7 == 0x05)
+        ):
+            found = (2, True)
+        elif (
+            len(code) >= 7
+            and (code[0] & 0xF2 == 0x40 or code[0] == 0x66)
+            and code[1] in (0x89, 0x8B, 0x8D)
+            and code[2] & 0xC7 == 0x05
+        ):
+            found = (3, True)
+        if found:
+            accepted = yield cursor + found[0], found[1]
+            cursor = cursor + found[0] + 4 if accepted else cursor + 1
+        else:
+            cursor += 1
+
+
+def elf_x64_references(data):
+    """References of each type as {location: target}."""
+    sections = elf64_sections(data)
+    units = []
+    for section in sections:
+        nobits = section["type"] == 8
+        if section["size"] and section["addr"] and not (nobits and section["flags"] & 0x400):
+            size = 0 if nobits else section["size"]
+            units.append((section["offset"], size, section["addr"], section["size"]))
+    translator = Translator(units)
+
+    relocs = {}
+    for section in sorted(sections, key=lambda section: section["offset"]):
+        if section["type"] not in (4, 9) or not section["addr"] or not section["size"]:
+            continue
+        end = section["offset"] + section["size"]
+        for entry in range(section["offset"], end, section["entsize"]):
+            r_offset, r_info = struct.unpack_from("<QQ", data, entry)
+            target = translator.rva_to_offset(r_offset)
+            if r_info & 0xFFFFFFFF == 8 and target is not None:
+                relocs[entry] = target
+
+    abs32 = {}
+    for location in sorted(relocs.values()):
+        if location + 8 > len(data):
+            continue
+        target = translator.rva_to_offset(struct.unpack_from("<Q", data, location)[0])
+        if target is not None and all(location >= other + 8 for other in abs32):
+            abs32[location] = target
+
+    rel32 = {}
+    for section in sections:
+        if section["type"] != 1 or not section["flags"] & 0x4 or not section["addr"]:
+            continue
+        begin, end = section["offset"], section["offset"] + section["size"]
+        bodies = [(location, location + 8) for location in abs32 if begin <= location < end]
+        gaps, lo = [], begin
+        for body_begin, body_end in sorted(bodies):
+            gaps.append((lo, body_begin))
+            lo = body_end
+        gaps.append((lo, end))
+        for lo, hi in gaps:
+            scanner = rel32_x64(data, lo, hi)
+            candidate = next(scanner, None)
+            while candidate:
+                location, may_leave = candidate
+                rva = translator.offset_to_rva(location)
+                disp, = struct.unpack_from("<i", data, location)
+                target_rva = (rva + 4 + disp) & 0xFFFFFFFF
+                target = translator.rva_to_offset(target_rva)
+                accepted = target is not None and (
+                    may_leave or section["addr"] <= target_rva < section["addr"] + section["size"]
+                )
+                if accepted:
+                    rel32[location] = target
+                candidate = next_or_none(scanner, accepted)
+    return {RELOC: relocs, ABS32: abs32, REL32: rel32}
+
+
+def next_or_none(generator, value):
+    try:
+        return generator.send(value)
+    except StopIteration:
+        return None
+
+
+def tokens(data, references, groups):
+    """Bytes, with reference bodies replaced by markers of their type and position."""
+    out = list(data)
+    for reference_type, _, width in groups:
+        for location in references.get(reference_type, {}):
+            for index in range(width):
+                out[location + index] = 256 + 16 * reference_type + index
+    return out
+
+
+def greedy_equivalences(old, new, min_length=8):
+    """Longest token matches for each new position, in new order, as (src, dst, length)."""
+    index = {}
+    for position in range(len(old) - min_length + 1):
+        index.setdefault(tuple(old[position : position + min_length]), []).append(position)
+    equivalences, dst = [], 0
+    while dst + min_length <= len(new):
+        best_src, best_length = None, 0
+        for src in index.get(tuple(new[dst : dst + min_length]), [])[:64]:
+            length = 0
+            limit = min(len(old) - src, len(new) - dst)
+            while length < limit and old[src + length] == new[dst + length]:
+                length += 1
+            if length > best_length:
+                best_src, best_length = src, length
+        if best_src is None:
+            dst += 1
+            continue
+        equivalences.append([best_src, dst, best_length])
+        dst += best_length
+    return equivalences
+
+
+def merge_equivalences(equivalences, old, new, max_gap=16):
+    """Join equivalences separated by the same few plain bytes in both images; the bytes that
+    differ become raw deltas."""
+    merged = []
+    for src, dst, length in equivalences:
+        if merged:
+            last = merged[-1]
+            gap = dst - (last[1] + last[2])
+            if (
+                0 < gap <= max_gap
+                and src - (last[0] + last[2]) == gap
+                and all(token < 256 for token in old[src - gap : src] + new[dst - gap : dst])
+            ):
+                last[2] += gap + length
+                continue
+        merged.append([src, dst, length])
+    return merged
+
+
+def trim_equivalences(equivalences, old, new):
+    """Keep equivalences from cutting reference bodies."""
+    trimmed = []
+    for src, dst, length in equivalences:
+        end = src + length
+        while src < end and old[src] >= 256 and (old[src] - 256) % 16:
+            src, dst = src + 1, dst + 1
+        while end > src and end < len(old) and old[end] >= 256 and (old[end] - 256) % 16:
+            end -= 1
+        if end - src >= 4:
+            trimmed.append((src, dst, end - src))
+    return trimmed
+
+
+def prune_by_source(equivalences):
+    """Chromium's OffsetMapper pruning: sort by source and resolve source overlaps."""
+    units = sorted([list(equivalence) for equivalence in equivalences], key=lambda unit: unit[0])
+    current = 0
+    while current < len(units):
+        src_end = units[current][0] + units[current][2]
+        following = current + 1
+        reaper = False
+        while following < len(units) and units[following][0] < src_end:
+            if units[current][2] < units[following][2]:
+                units[current][2] -= src_end - units[following][0]
+                reaper = True
+                break
+            following += 1
+        for reduced in units[current + 1 : following]:
+            if reaper:
+                reduced[2] = 0
+            else:
+                delta = src_end - reduced[0]
+                reduced[2] -= min(reduced[2], delta)
+                reduced[0] += delta
+                reduced[1] += delta
+        current = following if reaper else current + 1
+    return [unit for unit in units if unit[2]]
+
+
+def forward_project(units, offset):
+    for src, dst, length in units:
+        if src <= offset < src + length:
+            return offset - src + dst
+    return None
+
+
+def extended_forward_project(units, offset, old_size, new_size):
+    if offset >= old_size:
+        delta = offset - old_size
+        return delta + new_size if delta < OFFSET_BOUND - new_size else OFFSET_BOUND - 1
+    def distance(unit):
+        src, _, length = unit
+        return max(src - offset, offset - (src + length) + 1, 0)
+
+    # The nearest source block, the lower one on ties.
+    src, dst, _ = min(units, key=distance)
+    return (offset - src + dst) & 0xFFFFFFFF
+
+
+def key_for_nearest_offset(pool, offset):
+    key = bisect.bisect_left(pool, offset)
+    if key and (key == len(pool) or pool[key] - offset >= offset - pool[key - 1]):
+        key -= 1
+    return key
+
+
+def zigzag(value):
+    return varint(value << 1 if value >= 0 else (-value - 1) << 1 | 1)
+
+
+def buffer(data):
+    return struct.pack("<I", len(data)) + bytes(data)
+
+
+def zucchini_patch(old, new, exe_type, groups, old_references, new_references):
+    """A single-element Zucchini patch from old to new, given references of each type as
+    {location: target}."""
+    old_tokens = tokens(old, old_references, groups)
+    new_tokens = tokens(new, new_references, groups)
+    equivalences = greedy_equivalences(old_tokens, new_tokens)
+    equivalences = merge_equivalences(equivalences, old_tokens, new_tokens)
+    equivalences = trim_equivalences(equivalences, old_tokens, new_tokens)
+
+    src_skip, dst_skip, copy_count, extra = bytearray(), bytearray(), bytearray(), bytearray()
+    raw_offsets, raw_diffs = bytearray(), bytearray()
+    src_end = dst_end = copy_offset = compensation = 0
+    for src, dst, length in equivalences:
+        src_skip += zigzag(src - src_end)
+        dst_skip += varint(dst - dst_end)
+        copy_count += varint(length)
+        extra += new[dst_end:dst]
+        for index in range(length):
+            diff = (new[dst + index] - old[src + index]) & 0xFF
+            if diff and new_tokens[dst + index] < 256:
+                raw_offsets += varint(copy_offset + index - compensation)
+                raw_diffs.append(diff)
+                compensation = copy_offset + index + 1
+        src_end, dst_end, copy_offset = src + length, dst + length, copy_offset + length
+    extra += new[dst_end:]
+
+    units = prune_by_source(equivalences)
+    reference_deltas, pools = bytearray(), []
+    for pool_tag in sorted({pool for _, pool, _ in groups}):
+        pool_types = [reference_type for reference_type, pool, _ in groups if pool == pool_tag]
+        old_targets = [
+            target
+            for reference_type in pool_types
+            for target in old_references.get(reference_type, {}).values()
+        ]
+        projected = {forward_project(units, target) for target in old_targets} - {None}
+        corrections = []
+        for reference_type in pool_types:
+            old_refs = old_references.get(reference_type, {})
+            new_refs = new_references.get(reference_type, {})
+            for src, dst, length in equivalences:
+                for location in sorted(old_refs):
+                    if src <= location < src + length:
+                        new_target = new_refs[location - src + dst]
+                        corrections.append((old_refs[location], new_target))
+        extra_targets = sorted({target for _, target in corrections} - projected)
+        pool = sorted(projected | set(extra_targets))
+        for old_target, new_target in corrections:
+            projected_target = extended_forward_project(units, old_target, len(old), len(new))
+            key = key_for_nearest_offset(pool, projected_target)
+            reference_deltas += zigzag(pool.index(new_target) - key)
+        if extra_targets:
+            stream, previous = bytearray(), 0
+            for target in extra_targets:
+                stream += varint(target - previous)
+                previous = target + 1
+            pools.append(bytes([pool_tag]) + buffer(stream))
+
+    patch = b"Zucc" + struct.pack(
+        "<HHIIIII", 1, 0, len(old), zlib.crc32(old), len(new), zlib.crc32(new), 1
+    )
+    patch += struct.pack("<IIIIIH", 0, len(old), 0, len(new), exe_type, 1)
+    for stream in (src_skip, dst_skip, copy_count, extra, raw_offsets, raw_diffs, reference_deltas):
+        patch += buffer(stream)
+    patch += struct.pack("<I", len(pools)) + b"".join(pools)
+    references = sum(len(locations) for locations in old_references.values())
+    print(
+        f"zucchini: {len(equivalences)} equivalences, {len(raw_diffs)} raw deltas,"
+        f" {references} old references, {len(pools)} extra target pools"
+    )
+    return patch
+
+
+# DEX reference types of Chromium's DisassemblerDex, by type tag, with their pools and widths.
+DEX = 7
+DEX_POOLS = [0] * 7 + [1] * 8 + [2] * 2 + [3] * 3 + [4] * 4 + [5, 6, 7, 7, 8] + [9] * 4
+DEX_POOLS += [10, 11, 11, 11, 12, 13, 14, 15, 16]
+DEX_WIDTHS = [4, 4, 4, 4, 4, 2, 4, 4, 2, 2, 2, 4, 4, 2, 2, 2, 2, 2, 2, 4, 2, 2, 4, 4, 2, 2]
+DEX_WIDTHS += [4] * 8 + [1, 2, 4] + [4] * 5
+DEX_GROUPS = [(tag, pool, width) for tag, (pool, width) in enumerate(zip(DEX_POOLS, DEX_WIDTHS))]
+TYPE_DESCRIPTOR, PROTO_SHORTY, FIELD_NAME, METHOD_NAME, CLASS_SOURCE_FILE, CODE_STRING = range(6)
+PROTO_RETURN_TYPE, FIELD_CLASS, FIELD_TYPE, METHOD_CLASS = range(7, 11)
+CLASS_TYPE, CLASS_SUPERCLASS = 11, 12
+TYPE_LIST_TYPE, CODE_TYPE, METHOD_PROTO, CODE_FIELD, ANNOTATED_FIELD = 13, 14, 16, 17, 19
+CODE_METHOD, ANNOTATED_METHOD, ANNOTATED_PARAMETERS = 20, 22, 23
+PROTO_PARAMETERS, CLASS_INTERFACES, PARAMETER_ANNOTATIONS, REF_LIST_SET = range(26, 30)
+CLASS_ANNOTATIONS, FIELD_ANNOTATIONS, METHOD_ANNOTATIONS, CLASS_DATA = range(30, 34)
+CODE_REL8, CODE_REL16, CODE_REL32, STRING_DATA, SET_ANNOTATION = range(34, 39)
+CLASS_ANNOTATIONS_DIRECTORY = 40
+NO_INDEX = 0xFFFFFFFF
+
+# Code units and opcodes of the instructions the assembler knows.
+DEX_INSTRUCTIONS = {
+    "const/4": (1, 0x12), "const-string": (2, 0x1A), "check-cast": (2, 0x1F),
+    "new-instance": (2, 0x22), "packed-switch": (3, 0x2B), "goto": (1, 0x28),
+    "goto/16": (2, 0x29), "if-eqz": (2, 0x38), "sget-object": (2, 0x62),
+    "invoke-virtual": (3, 0x6E), "invoke-direct": (3, 0x70), "invoke-static": (3, 0x71),
+    "return-void": (1, 0x0E), "label": (0, None),
+}
+
+
+def shorty(proto):
+    return "".join("L" if kind[0] in "L[" else kind for kind in (proto[0], *proto[1]))
+
+
+class DexIds:
+    """Sorted string, type, proto, field and method ids, and where their items are."""
+
+    def __init__(self, classes, extra):
+        strings, types, protos, fields, methods = set(), set(), set(), set(), set()
+        for kind, key in extra:
+            {"type": types, "field": fields, "method": methods, "string": strings}[kind].add(key)
+        for cls in classes:
+            types.update([cls["name"], cls["super"], *cls["interfaces"]])
+            strings.add(cls["source"])
+            fields.update((cls["name"], *field) for field in cls["fields"])
+            for method in cls["methods"]:
+                methods.add((cls["name"], method["name"], method["proto"]))
+                for insn in method["code"]:
+                    if insn[0] == "const-string":
+                        strings.add(insn[2])
+                    elif insn[0] in ("check-cast", "new-instance"):
+                        types.add(insn[2])
+                    elif insn[0] == "sget-object":
+                        fields.add(insn[2])
+                    elif insn[0].startswith("invoke"):
+                        methods.add(insn[2])
+        for owner, _, kind in fields:
+            types.update([owner, kind])
+        for owner, _, proto in methods:
+            types.add(owner)
+            protos.add(proto)
+        for return_type, parameters in protos:
+            types.update([return_type, *parameters])
+        strings |= types | {name for _, name, _ in fields | methods}
+        strings |= {shorty(proto) for proto in protos}
+
+        self.strings = sorted(strings)
+        self.types = sorted(types)
+        self.protos = sorted(protos, key=lambda p: (self.type(p[0]), self.type_list(p[1])))
+        self.fields = sorted(
+            fields, key=lambda f: (self.type(f[0]), self.string(f[1]), self.type(f[2]))
+        )
+        self.methods = sorted(
+            methods, key=lambda m: (self.type(m[0]), self.string(m[1]), self.protos.index(m[2]))
+        )
+        self.offsets = {}
+        offset = 0x70
+        for kind, items, size in (
+            ("string", self.strings, 4),
+            ("type", self.types, 4),
+            ("proto", self.protos, 12),
+            ("field", self.fields, 8),
+            ("method", self.methods, 8),
+        ):
+            self.offsets[kind] = (offset, size)
+            offset += size * len(items)
+        self.class_defs = offset
+
+    def string(self, value):
+        return self.strings.index(value)
+
+    def type(self, value):
+        return self.types.index(value)
+
+    def type_list(self, values):
+        return [self.type(value) for value in values]
+
+    def index(self, kind, key):
+        return getattr(self, kind + "s").index(key)
+
+    def offset(self, kind, key):
+        base, size = self.offsets[kind]
+        return base + size * self.index(kind, key)
+
+
+def dex_code(code, ids):
+    """Code units of a method body, and its references as (insns offset, type, target), with
+    code targets relative to the insns."""
+    labels, unit = {}, 0
+    for insn in code:
+        if insn[0] == "label":
+            labels[insn[1]] = unit
+        unit += DEX_INSTRUCTIONS[insn[0]][0]
+    payload = unit + unit % 2
+    units, payloads, references = [], [], []
+    for insn in code:
+        op, at = insn[0], len(units)
+        opcode = DEX_INSTRUCTIONS[op][1]
+        if op == "const/4":
+            units += [opcode | (insn[2] & 0xF) << 12 | insn[1] << 8]
+        elif op in ("const-string", "check-cast", "new-instance", "sget-object"):
+            kind, tag = {
+                "const-string": ("string", CODE_STRING),
+                "check-cast": ("type", CODE_TYPE),
+                "new-instance": ("type", CODE_TYPE),
+                "sget-object": ("field", CODE_FIELD),
+            }[op]
+            units += [opcode | insn[1] << 8, ids.index(kind, insn[2])]
+            references.append((2 * at + 2, tag, ids.offset(kind, insn[2])))
+        elif op.startswith("invoke"):
+            registers = insn[1] + [0] * (5 - len(insn[1]))
+            c, d, e, f, g = registers
+            units += [opcode | len(insn[1]) << 12 | g << 8, ids.index("method", insn[2])]
+            units += [c | d << 4 | e << 8 | f << 12]
+            references.append((2 * at + 2, CODE_METHOD, ids.offset("method", insn[2])))
+        elif op == "goto":
+            units += [opcode | (labels[insn[1]] - at & 0xFF) << 8]
+            references.append((2 * at + 1, CODE_REL8, 2 * labels[insn[1]]))
+        elif op in ("goto/16", "if-eqz"):
+            register = insn[1] if op == "if-eqz" else 0
+            units += [opcode | register << 8, labels[insn[-1]] - at & 0xFFFF]
+            references.append((2 * at + 2, CODE_REL16, 2 * labels[insn[-1]]))
+        elif op == "packed-switch":
+            units += [opcode | insn[1] << 8, payload - at & 0xFFFF, (payload - at) >> 16]
+            references.append((2 * at + 2, CODE_REL32, 2 * payload))
+            payloads += [0x0100, len(insn[2]), 0, 0]
+            for label in insn[2]:
+                relative = labels[label] - at & 0xFFFFFFFF
+                payloads += [relative & 0xFFFF, relative >> 16]
+            payload += 4 + 2 * len(insn[2])
+        elif op == "return-void":
+            units += [opcode]
+    if payloads:
+        units += [0] * (len(units) % 2) + payloads
+    return units, references
+
+
+def dex_file(classes, extra_ids=(), annotation="Lcom/example/Keep;"):
+    """A DEX file of classes, and its references of each type as {location: target}."""
+    ids = DexIds(classes, [("type", annotation), *extra_ids])
+    data = bytearray(ids.class_defs + 32 * len(classes))
+    references = {}
+    sections = {}
+
+    def reference(tag, location, target):
+        if target not in (0, NO_INDEX):
+            references.setdefault(tag, {})[location] = target
+
+    def section(kind, count=1, align=True):
+        if align:
+            data.extend(bytes(-len(data) % 4))
+        if kind not in sections:
+            sections[kind] = [len(data), 0]
+        sections[kind][1] += count
+        return len(data)
+
+    def put(fmt, *values):
+        data.extend(struct.pack("<" + fmt, *values))
+
+    # Code items, in class and method order.
+    code_offsets = {}
+    for cls in classes:
+        for method in cls["methods"]:
+            if method["code"] is None:
+                continue
+            units, code_references = dex_code(method["code"], ids)
+            offset = section(0x2001)
+            code_offsets[cls["name"], method["name"]] = offset
+            put("HHHHII", 4, method["ins"], 2, 0, 0, len(units))
+            put(f"{len(units)}H", *units)
+            for location, tag, target in code_references:
+                if tag in (CODE_REL8, CODE_REL16, CODE_REL32):
+                    target += offset + 16
+                reference(tag, offset + 16 + location, target)
+
+    # Annotation items: one marker annotation, referred to by every annotation set.
+    annotation_offset = section(0x2004, align=False)
+    put("B", 1)
+    data.extend(varint(ids.type(annotation)) + varint(0))
+
+    def annotation_set():
+        offset = section(0x1003)
+        put("II", 1, annotation_offset)
+        reference(SET_ANNOTATION, offset + 4, annotation_offset)
+        return offset
+
+    # Annotation sets, then parameter annotation set ref lists, then annotations directories,
+    # each section contiguous.
+    annotated = [cls for cls in classes if any(cls["annotated"].values())]
+    sets = {
+        cls["name"]: {key: [annotation_set() for _ in ks] for key, ks in cls["annotated"].items()}
+        for cls in annotated
+    }
+    ref_lists = {}
+    for cls in annotated:
+        ref_lists[cls["name"]] = []
+        for set_offset in sets[cls["name"]]["parameters"]:
+            ref_lists[cls["name"]].append(section(0x1002))
+            put("II", 1, set_offset)
+            reference(REF_LIST_SET, ref_lists[cls["name"]][-1] + 4, set_offset)
+    directories = {}
+    for cls in annotated:
+        keys, offsets = cls["annotated"], sets[cls["name"]]
+        directory = directories[cls["name"]] = section(0x2006)
+        class_set = offsets["class"][0] if offsets["class"] else 0
+        put("IIII", class_set, *(len(keys[key]) for key in ("fields", "methods", "parameters")))
+        reference(CLASS_ANNOTATIONS, directory, class_set)
+        entries = [
+            ("field", ANNOTATED_FIELD, FIELD_ANNOTATIONS, keys["fields"], offsets["fields"]),
+            ("method", ANNOTATED_METHOD, METHOD_ANNOTATIONS, keys["methods"], offsets["methods"]),
+            (
+                "method",
+                ANNOTATED_PARAMETERS,
+                PARAMETER_ANNOTATIONS,
+                keys["parameters"],
+                ref_lists[cls["name"]],
+            ),
+        ]
+        for kind, id_tag, set_tag, ids_of, targets in entries:
+            for key, target in sorted(zip(ids_of, targets), key=lambda e: ids.index(kind, e[0])):
+                reference(id_tag, len(data), ids.offset(kind, key))
+                reference(set_tag, len(data) + 4, target)
+                put("II", ids.index(kind, key), target)
+
+    # Type lists of interfaces and proto parameters.
+    type_lists = {}
+    interfaces = {tuple(cls["interfaces"]) for cls in classes}
+    for values in sorted(interfaces | {parameters for _, parameters in ids.protos}):
+        if values:
+            type_lists[values] = section(0x1001)
+            put("I", len(values))
+            for value in values:
+                reference(TYPE_LIST_TYPE, len(data), ids.offset("type", value))
+                put("H", ids.type(value))
+
+    string_data = []
+    for value in ids.strings:
+        string_data.append(section(0x2002, align=False))
+        data.extend(varint(len(value)) + value.encode() + b"\0")
+
+    class_data = {}
+    for cls in classes:
+        class_data[cls["name"]] = section(0x2000, align=False)
+        fields = sorted(cls["fields"], key=lambda field: ids.index("field", (cls["name"], *field)))
+        direct = [method for method in cls["methods"] if not method["virtual"]]
+        virtual = [method for method in cls["methods"] if method["virtual"]]
+        static = [field for field in fields if field[0].isupper()]
+        instance = [field for field in fields if not field[0].isupper()]
+        for count in (len(static), len(instance), len(direct), len(virtual)):
+            data.extend(varint(count))
+        for group, access in ((static, 0x19), (instance, 0x2)):
+            previous = 0
+            for field in group:
+                index = ids.index("field", (cls["name"], *field))
+                data.extend(varint(index - previous) + varint(access))
+                previous = index
+        for group in (direct, virtual):
+            previous = 0
+            key = lambda method: ids.index("method", (cls["name"], method["name"], method["proto"]))
+            for method in sorted(group, key=key):
+                index = key(method)
+                code = code_offsets.get((cls["name"], method["name"]), 0)
+                data.extend(varint(index - previous) + varint(method["access"]) + varint(code))
+                previous = index
+
+    map_offset = section(0x1000)
+
+    # Ids and class definitions, now that the data they point to is placed.
+    string_base, _ = ids.offsets["string"]
+    for index, offset in enumerate(string_data):
+        struct.pack_into("<I", data, string_base + 4 * index, offset)
+        reference(STRING_DATA, string_base + 4 * index, offset)
+    type_base, _ = ids.offsets["type"]
+    for index, value in enumerate(ids.types):
+        location = type_base + 4 * index
+        struct.pack_into("<I", data, location, ids.string(value))
+        reference(TYPE_DESCRIPTOR, location, ids.offset("string", value))
+    proto_base, _ = ids.offsets["proto"]
+    for index, proto in enumerate(ids.protos):
+        location = proto_base + 12 * index
+        parameters = type_lists.get(proto[1], 0)
+        values = ids.string(shorty(proto)), ids.type(proto[0]), parameters
+        struct.pack_into("<III", data, location, *values)
+        reference(PROTO_SHORTY, location, ids.offset("string", shorty(proto)))
+        reference(PROTO_RETURN_TYPE, location + 4, ids.offset("type", proto[0]))
+        reference(PROTO_PARAMETERS, location + 8, parameters)
+    field_base, _ = ids.offsets["field"]
+    for index, (owner, name, kind) in enumerate(ids.fields):
+        location = field_base + 8 * index
+        struct.pack_into("<HHI", data, location, ids.type(owner), ids.type(kind), ids.string(name))
+        reference(FIELD_CLASS, location, ids.offset("type", owner))
+        reference(FIELD_TYPE, location + 2, ids.offset("type", kind))
+        reference(FIELD_NAME, location + 4, ids.offset("string", name))
+    method_base, _ = ids.offsets["method"]
+    for index, (owner, name, proto) in enumerate(ids.methods):
+        location = method_base + 8 * index
+        values = ids.type(owner), ids.protos.index(proto), ids.string(name)
+        struct.pack_into("<HHI", data, location, *values)
+        reference(METHOD_CLASS, location, ids.offset("type", owner))
+        reference(METHOD_PROTO, location + 2, ids.offset("proto", proto))
+        reference(METHOD_NAME, location + 4, ids.offset("string", name))
+    for index, cls in enumerate(classes):
+        location = ids.class_defs + 32 * index
+        interfaces = type_lists.get(tuple(cls["interfaces"]), 0)
+        directory = directories.get(cls["name"], 0)
+        values = [ids.type(cls["name"]), 1, ids.type(cls["super"]), interfaces]
+        values += [ids.string(cls["source"]), directory, class_data[cls["name"]], 0]
+        struct.pack_into("<8I", data, location, *values)
+        reference(CLASS_TYPE, location, ids.offset("type", cls["name"]))
+        reference(CLASS_SUPERCLASS, location + 8, ids.offset("type", cls["super"]))
+        reference(CLASS_INTERFACES, location + 12, interfaces)
+        reference(CLASS_SOURCE_FILE, location + 16, ids.offset("string", cls["source"]))
+        reference(CLASS_ANNOTATIONS_DIRECTORY, location + 20, directory)
+        reference(CLASS_DATA, location + 24, class_data[cls["name"]])
+
+    items = [
+        (0x0000, 1, 0),
+        (0x0001, len(ids.strings), string_base),
+        (0x0002, len(ids.types), type_base),
+        (0x0003, len(ids.protos), proto_base),
+        (0x0004, len(ids.fields), field_base),
+        (0x0005, len(ids.methods), method_base),
+        (0x0006, len(classes), ids.class_defs),
+    ]
+    items += sorted(
+        ((kind, count, offset) for kind, (offset, count) in sections.items()),
+        key=lambda item: item[2],
+    )
+    put("I", len(items))
+    for kind, count, offset in items:
+        put("HHII", kind, 0, count, offset)
+
+    data_offset = ids.class_defs + 32 * len(classes)
+    data[:8] = b"dex\n035\0"
+    struct.pack_into("<II", data, 32, len(data), 0x70)
+    struct.pack_into("<IIII", data, 40, 0x12345678, 0, 0, map_offset)
+    counts = [
+        (len(ids.strings), string_base), (len(ids.types), type_base),
+        (len(ids.protos), proto_base), (len(ids.fields), field_base),
+        (len(ids.methods), method_base), (len(classes), ids.class_defs),
+        (len(data) - data_offset, data_offset),
+    ]
+    struct.pack_into("<14I", data, 56, *(value for pair in counts for value in pair))
+    data[12:32] = hashlib.sha1(data[32:]).digest()
+    struct.pack_into("<I", data, 8, zlib.adler32(data[12:]))
+    return bytes(data), references
+
+
+def dex_program(new):
+    """Two classes whose methods print, branch, switch and call each other; the new version
+    adds strings, a field and a method, which shifts most ids."""
+    rng = random.Random(7)
+    greeter, util, string = "Lcom/example/Greeter;", "Lcom/example/Util;", "Ljava/lang/String;"
+    builder = "Ljava/lang/StringBuilder;"
+    println = ("Ljava/io/PrintStream;", "println", ("V", (string,)))
+    out = ("Ljava/lang/System;", "out", "Ljava/io/PrintStream;")
+    tag = (greeter, "TAG", string)
+    log = (util, "log", ("V", (string,)))
+    helpers = [(util, f"helper{index}", ("V", ())) for index in range(12)]
+    messages = [f"{rng.choice(['Hello', 'Goodbye', 'Welcome'])} number {n}" for n in range(48)]
+    if new:
+        messages += ["Aloha from the new version", "Bonjour number 7"]
+
+    labels = iter(range(1000))
+
+    def statement(kind, message, helper):
+        label = f"L{next(labels)}"
+        if kind == 0:
+            return [("sget-object", 0, out), ("const-string", 1, message),
+                    ("invoke-virtual", [0, 1], println)]
+        if kind == 1:
+            return [("invoke-static", [], helper)]
+        if kind == 2:
+            return [("sget-object", 0, tag), ("invoke-static", [0], log)]
+        if kind == 3:
+            return [("new-instance", 0, builder),
+                    ("invoke-direct", [0], (builder, "<init>", ("V", ()))),
+                    ("const-string", 1, message),
+                    ("invoke-virtual", [0, 1], (builder, "append", (builder, (string,))))]
+        if kind == 4:
+            return [("const/4", 0, 0), ("if-eqz", 0, label), ("invoke-static", [], helper),
+                    ("label", label)]
+        if kind == 5:
+            return [("goto/16", label), ("invoke-static", [], helper), ("label", label)]
+        end, first, second = label, f"L{next(labels)}", f"L{next(labels)}"
+        return [("const/4", 0, 1), ("packed-switch", 0, [first, second]), ("goto", end),
+                ("label", first), ("invoke-static", [], helper), ("goto", end),
+                ("label", second), ("sget-object", 0, tag), ("invoke-static", [0], log),
+                ("label", end)]
+
+    def method(name, proto, count, access=0x9, virtual=False, ins=0):
+        code = []
+        for _ in range(count):
+            kind, message, helper = rng.randrange(7), rng.choice(messages[:48]), rng.choice(helpers)
+            code += statement(kind, message, helper)
+        return {"name": name, "proto": proto, "access": access, "virtual": virtual, "ins": ins,
+                "code": code + [("return-void",)]}
+
+    greeter_methods = [
+        {"name": "<init>", "proto": ("V", ()), "access": 0x10001, "virtual": False, "ins": 1,
+         "code": [("invoke-direct", [3], ("Ljava/lang/Object;", "<init>", ("V", ()))),
+                  ("return-void",)]},
+    ]
+    greeter_methods += [method(f"greet{index}", ("V", ()), 6) for index in range(16)]
+    greeter_methods.append(method("run", ("V", ()), 4, access=0x1, virtual=True, ins=1))
+    util_methods = [method(name, proto, 3) for _, name, proto in helpers]
+    util_methods.append(method("log", log[2], 2, ins=1))
+    if new:
+        greeter_methods[5]["code"][:0] = statement(0, messages[-2], helpers[3])
+        greeter_methods[9]["code"][:0] = statement(3, messages[-1], helpers[8])
+        trace = (util, "trace", ("V", (string, "I")))
+        util_methods.append(method(trace[1], trace[2], 5, ins=2))
+        greeter_methods[11]["code"][:0] = [("invoke-static", [], trace)]
+    fields = [("TAG", string), ("count", "I"), ("name", string)]
+    if new:
+        fields.append(("greeting", string))
+    annotated = {"class": [greeter], "fields": [tag], "methods": [(greeter, "run", ("V", ()))],
+                 "parameters": []}
+    classes = [
+        {"name": greeter, "super": "Ljava/lang/Object;", "interfaces": ["Ljava/lang/Runnable;"],
+         "source": "Greeter.java", "fields": fields, "methods": greeter_methods,
+         "annotated": annotated},
+        {"name": util, "super": "Ljava/lang/Object;", "interfaces": [], "source": "Util.java",
+         "fields": [], "methods": util_methods,
+         "annotated": {"class": [], "fields": [], "methods": [], "parameters": [log]}},
+    ]
+    return dex_file(classes)
+
+
+def gen_zucchini():
+    out = FIXTURES / "zucchini"
+    for name in ("old", "new"):
+        if not (out / f"{name}.elf").exists():
+            subprocess.run(
+                ["gcc", "-O1", "-fPIC", "-shared", "-nostdlib", "-fvisibility=hidden",
+                 "-fno-asynchronous-unwind-tables", "-Wl,--build-id=none", "-Wl,--hash-style=gnu",
+                 "-o", out / f"{name}.elf", out / f"{name}.c"],
+                check=True,
+            )
+    old, new = (out / "old.elf").read_bytes(), (out / "new.elf").read_bytes()
+    patch = zucchini_patch(
+        old, new, ELF_X64, ELF_X64_GROUPS, elf_x64_references(old), elf_x64_references(new)
+    )
+    (out / "elf.zucchini").write_bytes(patch)
+    print("zucchini: new.elf sha256", hashlib.sha256(new).hexdigest())
+
+    (old, old_references), (new, new_references) = dex_program(False), dex_program(True)
+    (out / "old.dex").write_bytes(old)
+    (out / "new.dex").write_bytes(new)
+    patch = zucchini_patch(old, new, DEX, DEX_GROUPS, old_references, new_references)
+    (out / "dex.zucchini").write_bytes(patch)
+    print("zucchini: new.dex sha256", hashlib.sha256(new).hexdigest())
+
+
+# OTA zip whole-file signature, laid out the way `signapk -w` does: the zip with an empty comment
+# is signed up to its comment length field, and the comment then holds "signed by SignApk", the
+# PKCS#7 block and a 6-byte footer. signapk is not available here, so the detached PKCS#7
+# SignedData without signed attributes is made by `openssl cms` instead.
+
+
+def gen_zipsig():
+    out = FIXTURES / "zipsig"
+    out.mkdir(exist_ok=True)
+    for name in ("signer", "other"):
+        if not (out / f"{name}.pem").exists():
+            subprocess.run(
+                ["openssl", "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-sha256",
+                 "-days", "36500", "-subj", f"/CN=pay10ad-dumper test {name}",
+                 "-keyout", out / f"{name}.key", "-out", out / f"{name}.pem"],
+                check=True,
+                capture_output=True,
+            )
+    (out / "other.key").unlink(missing_ok=True)
+    otacert = (out / "signer.pem").read_bytes()
+
+    archive = io.BytesIO()
+    with zipfile.ZipFile(archive, "w") as zip_file:
+        for name, data, compression in (
+            ("META-INF/com/android/otacert", otacert, zipfile.ZIP_DEFLATED),
+            ("payload_properties.txt", b"FILE_HASH=\nFILE_SIZE=8\n", zipfile.ZIP_STORED),
+            ("payload.bin", b"CrAU\0\0\0\2", zipfile.ZIP_STORED),
+        ):
+            info = zipfile.ZipInfo(name, date_time=(2009, 1, 1, 0, 0, 0))
+            info.compress_type = compression
+            zip_file.writestr(info, data)
+    data = archive.getvalue()
+    assert data[-2:] == b"\0\0", "the zip must have an empty comment"
+
+    block = subprocess.run(
+        ["openssl", "cms", "-sign", "-binary", "-noattr", "-md", "sha256", "-outform", "DER",
+         "-signer", out / "signer.pem", "-inkey", out / "signer.key"],
+        input=data[:-2],
+        check=True,
+        capture_output=True,
+    ).stdout
+    comment = b"signed by SignApk\0" + block
+    comment_len = len(comment) + 6
+    comment += struct.pack("<HHH", len(block) + 6, 0xFFFF, comment_len)
+    signed = data[:-2] + struct.pack("<H", comment_len) + comment
+    (out / "ota.zip").write_bytes(signed)
+    print("zipsig: ota.zip sha256", hashlib.sha256(signed).hexdigest())
+
+
+GENERATORS = {
+    "bsdiff": gen_bsdiff,
+    "puffin": gen_puffin,
+    "lz4diff": gen_lz4diff,
+    "zucchini": gen_zucchini,
+    "zipsig": gen_zipsig,
+}
+
+if __name__ == "__main__":
+    for name in sys.argv[1:] or GENERATORS:
+        GENERATORS[name]()
@@ -1,7 +1,9 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 
 use argh::FromArgs;
 
+use crate::{http::DEFAULT_USER_AGENT, payload_dumper::FailurePolicy};
+
 #[allow(clippy::struct_excessive_bools, reason = "CLI")]
 #[derive(FromArgs)]
 /// Feature-rich Android OTA payload dumper written in Rust
@@ -26,6 +28,27 @@ pub struct Args {
     #[argh(option, short = 'p')]
     pub partitions: Vec<String>,
 
+    /// public key or certificate (PEM or DER) to check the payload
+    /// signatures against, reading the whole payload; extraction is refused
+    /// unless both signatures match one of the given keys
+    #[argh(option)]
+    pub verify_key: Vec<PathBuf>,
+
+    /// check the whole-file signature of an OTA zip against --verify-key,
+    /// reading the whole zip; extraction is refused unless it matches one of
+    /// the given keys. Without --verify-key, the zip can only be checked
+    /// against its own otacert, which anyone re-signing it can replace, so
+    /// extraction is refused too. The otacert is saved as otacert.pem in the
+    /// output directory
+    #[argh(switch)]
+    pub verify_zip: bool,
+
+    /// re-sign the payload with an RSA private key (PEM or DER), replacing
+    /// its signatures, and save it as payload.bin in the output directory
+    /// instead of extracting it
+    #[argh(option)]
+    pub resign_key: Option<PathBuf>,
+
     /// number of threads to use for parallel processing
     #[argh(option)]
     pub threads: Option<usize>,
@@ -40,6 +63,12 @@ pub struct Args {
     #[argh(switch)]
     pub metadata: bool,
 
+    /// save the metadata and payload signatures to the output directory and
+    /// print a summary, with the digest each one was made over when a
+    /// --verify-key recovers it
+    #[argh(switch)]
+    pub signatures: bool,
+
     /// disable parallel extraction
     #[argh(switch)]
     pub no_parallel: bool,
@@ -48,11 +77,50 @@ pub struct Args {
     #[argh(switch)]
     pub no_verify: bool,
 
+    /// keep a journal of the extraction in the output directory, and resume
+    /// an interrupted extraction into it that was started with --resume.
+    /// Resumed images are always checked against their expected hash
+    #[argh(switch)]
+    pub resume: bool,
+
+    /// keep images already in the output directory whose size and hash
+    /// match, extracting only missing or mismatching partitions
+    #[argh(switch)]
+    pub skip_existing: bool,
+
+    /// how to handle corrupt operation data: strict (fail the partition), retry[=N] (fetch it
+    /// again up to N times, 3 by default) or lenient (skip the operation)
+    #[argh(option, default = "FailurePolicy::Strict")]
+    pub on_corrupt: FailurePolicy,
+
+    /// how to report progress: bars (default) or json (one JSON event per line on stdout)
+    #[argh(option, default = "ProgressFormat::Bars")]
+    pub progress: ProgressFormat,
+
     /// the User-Agent to use if extracting from URL (Defaults to a representative browser UA)
-    #[argh(
-        option,
-        short = 'u',
-        default = "\"Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36\".into()"
-    )]
+    #[argh(option, short = 'u', default = "DEFAULT_USER_AGENT.into()")]
     pub user_agent: String,
 }
+
+/// How the CLI reports progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// Progress bars and human-readable messages.
+    Bars,
+    /// One JSON object per line on stdout, for programs wrapping the CLI.
+    Json,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bars" => Ok(Self::Bars),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "Unknown progress format {s}, expected bars or json"
+            )),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::{fmt, io, ops::Range, path::PathBuf};
+
+/// Errors returned by the library.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// The payload does not start with its magic.
+    #[error("Invalid payload file: magic 'CrAU' not found at offset {offset}")]
+    BadMagic { offset: u64 },
+    #[error("Unsupported payload version: {0}")]
+    UnsupportedVersion(u64),
+    #[error("Failed to decode payload {what}")]
+    Protobuf {
+        what: &'static str,
+        source: prost::DecodeError,
+    },
+
+    /// An operation not allowed by the manifest minor version.
+    #[error(
+        "{op_type} operations are only valid in minor version {required} payloads, found minor version {minor_version}"
+    )]
+    UnsupportedOperation {
+        op_type: &'static str,
+        minor_version: u32,
+        required: u32,
+    },
+    /// An operation reading the original image, without one (not a differential extraction).
+    #[error("{op_type} supported only for differential OTA")]
+    OldImageRequired { op_type: &'static str },
+    #[error("Failed to open original image: {}", path.display())]
+    OldImageMissing { path: PathBuf, source: io::Error },
+    #[error("Old partition hash verification failed")]
+    OldImageHashMismatch,
+    /// Source blocks of an operation, as byte ranges of the original image, do not match
+    /// `src_sha256_hash`.
+    #[error(
+        "Source hash mismatch in original image bytes [{}]",
+        DisplayRanges(ranges)
+    )]
+    SourceHashMismatch { ranges: Vec<Range<u64>> },
+
+    /// Operation data, as a byte range of the payload, does not match `data_sha256_hash`.
+    #[error("Data hash mismatch in payload bytes {}..{}", data.start, data.end)]
+    DataHashMismatch { data: Range<u64> },
+    #[error("Failed to decode payload bytes {}..{}", data.start, data.end)]
+    Decode { data: Range<u64>, source: io::Error },
+    #[error("Failed to apply {op_type} patch from payload bytes {}..{}", data.start, data.end)]
+    Patch {
+        op_type: &'static str,
+        data: Range<u64>,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("Operation produced {produced} bytes instead of dst_length {dst_length}")]
+    DstLengthMismatch { produced: u64, dst_length: u64 },
+    #[error("Operation produced {produced} blocks for {expected} destination blocks")]
+    DstBlocksMismatch { produced: u64, expected: u64 },
+    /// An extracted image does not match the hash in the manifest.
+    #[error("Output image hash mismatch")]
+    ImageHashMismatch,
+
+    #[error("Invalid URL {url}")]
+    InvalidUrl {
+        url: String,
+        source: url::ParseError,
+    },
+    #[error("Request to {url} failed")]
+    Request { url: String, source: reqwest::Error },
+    #[error("HTTP error {status} for {url}")]
+    Http { url: String, status: u16 },
+    #[error("Could not determine content length of {url}")]
+    UnknownContentLength { url: String },
+    /// The server answered a range request with the whole file.
+    #[error("{url} does not support HTTP range requests")]
+    RangeUnsupported { url: String },
+
+    #[error("Invalid ZIP file: {reason}")]
+    InvalidZip { reason: String },
+    #[error("Could not find payload.bin in ZIP file")]
+    PayloadNotFound,
+    #[error("payload.bin is compressed, expected uncompressed")]
+    CompressedPayload,
+
+    #[error("{name} is not an RSA public key or certificate")]
+    InvalidKey { name: String },
+    #[error("{name} is not an unencrypted RSA private key")]
+    InvalidPrivateKey { name: String },
+    #[error("Failed to sign with {name}")]
+    Sign { name: String, source: rsa::Error },
+    /// No key was given to check a signature against, and the OTA zip has no otacert.
+    #[error("No key to verify the signature with and no otacert in the ZIP file")]
+    NoTrustedKey,
+
+    /// The extraction was stopped through a [`CancellationToken`](crate::cancel::CancellationToken).
+    #[error("Extraction cancelled")]
+    Cancelled,
+
+    /// An operation of a partition failed.
+    #[error("Operation {index} ({op_type}) of {partition}")]
+    Operation {
+        partition: String,
+        index: usize,
+        op_type: &'static str,
+        source: Box<Self>,
+    },
+    /// A partition failed outside of its operations.
+    #[error("Partition {partition}")]
+    Partition {
+        partition: String,
+        source: Box<Self>,
+    },
+}
+
+impl Error {
+    /// Whether the error comes from corrupt payload data or source blocks rather than I/O,
+    /// looking through partition and operation context.
+    #[must_use]
+    pub fn is_corruption(&self) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+        match self {
+            Self::Operation { source, .. } | Self::Partition { source, .. } => {
+                source.is_corruption()
+            }
+            Self::SourceHashMismatch { .. } | Self::OldImageHashMismatch => true,
+            _ => self.is_payload_corruption(),
+        }
+    }
+
+    /// Whether the error comes from a cancellation, possibly surfacing through I/O of a reader
+    /// that was cancelled.
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        match self {
+            Self::Cancelled => true,
+            Self::Operation { source, .. } | Self::Partition { source, .. } => {
+                source.is_cancelled()
+            }
+            Self::Io(source) | Self::Decode { source, .. } => source
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<Self>())
+                .is_some_and(Self::is_cancelled),
+            _ => false,
+        }
+    }
+
+    /// Whether the error comes from corrupt payload data, which may be fetched again.
+    #[must_use]
+    pub const fn is_payload_corruption(&self) -> bool {
+        matches!(
+            self,
+            Self::DataHashMismatch { .. }
+                | Self::Decode { .. }
+                | Self::Patch { .. }
+                | Self::DstLengthMismatch { .. }
+                | Self::DstBlocksMismatch { .. }
+        )
+    }
+
+    pub(crate) fn invalid_zip(reason: impl Into<String>) -> Self {
+        Self::InvalidZip {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(e) => e,
+            e => Self::other(e),
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+struct DisplayRanges<'a>(&'a [Range<u64>]);
+
+impl fmt::Display for DisplayRanges<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}..{}", range.start, range.end)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,616 @@
+//! Extraction of the partitions of a payload, with the same scheduling and fallbacks as the CLI.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    ReadSeek,
+    cancel::CancellationToken,
+    error::Result,
+    http::{DEFAULT_USER_AGENT, HttpReader},
+    journal::Journal,
+    payload::Payload,
+    payload_dumper::{
+        DumpOptions, FailurePolicy, PartitionReport, create_payload_reader, dump_partition,
+        dump_partitions,
+    },
+    progress::{NoProgress, Progress},
+    proto::PartitionUpdate,
+    signature::PublicKey,
+    verify::{HashCheck, existing_image_matches},
+    zip::{
+        local_zip::{ZipDecoder, ZipPayloadReader},
+        remote_zip::RemoteZipReader,
+        signature::ZipSignatureReport,
+    },
+};
+
+/// Where a payload is read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// A local `payload.bin`.
+    File(PathBuf),
+    /// A local OTA zip holding an uncompressed `payload.bin`.
+    Zip(PathBuf),
+    /// A remote `payload.bin`, or an OTA zip if the URL or the server says so.
+    Url(String),
+    /// A remote OTA zip.
+    RemoteZip(String),
+}
+
+impl Source {
+    /// Pick the source type from a path or URL given on the command line.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Self {
+        let path_str = path.to_string_lossy();
+        if path_str.starts_with("http://") || path_str.starts_with("https://") {
+            Self::Url(path_str.into_owned())
+        } else if path.extension().and_then(|e| e.to_str()) == Some("zip") {
+            Self::Zip(path.to_path_buf())
+        } else {
+            Self::File(path.to_path_buf())
+        }
+    }
+
+    #[must_use]
+    pub const fn is_remote(&self) -> bool {
+        matches!(self, Self::Url(_) | Self::RemoteZip(_))
+    }
+
+    /// Tell remote zips from remote payloads, by their extension or content type.
+    fn resolve(&self, user_agent: &str) -> Self {
+        let Self::Url(url) = self else {
+            return self.clone();
+        };
+        let is_zip = Path::new(url)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+            || HttpReader::new(url.clone(), user_agent)
+                .is_ok_and(|reader| reader.content_type.as_deref() == Some("application/zip"));
+        if is_zip {
+            Self::RemoteZip(url.clone())
+        } else {
+            self.clone()
+        }
+    }
+
+    /// Open a reader on the source. Reads of remote sources fail once `cancel` is cancelled.
+    fn open(
+        &self,
+        user_agent: &str,
+        cancel: &CancellationToken,
+    ) -> Result<Box<dyn ReadSeek + Send>> {
+        Ok(match self {
+            Self::File(path) => create_payload_reader(path)?,
+            Self::Zip(path) => Box::new(ZipPayloadReader::new_for_parallel(path)?),
+            Self::Url(url) => Box::new(
+                HttpReader::new(url.clone(), user_agent)?.with_cancellation(cancel.clone()),
+            ),
+            Self::RemoteZip(url) => Box::new(
+                RemoteZipReader::new_for_parallel(url.clone(), user_agent)?
+                    .with_cancellation(cancel.clone()),
+            ),
+        })
+    }
+}
+
+/// Outcome of extracting one partition.
+#[derive(Debug)]
+pub struct PartitionResult {
+    pub name: String,
+    /// The report of the extraction, or the error it failed with, after the sequential retry
+    /// if there was one.
+    pub result: Result<PartitionReport>,
+}
+
+impl PartitionResult {
+    /// Whether the partition was extracted and did not fail hash verification.
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        self.result
+            .as_ref()
+            .is_ok_and(|report| report.hash != HashCheck::Mismatch)
+    }
+}
+
+/// Builder for extracting the partitions of a payload.
+///
+/// Partitions are extracted in parallel on a rayon pool, reading the payload through one reader
+/// per thread, and those that fail are retried once sequentially. With `parallel(false)`, they
+/// are extracted one after the other through a single reader.
+#[allow(clippy::struct_excessive_bools, reason = "Builder options")]
+pub struct Extractor {
+    source: Source,
+    /// `source` with a remote URL told apart, once it has been probed.
+    resolved: OnceLock<Source>,
+    out_dir: PathBuf,
+    partitions: Vec<String>,
+    old_dir: Option<PathBuf>,
+    parallel: bool,
+    threads: Option<usize>,
+    verify: bool,
+    policy: FailurePolicy,
+    resume: bool,
+    skip_existing: bool,
+    user_agent: String,
+    progress: Box<dyn Progress>,
+    cancel: CancellationToken,
+}
+
+impl Extractor {
+    #[must_use]
+    pub fn new(source: Source) -> Self {
+        Self {
+            source,
+            resolved: OnceLock::new(),
+            out_dir: PathBuf::from("output"),
+            partitions: Vec::new(),
+            old_dir: None,
+            parallel: true,
+            threads: None,
+            verify: true,
+            policy: FailurePolicy::default(),
+            resume: false,
+            skip_existing: false,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            progress: Box::new(NoProgress),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Directory the images are written to, `output` by default.
+    #[must_use]
+    pub fn out_dir(mut self, out_dir: impl Into<PathBuf>) -> Self {
+        self.out_dir = out_dir.into();
+        self
+    }
+
+    /// Only extract these partitions, instead of all of them. Unknown names are ignored.
+    #[must_use]
+    pub fn partitions<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.partitions = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Directory holding the original images, for differential payloads.
+    #[must_use]
+    pub fn old_dir(mut self, old_dir: impl Into<PathBuf>) -> Self {
+        self.old_dir = Some(old_dir.into());
+        self
+    }
+
+    #[must_use]
+    pub const fn parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    /// Number of threads for parallel extraction, instead of the global rayon pool.
+    #[must_use]
+    pub const fn threads(mut self, threads: Option<usize>) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Whether to check the images against the hashes in the manifest, on by default.
+    #[must_use]
+    pub const fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    #[must_use]
+    pub const fn on_corrupt(mut self, policy: FailurePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Keep a [`Journal`] of the completed operations in the output directory, resuming the
+    /// extraction it records if it was interrupted. Off by default.
+    #[must_use]
+    pub const fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Keep images already in the output directory whose size and hash match the manifest,
+    /// instead of extracting them again. Their reports are marked
+    /// [`reused`](PartitionReport::reused). Off by default.
+    #[must_use]
+    pub const fn skip_existing(mut self, skip_existing: bool) -> Self {
+        self.skip_existing = skip_existing;
+        self
+    }
+
+    /// User-Agent of requests to remote sources.
+    #[must_use]
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Report the progress of the extraction to `progress`, instead of nowhere.
+    #[must_use]
+    pub fn progress(mut self, progress: impl Progress + 'static) -> Self {
+        self.progress = Box::new(progress);
+        self
+    }
+
+    /// Stop the extraction once `cancel` is cancelled, between operations and remote reads.
+    /// Partitions left incomplete fail with [`Error::Cancelled`](crate::Error::Cancelled) and
+    /// their images are removed.
+    #[must_use]
+    pub fn cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Open the payload of the source, which [`Extractor::extract`] extracts.
+    pub fn open_payload(&self) -> Result<Payload<Box<dyn ReadSeek + Send>>> {
+        Payload::open(self.open_reader()?)
+    }
+
+    /// Check the whole-file signature of the source against `keys`, or the otacert of the zip
+    /// if empty, which is never trusted. `None` if the source is not a zip. A remote zip is
+    /// downloaded whole.
+    pub fn verify_zip_signature(&self, keys: &[PublicKey]) -> Result<Option<ZipSignatureReport>> {
+        let source = self
+            .resolved
+            .get_or_init(|| self.source.resolve(&self.user_agent));
+        match source {
+            Source::Zip(path) => {
+                let mut decoder = ZipDecoder::new(fs::File::open(path)?)?;
+                decoder.verify_signature(keys).map(Some)
+            }
+            Source::RemoteZip(url) => {
+                RemoteZipReader::new_for_parallel(url.clone(), &self.user_agent)?
+                    .with_cancellation(self.cancel.clone())
+                    .verify_signature(keys)
+                    .map(Some)
+            }
+            Source::File(_) | Source::Url(_) => Ok(None),
+        }
+    }
+
+    /// Open the payload and extract its partitions.
+    pub fn run(&self) -> Result<Vec<PartitionResult>> {
+        self.extract(self.open_payload()?)
+    }
+
+    /// Extract the selected partitions of `payload`, returning their results in manifest
+    /// order. Errors are those preventing the extraction from starting at all.
+    pub fn extract(
+        &self,
+        payload: Payload<Box<dyn ReadSeek + Send>>,
+    ) -> Result<Vec<PartitionResult>> {
+        if self.out_dir.to_string_lossy() != "-" {
+            fs::create_dir_all(&self.out_dir)?;
+        }
+        let data_offset = payload.data_offset();
+        let block_size = payload.block_size();
+        let minor_version = payload.minor_version();
+        let journal = self
+            .resume
+            .then(|| Journal::open(&self.out_dir, payload.manifest_hash()))
+            .transpose()?;
+        let (manifest, mut payload_reader) = payload.into_parts();
+        let names: HashSet<_> = self.partitions.iter().collect();
+        let selected: Vec<_> = manifest
+            .partitions
+            .iter()
+            .filter(|p| names.is_empty() || names.contains(&p.partition_name))
+            .collect();
+        let reused = if self.skip_existing {
+            self.in_pool(|| self.find_reusable(&selected))??
+        } else {
+            HashSet::new()
+        };
+        let partitions: Vec<_> = selected
+            .iter()
+            .copied()
+            .filter(|p| !reused.contains(p.partition_name.as_str()))
+            .collect();
+        let options = DumpOptions {
+            data_offset,
+            block_size,
+            minor_version,
+            out_dir: &self.out_dir,
+            old_dir: self.old_dir.as_deref(),
+            verify: self.verify,
+            policy: self.policy,
+            progress: self.progress.as_ref(),
+            cancel: &self.cancel,
+            journal: journal.as_ref(),
+        };
+        let dump_sequentially = |partition, reader: &mut Box<dyn ReadSeek + Send>| {
+            dump_partition(partition, &options, reader)
+        };
+
+        if !self.parallel {
+            let results = partitions
+                .iter()
+                .map(|partition| PartitionResult {
+                    name: partition.partition_name.clone(),
+                    result: dump_sequentially(partition, &mut payload_reader),
+                })
+                .collect();
+            return Self::merge_reused(&selected, &reused, results, journal);
+        }
+
+        let open_payload = || self.open_reader();
+        let dump = || dump_partitions(&partitions, &options, &open_payload);
+        let results = self.in_pool(dump)?;
+
+        let mut results: Vec<_> = partitions
+            .iter()
+            .zip(results)
+            .map(|(partition, result)| PartitionResult {
+                name: partition.partition_name.clone(),
+                result,
+            })
+            .collect();
+        if !self.cancel.is_cancelled() && results.iter().any(|r| r.result.is_err()) {
+            // Remote sources get a fresh connection for the retry.
+            let mut fresh_reader;
+            let reader = if self.source.is_remote() {
+                fresh_reader = self.open_reader()?;
+                &mut fresh_reader
+            } else {
+                &mut payload_reader
+            };
+            for (partition, result) in partitions.iter().zip(&mut results) {
+                if result.result.is_err() {
+                    result.result = dump_sequentially(partition, reader);
+                }
+            }
+        }
+        Self::merge_reused(&selected, &reused, results, journal)
+    }
+
+    /// Names of the `partitions` whose images are already extracted.
+    fn find_reusable<'a>(&self, partitions: &[&'a PartitionUpdate]) -> Result<HashSet<&'a str>> {
+        partitions
+            .par_iter()
+            .filter_map(
+                |partition| match existing_image_matches(partition, &self.out_dir) {
+                    Ok(true) => {
+                        self.progress.partition_reused(partition);
+                        Some(Ok(partition.partition_name.as_str()))
+                    }
+                    Ok(false) => None,
+                    Err(e) => Some(Err(e)),
+                },
+            )
+            .collect()
+    }
+
+    /// Add the reports of the `reused` partitions to the `results` of the extracted ones, in
+    /// the order of `selected`, and close the journal.
+    fn merge_reused(
+        selected: &[&PartitionUpdate],
+        reused: &HashSet<&str>,
+        results: Vec<PartitionResult>,
+        journal: Option<Journal>,
+    ) -> Result<Vec<PartitionResult>> {
+        let mut results: HashMap<_, _> = results
+            .into_iter()
+            .map(|result| (result.name.clone(), result))
+            .collect();
+        let mut merged = Vec::with_capacity(selected.len());
+        for partition in selected {
+            let name = &partition.partition_name;
+            if reused.contains(name.as_str()) {
+                if let Some(journal) = &journal {
+                    journal.finish(partition)?;
+                }
+                merged.push(PartitionResult {
+                    name: name.clone(),
+                    result: Ok(PartitionReport {
+                        hash: HashCheck::Verified,
+                        warnings: Vec::new(),
+                        reused: true,
+                    }),
+                });
+            } else if let Some(result) = results.remove(name) {
+                merged.push(result);
+            }
+        }
+        if let Some(journal) = journal {
+            journal.close()?;
+        }
+        Ok(merged)
+    }
+
+    /// Run `f` on a pool of [`Extractor::threads`] threads, or the global rayon pool.
+    fn in_pool<T: Send>(&self, f: impl FnOnce() -> T + Send) -> Result<T> {
+        Ok(match self.threads {
+            Some(threads) => rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .map_err(std::io::Error::other)?
+                .install(f),
+            None => f(),
+        })
+    }
+
+    fn open_reader(&self) -> Result<Box<dyn ReadSeek + Send>> {
+        self.resolved
+            .get_or_init(|| self.source.resolve(&self.user_agent))
+            .open(&self.user_agent, &self.cancel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::{
+        payload::tests::build_payload,
+        proto::{
+            DeltaArchiveManifest, Extent, InstallOperation, PartitionInfo, PartitionUpdate,
+            install_operation,
+        },
+    };
+
+    fn replace_partition(name: &str, data_offset: u64, data: &[u8]) -> PartitionUpdate {
+        let mut op = InstallOperation {
+            data_offset: Some(data_offset),
+            data_length: Some(data.len() as u64),
+            data_sha256_hash: Some(Sha256::digest(data).to_vec()),
+            dst_extents: vec![Extent {
+                start_block: Some(0),
+                num_blocks: Some(1),
+            }],
+            ..Default::default()
+        };
+        op.set_type(install_operation::Type::Replace);
+        PartitionUpdate {
+            partition_name: name.to_string(),
+            new_partition_info: Some(PartitionInfo {
+                size: Some(data.len() as u64),
+                hash: Some(Sha256::digest(data).to_vec()),
+            }),
+            operations: vec![op],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_extractor() {
+        let boot = [b'b'; 4096];
+        let system = [b's'; 4096];
+        let mut vendor = replace_partition("vendor", 8192, &[b'v'; 4096]);
+        // Corrupt data fails in parallel and again in the sequential retry.
+        vendor.operations[0].data_sha256_hash = Some(vec![0; 32]);
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![
+                replace_partition("boot", 0, &boot),
+                replace_partition("system", 4096, &system),
+                vendor,
+            ],
+            ..Default::default()
+        };
+        let data = [&boot[..], &system, &[b'v'; 4096]].concat();
+
+        let dir = std::env::temp_dir().join(format!("extractor_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let payload_path = dir.join("payload.bin");
+        fs::write(&payload_path, build_payload(&manifest, &[], &data)).unwrap();
+
+        let out_dir = dir.join("out");
+        let results = Extractor::new(Source::from_path(&payload_path))
+            .out_dir(&out_dir)
+            .partitions(["boot", "vendor", "missing"])
+            .threads(Some(2))
+            .run()
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "boot");
+        assert!(results[0].is_success());
+        assert_eq!(fs::read(out_dir.join("boot.img")).unwrap(), boot);
+        assert_eq!(results[1].name, "vendor");
+        assert!(matches!(
+            results[1].result.as_ref().unwrap_err(),
+            crate::Error::Operation { source, .. } if matches!(**source, crate::Error::DataHashMismatch { .. })
+        ));
+        assert!(!out_dir.join("system.img").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skip_existing() {
+        let boot = [b'b'; 4096];
+        let system = [b's'; 4096];
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![
+                replace_partition("boot", 0, &boot),
+                replace_partition("system", 4096, &system),
+            ],
+            ..Default::default()
+        };
+        let data = [&boot[..], &system].concat();
+
+        let dir = std::env::temp_dir().join(format!("extractor_skip_{}", std::process::id()));
+        let out_dir = dir.join("out");
+        fs::create_dir_all(&out_dir).unwrap();
+        let payload_path = dir.join("payload.bin");
+        fs::write(&payload_path, build_payload(&manifest, &[], &data)).unwrap();
+        fs::write(out_dir.join("boot.img"), boot).unwrap();
+        fs::write(out_dir.join("system.img"), [0; 4096]).unwrap();
+
+        let results = Extractor::new(Source::from_path(&payload_path))
+            .out_dir(&out_dir)
+            .skip_existing(true)
+            .run()
+            .unwrap();
+        let reports: Vec<_> = results
+            .iter()
+            .map(|r| (r.name.as_str(), r.result.as_ref().unwrap()))
+            .collect();
+        assert_eq!(reports[0].0, "boot");
+        assert!(reports[0].1.reused);
+        assert_eq!(reports[1].0, "system");
+        assert!(!reports[1].1.reused);
+        assert_eq!(reports[1].1.hash, HashCheck::Verified);
+        assert_eq!(fs::read(out_dir.join("system.img")).unwrap(), system);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let image = [[b'a'; 4096], [b'b'; 4096]].concat();
+        let mut boot = replace_partition("boot", 0, &image[..4096]);
+        let mut second = replace_partition("boot", 4096, &image[4096..]);
+        second.operations[0].dst_extents[0].start_block = Some(1);
+        boot.operations.extend(second.operations);
+        boot.new_partition_info = Some(PartitionInfo {
+            size: Some(8192),
+            hash: Some(Sha256::digest(&image).to_vec()),
+        });
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![boot],
+            ..Default::default()
+        };
+        // Corrupt data of the first operation, which an interrupted run already wrote.
+        let data = [&[0; 4096][..], &image[4096..]].concat();
+
+        let dir = std::env::temp_dir().join(format!("extractor_resume_{}", std::process::id()));
+        let out_dir = dir.join("out");
+        fs::create_dir_all(&out_dir).unwrap();
+        let payload_path = dir.join("payload.bin");
+        fs::write(&payload_path, build_payload(&manifest, &[], &data)).unwrap();
+        let extractor = Extractor::new(Source::from_path(&payload_path))
+            .out_dir(&out_dir)
+            .verify(false)
+            .resume(true);
+        let payload = extractor.open_payload().unwrap();
+        let journal = Journal::open(&out_dir, payload.manifest_hash()).unwrap();
+        journal.record(&manifest.partitions[0], 0).unwrap();
+        drop(journal);
+        fs::write(
+            out_dir.join("boot.img"),
+            [&image[..4096], &[0; 4096]].concat(),
+        )
+        .unwrap();
+
+        let results = extractor.extract(payload).unwrap();
+        // Resumed images are checked even without verification.
+        assert_eq!(
+            results[0].result.as_ref().unwrap().hash,
+            HashCheck::Verified
+        );
+        assert_eq!(fs::read(out_dir.join("boot.img")).unwrap(), image);
+        assert!(!out_dir.join(crate::journal::JOURNAL_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,22 @@
 use std::{
     io::{self, Read, Seek, SeekFrom},
-    sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
 
-use anyhow::{Context, Result, anyhow};
 use reqwest::{
+    StatusCode,
     blocking::{Client, Response},
     header,
 };
 use url::Url;
 
-use crate::utils::format_size;
+use crate::{
+    cancel::CancellationToken,
+    error::{Error, Result},
+};
 
-static ACCEPT_RANGES_WARNING_SHOWN: AtomicBool = AtomicBool::new(false);
-static FILE_SIZE_INFO_SHOWN: AtomicBool = AtomicBool::new(false);
+/// User-Agent of a representative browser, which some OTA servers expect.
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
 
 #[derive(Clone)]
 pub struct HttpReader {
@@ -23,28 +25,38 @@ pub struct HttpReader {
     pub content_length: u64,
     client: Client,
     pub content_type: Option<String>,
+    /// Whether the server advertises `Accept-Ranges: bytes`. Servers that don't may still honor
+    /// range requests; reads fail with [`Error::RangeUnsupported`] otherwise.
+    pub accept_ranges: bool,
+    /// Checked before each request.
+    cancel: Option<CancellationToken>,
 }
 
 impl HttpReader {
     pub fn new(url: String, user_agent: &str) -> Result<Self> {
-        Self::new_internal(url, true, user_agent)
+        let client = Self::create_client(user_agent).map_err(|source| Error::Request {
+            url: url.clone(),
+            source,
+        })?;
+        Self::connect(url, client)
     }
 
+    /// Same as [`HttpReader::new`], which no longer prints anything.
+    #[deprecated(note = "use HttpReader::new")]
     pub fn new_silent(url: String, user_agent: &str) -> Result<Self> {
-        Self::new_internal(url, false, user_agent)
+        Self::new(url, user_agent)
     }
 
-    fn create_client(user_agent: &str) -> Result<Client> {
+    fn create_client(user_agent: &str) -> reqwest::Result<Client> {
         let mut headers = header::HeaderMap::new();
         headers.insert(
             header::ACCEPT_ENCODING,
             header::HeaderValue::from_static("gzip, deflate, br"),
         );
         headers.insert(header::ACCEPT, header::HeaderValue::from_static("*/*"));
-        headers.insert(
-            header::USER_AGENT,
-            header::HeaderValue::from_str(user_agent)?,
-        );
+        if let Ok(user_agent) = header::HeaderValue::from_str(user_agent) {
+            headers.insert(header::USER_AGENT, user_agent);
+        }
         headers.insert(
             header::ACCEPT_RANGES,
             header::HeaderValue::from_static("bytes"),
@@ -59,23 +71,27 @@ impl HttpReader {
         );
 
         let client_builder = Client::builder()
-            .timeout(Duration::from_secs(600))
+            .timeout(Duration::from_mins(10))
             .tcp_keepalive(Some(Duration::from_secs(30)))
             .pool_max_idle_per_host(10)
             .default_headers(headers)
             .redirect(reqwest::redirect::Policy::limited(10));
 
-        let client = client_builder.build()?;
-        Ok(client)
+        client_builder.build()
     }
 
-    fn new_internal(url: String, print_size: bool, user_agent: &str) -> Result<Self> {
-        let client = Self::create_client(user_agent).with_context(|| "Failed to build client")?;
-        let parsed_url = Url::parse(&url).map_err(|e| anyhow!("Invalid URL: {e}"))?;
+    fn connect(url: String, client: Client) -> Result<Self> {
+        let parsed_url = match Url::parse(&url) {
+            Ok(parsed_url) => parsed_url,
+            Err(source) => return Err(Error::InvalidUrl { url, source }),
+        };
 
-        let _host = parsed_url
-            .host_str()
-            .ok_or_else(|| anyhow!("No host in URL"))?;
+        if parsed_url.host_str().is_none() {
+            return Err(Error::InvalidUrl {
+                url,
+                source: url::ParseError::EmptyHost,
+            });
+        }
         let _port = parsed_url.port().unwrap_or_else(|| {
             if parsed_url.scheme() == "https" {
                 443
@@ -91,44 +107,42 @@ impl HttpReader {
         while retry_count < max_retries {
             match client.head(&url).send() {
                 Ok(response) => {
+                    if !response.status().is_success() {
+                        return Err(Error::Http {
+                            url,
+                            status: response.status().as_u16(),
+                        });
+                    }
                     let content_type = response
                         .headers()
                         .get(header::CONTENT_TYPE)
                         .and_then(|v| v.to_str().ok())
                         .map(std::string::ToString::to_string);
 
-                    let content_length = response
+                    let Some(content_length) = response
                         .headers()
                         .get(header::CONTENT_LENGTH)
                         .and_then(|v| v.to_str().ok())
                         .and_then(|v| v.parse::<u64>().ok())
-                        .ok_or_else(|| anyhow!("Could not determine content length"))?;
+                    else {
+                        return Err(Error::UnknownContentLength { url });
+                    };
 
                     // Check if server supports range requests
-                    let supports_ranges = response
+                    let accept_ranges = response
                         .headers()
                         .get(header::ACCEPT_RANGES)
                         .and_then(|v| v.to_str().ok())
                         .is_some_and(|v| v == "bytes");
 
-                    if !supports_ranges && !ACCEPT_RANGES_WARNING_SHOWN.swap(true, Ordering::SeqCst)
-                    {
-                        eprintln!(
-                            "- Warning: Server doesn't advertise Accept-Ranges. The process may fail."
-                        );
-                    }
-
-                    // Print file size info if requested
-                    if print_size && !FILE_SIZE_INFO_SHOWN.swap(true, Ordering::SeqCst) {
-                        eprintln!("- File size: {}", format_size(content_length));
-                    }
-
                     return Ok(Self {
                         url,
                         position: 0,
                         content_length,
                         client,
                         content_type,
+                        accept_ranges,
+                        cancel: None,
                     });
                 }
                 Err(e) => {
@@ -141,13 +155,20 @@ impl HttpReader {
             }
         }
 
-        Err(anyhow!(
-            "Failed to connect after {max_retries} retries. Last error: {}",
-            last_error.unwrap()
-        ))
+        Err(Error::Request {
+            url,
+            source: last_error.expect("at least one attempt was made"),
+        })
+    }
+
+    /// Fail reads with [`Error::Cancelled`] once `cancel` is cancelled.
+    #[must_use]
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
     }
 
-    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
         if offset >= self.content_length {
             return Ok(0);
         }
@@ -165,7 +186,10 @@ impl HttpReader {
         let mut retry_count = 0;
         let max_retries = 3;
 
-        while retry_count < max_retries {
+        loop {
+            if let Some(cancel) = &self.cancel {
+                cancel.check()?;
+            }
             match self
                 .client
                 .get(&self.url)
@@ -173,38 +197,44 @@ impl HttpReader {
                 .send()
             {
                 Ok(mut response) => {
-                    if !response.status().is_success() && response.status().as_u16() != 206 {
-                        return Err(io::Error::other(format!(
-                            "HTTP error: {} for range {}",
-                            response.status(),
-                            range
-                        )));
+                    match response.status() {
+                        StatusCode::PARTIAL_CONTENT => {}
+                        // The whole file only matches the range when it starts at 0.
+                        StatusCode::OK if offset == 0 => {}
+                        StatusCode::OK => {
+                            return Err(Error::RangeUnsupported {
+                                url: self.url.clone(),
+                            });
+                        }
+                        status => {
+                            return Err(Error::Http {
+                                url: self.url.clone(),
+                                status: status.as_u16(),
+                            });
+                        }
                     }
 
                     return copy_from_response(&mut response, &mut buf[..to_read]);
                 }
-                Err(e) => {
+                Err(source) => {
                     retry_count += 1;
                     if retry_count == max_retries {
-                        return Err(io::Error::other(format!(
-                            "Failed to read range {range} after {max_retries} retries: {e}"
-                        )));
+                        return Err(Error::Request {
+                            url: self.url.clone(),
+                            source,
+                        });
                     }
                     std::thread::sleep(Duration::from_secs(2 * retry_count as u64));
                 }
             }
         }
-
-        Err(io::Error::other(
-            "Failed to read range after maximum retries",
-        ))
     }
 }
 
 impl Read for HttpReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // Use read_at with current position instead of read_range
-        let bytes_read = self.read_at(self.position, buf)?;
+        let bytes_read = self.read_at(self.position, buf).map_err(io::Error::from)?;
         self.position += bytes_read as u64;
         Ok(bytes_read)
     }
@@ -245,7 +275,7 @@ impl Seek for HttpReader {
     }
 }
 
-pub fn copy_from_response(response: &mut Response, buf: &mut [u8]) -> io::Result<usize> {
+pub fn copy_from_response(response: &mut Response, buf: &mut [u8]) -> Result<usize> {
     let mut total_read = 0;
 
     while total_read < buf.len() {
@@ -253,7 +283,7 @@ pub fn copy_from_response(response: &mut Response, buf: &mut [u8]) -> io::Result
             Ok(0) => break, // EOF
             Ok(n) => total_read += n,
             Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         }
     }
 
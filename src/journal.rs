@@ -0,0 +1,240 @@
+//! Journal of the operations completed in an output directory, so that an interrupted
+//! extraction resumes where it stopped instead of starting over.
+//!
+//! The journal is a text file appended to after each operation. Its first line holds the hash
+//! of the manifest it belongs to; a journal of another payload is discarded. Each following line
+//! records either a completed operation, with the hash of its data, or a partition whose
+//! extraction ended, after which its operations no longer count as resumable:
+//!
+//! ```text
+//! pay10ad-dumper journal 1 <manifest sha256>
+//! op <index> <data sha256 or -> <partition>
+//! done <partition>
+//! ```
+//!
+//! Records are synced as they are appended, but the image is not, so a record may outlive the
+//! data it stands for. Only partitions with an expected hash are resumed, and their image is
+//! always checked against it by re-reading it once extracted, even when verification is off.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{error::Result, proto::PartitionUpdate};
+
+/// Name of the journal in the output directory.
+pub const JOURNAL_FILE: &str = ".pay10ad-journal";
+const HEADER: &str = "pay10ad-dumper journal 1";
+
+/// Operations completed by earlier runs into the same output directory, and those completed
+/// by this one.
+pub struct Journal {
+    path: PathBuf,
+    /// Data hashes of the completed operations of each unfinished partition, by index.
+    completed: Mutex<HashMap<String, HashMap<usize, String>>>,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Open the journal in `out_dir`, starting a new one if there is none or it belongs to
+    /// another manifest.
+    pub fn open(out_dir: &Path, manifest_hash: &[u8]) -> Result<Self> {
+        let path = out_dir.join(JOURNAL_FILE);
+        let header = format!("{HEADER} {}", hex::encode(manifest_hash));
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = contents.lines();
+        let completed = if lines.next() == Some(header.as_str()) {
+            if !contents.ends_with('\n') {
+                // End a line cut short by a crash, so it does not swallow the next record.
+                OpenOptions::new()
+                    .append(true)
+                    .open(&path)?
+                    .write_all(b"\n")?;
+            }
+            parse_entries(lines)
+        } else {
+            let mut file = File::create(&path)?;
+            writeln!(file, "{header}")?;
+            HashMap::new()
+        };
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Self {
+            path,
+            completed: Mutex::new(completed),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Indices of the operations of `partition` completed since it was last finished, by
+    /// earlier runs or this one, leaving out those whose data hash no longer matches.
+    #[must_use]
+    pub fn completed(&self, partition: &PartitionUpdate) -> HashSet<usize> {
+        lock(&self.completed)
+            .get(&partition.partition_name)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|&(&index, hash)| {
+                        partition
+                            .operations
+                            .get(index)
+                            .is_some_and(|op| data_hash(op.data_sha256_hash.as_deref()) == *hash)
+                    })
+                    .map(|(&index, _)| index)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Record that operation `index` of `partition` was written.
+    pub fn record(&self, partition: &PartitionUpdate, index: usize) -> Result<()> {
+        let hash = data_hash(
+            partition
+                .operations
+                .get(index)
+                .and_then(|op| op.data_sha256_hash.as_deref()),
+        );
+        lock(&self.completed)
+            .entry(partition.partition_name.clone())
+            .or_default()
+            .insert(index, hash.clone());
+        self.append(&format!("op {index} {hash} {}", partition.partition_name))
+    }
+
+    /// Record that the extraction of `partition` ended, so a later run starts it over.
+    pub fn finish(&self, partition: &PartitionUpdate) -> Result<()> {
+        if lock(&self.completed)
+            .remove(&partition.partition_name)
+            .is_none()
+        {
+            return Ok(());
+        }
+        self.append(&format!("done {}", partition.partition_name))
+    }
+
+    /// Remove the journal if no partition is left unfinished.
+    pub fn close(self) -> Result<()> {
+        let completed = self
+            .completed
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        drop(self.file);
+        if completed.is_empty() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn append(&self, line: &str) -> Result<()> {
+        // One write per line, so concurrent records do not interleave.
+        let mut file = lock(&self.file);
+        file.write_all(format!("{line}\n").as_bytes())?;
+        file.sync_data()?;
+        drop(file);
+        Ok(())
+    }
+}
+
+/// Completed operations of the partitions left unfinished by the lines of a journal. Lines
+/// that do not parse, such as one cut short by a crash, are ignored.
+fn parse_entries<'a>(
+    lines: impl Iterator<Item = &'a str>,
+) -> HashMap<String, HashMap<usize, String>> {
+    let mut completed: HashMap<String, HashMap<usize, String>> = HashMap::new();
+    for line in lines {
+        match line.split_once(' ') {
+            Some(("op", entry)) => {
+                let mut fields = entry.splitn(3, ' ');
+                if let (Some(Ok(index)), Some(hash), Some(partition)) =
+                    (fields.next().map(str::parse), fields.next(), fields.next())
+                {
+                    completed
+                        .entry(partition.to_string())
+                        .or_default()
+                        .insert(index, hash.to_string());
+                }
+            }
+            Some(("done", partition)) => {
+                completed.remove(partition);
+            }
+            _ => {}
+        }
+    }
+    completed
+}
+
+fn data_hash(hash: Option<&[u8]>) -> String {
+    hash.filter(|hash| !hash.is_empty())
+        .map_or_else(|| "-".to_string(), hex::encode)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::InstallOperation;
+
+    #[test]
+    fn test_journal() {
+        let dir = std::env::temp_dir().join(format!("journal_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut boot = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![
+                InstallOperation {
+                    data_sha256_hash: Some(vec![1; 32]),
+                    ..Default::default()
+                },
+                InstallOperation::default(),
+                InstallOperation::default(),
+            ],
+            ..Default::default()
+        };
+        let system = PartitionUpdate {
+            partition_name: "system".to_string(),
+            operations: vec![InstallOperation::default()],
+            ..Default::default()
+        };
+
+        let journal = Journal::open(&dir, b"manifest").unwrap();
+        assert!(journal.completed(&boot).is_empty());
+        journal.record(&boot, 0).unwrap();
+        journal.record(&boot, 2).unwrap();
+        journal.record(&system, 0).unwrap();
+        assert_eq!(journal.completed(&system), HashSet::from([0]));
+        journal.finish(&system).unwrap();
+        assert!(journal.completed(&system).is_empty());
+        journal.close().unwrap();
+        // A torn last line is ignored.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(JOURNAL_FILE))
+            .unwrap();
+        file.write_all(b"op 1 ").unwrap();
+
+        let journal = Journal::open(&dir, b"manifest").unwrap();
+        assert_eq!(journal.completed(&boot), HashSet::from([0, 2]));
+        assert!(journal.completed(&system).is_empty());
+        // Operations whose data changed are not resumed.
+        boot.operations[0].data_sha256_hash = Some(vec![2; 32]);
+        assert_eq!(journal.completed(&boot), HashSet::from([2]));
+        drop(journal);
+
+        let journal = Journal::open(&dir, b"other manifest").unwrap();
+        assert!(journal.completed(&boot).is_empty());
+        journal.close().unwrap();
+        assert!(!dir.join(JOURNAL_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
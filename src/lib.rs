@@ -17,17 +17,31 @@
 
 #[cfg(feature = "cli")]
 pub mod args;
+pub mod cancel;
+pub mod error;
+pub mod extractor;
 pub mod http;
+pub mod journal;
+pub mod lz4diff;
 pub mod metadata;
 pub mod patch;
+pub mod payload;
 pub mod payload_dumper;
+pub mod progress;
 pub mod proto;
+pub mod puffin;
+pub mod sign;
+pub mod signature;
+pub mod stream;
 pub mod structs;
 pub mod utils;
 pub mod verify;
 pub mod zip;
+pub mod zucchini;
 
 use std::io::{Read, Seek};
 
+pub use error::{Error, Result};
+
 pub trait ReadSeek: Read + Seek {}
 impl<T: Read + Seek> ReadSeek for T {}
@@ -0,0 +1,403 @@
+//! Native implementation of AOSP lz4diff patches, used by `LZ4DIFF_BSDIFF` and
+//! `LZ4DIFF_PUFFDIFF` operations on LZ4-compressed (e.g. EROFS) partitions.
+//!
+//! The source blocks are decompressed as described by the patch header, the inner bsdiff or
+//! puffin patch is applied to the decompressed data, and the result is recompressed with the
+//! recorded LZ4 parameters. Blocks that do not recompress bit-exactly carry a small "postfix"
+//! bsdiff patch that fixes them up.
+
+use anyhow::{Context, Result, bail};
+use lz4_sys::{c_char, c_int, c_void};
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+use crate::{patch::bspatch, puffin::puffpatch};
+
+/// Magic at the start of every lz4diff patch.
+pub const LZ4DIFF_MAGIC: &[u8; 7] = b"LZ4DIFF";
+/// The only lz4diff patch version in use.
+const LZ4DIFF_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, ::prost::Message)]
+pub struct CompressionAlgorithm {
+    #[prost(enumeration = "compression_algorithm::Type", tag = "1")]
+    pub r#type: i32,
+    #[prost(uint32, tag = "2")]
+    pub level: u32,
+}
+
+/// Nested message and enum types in `CompressionAlgorithm`.
+pub mod compression_algorithm {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Type {
+        Uncompressed = 0,
+        Lz4 = 1,
+        Lz4hc = 2,
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, ::prost::Message)]
+pub struct CompressedBlockInfo {
+    #[prost(uint64, tag = "1")]
+    pub uncompressed_offset: u64,
+    #[prost(uint64, tag = "2")]
+    pub uncompressed_length: u64,
+    #[prost(uint64, tag = "3")]
+    pub compressed_length: u64,
+    /// SHA-256 of the recompressed block, before the postfix patch is applied.
+    #[prost(bytes = "vec", tag = "4")]
+    pub sha256_hash: ::prost::alloc::vec::Vec<u8>,
+    /// Bsdiff patch turning the recompressed block into the expected one.
+    #[prost(bytes = "vec", tag = "5")]
+    pub postfix_bspatch: ::prost::alloc::vec::Vec<u8>,
+}
+
+impl CompressedBlockInfo {
+    /// Blocks that did not shrink are stored uncompressed.
+    const fn is_compressed(&self) -> bool {
+        self.compressed_length < self.uncompressed_length
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, ::prost::Message)]
+pub struct CompressionInfo {
+    #[prost(message, repeated, tag = "1")]
+    pub block_info: ::prost::alloc::vec::Vec<CompressedBlockInfo>,
+    /// Compressed data is right-aligned in its block, preceded by zeros (EROFS `0PADDING`).
+    #[prost(bool, tag = "2")]
+    pub zero_padding_enabled: bool,
+    #[prost(message, optional, tag = "3")]
+    pub algo: ::core::option::Option<CompressionAlgorithm>,
+}
+
+#[derive(Clone, PartialEq, Eq, ::prost::Message)]
+pub struct Lz4diffHeader {
+    #[prost(message, optional, tag = "1")]
+    pub src_info: ::core::option::Option<CompressionInfo>,
+    #[prost(message, optional, tag = "2")]
+    pub dst_info: ::core::option::Option<CompressionInfo>,
+    #[prost(enumeration = "lz4diff_header::InnerPatchType", tag = "3")]
+    pub inner_type: i32,
+}
+
+/// Nested message and enum types in `Lz4diffHeader`.
+pub mod lz4diff_header {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum InnerPatchType {
+        Bsdiff = 0,
+        Puffdiff = 1,
+    }
+}
+
+unsafe extern "C" {
+    fn LZ4_decompress_safe_partial(
+        src: *const c_char,
+        dst: *mut c_char,
+        src_size: c_int,
+        target_output_size: c_int,
+        dst_capacity: c_int,
+    ) -> c_int;
+    fn LZ4_compress_destSize(
+        src: *const c_char,
+        dst: *mut c_char,
+        src_size_ptr: *mut c_int,
+        target_dst_size: c_int,
+    ) -> c_int;
+    fn LZ4_sizeofStateHC() -> c_int;
+    fn LZ4_compress_HC_destSize(
+        state: *mut c_void,
+        src: *const c_char,
+        dst: *mut c_char,
+        src_size_ptr: *mut c_int,
+        target_dst_size: c_int,
+        compression_level: c_int,
+    ) -> c_int;
+}
+
+/// Apply an lz4diff patch to the compressed `src` blocks, producing the compressed destination.
+pub fn apply(src: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (header, inner_patch) = parse_patch(patch)?;
+    let inner_type = header.inner_type();
+    let src_info = header.src_info.unwrap_or_default();
+    let dst_info = header.dst_info.unwrap_or_default();
+
+    let decompressed_src =
+        decompress_blocks(src, &src_info).context("Failed to decompress source")?;
+    let decompressed_dst = match inner_type {
+        lz4diff_header::InnerPatchType::Bsdiff => bspatch(&decompressed_src, inner_patch)?,
+        lz4diff_header::InnerPatchType::Puffdiff => puffpatch(&decompressed_src, inner_patch)?,
+    };
+    drop(decompressed_src);
+
+    let recompressed = compress_blocks(&decompressed_dst, &dst_info)
+        .context("Failed to recompress destination")?;
+    apply_postfix_patches(&recompressed, &dst_info.block_info)
+}
+
+/// Split an lz4diff patch into its header and the inner patch.
+fn parse_patch(patch: &[u8]) -> Result<(Lz4diffHeader, &[u8])> {
+    let magic_len = LZ4DIFF_MAGIC.len();
+    if patch.len() < magic_len + 8 || &patch[..magic_len] != LZ4DIFF_MAGIC {
+        bail!("Invalid lz4diff patch: magic 'LZ4DIFF' not found");
+    }
+    let read_u32 = |offset: usize| {
+        u32::from_be_bytes([
+            patch[offset],
+            patch[offset + 1],
+            patch[offset + 2],
+            patch[offset + 3],
+        ])
+    };
+    let version = read_u32(magic_len);
+    if version != LZ4DIFF_VERSION {
+        bail!("Unsupported lz4diff version {version}");
+    }
+    let header_start = magic_len + 8;
+    let header_size = read_u32(magic_len + 4) as usize;
+    let Some(header) = patch.get(header_start..header_start + header_size) else {
+        bail!("Invalid lz4diff patch: truncated header");
+    };
+    let header = Lz4diffHeader::decode(header).context("Invalid lz4diff patch header")?;
+    Ok((header, &patch[header_start + header_size..]))
+}
+
+/// Decompress consecutive blocks of `blob`. Data past the last block is copied as is.
+fn decompress_blocks(blob: &[u8], info: &CompressionInfo) -> Result<Vec<u8>> {
+    let total = info
+        .block_info
+        .iter()
+        .map(|block| block.uncompressed_length as usize)
+        .sum::<usize>();
+    let mut output = Vec::with_capacity(total);
+    let mut offset = 0;
+
+    for block in &info.block_info {
+        let end = offset + block.compressed_length as usize;
+        let Some(mut cluster) = blob.get(offset..end) else {
+            bail!("Compressed block at {offset} is out of bounds");
+        };
+        offset = end;
+        if !block.is_compressed() {
+            output.extend_from_slice(cluster);
+            continue;
+        }
+        if info.zero_padding_enabled {
+            let padding = cluster.iter().take_while(|&&b| b == 0).count();
+            cluster = &cluster[padding..];
+        }
+
+        let length = block.uncompressed_length as usize;
+        let start = output.len();
+        output.resize(start + length, 0);
+        // SAFETY: both buffers are valid for the sizes passed, and liblz4 never writes past
+        // `dst_capacity`.
+        let decompressed = unsafe {
+            LZ4_decompress_safe_partial(
+                cluster.as_ptr().cast(),
+                output[start..].as_mut_ptr().cast(),
+                c_int::try_from(cluster.len())?,
+                c_int::try_from(length)?,
+                c_int::try_from(length)?,
+            )
+        };
+        if usize::try_from(decompressed).ok() != Some(length) {
+            bail!(
+                "Block at {} decompressed to {decompressed} bytes, expected {length}",
+                block.uncompressed_offset
+            );
+        }
+    }
+    output.extend_from_slice(&blob[offset.min(blob.len())..]);
+    Ok(output)
+}
+
+/// Compress `blob` into blocks of the recorded sizes, inverting [`decompress_blocks`].
+fn compress_blocks(blob: &[u8], info: &CompressionInfo) -> Result<Vec<u8>> {
+    let algo = info.algo.unwrap_or_default();
+    let total = info
+        .block_info
+        .iter()
+        .map(|block| block.compressed_length as usize)
+        .sum::<usize>();
+    let mut output = vec![0u8; total];
+    let mut hc_state = Vec::new();
+    let mut offset = 0;
+    let mut uncompressed_end = 0;
+
+    for block in &info.block_info {
+        let start = block.uncompressed_offset as usize;
+        uncompressed_end = start + block.uncompressed_length as usize;
+        let Some(uncompressed) = blob.get(start..uncompressed_end) else {
+            bail!("Uncompressed block at {start} is out of bounds");
+        };
+        let out = &mut output[offset..offset + block.compressed_length as usize];
+        offset += out.len();
+        if !block.is_compressed() {
+            out.copy_from_slice(uncompressed);
+            continue;
+        }
+
+        let mut src_size = c_int::try_from(uncompressed.len())?;
+        let target_size = c_int::try_from(out.len())?;
+        // SAFETY: `src_size` and `target_size` match the buffer lengths, and the HC state is
+        // allocated with the size and alignment liblz4 asks for.
+        let written = match algo.r#type() {
+            compression_algorithm::Type::Lz4 => unsafe {
+                LZ4_compress_destSize(
+                    uncompressed.as_ptr().cast(),
+                    out.as_mut_ptr().cast(),
+                    &raw mut src_size,
+                    target_size,
+                )
+            },
+            compression_algorithm::Type::Lz4hc => unsafe {
+                if hc_state.is_empty() {
+                    let state_size = usize::try_from(LZ4_sizeofStateHC())?;
+                    hc_state = vec![0u64; state_size.div_ceil(8)];
+                }
+                LZ4_compress_HC_destSize(
+                    hc_state.as_mut_ptr().cast(),
+                    uncompressed.as_ptr().cast(),
+                    out.as_mut_ptr().cast(),
+                    &raw mut src_size,
+                    target_size,
+                    c_int::try_from(algo.level)?,
+                )
+            },
+            compression_algorithm::Type::Uncompressed => {
+                bail!("Compressed block at {start} has no compression algorithm");
+            }
+        };
+        let written = usize::try_from(written).unwrap_or(0);
+        if written == 0 || usize::try_from(src_size).ok() != Some(uncompressed.len()) {
+            bail!("Failed to compress block at {start} into {target_size} bytes");
+        }
+        if info.zero_padding_enabled && written < out.len() {
+            let padding = out.len() - written;
+            out.copy_within(..written, padding);
+            out[..padding].fill(0);
+        }
+    }
+    if uncompressed_end < blob.len() {
+        output.extend_from_slice(&blob[uncompressed_end..]);
+    }
+    Ok(output)
+}
+
+/// Check recompressed blocks against their recorded hashes and apply any postfix patches.
+fn apply_postfix_patches(recompressed: &[u8], blocks: &[CompressedBlockInfo]) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(recompressed.len());
+    let mut offset = 0;
+    for block in blocks {
+        let end = offset + block.compressed_length as usize;
+        let data = &recompressed[offset..end];
+        if !block.sha256_hash.is_empty() && Sha256::digest(data).as_slice() != block.sha256_hash {
+            bail!(
+                "Recompressed block at {} does not match the expected hash; the patch was likely generated with a different LZ4 version",
+                block.uncompressed_offset
+            );
+        }
+        if block.postfix_bspatch.is_empty() {
+            output.extend_from_slice(data);
+        } else {
+            output.extend_from_slice(&bspatch(data, &block.postfix_bspatch)?);
+        }
+        offset = end;
+    }
+    output.extend_from_slice(&recompressed[offset..]);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(seed: u8, length: usize) -> Vec<u8> {
+        (0..length)
+            .map(|i| match i % 5 {
+                0 => seed,
+                1 => (i % 13) as u8,
+                _ => b"lz4diff "[i % 8],
+            })
+            .collect()
+    }
+
+    /// Compress `data` in 4 KiB clusters into 1 KiB blocks, as EROFS does.
+    fn compress(data: &[u8], algo: CompressionAlgorithm) -> (Vec<u8>, CompressionInfo) {
+        let block_info = data
+            .chunks(4096)
+            .enumerate()
+            .map(|(i, chunk)| CompressedBlockInfo {
+                uncompressed_offset: i as u64 * 4096,
+                uncompressed_length: chunk.len() as u64,
+                compressed_length: 1024,
+                ..Default::default()
+            })
+            .collect();
+        let info = CompressionInfo {
+            algo: Some(algo),
+            block_info,
+            zero_padding_enabled: true,
+        };
+        (compress_blocks(data, &info).unwrap(), info)
+    }
+
+    #[test]
+    fn test_lz4diff_bsdiff() {
+        for algo in [
+            compression_algorithm::Type::Lz4,
+            compression_algorithm::Type::Lz4hc,
+        ] {
+            let algo = CompressionAlgorithm {
+                r#type: algo as i32,
+                level: 9,
+            };
+            let old = sample(1, 16384);
+            let new = sample(2, 12288);
+            let (src, src_info) = compress(&old, algo);
+            let (mut dst, mut dst_info) = compress(&new, algo);
+            assert_eq!(decompress_blocks(&src, &src_info).unwrap(), old);
+
+            // Pretend the first block was produced by a different LZ4 build.
+            let block = &mut dst_info.block_info[0];
+            block.sha256_hash = Sha256::digest(&dst[..1024]).to_vec();
+            let mut fixed = dst[..1024].to_vec();
+            fixed[1000] ^= 0xFF;
+            bsdiff::diff(&dst[..1024], &fixed, &mut block.postfix_bspatch).unwrap();
+            dst[..1024].copy_from_slice(&fixed);
+
+            let mut inner_patch = Vec::new();
+            bsdiff::diff(&old, &new, &mut inner_patch).unwrap();
+            let header = Lz4diffHeader {
+                inner_type: lz4diff_header::InnerPatchType::Bsdiff as i32,
+                src_info: Some(src_info),
+                dst_info: Some(dst_info),
+            }
+            .encode_to_vec();
+            let mut patch = LZ4DIFF_MAGIC.to_vec();
+            patch.extend_from_slice(&LZ4DIFF_VERSION.to_be_bytes());
+            patch.extend_from_slice(&(header.len() as u32).to_be_bytes());
+            patch.extend_from_slice(&header);
+            patch.extend_from_slice(&inner_patch);
+
+            assert_eq!(apply(&src, &patch).unwrap(), dst);
+        }
+    }
+
+    /// A patch made by `scripts/gen_fixtures.py` with the system liblz4, whose target has a
+    /// block from a different LZ4 encoder that needs a postfix patch, and a stored block.
+    #[test]
+    fn test_lz4diff_fixture() {
+        const SOURCE: &[u8] = include_bytes!("../tests/fixtures/lz4diff/source.bin");
+        const TARGET: &[u8] = include_bytes!("../tests/fixtures/lz4diff/target.bin");
+        const PATCH: &[u8] = include_bytes!("../tests/fixtures/lz4diff/patch.lz4diff");
+        const TARGET_SHA256: &str =
+            "7b7233b1e21c3eb69e4f731f028f6fd61b54a63334107d1e55f518520940a1d5";
+
+        let target = apply(SOURCE, PATCH).unwrap();
+        assert_eq!(hex::encode(Sha256::digest(&target)), TARGET_SHA256);
+        assert_eq!(target, TARGET);
+    }
+}
@@ -10,54 +10,57 @@
 #![allow(clippy::too_many_lines, clippy::cognitive_complexity, reason = "TBD")]
 
 use std::{
-    collections::HashSet,
-    fs::{self, File},
-    io::{Read, Seek, SeekFrom},
-    sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
-    },
+    fs,
+    io::{self, Seek, SeekFrom},
+    path::Path,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use anyhow::{Result, anyhow, bail};
-use byteorder::{BigEndian, ReadBytesExt};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use anyhow::{Context, Result, bail};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use pay10ad_dumper::{
-    ReadSeek,
-    args::Args,
-    http::HttpReader,
+    args::{Args, ProgressFormat},
+    cancel::CancellationToken,
+    extractor::{Extractor, Source},
     metadata::save_metadata,
-    payload_dumper::{create_payload_reader, dump_partition},
-    proto::{DeltaArchiveManifest, PartitionUpdate},
+    progress::{IndicatifProgress, JsonProgress},
+    sign::{SigningKey, resign_payload},
+    signature::{PublicKey, SignatureCheck, dump_signatures, verify_payload_signatures},
     utils::{format_elapsed_time, format_size, is_differential_ota, list_partitions},
-    verify::verify_partitions_hash,
-    zip::{local_zip::ZipPayloadReader, remote_zip::RemoteZipReader},
-};
-use prost::Message;
-use rayon::{
-    iter::{IntoParallelRefIterator, ParallelIterator},
-    slice::ParallelSlice,
+    verify::HashCheck,
 };
+use serde_json::{Value, json};
 
-static FILE_SIZE_INFO_SHOWN: AtomicBool = AtomicBool::new(false);
+type Events = Arc<JsonProgress<io::Stdout>>;
 
 fn main() -> Result<()> {
     let args: Args = argh::from_env();
-    let thread_count = if args.no_parallel {
-        1
-    } else if let Some(threads) = args.threads {
-        threads
-    } else {
-        num_cpus::get()
-    };
-
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(thread_count)
-        .build_global()?;
 
     let start_time = Instant::now();
-    let multi_progress = MultiProgress::new();
+    // JSON events replace the bars and human-readable output on stdout.
+    let events =
+        (args.progress == ProgressFormat::Json).then(|| Arc::new(JsonProgress::new(io::stdout())));
+    let result = run(&args, events.as_ref(), start_time);
+    if let (Some(events), Err(e)) = (&events, &result) {
+        events.emit(
+            "summary",
+            json!({
+                "elapsed_ms": start_time.elapsed().as_millis(),
+                "error": format!("{e:#}"),
+                "success": false,
+            }),
+        );
+    }
+    result
+}
+
+fn run(args: &Args, events: Option<&Events>, start_time: Instant) -> Result<()> {
+    let multi_progress = if events.is_some() {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
     let main_pb = multi_progress.add(ProgressBar::new_spinner());
     main_pb.set_style(
         ProgressStyle::default_spinner()
@@ -66,118 +69,314 @@ fn main() -> Result<()> {
     );
     main_pb.enable_steady_tick(Duration::from_millis(100));
 
-    let payload_path_str = args.payload_path.to_string_lossy().to_string();
-    let is_url =
-        payload_path_str.starts_with("http://") || payload_path_str.starts_with("https://");
-    let is_local_zip =
-        !is_url && args.payload_path.extension().and_then(|e| e.to_str()) == Some("zip");
-
-    main_pb.set_message("Opening file...");
-
-    if !is_url {
-        if let Ok(metadata) = fs::metadata(&args.payload_path) {
-            if metadata.len() > 1024 * 1024 {
-                println!(
-                    "Processing file: {}, size: {}",
-                    payload_path_str,
-                    format_size(metadata.len())
+    // The first Ctrl-C stops the extraction between operations, the second one right away.
+    let cancel = CancellationToken::new();
+    let handler_cancel = cancel.clone();
+    let handler_events = events.cloned();
+    ctrlc::set_handler(move || {
+        if handler_cancel.is_cancelled() {
+            if let Some(events) = &handler_events {
+                events.emit(
+                    "summary",
+                    json!({ "error": "Interrupted", "success": false }),
                 );
             }
+            std::process::exit(130);
         }
+        eprintln!("\nInterrupted, stopping after the current operations...");
+        handler_cancel.cancel();
+    })
+    .context("Failed to set Ctrl-C handler")?;
+
+    let keys = args
+        .verify_key
+        .iter()
+        .map(|path| PublicKey::from_file(path))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to load verification key")?;
+    let signing_key = args
+        .resign_key
+        .as_deref()
+        .map(SigningKey::from_file)
+        .transpose()
+        .context("Failed to load signing key")?;
+
+    let source = Source::from_path(&args.payload_path);
+    let use_parallel = (args.payload_path.extension().and_then(|e| e.to_str()) == Some("bin")
+        || !matches!(source, Source::File(_)))
+        && !args.no_parallel;
+    let mut extractor = Extractor::new(source.clone())
+        .out_dir(&args.out)
+        .partitions(args.partitions.iter().cloned())
+        .parallel(use_parallel)
+        .threads(args.threads)
+        .verify(!args.no_verify)
+        .on_corrupt(args.on_corrupt)
+        .resume(args.resume)
+        .skip_existing(args.skip_existing)
+        .user_agent(args.user_agent.clone())
+        .cancellation(cancel.clone());
+    extractor = match events {
+        Some(events) => extractor.progress(Arc::clone(events)),
+        None => extractor.progress(IndicatifProgress::new(multi_progress.clone())),
+    };
+    if args.diff {
+        extractor = extractor.old_dir(&args.old);
     }
 
-    let mut payload_reader: Box<dyn ReadSeek> = if is_url {
+    if source.is_remote() {
         main_pb.set_message("Initializing remote connection...");
+    } else {
+        main_pb.set_message("Opening file...");
+        if events.is_none()
+            && let Ok(metadata) = fs::metadata(&args.payload_path)
+            && metadata.len() > 1024 * 1024
+        {
+            println!(
+                "Processing file: {}, size: {}",
+                args.payload_path.display(),
+                format_size(metadata.len())
+            );
+        }
+    }
 
-        let url = payload_path_str.clone();
-        let is_zip = std::path::Path::new(&url)
-            .extension()
-            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
-        let content_type = if is_zip {
-            None
-        } else {
-            let http_reader = HttpReader::new_silent(url.clone(), &args.user_agent);
-            http_reader.map_or(None, |reader| {
-                let file_size = reader.content_length;
-                main_pb.set_message("Connection established");
-                if file_size > 1024 * 1024 && !FILE_SIZE_INFO_SHOWN.swap(true, Ordering::SeqCst) {
-                    println!("- Remote file size: {}", format_size(file_size));
-                }
-                reader.content_type
-            })
-        };
+    let mut payload = extractor.open_payload().context("Failed to open payload")?;
+    let payload_size = payload.reader_mut().seek(SeekFrom::End(0))?;
+    if let Some(events) = events {
+        events.emit(
+            "source_opened",
+            json!({ "source": args.payload_path, "size": payload_size }),
+        );
+    } else if source.is_remote() {
+        main_pb.set_message("Connection established");
+        if payload_size > 1024 * 1024 {
+            println!("- Remote payload size: {}", format_size(payload_size));
+        }
+    }
 
-        if is_zip || content_type.as_deref() == Some("application/zip") {
-            let reader = RemoteZipReader::new_for_parallel(url, &args.user_agent)?;
-            let file_size = reader.http_reader.content_length;
-            main_pb.set_message("Connection established");
-            if file_size > 1024 * 1024 && !FILE_SIZE_INFO_SHOWN.swap(true, Ordering::SeqCst) {
-                println!("- Remote ZIP size: {}", format_size(file_size));
-            }
-            Box::new(reader) as Box<dyn ReadSeek>
+    if !keys.is_empty() && !args.signatures {
+        main_pb.set_message("Verifying payload signatures...");
+        let report = verify_payload_signatures(&mut payload, &keys)
+            .context("Failed to verify payload signatures")?;
+        if let Some(events) = events {
+            events.emit(
+                "signature_verified",
+                json!({
+                    "metadata": report.metadata.to_string(),
+                    "payload": report.payload.to_string(),
+                    "verified": report.is_verified(),
+                }),
+            );
         } else {
-            let reader = HttpReader::new(url, &args.user_agent)?;
-            let file_size = reader.content_length;
-            main_pb.set_message("Connection established");
-            if file_size > 1024 * 1024 && !FILE_SIZE_INFO_SHOWN.swap(true, Ordering::SeqCst) {
-                println!("- Remote file size: {}", format_size(file_size));
-            }
-            Box::new(reader) as Box<dyn ReadSeek>
+            println!("- Metadata signature: {}", report.metadata);
+            println!("- Payload signature: {}", report.payload);
         }
-    } else if is_local_zip {
-        ZipPayloadReader::<std::fs::File>::from_file(&args.payload_path)
-            .map_err(|e| anyhow::anyhow!("Failed to open ZIP file: {e}"))
-            .map(|reader| Box::new(reader) as Box<dyn ReadSeek>)?
-    } else {
-        Box::new(File::open(&args.payload_path)?) as Box<dyn ReadSeek>
-    };
+        if !report.is_verified() {
+            main_pb.finish_and_clear();
+            bail!("Payload signature verification failed, refusing to continue");
+        }
+    }
 
     if args.out.to_string_lossy() != "-" {
         fs::create_dir_all(&args.out)?;
     }
 
-    let mut magic = [0u8; 4];
-    payload_reader.read_exact(&mut magic)?;
-    if magic != *b"CrAU" {
-        bail!("Invalid payload file: magic 'CrAU' not found");
-    }
-    let file_format_version = payload_reader.read_u64::<BigEndian>()?;
-    if file_format_version != 2 {
-        bail!("Unsupported payload version: {file_format_version}");
+    if args.verify_zip {
+        main_pb.set_message("Verifying zip signature...");
+        let report = extractor
+            .verify_zip_signature(&keys)
+            .context("Failed to verify zip signature")?
+            .context("--verify-zip needs an OTA zip")?;
+        let otacert_path = args.out.join("otacert.pem");
+        let saved = match &report.otacert {
+            Some(otacert) if args.out.to_string_lossy() != "-" => {
+                fs::write(&otacert_path, otacert).context("Failed to save otacert")?;
+                Some(otacert_path)
+            }
+            _ => None,
+        };
+        if let Some(events) = events {
+            events.emit(
+                "zip_signature_verified",
+                json!({
+                    "signature": report.check.to_string(),
+                    "signers": report.signers,
+                    "otacert": saved,
+                    "verified": report.check.is_verified(),
+                }),
+            );
+        } else {
+            println!("- Zip signature: {}", report.check);
+            for signer in &report.signers {
+                println!("- Zip signer: {signer}");
+            }
+            if let Some(path) = &saved {
+                println!("✓ otacert saved to: {}", path.display());
+            }
+        }
+        if let SignatureCheck::SelfSigned { .. } = report.check {
+            main_pb.finish_and_clear();
+            bail!(
+                "Zip signature only matches the zip's own otacert, refusing to continue; pass \
+                 --verify-key with a trusted key or certificate"
+            );
+        }
+        if !report.check.is_verified() {
+            main_pb.finish_and_clear();
+            bail!("Zip signature verification failed, refusing to continue");
+        }
     }
-    let manifest_size = payload_reader.read_u64::<BigEndian>()?;
-    let metadata_signature_size = payload_reader.read_u32::<BigEndian>()?;
-    main_pb.set_message("Reading manifest...");
-    let mut manifest = vec![0u8; manifest_size as usize];
-    payload_reader.read_exact(&mut manifest)?;
-    let mut metadata_signature = vec![0u8; metadata_signature_size as usize];
-    payload_reader.read_exact(&mut metadata_signature)?;
-    let data_offset = payload_reader.stream_position()?;
-    let manifest = DeltaArchiveManifest::decode(&manifest[..])?;
-
-    if is_differential_ota(&manifest) && !args.diff {
+
+    let data_offset = payload.data_offset();
+    let manifest = payload.manifest();
+
+    if is_differential_ota(manifest) && !args.diff {
         bail!(
             "This appears to be a differential OTA package. Use --diff argument and provide the original partitions directory with --old <path>"
         );
     }
-    if let Some(security_patch) = &manifest.security_patch_level {
+    if let Some(events) = events {
+        events.emit(
+            "manifest_parsed",
+            json!({
+                "version": payload.version(),
+                "minor_version": payload.minor_version(),
+                "block_size": payload.block_size(),
+                "partitions": manifest.partitions.len(),
+                "security_patch_level": manifest.security_patch_level,
+            }),
+        );
+    } else if let Some(security_patch) = &manifest.security_patch_level {
         println!("- Security Patch: {security_patch}");
     }
 
+    if args.signatures {
+        main_pb.set_message("Reading signatures...");
+        let out_dir = (args.out.to_string_lossy() != "-").then_some(args.out.as_path());
+        let sets =
+            dump_signatures(&mut payload, out_dir, &keys).context("Failed to dump signatures")?;
+        main_pb.finish_and_clear();
+        multi_progress.clear()?;
+        if sets.is_empty() {
+            bail!("The payload is not signed");
+        }
+        for set in &sets {
+            if let Some(events) = events {
+                let signatures = set
+                    .signatures
+                    .iter()
+                    .map(|signature| {
+                        json!({
+                            "size": signature.size,
+                            "unpadded_size": signature.unpadded_size,
+                            "path": signature.path,
+                            "key": signature.recovered.as_ref().map(|r| &r.key),
+                            "algorithm": signature
+                                .recovered
+                                .as_ref()
+                                .map(|r| r.info.algorithm_name()),
+                            "digest": signature
+                                .recovered
+                                .as_ref()
+                                .map(|r| hex::encode(&r.info.digest)),
+                            "matches": signature.recovered.as_ref().map(|r| r.matches),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                events.emit(
+                    "signatures_dumped",
+                    json!({
+                        "name": set.name,
+                        "digest": hex::encode(set.digest),
+                        "path": set.path,
+                        "signatures": signatures,
+                    }),
+                );
+                continue;
+            }
+            println!(
+                "- {} signatures over SHA-256 {}",
+                capitalize(set.name),
+                hex::encode(set.digest)
+            );
+            if let Some(path) = &set.path {
+                println!("  Saved to: {}", path.display());
+            }
+            for (index, signature) in set.signatures.iter().enumerate() {
+                let unpadded = signature
+                    .unpadded_size
+                    .map(|size| format!(", {size} bytes unpadded"))
+                    .unwrap_or_default();
+                println!("  - Signature {index}: {} bytes{unpadded}", signature.size);
+                match &signature.recovered {
+                    Some(recovered) => println!(
+                        "    Digest info with {}: {} ({})",
+                        recovered.key,
+                        recovered.info,
+                        if recovered.matches {
+                            "matches"
+                        } else {
+                            "does not match"
+                        }
+                    ),
+                    None if !keys.is_empty() => {
+                        println!("    Not made by any of the given keys");
+                    }
+                    None => {}
+                }
+            }
+        }
+        finish_early(events, start_time);
+        return Ok(());
+    }
+
+    if let Some(signing_key) = &signing_key {
+        if args.out.to_string_lossy() == "-" {
+            bail!("--resign-key needs an output directory");
+        }
+        main_pb.set_message(format!("Re-signing payload with {}...", signing_key.name()));
+        // Written next to the output first, in case the payload is read from there.
+        let path = args.out.join("payload.bin");
+        let temp_path = args.out.join("payload.bin.tmp");
+        let mut out = io::BufWriter::new(fs::File::create(&temp_path)?);
+        let resigned = resign_payload(&mut payload, signing_key, &mut out)
+            .context("Failed to re-sign payload")?;
+        drop(out);
+        fs::rename(&temp_path, &path)?;
+        main_pb.finish_and_clear();
+        multi_progress.clear()?;
+        if let Some(events) = events {
+            events.emit(
+                "payload_resigned",
+                json!({
+                    "path": path,
+                    "size": resigned.size,
+                    "hash": hex::encode(resigned.hash),
+                    "metadata_size": resigned.metadata_size,
+                    "metadata_hash": hex::encode(resigned.metadata_hash),
+                }),
+            );
+        } else {
+            println!("✓ Re-signed payload saved to: {}", path.display());
+            println!("- Size: {} bytes", resigned.size);
+            println!("- SHA-256: {}", hex::encode(resigned.hash));
+            println!(
+                "- Metadata: {} bytes, SHA-256 {}",
+                resigned.metadata_size,
+                hex::encode(resigned.metadata_hash)
+            );
+        }
+        finish_early(events, start_time);
+        return Ok(());
+    }
+
     if args.metadata && !args.list {
         main_pb.set_message("Extracting metadata...");
-        let is_stdout = args.out.to_string_lossy() == "-";
-        match save_metadata(&manifest, &args.out, data_offset) {
+        match save_metadata(manifest, &args.out, data_offset) {
             Ok(json) => {
-                if is_stdout {
-                    println!("{json}");
-                } else {
-                    println!(
-                        "✓ Metadata saved to: {}/payload_metadata.json",
-                        args.out.display()
-                    );
-                }
+                report_metadata(&json, &args.out, events)?;
                 multi_progress.clear()?;
+                finish_early(events, start_time);
                 return Ok(());
             }
             Err(e) => {
@@ -192,17 +391,12 @@ fn main() -> Result<()> {
         multi_progress.clear()?;
 
         if args.metadata {
-            let is_stdout = args.out.to_string_lossy() == "-";
-            match save_metadata(&manifest, &args.out, data_offset) {
+            match save_metadata(manifest, &args.out, data_offset) {
                 Ok(json) => {
-                    if is_stdout {
-                        println!("{json}");
+                    report_metadata(&json, &args.out, events)?;
+                    if events.is_none() && args.out.to_string_lossy() == "-" {
                         return Ok(());
                     }
-                    println!(
-                        "✓ Metadata saved to: {}/payload_metadata.json",
-                        args.out.display()
-                    );
                 }
                 Err(e) => {
                     eprintln!("Failed to save metadata: {e}");
@@ -210,236 +404,144 @@ fn main() -> Result<()> {
             }
         }
 
-        println!();
-        payload_reader.seek(SeekFrom::Start(0))?;
-        return list_partitions(&mut payload_reader);
+        if let Some(events) = events {
+            let partitions = manifest
+                .partitions
+                .iter()
+                .map(|partition| {
+                    json!({
+                        "partition": partition.partition_name,
+                        "operations": partition.operations.len(),
+                        "size": partition.new_partition_info.as_ref().and_then(|info| info.size),
+                    })
+                })
+                .collect::<Vec<_>>();
+            events.emit("partitions_listed", json!({ "partitions": partitions }));
+        } else {
+            println!();
+            list_partitions(&payload, &mut io::stdout())?;
+        }
+        finish_early(events, start_time);
+        return Ok(());
     }
 
-    let block_size = manifest.block_size.unwrap_or(4096);
-    let partitions_to_extract: Vec<_> = if args.partitions.is_empty() {
-        manifest.partitions.iter().collect()
-    } else {
-        let partitions: HashSet<_> = args.partitions.iter().collect();
-        manifest
-            .partitions
-            .iter()
-            .filter(|p| partitions.contains(&p.partition_name))
-            .collect()
-    };
-    if partitions_to_extract.is_empty() {
+    let partition_count = manifest
+        .partitions
+        .iter()
+        .filter(|p| args.partitions.is_empty() || args.partitions.contains(&p.partition_name))
+        .count();
+    if partition_count == 0 {
         main_pb.finish_with_message("No partitions to extract");
         multi_progress.clear()?;
+        finish_early(events, start_time);
         return Ok(());
     }
-    main_pb.set_message(format!(
-        "Found {} partitions to extract",
-        partitions_to_extract.len()
-    ));
-
-    let use_parallel = (args.payload_path.extension().and_then(|e| e.to_str()) == Some("bin")
-        || is_local_zip
-        || is_url)
-        && !args.no_parallel;
-    main_pb.set_message(if use_parallel {
-        "Extracting Partitions..."
+    main_pb.set_message(if args.skip_existing {
+        format!("Checking existing images of {partition_count} partitions...")
+    } else if use_parallel {
+        format!("Extracting {partition_count} partitions...")
     } else {
-        "Processing partitions..."
+        format!("Processing {partition_count} partitions...")
     });
-    let multi_progress = Arc::new(multi_progress);
-    let args = Arc::new(args);
-    let mut failed_partitions = Vec::new();
 
-    if use_parallel {
-        let payload_path = Arc::new(args.payload_path.to_str().unwrap_or_default().to_string());
-        let payload_url = Arc::new(if is_url {
-            payload_path_str
-        } else {
-            String::new()
-        });
-
-        let max_retries = 3;
-        let num_cpus = num_cpus::get();
-        let chunk_size = std::cmp::max(1, partitions_to_extract.len() / num_cpus);
-        let active_readers = Arc::new(std::sync::atomic::AtomicUsize::new(0));
-        let max_concurrent_readers = num_cpus;
-
-        let results: Vec<_> = partitions_to_extract
-            .par_chunks(chunk_size)
-            .flat_map(|chunk| {
-                chunk.par_iter().map(|partition| {
-                    let active_readers = Arc::clone(&active_readers);
-
-                    (0..max_retries)
-                        .find_map(|attempt| {
-                            if attempt > 0 {
-                                let delay = 100 * (1 << attempt.min(4));
-                                std::thread::sleep(Duration::from_millis(delay));
-                            }
-
-                            let needs_reader_limit = !is_url && is_local_zip;
-
-                            if needs_reader_limit {
-                                let current =
-                                    active_readers.load(std::sync::atomic::Ordering::SeqCst);
-                                if current >= max_concurrent_readers {
-                                    while active_readers.load(std::sync::atomic::Ordering::SeqCst)
-                                        >= max_concurrent_readers
-                                    {
-                                        std::thread::sleep(Duration::from_millis(10));
-                                    }
-                                }
-
-                                active_readers.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                            }
-
-                            let reader_result = if is_url {
-                                RemoteZipReader::new_for_parallel(
-                                    (*payload_url).clone(),
-                                    &args.user_agent,
-                                )
-                                .map(|reader| Box::new(reader) as Box<dyn ReadSeek>)
-                                // Fix for the error type mismatch around line 384-398
-                            } else if is_local_zip {
-                                let result =
-                                    ZipPayloadReader::new_for_parallel((*payload_path).clone())
-                                        .map(|reader| Box::new(reader) as Box<dyn ReadSeek>)
-                                        .map_err(|e| {
-                                            anyhow::anyhow!("Failed to create ZIP reader: {e}")
-                                        }); // Convert io::Error to anyhow::Error
-                                active_readers.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
-                                result
-                            } else {
-                                create_payload_reader(&args.payload_path).map_err(|e| {
-                                    anyhow::anyhow!("Failed to create payload reader: {e}")
-                                })
-                            };
-
-                            let mut reader = match reader_result {
-                                Ok(reader) => reader,
-                                Err(e) => {
-                                    return if attempt == max_retries - 1 {
-                                        Some(Err((partition.partition_name.clone(), e)))
-                                    } else {
-                                        None // Try again
-                                    };
-                                }
-                            };
-
-                            match dump_partition(
-                                partition,
-                                data_offset,
-                                u64::from(block_size),
-                                &args.out,
-                                &args.old,
-                                args.diff,
-                                &mut reader,
-                                Some(&multi_progress),
-                            ) {
-                                Ok(()) => Some(Ok(())),
-                                Err(e) => {
-                                    if attempt == max_retries - 1 {
-                                        Some(Err((partition.partition_name.clone(), e)))
-                                    } else {
-                                        None // Try again
-                                    }
-                                }
-                            }
-                        })
-                        .unwrap_or_else(|| {
-                            Err((
-                                partition.partition_name.clone(),
-                                anyhow!("All retry attempts failed"),
-                            ))
-                        })
-                })
-            })
-            .collect();
-        for result in results {
-            if let Err((partition_name, error)) = result {
-                eprintln!("Failed to process partition {partition_name}: {error}");
-                failed_partitions.push(partition_name);
-            }
-        }
-        if !failed_partitions.is_empty() {
-            main_pb.set_message(format!(
-                "Retrying {} failed partitions sequentially...",
-                failed_partitions.len()
-            ));
-
-            let mut reader: Box<dyn ReadSeek> = if is_url {
-                Box::new(RemoteZipReader::new_for_parallel(
-                    payload_url.to_string(),
-                    &args.user_agent,
-                )?) as Box<dyn ReadSeek>
-            } else {
-                payload_reader
-            };
-
-            let mut remaining_failed_partitions = Vec::new();
-            for partition in partitions_to_extract
-                .iter()
-                .filter(|p| failed_partitions.contains(&p.partition_name))
-            {
-                if let Err(e) = dump_partition(
-                    partition,
-                    data_offset,
-                    u64::from(block_size),
-                    &args.out,
-                    &args.old,
-                    args.diff,
-                    &mut reader,
-                    Some(&multi_progress),
-                ) {
-                    eprintln!(
-                        "Failed to process partition {} in sequential mode: {}",
-                        partition.partition_name, e
-                    );
-                    remaining_failed_partitions.push(partition.partition_name.clone());
+    let mut completed_partitions = Vec::new();
+    let mut reused_partitions = Vec::new();
+    let mut cancelled_partitions = Vec::new();
+    let mut failed_partitions = Vec::new();
+    let mut mismatched_partitions = Vec::new();
+    for partition in extractor.extract(payload)? {
+        match partition.result {
+            Err(e) if e.is_cancelled() => cancelled_partitions.push(partition.name),
+            Ok(report) => {
+                completed_partitions.push(partition.name.clone());
+                if report.reused {
+                    reused_partitions.push(partition.name.clone());
+                }
+                for warning in report.warnings {
+                    eprintln!("Warning: {:#}", anyhow::Error::new(warning));
+                }
+                if report.hash == HashCheck::Mismatch {
+                    mismatched_partitions.push(partition.name);
                 }
             }
-            failed_partitions = remaining_failed_partitions;
+            Err(e) => {
+                eprintln!("Error: {:#}", anyhow::Error::new(e));
+                failed_partitions.push(partition.name);
+            }
         }
-    } else {
-        for partition in &partitions_to_extract {
-            if let Err(e) = dump_partition(
-                partition,
-                data_offset,
-                u64::from(block_size),
-                &args.out,
-                &args.old,
-                args.diff,
-                &mut payload_reader,
-                Some(&multi_progress),
-            ) {
-                eprintln!(
-                    "Failed to process partition {}: {}",
-                    partition.partition_name, e
-                );
-                failed_partitions.push(partition.partition_name.clone());
+    }
+
+    if cancel.is_cancelled() {
+        if let Some(events) = events {
+            events.emit(
+                "summary",
+                json!({
+                    "elapsed_ms": start_time.elapsed().as_millis(),
+                    "partitions": partition_count,
+                    "completed": completed_partitions,
+                    "cancelled": cancelled_partitions,
+                    "failed": failed_partitions,
+                    "success": false,
+                }),
+            );
+        } else {
+            main_pb.finish_with_message("Extraction cancelled");
+            println!(
+                "\nExtraction cancelled after {}. Output directory: {}",
+                format_elapsed_time(start_time.elapsed()),
+                args.out.display()
+            );
+            println!(
+                "- Completed ({}): {}",
+                completed_partitions.len(),
+                completed_partitions.join(", ")
+            );
+            println!(
+                "- Not extracted ({}): {}",
+                cancelled_partitions.len(),
+                cancelled_partitions.join(", ")
+            );
+            if args.resume {
+                println!("Run the same command again to resume.");
             }
         }
+        std::process::exit(130);
     }
 
     if args.no_verify {
         main_pb.set_message("Hash verification skipped (--no-verify flag)");
-    } else {
-        main_pb.set_message("Verifying partition hashes...");
-        let partitions_to_verify: Vec<&PartitionUpdate> = partitions_to_extract
-            .iter()
-            .filter(|p| !failed_partitions.contains(&p.partition_name))
-            .copied()
-            .collect();
-        let failed_verifications = if args.no_verify {
-            vec![]
-        } else {
-            verify_partitions_hash(&partitions_to_verify, &args.out, &multi_progress)
-        };
-        if !failed_verifications.is_empty() {
-            eprintln!(
-                "Hash verification failed for {} partitions.",
-                failed_verifications.len()
-            );
+    } else if !mismatched_partitions.is_empty() {
+        eprintln!(
+            "Hash verification failed for {} partitions: {}",
+            mismatched_partitions.len(),
+            mismatched_partitions.join(", ")
+        );
+    }
+    // Images failing verification are as corrupt as partitions that failed to extract.
+    failed_partitions.extend(mismatched_partitions);
+    if events.is_none() && !reused_partitions.is_empty() {
+        println!(
+            "- Reused {} existing partitions: {}",
+            reused_partitions.len(),
+            reused_partitions.join(", ")
+        );
+    }
+
+    if let Some(events) = events {
+        events.emit(
+            "summary",
+            json!({
+                "elapsed_ms": start_time.elapsed().as_millis(),
+                "partitions": partition_count,
+                "reused": reused_partitions,
+                "failed": failed_partitions,
+                "success": failed_partitions.is_empty(),
+            }),
+        );
+        if !failed_partitions.is_empty() {
+            std::process::exit(1);
         }
+        return Ok(());
     }
 
     let elapsed_time = format_elapsed_time(start_time.elapsed());
@@ -464,7 +566,52 @@ fn main() -> Result<()> {
             elapsed_time,
             args.out.display()
         );
+        std::process::exit(1);
     }
 
     Ok(())
 }
+
+/// Report where the payload metadata in `json` was saved, or show it when saved to stdout.
+fn report_metadata(json: &str, out: &Path, events: Option<&Events>) -> Result<()> {
+    let is_stdout = out.to_string_lossy() == "-";
+    if let Some(events) = events {
+        let metadata = if is_stdout {
+            Some(serde_json::from_str::<Value>(json)?)
+        } else {
+            None
+        };
+        events.emit(
+            "metadata_saved",
+            json!({
+                "path": (!is_stdout).then(|| out.join("payload_metadata.json")),
+                "metadata": metadata,
+            }),
+        );
+    } else if is_stdout {
+        println!("{json}");
+    } else {
+        println!(
+            "✓ Metadata saved to: {}/payload_metadata.json",
+            out.display()
+        );
+    }
+    Ok(())
+}
+
+/// Report the end of a run that had nothing to extract.
+fn finish_early(events: Option<&Events>, start_time: Instant) {
+    if let Some(events) = events {
+        events.emit(
+            "summary",
+            json!({ "elapsed_ms": start_time.elapsed().as_millis(), "success": true }),
+        );
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    chars.next().map_or_else(String::new, |first| {
+        first.to_uppercase().chain(chars).collect()
+    })
+}
@@ -1,9 +1,51 @@
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
+use bzip2::read::BzDecoder;
 
-/// Apply a bsdiff patch to old data to produce new data
+/// Magic of the classic bsdiff format, with bzip2-compressed streams.
+const BSDIFF40_MAGIC: &[u8; 8] = b"BSDIFF40";
+/// Magic of the AOSP bsdiff format, followed by one compression type byte per stream.
+const BSDF2_MAGIC: &[u8; 5] = b"BSDF2";
+/// Size of the magic plus the control length, diff length and new size fields.
+const HEADER_SIZE: usize = 32;
+
+/// Compression of a single BSDF2 stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamCompression {
+    None,
+    Bz2,
+    Brotli,
+}
+
+impl TryFrom<u8> for StreamCompression {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Bz2),
+            2 => Ok(Self::Brotli),
+            _ => bail!("Unknown BSDF2 stream compression {value}"),
+        }
+    }
+}
+
+/// Apply a bsdiff patch to old data to produce new data. `BSDF2` and `BSDIFF40` patches are
+/// recognized by their magic; anything else is treated as a raw `bsdiff` crate patch.
 pub fn bspatch(old_data: &[u8], patch_data: &[u8]) -> Result<Vec<u8>> {
+    if patch_data.starts_with(BSDIFF40_MAGIC) {
+        return apply_patch(old_data, patch_data, [StreamCompression::Bz2; 3]);
+    }
+    if patch_data.starts_with(BSDF2_MAGIC) && patch_data.len() >= HEADER_SIZE {
+        let compression = [
+            patch_data[5].try_into()?,
+            patch_data[6].try_into()?,
+            patch_data[7].try_into()?,
+        ];
+        return apply_patch(old_data, patch_data, compression);
+    }
+
     let mut new_data = Vec::new();
     let mut patch_cursor = Cursor::new(patch_data);
 
@@ -13,8 +55,124 @@ pub fn bspatch(old_data: &[u8], patch_data: &[u8]) -> Result<Vec<u8>> {
     Ok(new_data)
 }
 
+/// Apply a `BSDIFF40` or `BSDF2` patch whose control, diff and extra streams use `compression`.
+fn apply_patch(
+    old_data: &[u8],
+    patch_data: &[u8],
+    compression: [StreamCompression; 3],
+) -> Result<Vec<u8>> {
+    if patch_data.len() < HEADER_SIZE {
+        bail!("Truncated bsdiff header");
+    }
+    let ctrl_len = header_field(patch_data, 8, "control length")?;
+    let diff_len = header_field(patch_data, 16, "diff length")?;
+    let new_size = header_field(patch_data, 24, "new size")?;
+
+    let ctrl_end = HEADER_SIZE
+        .checked_add(ctrl_len)
+        .filter(|&end| end <= patch_data.len())
+        .ok_or_else(|| anyhow!("Truncated bsdiff control stream"))?;
+    let diff_end = ctrl_end
+        .checked_add(diff_len)
+        .filter(|&end| end <= patch_data.len())
+        .ok_or_else(|| anyhow!("Truncated bsdiff diff stream"))?;
+    let ctrl = decompress(&patch_data[HEADER_SIZE..ctrl_end], compression[0])
+        .context("Failed to decompress bsdiff control stream")?;
+    let diff = decompress(&patch_data[ctrl_end..diff_end], compression[1])
+        .context("Failed to decompress bsdiff diff stream")?;
+    let extra = decompress(&patch_data[diff_end..], compression[2])
+        .context("Failed to decompress bsdiff extra stream")?;
+
+    // Every output byte comes from the diff or extra stream, so a larger new size cannot be
+    // satisfied and must not be trusted for the allocation.
+    if diff.len().saturating_add(extra.len()) < new_size {
+        bail!("bsdiff new size {new_size} exceeds the diff and extra streams");
+    }
+    let mut new_data = Vec::with_capacity(new_size);
+    let (mut diff_pos, mut extra_pos) = (0usize, 0usize);
+    let mut old_pos = 0i64;
+    let mut controls = ctrl.chunks_exact(24);
+    while new_data.len() < new_size {
+        let Some(control) = controls.next() else {
+            bail!("bsdiff control stream ended early");
+        };
+        let add_len = control_length(offtin(&control[..8]), new_data.len(), new_size)?;
+        let copy_len = control_length(offtin(&control[8..16]), new_data.len() + add_len, new_size)?;
+        let seek = offtin(&control[16..]);
+
+        let Some(diff_chunk) = diff.get(diff_pos..diff_pos + add_len) else {
+            bail!("bsdiff diff stream ended early");
+        };
+        new_data.extend(diff_chunk.iter().enumerate().map(|(i, &byte)| {
+            old_pos
+                .checked_add(i as i64)
+                .and_then(|pos| usize::try_from(pos).ok())
+                .and_then(|pos| old_data.get(pos))
+                .map_or(byte, |&old| byte.wrapping_add(old))
+        }));
+        diff_pos += add_len;
+        old_pos = old_pos
+            .checked_add(add_len as i64)
+            .and_then(|pos| pos.checked_add(seek))
+            .ok_or_else(|| anyhow!("Invalid bsdiff control entry"))?;
+
+        let Some(extra_chunk) = extra.get(extra_pos..extra_pos + copy_len) else {
+            bail!("bsdiff extra stream ended early");
+        };
+        new_data.extend_from_slice(extra_chunk);
+        extra_pos += copy_len;
+    }
+
+    Ok(new_data)
+}
+
+/// Read a non-negative header field at `offset`.
+fn header_field(patch_data: &[u8], offset: usize, name: &str) -> Result<usize> {
+    usize::try_from(offtin(&patch_data[offset..offset + 8]))
+        .map_err(|_| anyhow!("Invalid bsdiff {name}"))
+}
+
+/// Validate a control length, which must not write past `new_size` from `position`.
+fn control_length(value: i64, position: usize, new_size: usize) -> Result<usize> {
+    usize::try_from(value)
+        .ok()
+        .filter(|&len| len <= new_size - position.min(new_size))
+        .ok_or_else(|| anyhow!("Invalid bsdiff control entry"))
+}
+
+/// Decode bsdiff's sign-magnitude little-endian 64-bit integer.
+fn offtin(bytes: &[u8]) -> i64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(bytes);
+    let magnitude = (u64::from_le_bytes(value) & !(1 << 63)) as i64;
+    if bytes[7] & 0x80 == 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+fn decompress(data: &[u8], compression: StreamCompression) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    match compression {
+        StreamCompression::None => decompressed.extend_from_slice(data),
+        StreamCompression::Bz2 => {
+            BzDecoder::new(data).read_to_end(&mut decompressed)?;
+        }
+        StreamCompression::Brotli => {
+            brotli_decompressor::Decompressor::new(data, 4096).read_to_end(&mut decompressed)?;
+        }
+    }
+    Ok(decompressed)
+}
+
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
+
+    use bzip2::{Compression, write::BzEncoder};
+    use sha2::{Digest, Sha256};
+
     use super::*;
 
     #[test]
@@ -31,4 +189,192 @@ mod tests {
         let result = bspatch(&old_data, &patch).unwrap();
         assert_eq!(result, new_data);
     }
+
+    fn offtout(value: i64) -> [u8; 8] {
+        let mut bytes = value.unsigned_abs().to_le_bytes();
+        if value < 0 {
+            bytes[7] |= 0x80;
+        }
+        bytes
+    }
+
+    fn compress(data: &[u8], compression: StreamCompression) -> Vec<u8> {
+        match compression {
+            StreamCompression::None => data.to_vec(),
+            StreamCompression::Bz2 => {
+                let mut encoder = BzEncoder::new(Vec::new(), Compression::best());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            StreamCompression::Brotli => {
+                let mut compressed = Vec::new();
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 9, 22);
+                encoder.write_all(data).unwrap();
+                drop(encoder);
+                compressed
+            }
+        }
+    }
+
+    /// Build a patch in the `BSDF2` or `BSDIFF40` container from its control entries and
+    /// uncompressed diff and extra streams, the way AOSP's `bsdiff` lays them out.
+    fn build_patch(
+        magic: &[u8],
+        compression: [StreamCompression; 3],
+        controls: &[(i64, i64, i64)],
+        diff: &[u8],
+        extra: &[u8],
+        new_size: usize,
+    ) -> Vec<u8> {
+        let ctrl = controls
+            .iter()
+            .flat_map(|&(x, y, z)| [offtout(x), offtout(y), offtout(z)])
+            .flatten()
+            .collect::<Vec<_>>();
+        let ctrl = compress(&ctrl, compression[0]);
+        let diff = compress(diff, compression[1]);
+        let extra = compress(extra, compression[2]);
+
+        let mut patch = magic.to_vec();
+        patch.extend_from_slice(&offtout(ctrl.len() as i64));
+        patch.extend_from_slice(&offtout(diff.len() as i64));
+        patch.extend_from_slice(&offtout(new_size as i64));
+        patch.extend_from_slice(&ctrl);
+        patch.extend_from_slice(&diff);
+        patch.extend_from_slice(&extra);
+        patch
+    }
+
+    /// "Hello, old world!" -> "Hello, new world! Bye.", with a backwards seek.
+    const OLD: &[u8] = b"Hello, old world!";
+    const NEW: &[u8] = b"Hello, new world! Bye.";
+    const CONTROLS: &[(i64, i64, i64)] = &[(7, 3, 3), (7, 0, -17), (0, 5, 0)];
+    const DIFF: &[u8] = &[0; 14];
+    const EXTRA: &[u8] = b"new Bye.";
+
+    #[test]
+    fn test_bspatch_bsdiff40() {
+        let patch = build_patch(
+            BSDIFF40_MAGIC,
+            [StreamCompression::Bz2; 3],
+            CONTROLS,
+            DIFF,
+            EXTRA,
+            NEW.len(),
+        );
+        assert_eq!(bspatch(OLD, &patch).unwrap(), NEW);
+    }
+
+    #[test]
+    fn test_bspatch_bsdf2() {
+        use StreamCompression::{Brotli, Bz2, None};
+        for compression in [[None; 3], [Bz2; 3], [Brotli; 3], [None, Bz2, Brotli]] {
+            let mut magic = BSDF2_MAGIC.to_vec();
+            magic.extend(compression.iter().map(|&c| c as u8));
+            let patch = build_patch(&magic, compression, CONTROLS, DIFF, EXTRA, NEW.len());
+            assert_eq!(bspatch(OLD, &patch).unwrap(), NEW, "{compression:?}");
+        }
+    }
+
+    #[test]
+    fn test_bspatch_diff_bytes() {
+        // Diff bytes are added to the old data, wrapping around.
+        let patch = build_patch(
+            b"BSDF2\0\0\0",
+            [StreamCompression::None; 3],
+            &[(3, 0, 0)],
+            &[1, 0xFF, 0x80],
+            &[],
+            3,
+        );
+        assert_eq!(bspatch(&[1, 2, 0x80], &patch).unwrap(), [2, 1, 0]);
+    }
+
+    #[test]
+    fn test_bspatch_invalid() {
+        let mut patch = build_patch(
+            b"BSDF2\0\0\0",
+            [StreamCompression::None; 3],
+            CONTROLS,
+            DIFF,
+            EXTRA,
+            NEW.len(),
+        );
+        // Claim a larger output than the control entries describe.
+        patch[24] += 1;
+        assert!(bspatch(OLD, &patch).is_err());
+        patch[5] = 3;
+        assert!(bspatch(OLD, &patch).is_err());
+
+        // A huge new size must fail before anything is allocated for it.
+        let mut patch = build_patch(
+            b"BSDF2\0\0\0",
+            [StreamCompression::None; 3],
+            CONTROLS,
+            DIFF,
+            EXTRA,
+            NEW.len(),
+        );
+        patch[24..32].copy_from_slice(&offtout(i64::MAX));
+        assert!(bspatch(OLD, &patch).is_err());
+
+        // Seeking past the range of the old position is an error rather than an overflow.
+        let patch = build_patch(
+            b"BSDF2\0\0\0",
+            [StreamCompression::None; 3],
+            &[(1, 0, i64::MAX), (1, 0, i64::MAX), (1, 0, 0)],
+            &[0; 3],
+            &[],
+            3,
+        );
+        assert!(bspatch(OLD, &patch).is_err());
+    }
+
+    /// Patches made by the independent bsdiff 4.3 port in `scripts/gen_fixtures.py`.
+    #[test]
+    fn test_bspatch_fixtures() {
+        const OLD: &[u8] = include_bytes!("../tests/fixtures/bsdiff/old.bin");
+        const NEW: &[u8] = include_bytes!("../tests/fixtures/bsdiff/new.bin");
+        const NEW_SHA256: &str = "7c3f7d6042181065b9501c6cfe0e3de86111c0077c6bf68ddf80e9d6416a7729";
+
+        for patch in [
+            &include_bytes!("../tests/fixtures/bsdiff/patch.bsdiff40")[..],
+            &include_bytes!("../tests/fixtures/bsdiff/patch.bsdf2")[..],
+        ] {
+            let new_data = bspatch(OLD, patch).unwrap();
+            assert_eq!(hex::encode(Sha256::digest(&new_data)), NEW_SHA256);
+            assert_eq!(new_data, NEW);
+        }
+    }
+
+    /// The `bsdiff` crate ports the diff of bsdiff 4.3 separately from the script, and writes
+    /// the same controls, diff and extra bytes interleaved and uncompressed. Both choosing the
+    /// same matches ties the fixture to what bsdiff 4.3 itself produces.
+    #[test]
+    fn test_bsdiff40_fixture_matches_bsdiff_port() {
+        const OLD: &[u8] = include_bytes!("../tests/fixtures/bsdiff/old.bin");
+        const NEW: &[u8] = include_bytes!("../tests/fixtures/bsdiff/new.bin");
+        const PATCH: &[u8] = include_bytes!("../tests/fixtures/bsdiff/patch.bsdiff40");
+
+        let ctrl_end = HEADER_SIZE + header_field(PATCH, 8, "control").unwrap();
+        let diff_end = ctrl_end + header_field(PATCH, 16, "diff").unwrap();
+        let ctrl = decompress(&PATCH[HEADER_SIZE..ctrl_end], StreamCompression::Bz2).unwrap();
+        let diff = decompress(&PATCH[ctrl_end..diff_end], StreamCompression::Bz2).unwrap();
+        let extra = decompress(&PATCH[diff_end..], StreamCompression::Bz2).unwrap();
+
+        let (mut diff, mut extra) = (&diff[..], &extra[..]);
+        let mut interleaved = Vec::new();
+        for control in ctrl.chunks(24) {
+            let (add, rest) = diff.split_at(offtin(&control[..8]) as usize);
+            let (copy, rest_extra) = extra.split_at(offtin(&control[8..16]) as usize);
+            interleaved.extend_from_slice(control);
+            interleaved.extend_from_slice(add);
+            interleaved.extend_from_slice(copy);
+            (diff, extra) = (rest, rest_extra);
+        }
+
+        let mut expected = Vec::new();
+        bsdiff::diff(OLD, NEW, &mut expected).unwrap();
+        assert!(interleaved == expected);
+    }
 }
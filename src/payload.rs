@@ -0,0 +1,398 @@
+//! Parsing of the payload header and manifest.
+
+use std::io::{self, Read, SeekFrom};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ReadSeek,
+    error::{Error, Result},
+    proto::{DeltaArchiveManifest, InstallOperation, PartitionUpdate, Signatures},
+};
+
+pub const PAYLOAD_MAGIC: &[u8; 4] = b"CrAU";
+/// Version of Chrome OS and early Android payloads, which update a kernel and a root partition
+/// described by legacy manifest fields, and have no metadata signature.
+pub const CHROMEOS_MAJOR_VERSION: u64 = 1;
+/// Version of payloads with any number of partitions.
+pub const BRILLO_MAJOR_VERSION: u64 = 2;
+/// How far into the source to look for the magic when it does not start with it, such as an
+/// OTA zip whose `payload.bin` entry could not be located.
+const MAGIC_SCAN_LIMIT: u64 = 1024 * 1024;
+/// Size of the magic, version, manifest size and metadata signature size fields of a version 2
+/// payload.
+const HEADER_SIZE: u64 = 24;
+
+/// An update payload opened from any seekable source.
+///
+/// Offsets are relative to the start of the source, so operation data can be read through
+/// other readers opened on the same source.
+pub struct Payload<R> {
+    reader: R,
+    /// Offset of the magic in the source.
+    offset: u64,
+    version: u64,
+    manifest: DeltaArchiveManifest,
+    /// SHA-256 of the serialized manifest.
+    manifest_hash: [u8; 32],
+    metadata_signature: Vec<u8>,
+    data_offset: u64,
+}
+
+impl<R: ReadSeek> Payload<R> {
+    /// Parse the header and manifest of the payload in `reader`, scanning for its magic if the
+    /// source does not start with it.
+    pub fn open(mut reader: R) -> Result<Self> {
+        let offset = find_magic(&mut reader)?;
+        reader.seek(SeekFrom::Start(offset + PAYLOAD_MAGIC.len() as u64))?;
+
+        let version = reader.read_u64::<BigEndian>()?;
+        if !matches!(version, CHROMEOS_MAJOR_VERSION | BRILLO_MAJOR_VERSION) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let manifest_size = reader.read_u64::<BigEndian>()?;
+        let metadata_signature_size = if version == BRILLO_MAJOR_VERSION {
+            reader.read_u32::<BigEndian>()?
+        } else {
+            0
+        };
+
+        let mut manifest = vec![0u8; manifest_size as usize];
+        reader.read_exact(&mut manifest)?;
+        let manifest_hash = Sha256::digest(&manifest).into();
+        let mut manifest =
+            DeltaArchiveManifest::decode(&manifest[..]).map_err(|source| Error::Protobuf {
+                what: "manifest",
+                source,
+            })?;
+        if version == CHROMEOS_MAJOR_VERSION {
+            map_legacy_partitions(&mut manifest);
+        }
+        let mut metadata_signature = vec![0u8; metadata_signature_size as usize];
+        reader.read_exact(&mut metadata_signature)?;
+        let data_offset = reader.stream_position()?;
+
+        Ok(Self {
+            reader,
+            offset,
+            version,
+            manifest,
+            manifest_hash,
+            metadata_signature,
+            data_offset,
+        })
+    }
+
+    /// Read the serialized [`Signatures`] of the whole payload, if it is signed.
+    pub fn payload_signature(&mut self) -> Result<Option<Vec<u8>>> {
+        let (Some(offset), Some(size)) = (
+            self.manifest.signatures_offset,
+            self.manifest.signatures_size,
+        ) else {
+            return Ok(None);
+        };
+        let mut signature = vec![0u8; size as usize];
+        self.reader
+            .seek(SeekFrom::Start(self.data_offset + offset))?;
+        self.reader.read_exact(&mut signature)?;
+        Ok(Some(signature))
+    }
+
+    /// Read and decode the signatures of the whole payload, if it is signed.
+    pub fn payload_signatures(&mut self) -> Result<Option<Signatures>> {
+        self.payload_signature()?
+            .map(|signature| decode_signatures(&signature))
+            .transpose()
+    }
+
+    pub const fn reader_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Give back the manifest and the source.
+    pub fn into_parts(self) -> (DeltaArchiveManifest, R) {
+        (self.manifest, self.reader)
+    }
+}
+
+impl<R> Payload<R> {
+    #[must_use]
+    pub const fn manifest(&self) -> &DeltaArchiveManifest {
+        &self.manifest
+    }
+
+    /// SHA-256 of the manifest as serialized in the payload, identifying the update.
+    #[must_use]
+    pub const fn manifest_hash(&self) -> &[u8; 32] {
+        &self.manifest_hash
+    }
+
+    /// Offset of the payload in the source.
+    #[must_use]
+    pub const fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    #[must_use]
+    pub const fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Size of the header and manifest, the part of the payload covered by the metadata
+    /// signature.
+    #[must_use]
+    pub const fn metadata_size(&self) -> u64 {
+        self.data_offset - self.offset - self.metadata_signature.len() as u64
+    }
+
+    /// Offset of the operation data in the source, which `data_offset` of operations and
+    /// `signatures_offset` of the manifest are relative to.
+    #[must_use]
+    pub const fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+
+    #[must_use]
+    pub fn block_size(&self) -> u64 {
+        u64::from(self.manifest.block_size.unwrap_or(4096))
+    }
+
+    #[must_use]
+    pub fn minor_version(&self) -> u32 {
+        self.manifest.minor_version.unwrap_or(0)
+    }
+
+    /// Serialized [`Signatures`] of the header and manifest, empty if they are not signed.
+    #[must_use]
+    pub fn metadata_signature(&self) -> &[u8] {
+        &self.metadata_signature
+    }
+
+    pub fn metadata_signatures(&self) -> Result<Signatures> {
+        decode_signatures(&self.metadata_signature)
+    }
+
+    #[must_use]
+    pub fn partition(&self, name: &str) -> Option<&PartitionUpdate> {
+        self.manifest
+            .partitions
+            .iter()
+            .find(|partition| partition.partition_name == name)
+    }
+}
+
+pub(crate) fn decode_signatures(data: &[u8]) -> Result<Signatures> {
+    Signatures::decode(data).map_err(|source| Error::Protobuf {
+        what: "signatures",
+        source,
+    })
+}
+
+/// Move the rootfs and kernel operations and info of a version 1 manifest to partitions named
+/// `root` and `kernel`, as the update engine does.
+#[allow(deprecated, reason = "Legacy fields of version 1 payloads")]
+fn map_legacy_partitions(manifest: &mut DeltaArchiveManifest) {
+    let signatures_offset = manifest.signatures_offset;
+    let legacy = [
+        (
+            "root",
+            std::mem::take(&mut manifest.install_operations),
+            manifest.old_rootfs_info.take(),
+            manifest.new_rootfs_info.take(),
+        ),
+        (
+            "kernel",
+            std::mem::take(&mut manifest.kernel_install_operations),
+            manifest.old_kernel_info.take(),
+            manifest.new_kernel_info.take(),
+        ),
+    ];
+    for (name, operations, old_partition_info, new_partition_info) in legacy {
+        if operations.is_empty() && new_partition_info.is_none() {
+            continue;
+        }
+        manifest.partitions.push(PartitionUpdate {
+            partition_name: name.to_string(),
+            operations: operations
+                .into_iter()
+                .filter(|op| !is_signature_operation(op, signatures_offset))
+                .collect(),
+            old_partition_info,
+            new_partition_info,
+            ..Default::default()
+        });
+    }
+}
+
+/// Whether `op` is the operation version 1 payloads end with so that older clients hash the
+/// signatures, writing them to a sparse hole rather than to the partition.
+fn is_signature_operation(op: &InstallOperation, signatures_offset: Option<u64>) -> bool {
+    op.data_offset.is_some()
+        && op.data_offset == signatures_offset
+        && !op.dst_extents.is_empty()
+        && op
+            .dst_extents
+            .iter()
+            .all(|extent| extent.start_block == Some(u64::MAX))
+}
+
+/// Find the offset of the payload magic in the first [`MAGIC_SCAN_LIMIT`] bytes of `reader`.
+fn find_magic(reader: &mut impl ReadSeek) -> Result<u64> {
+    // Most sources start with the magic; checking that first spares remote ones a large read.
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; PAYLOAD_MAGIC.len()];
+    match reader.read_exact(&mut magic) {
+        Ok(()) if magic == *PAYLOAD_MAGIC => return Ok(0),
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut head = Vec::new();
+    reader.take(MAGIC_SCAN_LIMIT).read_to_end(&mut head)?;
+    if head.len() < HEADER_SIZE as usize {
+        return Err(Error::BadMagic { offset: 0 });
+    }
+    head.windows(PAYLOAD_MAGIC.len())
+        .position(|window| window == PAYLOAD_MAGIC)
+        .map(|position| position as u64)
+        .ok_or(Error::BadMagic { offset: 0 })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::proto::{Extent, PartitionInfo, install_operation, signatures::Signature};
+
+    /// Serialize a version 2 payload with `manifest`, `data` and signatures.
+    pub fn build_payload(
+        manifest: &DeltaArchiveManifest,
+        metadata_signature: &[u8],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let manifest = manifest.encode_to_vec();
+        let mut payload = PAYLOAD_MAGIC.to_vec();
+        payload.extend_from_slice(&2u64.to_be_bytes());
+        payload.extend_from_slice(&(manifest.len() as u64).to_be_bytes());
+        payload.extend_from_slice(&(metadata_signature.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&manifest);
+        payload.extend_from_slice(metadata_signature);
+        payload.extend_from_slice(data);
+        payload
+    }
+
+    #[test]
+    fn test_open_payload() {
+        let signatures = Signatures {
+            signatures: vec![Signature {
+                data: Some(b"signature".to_vec()),
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec();
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            minor_version: Some(0),
+            signatures_offset: Some(4),
+            signatures_size: Some(signatures.len() as u64),
+            partitions: vec![PartitionUpdate {
+                partition_name: "boot".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut data = b"data".to_vec();
+        data.extend_from_slice(&signatures);
+        let mut source = b"junk".to_vec();
+        source.extend(build_payload(&manifest, &signatures, &data));
+
+        let mut payload = Payload::open(Cursor::new(source)).unwrap();
+        assert_eq!(payload.offset(), 4);
+        assert_eq!(payload.manifest(), &manifest);
+        assert_eq!(
+            payload.manifest_hash()[..],
+            Sha256::digest(manifest.encode_to_vec())[..]
+        );
+        assert_eq!(
+            payload.metadata_size(),
+            HEADER_SIZE + manifest.encoded_len() as u64
+        );
+        assert_eq!(
+            payload.data_offset(),
+            4 + payload.metadata_size() + signatures.len() as u64
+        );
+        assert!(payload.partition("boot").is_some());
+        assert!(payload.partition("system").is_none());
+        assert_eq!(payload.metadata_signature(), signatures);
+        assert_eq!(payload.payload_signature().unwrap(), Some(signatures));
+
+        // A payload at the start of the source is found without scanning.
+        let mut source = Cursor::new(build_payload(&manifest, &[], &[0; 4096]));
+        assert_eq!(find_magic(&mut source).unwrap(), 0);
+        assert_eq!(source.position(), PAYLOAD_MAGIC.len() as u64);
+
+        assert!(matches!(
+            Payload::open(Cursor::new(vec![0u8; 64])),
+            Err(Error::BadMagic { .. })
+        ));
+    }
+
+    #[test]
+    #[allow(deprecated, reason = "Legacy fields of version 1 payloads")]
+    fn test_open_version_1_payload() {
+        let replace = |data_offset: u64, start_block: u64| InstallOperation {
+            r#type: install_operation::Type::Replace as i32,
+            data_offset: Some(data_offset),
+            data_length: Some(4),
+            dst_extents: vec![Extent {
+                start_block: Some(start_block),
+                num_blocks: Some(1),
+            }],
+            ..Default::default()
+        };
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: Some(8),
+            signatures_size: Some(4),
+            install_operations: vec![replace(0, 0), replace(8, u64::MAX)],
+            kernel_install_operations: vec![replace(4, 0)],
+            new_rootfs_info: Some(PartitionInfo {
+                size: Some(4096),
+                hash: None,
+            }),
+            ..Default::default()
+        };
+        let encoded = manifest.encode_to_vec();
+        let mut source = PAYLOAD_MAGIC.to_vec();
+        source.extend_from_slice(&CHROMEOS_MAJOR_VERSION.to_be_bytes());
+        source.extend_from_slice(&(encoded.len() as u64).to_be_bytes());
+        source.extend_from_slice(&encoded);
+        source.extend_from_slice(b"rootkernsign");
+
+        let mut payload = Payload::open(Cursor::new(&source)).unwrap();
+        assert_eq!(payload.version(), CHROMEOS_MAJOR_VERSION);
+        assert!(payload.metadata_signature().is_empty());
+        assert_eq!(payload.metadata_size(), 20 + encoded.len() as u64);
+        assert_eq!(payload.data_offset(), 20 + encoded.len() as u64);
+        let root = payload.partition("root").unwrap();
+        assert_eq!(root.operations, [replace(0, 0)]);
+        assert_eq!(root.new_partition_info, manifest.new_rootfs_info);
+        assert_eq!(
+            payload.partition("kernel").unwrap().operations,
+            [replace(4, 0)]
+        );
+        assert!(payload.manifest().install_operations.is_empty());
+        assert_eq!(payload.payload_signature().unwrap(), Some(b"sign".to_vec()));
+
+        source[11] = 3;
+        assert!(matches!(
+            Payload::open(Cursor::new(&source)),
+            Err(Error::UnsupportedVersion(3))
+        ));
+    }
+}
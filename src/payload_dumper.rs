@@ -1,320 +1,1143 @@
 use std::{
-    fs::{self, File},
-    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    ops::Range,
     path::{Path, PathBuf},
-    time::Duration,
+    str::FromStr,
+    sync::{Mutex, PoisonError},
 };
 
-use anyhow::{Context, Result, anyhow, bail};
 use bzip2::read::BzDecoder;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelBridge,
+    ParallelIterator,
+};
 use sha2::{Digest, Sha256};
-use xz4rust::{XzDecoder, XzNextBlockResult};
 
 use crate::{
     ReadSeek,
+    cancel::CancellationToken,
+    error::{Error, Result},
+    journal::Journal,
+    lz4diff,
     patch::bspatch,
-    proto::{InstallOperation, PartitionUpdate, install_operation},
-    verify::{verify_hash, verify_old_partition},
+    progress::Progress,
+    proto::{Extent, InstallOperation, PartitionUpdate, install_operation},
+    puffin::puffpatch,
+    stream::{
+        ExtentWriter, HashingReader, HashingWriter, OutputHasher, STREAM_BUFFER_SIZE,
+        copy_decode_xz,
+    },
+    verify::{HashCheck, verify_hash, verify_old_partition},
+    zucchini,
 };
 
+/// Manifest minor version of payloads applied in place, with `MOVE` and `BSDIFF` operations
+/// reading from the partition being written.
+pub const IN_PLACE_MINOR_VERSION: u32 = 1;
+
+/// Largest read of operation data from the payload at once.
+const DATA_READ_SIZE: u64 = 1024 * 1024;
+
+/// How to handle operations whose payload data or source blocks turn out to be corrupt.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Fail the partition.
+    #[default]
+    Strict,
+    /// Fetch the operation data again up to this many times, then fail the partition. Helps with
+    /// flaky remote sources.
+    Retry(u32),
+    /// Skip the operation with a warning, leaving its destination blocks as they were, or zeroed
+    /// if corrupt data already reached them.
+    Lenient,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            _ if s == "strict" => Ok(Self::Strict),
+            _ if s == "lenient" => Ok(Self::Lenient),
+            _ if s == "retry" => Ok(Self::Retry(3)),
+            Some(("retry", retries)) => retries
+                .parse()
+                .map(Self::Retry)
+                .map_err(|e| format!("Invalid retry count {retries}: {e}")),
+            _ => Err(format!(
+                "Unknown failure policy {s}, expected strict, retry[=N] or lenient"
+            )),
+        }
+    }
+}
+
+/// Outcome of extracting a partition.
+#[derive(Debug)]
+pub struct PartitionReport {
+    pub hash: HashCheck,
+    /// Problems that did not fail the partition, such as operations skipped under
+    /// [`FailurePolicy::Lenient`].
+    pub warnings: Vec<Error>,
+    /// The image was already extracted with a matching hash and kept as is.
+    pub reused: bool,
+}
+
+/// Output that can zero a byte range. The default writes zeros; files punch holes instead.
+pub trait WriteZeros: Write + Seek {
+    fn write_zeros(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        fill_zeros(self, offset, len)
+    }
+}
+
+impl WriteZeros for Cursor<Vec<u8>> {}
+
+impl WriteZeros for File {
+    fn write_zeros(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if punch_hole(self, offset, len)? {
+            Ok(())
+        } else {
+            fill_zeros(self, offset, len)
+        }
+    }
+}
+
+/// Turn a byte range of `file` into a hole without writing zeros, returning whether that was
+/// possible. Images are sized up front, and resizing them here would race with concurrent
+/// writes, so ranges reaching past the end are left to be written instead.
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+    if offset.saturating_add(len) > file.metadata()?.len() {
+        return Ok(false);
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: plain syscall on a file descriptor we own.
+        let result = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if result == 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Write `len` zero bytes at `offset`.
+fn fill_zeros(writer: &mut (impl Write + Seek + ?Sized), offset: u64, len: u64) -> io::Result<()> {
+    writer.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut io::repeat(0).take(len), writer)?;
+    Ok(())
+}
+
+/// `Read`/`Write`/`Seek` view of a shared file using positioned I/O, so that concurrent
+/// operations can access the same file without sharing a cursor.
+pub struct PositionedFile<'a> {
+    file: &'a File,
+    position: u64,
+}
+
+impl<'a> PositionedFile<'a> {
+    #[must_use]
+    pub const fn new(file: &'a File) -> Self {
+        Self { file, position: 0 }
+    }
+}
+
+impl Read for PositionedFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let count = std::os::unix::fs::FileExt::read_at(self.file, buf, self.position)?;
+        #[cfg(windows)]
+        let count = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.position)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for PositionedFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let count = std::os::unix::fs::FileExt::write_at(self.file, buf, self.position)?;
+        #[cfg(windows)]
+        let count = std::os::windows::fs::FileExt::seek_write(self.file, buf, self.position)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PositionedFile<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.position, offset),
+            SeekFrom::End(offset) => (self.file.metadata()?.len(), offset),
+        };
+        self.position = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.position)
+    }
+}
+
+impl WriteZeros for PositionedFile<'_> {
+    fn write_zeros(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if punch_hole(self.file, offset, len)? {
+            Ok(())
+        } else {
+            fill_zeros(self, offset, len)
+        }
+    }
+}
+
+impl<W: WriteZeros> WriteZeros for HashingWriter<'_, W> {
+    fn write_zeros(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.inner_mut().write_zeros(offset, len)?;
+        self.record_zeros(offset, len)
+    }
+}
+
+/// Apply one operation to `out_file`. `MOVE` and `BSDIFF` operations read their source blocks
+/// from `out_file` itself, which must hold the original partition image.
+///
+/// Corrupt payload data or source blocks are handled according to `policy`. Returns the error
+/// that made [`FailurePolicy::Lenient`] skip the operation, if any.
 pub fn process_operation(
-    operation_index: usize,
     op: &InstallOperation,
     data_offset: u64,
     block_size: u64,
+    policy: FailurePolicy,
     payload_file: &mut (impl Read + Seek),
-    out_file: &mut (impl Write + Seek),
-    old_file: Option<&mut dyn ReadSeek>,
-) -> Result<()> {
-    payload_file.seek(SeekFrom::Start(data_offset + op.data_offset.unwrap_or(0)))?;
-    let mut data = vec![0u8; op.data_length.unwrap_or(0) as usize];
-    payload_file.read_exact(&mut data)?;
+    out_file: &mut (impl Read + WriteZeros),
+    mut old_file: Option<&mut dyn ReadSeek>,
+) -> Result<Option<Error>> {
+    apply_with_policy(
+        policy,
+        || {
+            apply_operation(
+                op,
+                data_offset,
+                block_size,
+                policy,
+                payload_file,
+                out_file,
+                old_file.as_deref_mut().map(|f| f as &mut dyn ReadSeek),
+            )
+        },
+        |_| {},
+    )
+}
 
-    if let Some(expected_hash) = op.data_sha256_hash.as_deref() {
-        if !verify_hash(&data, expected_hash) {
-            println!("  Warning: Operation {operation_index} data hash mismatch.");
-            return Ok(());
+/// Run `apply` until it succeeds or `policy` gives up on its error, calling `on_retry` with the
+/// error of each attempt that is retried.
+fn apply_with_policy(
+    policy: FailurePolicy,
+    mut apply: impl FnMut() -> Result<()>,
+    mut on_retry: impl FnMut(&Error),
+) -> Result<Option<Error>> {
+    let mut retries = 0;
+    loop {
+        let Err(e) = apply() else {
+            return Ok(None);
+        };
+        match policy {
+            _ if !e.is_corruption() => return Err(e),
+            FailurePolicy::Lenient => return Ok(Some(e)),
+            FailurePolicy::Retry(max_retries)
+                if e.is_payload_corruption() && retries < max_retries =>
+            {
+                on_retry(&e);
+                retries += 1;
+            }
+            FailurePolicy::Strict | FailurePolicy::Retry(_) => return Err(e),
         }
     }
+}
+
+/// Apply one operation once. Unless `policy` is strict, corrupt data is not left in the
+/// destination blocks.
+fn apply_operation(
+    op: &InstallOperation,
+    data_offset: u64,
+    block_size: u64,
+    policy: FailurePolicy,
+    payload_file: &mut (impl Read + Seek),
+    out_file: &mut (impl Read + WriteZeros),
+    old_file: Option<&mut dyn ReadSeek>,
+) -> Result<()> {
+    let data_start = data_offset + op.data_offset.unwrap_or(0);
+    let data_length = op.data_length.unwrap_or(0);
+    let data = data_start..data_start + data_length;
+    payload_file.seek(SeekFrom::Start(data.start))?;
+    // Decoders read in small pieces; batch them so remote payloads see few large range requests.
+    let reader = HashingReader::new(BufReader::with_capacity(
+        data_length.min(DATA_READ_SIZE) as usize,
+        payload_file.take(data_length),
+    ));
+    let op_type = op.r#type().as_str_name();
+
     match op.r#type() {
-        install_operation::Type::ReplaceXz => {
-            let mut decompressed = Vec::new();
-            let mut decoder = XzDecoder::in_heap_with_alloc_dict_size(
-                xz4rust::DICT_SIZE_MIN,
-                xz4rust::DICT_SIZE_MAX,
-            );
+        install_operation::Type::Replace
+        | install_operation::Type::ReplaceBz
+        | install_operation::Type::ReplaceXz
+        | install_operation::Type::Zstd => {
+            let clear_corrupt = policy != FailurePolicy::Strict;
+            stream_operation(op, data, block_size, reader, out_file, clear_corrupt)?;
+        }
+        install_operation::Type::SourceCopy => {
+            let old_file = old_file.ok_or(Error::OldImageRequired { op_type })?;
+            let old_data = read_extents(old_file, &op.src_extents, block_size)?;
+            verify_source(op, block_size, &old_data)?;
+            write_output(op, block_size, out_file, old_data)?;
+        }
+        install_operation::Type::SourceBsdiff
+        | install_operation::Type::BrotliBsdiff
+        | install_operation::Type::Puffdiff
+        | install_operation::Type::Zucchini
+        | install_operation::Type::Lz4diffBsdiff
+        | install_operation::Type::Lz4diffPuffdiff => {
+            let old_file = old_file.ok_or(Error::OldImageRequired { op_type })?;
+            let patch = read_data(op, &data, reader)?;
 
-            let mut input_position = 0;
-            let mut temp_buffer = [0u8; 4096];
-
-            loop {
-                match decoder.decode(&data[input_position..], &mut temp_buffer) {
-                    Ok(XzNextBlockResult::NeedMoreData(input_consumed, output_produced)) => {
-                        input_position += input_consumed;
-                        decompressed.extend_from_slice(&temp_buffer[..output_produced]);
-                    }
-                    Ok(XzNextBlockResult::EndOfStream(_, output_produced)) => {
-                        decompressed.extend_from_slice(&temp_buffer[..output_produced]);
-                        break;
-                    }
-                    Err(e) => {
-                        println!(
-                            "  Warning: Skipping operation {operation_index} due to XZ decompression error: {e}"
-                        );
-                        return Ok(());
-                    }
-                }
+            let old_data = read_extents(old_file, &op.src_extents, block_size)?;
+            verify_source(op, block_size, &old_data)?;
+            let patched = match op.r#type() {
+                install_operation::Type::Puffdiff => puffpatch(&old_data, &patch),
+                install_operation::Type::Zucchini => zucchini::apply(&old_data, &patch),
+                install_operation::Type::Lz4diffBsdiff
+                | install_operation::Type::Lz4diffPuffdiff => lz4diff::apply(&old_data, &patch),
+                _ => bspatch(&old_data, &patch),
+            };
+            let new_data = patched.map_err(|e| Error::Patch {
+                op_type,
+                data,
+                source: e.into(),
+            })?;
+            write_output(op, block_size, out_file, new_data)?;
+        }
+        install_operation::Type::Move => {
+            // Read every source block before writing, as source and destination may overlap.
+            let new_data = read_extents(out_file, &op.src_extents, block_size)?;
+            write_output(op, block_size, out_file, new_data)?;
+        }
+        install_operation::Type::Bsdiff => {
+            let patch = read_data(op, &data, reader)?;
+            let mut old_data = read_extents(out_file, &op.src_extents, block_size)?;
+            if let Some(src_length) = op.src_length {
+                old_data.truncate(src_length as usize);
             }
+            let new_data = bspatch(&old_data, &patch).map_err(|e| Error::Patch {
+                op_type,
+                data,
+                source: e.into(),
+            })?;
+            write_output(op, block_size, out_file, new_data)?;
+        }
+        install_operation::Type::Zero | install_operation::Type::Discard => {
+            zero_extents(op, block_size, out_file)?;
+        }
+    }
+    Ok(())
+}
 
-            out_file.seek(SeekFrom::Start(
-                op.dst_extents[0].start_block.unwrap_or(0) * block_size,
-            ))?;
-            out_file.write_all(&decompressed)?;
-        }
-        install_operation::Type::Zstd => match zstd::decode_all(Cursor::new(&data)) {
-            Ok(decompressed) => {
-                let mut pos = 0;
-                for ext in &op.dst_extents {
-                    let ext_size = (ext.num_blocks.unwrap_or(0) * block_size) as usize;
-                    let end_pos = pos + ext_size;
-
-                    if end_pos <= decompressed.len() {
-                        out_file
-                            .seek(SeekFrom::Start(ext.start_block.unwrap_or(0) * block_size))?;
-                        out_file.write_all(&decompressed[pos..end_pos])?;
-                        pos = end_pos;
-                    } else {
-                        println!(
-                            "  Warning: Skipping extent in operation {operation_index} due to insufficient decompressed data."
-                        );
-                        break;
-                    }
-                }
-            }
-            Err(e) => {
-                println!(
-                    "  Warning: Skipping operation {operation_index} due to unknown Zstd format: {e}"
-                );
-                return Ok(());
-            }
-        },
+/// Read the whole data blob of `op`, found at `data` in the payload, checking its hash.
+fn read_data(
+    op: &InstallOperation,
+    data: &Range<u64>,
+    mut reader: HashingReader<impl Read>,
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(op.data_length.unwrap_or(0) as usize);
+    reader.read_to_end(&mut buf)?;
+    check_data_hash(op, data, reader)?;
+    Ok(buf)
+}
+
+/// Check the data of `op` read through `reader`, and what remains of it, against its hash.
+fn check_data_hash(
+    op: &InstallOperation,
+    data: &Range<u64>,
+    reader: HashingReader<impl Read>,
+) -> Result<()> {
+    if let Some(expected_hash) = op.data_sha256_hash.as_deref()
+        && !expected_hash.is_empty()
+        && reader.finish()? != expected_hash
+    {
+        return Err(Error::DataHashMismatch { data: data.clone() });
+    }
+    Ok(())
+}
+
+/// Decompress the data of a `REPLACE*` or `ZSTD` operation straight into its destination
+/// extents, hashing it on the way, without holding the whole operation in memory.
+///
+/// With `clear_corrupt`, destination blocks that corrupt data already reached are zeroed again,
+/// so that an operation skipped or retried for it leaves nothing of that data behind.
+fn stream_operation(
+    op: &InstallOperation,
+    data: Range<u64>,
+    block_size: u64,
+    mut reader: HashingReader<impl Read>,
+    out_file: &mut impl WriteZeros,
+    clear_corrupt: bool,
+) -> Result<()> {
+    // The output size of uncompressed data is known before writing anything.
+    if op.r#type() == install_operation::Type::Replace {
+        check_output_size(op, block_size, op.data_length.unwrap_or(0))?;
+    }
+
+    let mut writer = ExtentWriter::new(out_file, &op.dst_extents, block_size);
+    let result = decode_data(op, &mut reader, &mut writer)
+        .map_err(|source| Error::Decode {
+            data: data.clone(),
+            source,
+        })
+        .and_then(|()| check_output_size(op, block_size, writer.written()))
+        .and_then(|()| Ok(writer.pad_block()?))
+        .and_then(|()| check_data_hash(op, &data, reader));
+    let written = writer.written();
+    if clear_corrupt && written > 0 && result.as_ref().is_err_and(Error::is_corruption) {
+        zero_extents(op, block_size, out_file)?;
+    }
+    result
+}
+
+/// Zero the destination extents of `op`.
+fn zero_extents(
+    op: &InstallOperation,
+    block_size: u64,
+    out_file: &mut impl WriteZeros,
+) -> io::Result<()> {
+    for ext in &op.dst_extents {
+        out_file.write_zeros(
+            ext.start_block.unwrap_or(0) * block_size,
+            ext.num_blocks.unwrap_or(0) * block_size,
+        )?;
+    }
+    Ok(())
+}
+
+/// Decompress the data of a `REPLACE*` or `ZSTD` operation from `reader` into `writer`.
+fn decode_data(
+    op: &InstallOperation,
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    match op.r#type() {
+        install_operation::Type::ReplaceXz => copy_decode_xz(reader, writer),
         install_operation::Type::ReplaceBz => {
-            let mut decoder = BzDecoder::new(Cursor::new(&data));
-            let mut decompressed = Vec::new();
-            match decoder.read_to_end(&mut decompressed) {
-                Ok(_) => {
-                    out_file.seek(SeekFrom::Start(
-                        op.dst_extents[0].start_block.unwrap_or(0) * block_size,
-                    ))?;
-                    out_file.write_all(&decompressed)?;
-                }
-                Err(e) => {
-                    println!(
-                        " Warning: Skipping operation {operation_index} due to unknown BZ2 format.  : {e}"
-                    );
-                    return Ok(());
-                }
+            io::copy(&mut BzDecoder::new(reader), writer).map(drop)
+        }
+        install_operation::Type::Zstd => zstd::stream::copy_decode(reader, writer),
+        _ => io::copy(reader, writer).map(drop),
+    }
+}
+
+/// Check that `len` bytes of output match `dst_length` and fill the destination blocks of `op`.
+fn check_output_size(op: &InstallOperation, block_size: u64, len: u64) -> Result<()> {
+    if let Some(dst_length) = op.dst_length
+        && len != dst_length
+    {
+        return Err(Error::DstLengthMismatch {
+            produced: len,
+            dst_length,
+        });
+    }
+    let blocks = len.div_ceil(block_size);
+    let dst_blocks = extents_size(&op.dst_extents, block_size) as u64 / block_size;
+    if blocks != dst_blocks {
+        return Err(Error::DstBlocksMismatch {
+            produced: blocks,
+            expected: dst_blocks,
+        });
+    }
+    Ok(())
+}
+
+/// Scatter the output of `op` across its `dst_extents`, zero-filling the tail of the last block.
+/// Output must match `dst_length` and the destination block count.
+fn write_output(
+    op: &InstallOperation,
+    block_size: u64,
+    out_file: &mut (impl Write + Seek),
+    mut data: Vec<u8>,
+) -> Result<()> {
+    check_output_size(op, block_size, data.len() as u64)?;
+    data.resize(extents_size(&op.dst_extents, block_size), 0);
+    write_extents(out_file, &op.dst_extents, block_size, &data)
+}
+
+/// Check source data read for `op` against its `src_sha256_hash`.
+fn verify_source(op: &InstallOperation, block_size: u64, data: &[u8]) -> Result<()> {
+    let Some(expected_hash) = op.src_sha256_hash.as_deref() else {
+        return Ok(());
+    };
+    if verify_hash(data, expected_hash) {
+        return Ok(());
+    }
+    let ranges = op
+        .src_extents
+        .iter()
+        .map(|ext| {
+            let start = ext.start_block.unwrap_or(0) * block_size;
+            start..start + ext.num_blocks.unwrap_or(0) * block_size
+        })
+        .collect();
+    Err(Error::SourceHashMismatch { ranges })
+}
+
+/// Bytes an operation writes to the image.
+fn dst_size(op: &InstallOperation, block_size: u64) -> u64 {
+    op.dst_extents
+        .iter()
+        .map(|extent| extent.num_blocks.unwrap_or(0))
+        .sum::<u64>()
+        * block_size
+}
+
+/// Total size in bytes of the blocks covered by `extents`.
+fn extents_size(extents: &[Extent], block_size: u64) -> usize {
+    extents
+        .iter()
+        .map(|ext| (ext.num_blocks.unwrap_or(0) * block_size) as usize)
+        .sum()
+}
+
+/// Read the blocks covered by `extents` into one contiguous buffer.
+fn read_extents(
+    file: &mut (impl Read + Seek + ?Sized),
+    extents: &[Extent],
+    block_size: u64,
+) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; extents_size(extents, block_size)];
+    let mut pos = 0;
+    for ext in extents {
+        let ext_size = (ext.num_blocks.unwrap_or(0) * block_size) as usize;
+        file.seek(SeekFrom::Start(ext.start_block.unwrap_or(0) * block_size))?;
+        file.read_exact(&mut data[pos..pos + ext_size])?;
+        pos += ext_size;
+    }
+    Ok(data)
+}
+
+/// Scatter `data` across the blocks covered by `extents`, in order.
+fn write_extents(
+    out_file: &mut (impl Write + Seek),
+    extents: &[Extent],
+    block_size: u64,
+    data: &[u8],
+) -> Result<()> {
+    let mut pos = 0;
+    for ext in extents {
+        let ext_size = (ext.num_blocks.unwrap_or(0) * block_size) as usize;
+        out_file.seek(SeekFrom::Start(ext.start_block.unwrap_or(0) * block_size))?;
+        out_file.write_all(&data[pos..pos + ext_size])?;
+        pos += ext_size;
+    }
+    Ok(())
+}
+
+/// Settings shared by the partitions of one extraction.
+#[derive(Clone, Copy)]
+pub struct DumpOptions<'a> {
+    /// Offset of the operation data in the payload.
+    pub data_offset: u64,
+    pub block_size: u64,
+    pub minor_version: u32,
+    pub out_dir: &'a Path,
+    /// Directory holding the original images, for differential payloads.
+    pub old_dir: Option<&'a Path>,
+    /// Check extracted images against their expected hash.
+    pub verify: bool,
+    pub policy: FailurePolicy,
+    pub progress: &'a dyn Progress,
+    pub cancel: &'a CancellationToken,
+    /// Journal of completed operations, to resume interrupted extractions from.
+    pub journal: Option<&'a Journal>,
+}
+
+impl DumpOptions<'_> {
+    const fn in_place(&self) -> bool {
+        self.minor_version == IN_PLACE_MINOR_VERSION
+    }
+}
+
+/// Extract `partition` into `out_dir`, checking the result against its expected hash when
+/// `verify` is set. Corrupt operations are handled according to `policy`.
+///
+/// With a `journal`, operations completed by an interrupted extraction are skipped and those
+/// completed now are recorded, and images of cancelled partitions are kept to be resumed.
+/// Resumed images are checked against their expected hash even without `verify`. In-place
+/// partitions are not journaled, as their operations read what earlier ones wrote.
+pub fn dump_partition(
+    partition: &PartitionUpdate,
+    options: &DumpOptions,
+    payload_file: &mut (impl Read + Seek),
+) -> Result<PartitionReport> {
+    let options = DumpOptions {
+        journal: options.journal.filter(|_| !options.in_place()),
+        ..*options
+    };
+    let progress = options.progress;
+    progress.partition_started(partition);
+    let result = options
+        .cancel
+        .check()
+        .map_err(|e| partition_error(partition, e))
+        .and_then(|()| {
+            let result = extract_partition(partition, &options, payload_file);
+            if options.journal.is_none() && result.as_ref().is_err_and(Error::is_cancelled) {
+                remove_partial_output(options.out_dir, partition);
             }
+            result
+        });
+    progress.partition_finished(partition, &result);
+    result
+}
+
+fn extract_partition(
+    partition: &PartitionUpdate,
+    options: &DumpOptions,
+    payload_file: &mut (impl Read + Seek),
+) -> Result<PartitionReport> {
+    let DumpOptions {
+        data_offset,
+        block_size,
+        verify,
+        policy,
+        progress,
+        cancel,
+        journal,
+        ..
+    } = *options;
+    let mut warnings = Vec::new();
+    let completed = resumable_operations(journal, partition, options.out_dir);
+    let (out_file, mut old_file) =
+        open_partition_files(partition, options, !completed.is_empty(), &mut warnings)
+            .map_err(|e| partition_error(partition, e))?;
+    if !completed.is_empty() {
+        progress.partition_resumed(partition, completed.len());
+    }
+    let hasher = Mutex::new(output_hasher(options, &completed));
+    let mut output = HashingWriter::new(PositionedFile::new(&out_file), &hasher)
+        .map_err(|e| partition_error(partition, e.into()))?;
+
+    for (i, op) in partition.operations.iter().enumerate() {
+        if completed.contains(&i) {
+            continue;
         }
-        install_operation::Type::Replace => {
-            out_file.seek(SeekFrom::Start(
-                op.dst_extents[0].start_block.unwrap_or(0) * block_size,
-            ))?;
-            out_file.write_all(&data)?;
+        cancel.check().map_err(|e| partition_error(partition, e))?;
+        let skipped = apply_with_policy(
+            policy,
+            || {
+                apply_operation(
+                    op,
+                    data_offset,
+                    block_size,
+                    policy,
+                    payload_file,
+                    &mut output,
+                    old_file.as_mut().map(|f| f as &mut dyn ReadSeek),
+                )
+            },
+            |e| progress.operation_retried(partition, i, e),
+        )
+        .map_err(|e| operation_error(partition, i, e))?;
+        match skipped {
+            Some(e) => warnings.push(operation_error(partition, i, e)),
+            None => record_operation(journal, partition, i)?,
         }
-        install_operation::Type::SourceCopy => {
-            let old_file = old_file
-                .ok_or_else(|| anyhow!("SOURCE_COPY supported only for differential OTA"))?;
-            out_file.seek(SeekFrom::Start(
-                op.dst_extents[0].start_block.unwrap_or(0) * block_size,
-            ))?;
-            for ext in &op.src_extents {
-                old_file.seek(SeekFrom::Start(ext.start_block.unwrap_or(0) * block_size))?;
-                let mut buffer = vec![0u8; (ext.num_blocks.unwrap_or(0) * block_size) as usize];
-                old_file.read_exact(&mut buffer)?;
-                out_file.write_all(&buffer)?;
+        progress.operation_completed(partition, i, dst_size(op, block_size));
+    }
+    let hasher = hasher.into_inner().unwrap_or_else(PoisonError::into_inner);
+    // Resumed images are always checked, as the journal does not vouch for them.
+    let verify = verify || !completed.is_empty();
+    let hash = check_output_hash(partition, &out_file, hasher, verify, progress)
+        .and_then(|hash| {
+            journal
+                .map_or(Ok(()), |j| j.finish(partition))
+                .map(|()| hash)
+        })
+        .map_err(|e| partition_error(partition, e))?;
+    Ok(PartitionReport {
+        hash,
+        warnings,
+        reused: false,
+    })
+}
+
+/// Extract several partitions at once, decoding their operations concurrently.
+///
+/// All operations of all partitions are scheduled together on the rayon pool, so one huge
+/// partition is spread over every thread. Workers write with positioned I/O into the shared
+/// output files and read the payload through readers from `open_payload`, reused across
+/// operations.
+///
+/// Operations are handed out in order, so images are hashed as they are written despite
+/// operations completing slightly out of order. Images whose operations overwrite each other
+/// or finish too far out of order are hashed by re-reading them once extracted.
+///
+/// In-place (minor version 1) partitions depend on operation order and are extracted one
+/// operation at a time, though still concurrently with other partitions.
+///
+/// A `journal` is used as in [`dump_partition`].
+///
+/// Returns the result of each partition, in order.
+pub fn dump_partitions(
+    partitions: &[&PartitionUpdate],
+    options: &DumpOptions,
+    open_payload: &(dyn Fn() -> Result<Box<dyn ReadSeek + Send>> + Sync),
+) -> Vec<Result<PartitionReport>> {
+    let DumpOptions {
+        data_offset,
+        block_size,
+        policy,
+        progress,
+        cancel,
+        journal,
+        ..
+    } = *options;
+    let readers = Mutex::new(Vec::new());
+
+    if options.in_place() {
+        return partitions
+            .par_iter()
+            .map(|partition| {
+                with_payload_reader(&readers, open_payload, |reader| {
+                    dump_partition(partition, options, reader)
+                })
+            })
+            .collect();
+    }
+
+    let states: Vec<_> = partitions
+        .par_iter()
+        .map(|partition| {
+            progress.partition_started(partition);
+            let mut warnings = Vec::new();
+            let completed = resumable_operations(journal, partition, options.out_dir);
+            let files = cancel
+                .check()
+                .and_then(|()| {
+                    open_partition_files(partition, options, !completed.is_empty(), &mut warnings)
+                })
+                .map_err(|e| partition_error(partition, e));
+            if files.is_ok() && !completed.is_empty() {
+                progress.partition_resumed(partition, completed.len());
             }
-        }
-        install_operation::Type::SourceBsdiff | install_operation::Type::BrotliBsdiff => {
-            let old_file =
-                old_file.ok_or_else(|| anyhow!("BSDIFF supported only for differential OTA"))?;
+            PartitionState {
+                files,
+                hasher: Mutex::new(output_hasher(options, &completed)),
+                completed,
+                error: Mutex::new(None),
+                warnings: Mutex::new(warnings),
+            }
+        })
+        .collect();
 
-            let mut old_data = Vec::new();
-            for ext in &op.src_extents {
-                old_file.seek(SeekFrom::Start(ext.start_block.unwrap_or(0) * block_size))?;
-                let mut buffer = vec![0u8; (ext.num_blocks.unwrap_or(0) * block_size) as usize];
-                old_file.read_exact(&mut buffer)?;
-                old_data.extend_from_slice(&buffer);
+    let ops: Vec<_> = states
+        .iter()
+        .zip(partitions)
+        .filter(|(state, _)| state.files.is_ok())
+        .flat_map(|(state, partition)| {
+            partition
+                .operations
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !state.completed.contains(i))
+                .map(move |(i, op)| (state, *partition, i, op))
+        })
+        .collect();
+    ops.into_iter()
+        .par_bridge()
+        .for_each(|(state, partition, i, op)| {
+            let Ok((out_file, old_file)) = &state.files else {
+                return;
+            };
+            if state.failed() {
+                return;
             }
-            let new_data = match bspatch(&old_data, &data) {
-                Ok(new_data) => new_data,
-                Err(e) => {
-                    println!(
-                        "  Warning: Skipping operation {operation_index} due to failed BSDIFF patch.  : {e}"
-                    );
-                    return Ok(());
+            if let Err(e) = cancel.check() {
+                lock(&state.error).get_or_insert(partition_error(partition, e));
+                return;
+            }
+            let result = with_payload_reader(&readers, open_payload, |reader| {
+                let mut output = HashingWriter::new(PositionedFile::new(out_file), &state.hasher)?;
+                let mut old_file = old_file.as_ref().map(PositionedFile::new);
+                apply_with_policy(
+                    policy,
+                    || {
+                        apply_operation(
+                            op,
+                            data_offset,
+                            block_size,
+                            policy,
+                            reader,
+                            &mut output,
+                            old_file.as_mut().map(|f| f as &mut dyn ReadSeek),
+                        )
+                    },
+                    |e| progress.operation_retried(partition, i, e),
+                )
+            });
+            let error = match result {
+                Ok(Some(e)) => {
+                    lock(&state.warnings).push(operation_error(partition, i, e));
+                    None
                 }
+                Ok(None) => record_operation(journal, partition, i).err(),
+                Err(e) => Some(operation_error(partition, i, e)),
             };
-            let mut pos = 0;
-            for ext in &op.dst_extents {
-                let ext_size = (ext.num_blocks.unwrap_or(0) * block_size) as usize;
-                let end_pos = pos + ext_size;
-                if end_pos <= new_data.len() {
-                    out_file.seek(SeekFrom::Start(ext.start_block.unwrap_or(0) * block_size))?;
-                    out_file.write_all(&new_data[pos..end_pos])?;
-                    pos = end_pos;
-                } else {
-                    println!(
-                        "  Warning: Skipping operation {operation_index} due to insufficient patched data.  ."
-                    );
-                    return Ok(());
-                }
+            if let Some(e) = error {
+                lock(&state.error).get_or_insert(e);
+                return;
             }
-        }
-        install_operation::Type::Zero => {
-            let zeros = vec![0u8; block_size as usize];
-            for ext in &op.dst_extents {
-                out_file.seek(SeekFrom::Start(ext.start_block.unwrap_or(0) * block_size))?;
-                for _ in 0..ext.num_blocks.unwrap_or(0) {
-                    out_file.write_all(&zeros)?;
-                }
+            progress.operation_completed(partition, i, dst_size(op, block_size));
+        });
+
+    states
+        .into_par_iter()
+        .zip(partitions.par_iter())
+        .map(|(state, partition)| {
+            let result = state.finish(partition, options);
+            progress.partition_finished(partition, &result);
+            result
+        })
+        .collect()
+}
+
+/// Run `f` with a payload reader from the pool in `readers`, opening a new one when the pool is
+/// empty.
+fn with_payload_reader<T>(
+    readers: &Mutex<Vec<Box<dyn ReadSeek + Send>>>,
+    open_payload: &(dyn Fn() -> Result<Box<dyn ReadSeek + Send>> + Sync),
+    f: impl FnOnce(&mut Box<dyn ReadSeek + Send>) -> Result<T>,
+) -> Result<T> {
+    let reader = lock(readers).pop();
+    let mut reader = match reader {
+        Some(reader) => reader,
+        None => open_payload()?,
+    };
+    let result = f(&mut reader);
+    // A reader that failed mid-operation may be in a bad state; let it go.
+    if result.is_ok() {
+        lock(readers).push(reader);
+    }
+    result
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn partition_error(partition: &PartitionUpdate, source: Error) -> Error {
+    Error::Partition {
+        partition: partition.partition_name.clone(),
+        source: Box::new(source),
+    }
+}
+
+fn operation_error(partition: &PartitionUpdate, index: usize, source: Error) -> Error {
+    Error::Operation {
+        partition: partition.partition_name.clone(),
+        index,
+        op_type: partition.operations[index].r#type().as_str_name(),
+        source: Box::new(source),
+    }
+}
+
+/// Shared state of a partition extracted by [`dump_partitions`].
+struct PartitionState {
+    files: Result<(File, Option<File>)>,
+    hasher: Mutex<OutputHasher>,
+    /// Operations completed by an interrupted extraction, which are skipped.
+    completed: HashSet<usize>,
+    /// First operation failure; remaining operations are skipped.
+    error: Mutex<Option<Error>>,
+    warnings: Mutex<Vec<Error>>,
+}
+
+impl PartitionState {
+    fn failed(&self) -> bool {
+        lock(&self.error).is_some()
+    }
+
+    /// Check the image once all operations ran, unless one of them failed.
+    fn finish(self, partition: &PartitionUpdate, options: &DumpOptions) -> Result<PartitionReport> {
+        let DumpOptions {
+            out_dir,
+            verify,
+            progress,
+            journal,
+            ..
+        } = *options;
+        let (out_file, _) = self.files?;
+        if let Some(e) = self
+            .error
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            if journal.is_none() && e.is_cancelled() {
+                drop(out_file);
+                remove_partial_output(out_dir, partition);
             }
+            return Err(e);
         }
-        _ => {
-            println!(
-                "  Warning: Skipping operation {operation_index} due to unknown compression method"
-            );
-            return Ok(());
-        }
+        let hasher = self
+            .hasher
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        let verify = verify || !self.completed.is_empty();
+        let hash = check_output_hash(partition, &out_file, hasher, verify, progress)
+            .and_then(|hash| {
+                journal
+                    .map_or(Ok(()), |j| j.finish(partition))
+                    .map(|()| hash)
+            })
+            .map_err(|e| partition_error(partition, e))?;
+        Ok(PartitionReport {
+            hash,
+            warnings: self
+                .warnings
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner),
+            reused: false,
+        })
     }
-    Ok(())
 }
 
-pub fn dump_partition(
+/// Operations of `partition` completed by an interrupted extraction, provided its image is
+/// still there at its full size. The journal does not vouch for the image, so partitions
+/// without an expected hash to check it against start over.
+fn resumable_operations(
+    journal: Option<&Journal>,
     partition: &PartitionUpdate,
-    data_offset: u64,
-    block_size: u64,
     out_dir: &Path,
-    old_dir: &Path,
-    use_diff: bool,
-    payload_file: &mut (impl Read + Seek),
-    multi_progress: Option<&MultiProgress>,
+) -> HashSet<usize> {
+    let Some(journal) = journal else {
+        return HashSet::new();
+    };
+    let out_path = out_dir.join(format!("{}.img", partition.partition_name));
+    let Some(info) = partition
+        .new_partition_info
+        .as_ref()
+        .filter(|info| info.hash.as_ref().is_some_and(|hash| !hash.is_empty()))
+    else {
+        return HashSet::new();
+    };
+    let size = info.size;
+    match fs::metadata(out_path) {
+        Ok(metadata) if size.is_none_or(|size| metadata.len() == size) => {
+            journal.completed(partition)
+        }
+        _ => HashSet::new(),
+    }
+}
+
+fn record_operation(
+    journal: Option<&Journal>,
+    partition: &PartitionUpdate,
+    index: usize,
 ) -> Result<()> {
+    journal.map_or(Ok(()), |journal| {
+        journal
+            .record(partition, index)
+            .map_err(|e| partition_error(partition, e))
+    })
+}
+
+/// Remove the incomplete image of a cancelled partition.
+fn remove_partial_output(out_dir: &Path, partition: &PartitionUpdate) {
+    // Best effort, the cancellation is what gets reported.
+    let _ = fs::remove_file(out_dir.join(format!("{}.img", partition.partition_name)));
+}
+
+/// Create the output image of `partition`, sized to the new partition, and open its original
+/// image when applying a differential payload. In-place payloads start the output as a copy of
+/// the original image instead. With `resume`, the existing image is opened as is.
+fn open_partition_files(
+    partition: &PartitionUpdate,
+    options: &DumpOptions,
+    resume: bool,
+    warnings: &mut Vec<Error>,
+) -> Result<(File, Option<File>)> {
     let partition_name = &partition.partition_name;
-    let total_ops = partition.operations.len() as u64;
-    let progress_bar = multi_progress.map_or_else(|| None, |mp| {
-        let pb = mp.add(ProgressBar::new(100));
-        pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/white}] {percent}% - {msg}")
-            .unwrap()
-            .progress_chars("▰▱"));
-        pb.enable_steady_tick(Duration::from_millis(500));
-        pb.set_message(format!("Processing {partition_name} ({total_ops} ops)"));
-        Some(pb)
-    });
+    let out_dir = options.out_dir;
+    let in_place = options.in_place();
+    if let Some(op) = partition.operations.iter().find(|op| {
+        matches!(
+            op.r#type(),
+            install_operation::Type::Move | install_operation::Type::Bsdiff
+        )
+    }) {
+        let op_type = op.r#type().as_str_name();
+        if !in_place {
+            return Err(Error::UnsupportedOperation {
+                op_type,
+                minor_version: options.minor_version,
+                required: IN_PLACE_MINOR_VERSION,
+            });
+        }
+        // Without the original image these would read the zero-filled output.
+        if options.old_dir.is_none() {
+            return Err(Error::OldImageRequired { op_type });
+        }
+    }
     if out_dir.to_string_lossy() != "-" {
         fs::create_dir_all(out_dir)?;
     }
     let out_path = out_dir.join(format!("{partition_name}.img"));
-    let mut out_file = File::create(&out_path)?;
-
-    if let Some(info) = &partition.new_partition_info {
-        if info.size.unwrap_or(0) > 0 {
-            #[cfg(target_family = "unix")]
-            {
-                if let Some(size) = info.size {
-                    out_file.set_len(size)?;
-                } else {
-                    bail!("Partition size is missing");
-                }
-            }
-        }
-    }
+    let mut out_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(!resume)
+        .open(&out_path)?;
+    // Sized once here, as operations write concurrently and must not resize it.
+    out_file.set_len(image_size(partition, options.block_size))?;
 
-    let mut old_file = if use_diff {
+    let mut old_file = if let Some(old_dir) = options.old_dir {
         let old_path = old_dir.join(format!("{partition_name}.img"));
-        let mut file = File::open(&old_path)
-            .with_context(|| format!("Failed to open original image: {}", old_path.display()))?;
+        let mut file = match File::open(&old_path) {
+            Ok(file) => file,
+            Err(source) => {
+                return Err(Error::OldImageMissing {
+                    path: old_path,
+                    source,
+                });
+            }
+        };
 
-        // Verify old partition hash if available
-        if let Some(old_partition_info) = &partition.old_partition_info {
-            if let Err(e) = verify_old_partition(&mut file, old_partition_info) {
-                bail!("Old partition verification failed for {partition_name}: {e}");
+        // Verify old partition hash if available. When every operation reading the old image
+        // carries its own source hash, a mismatch elsewhere in the image is harmless.
+        if let Some(old_partition_info) = &partition.old_partition_info
+            && let Err(e) = verify_old_partition(&mut file, old_partition_info)
+        {
+            let source_hashed = partition.operations.iter().all(|op| {
+                op.src_extents.is_empty()
+                    || op
+                        .src_sha256_hash
+                        .as_ref()
+                        .is_some_and(|hash| !hash.is_empty())
+            });
+            if !source_hashed {
+                return Err(e);
             }
+            warnings.push(partition_error(partition, e));
         }
 
         Some(file)
     } else {
         None
     };
+    if in_place && let Some(mut old_file) = old_file.take() {
+        // In-place payloads patch the original image, so start from a copy of it.
+        old_file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut old_file, &mut out_file)?;
+        if let Some(size) = partition
+            .new_partition_info
+            .as_ref()
+            .and_then(|info| info.size)
+        {
+            out_file.set_len(size)?;
+        }
+    }
+    Ok((out_file, old_file))
+}
 
-    for (i, op) in partition.operations.iter().enumerate() {
-        process_operation(
-            i,
-            op,
-            data_offset,
-            block_size,
-            payload_file,
-            &mut out_file,
-            old_file.as_mut().map(|f| f as &mut dyn ReadSeek),
-        )?;
+/// Size of the image of `partition`, or the end of its furthest destination extent when the
+/// manifest does not say.
+fn image_size(partition: &PartitionUpdate, block_size: u64) -> u64 {
+    partition
+        .new_partition_info
+        .as_ref()
+        .and_then(|info| info.size)
+        .unwrap_or_else(|| {
+            partition
+                .operations
+                .iter()
+                .flat_map(|op| &op.dst_extents)
+                .map(|ext| {
+                    ext.start_block
+                        .unwrap_or(0)
+                        .saturating_add(ext.num_blocks.unwrap_or(0))
+                        .saturating_mul(block_size)
+                })
+                .max()
+                .unwrap_or(0)
+        })
+}
 
-        if let Some(pb) = &progress_bar {
-            let percentage = ((i + 1) as f64 / total_ops as f64 * 100.0) as u64;
-            pb.set_position(percentage);
-        }
-    }
-    if let Some(pb) = progress_bar {
-        pb.finish_with_message(format!("✓ Completed {partition_name} ({total_ops} ops)"));
-    }
-    drop(out_file);
-    let mut out_file = File::open(&out_path)
-        .with_context(|| format!("Failed to reopen {partition_name} for hash verification"))?;
-    if let Some(info) = &partition.new_partition_info {
-        if info.hash.as_ref().is_none_or(std::vec::Vec::is_empty) {
-            let hash_pb = multi_progress.map_or_else(
-                || None,
-                |mp| {
-                    let pb = mp.add(ProgressBar::new_spinner());
-                    pb.set_style(
-                        ProgressStyle::default_spinner()
-                            .template("{spinner:.green} {msg}")
-                            .unwrap(),
-                    );
-                    pb.enable_steady_tick(Duration::from_millis(100));
-                    pb.set_message(format!("Verifying hash for {partition_name}"));
-                    Some(pb)
-                },
-            );
-            out_file.seek(SeekFrom::Start(0))?;
-            let mut hasher = Sha256::new();
-            io::copy(&mut out_file, &mut hasher)?;
-            let hash = hasher.finalize();
-            if let Some(pb) = hash_pb {
-                if hash.as_slice() == info.hash.as_deref().unwrap_or(&[]) {
-                    pb.finish_with_message(format!("✓ Hash verified for {partition_name}"));
-                } else {
-                    pb.finish_with_message(format!("✕ Hash mismatch for {partition_name}"));
-                }
-            }
-        }
+/// Hasher tracking the output of a partition while it is written. In-place images start out as
+/// the original image rather than zeros, and resumed images with what earlier runs wrote, so
+/// their hash can only be computed by re-reading them.
+fn output_hasher(options: &DumpOptions, completed: &HashSet<usize>) -> OutputHasher {
+    if options.verify && !options.in_place() && completed.is_empty() {
+        OutputHasher::new()
+    } else {
+        OutputHasher::disabled()
     }
-    Ok(())
 }
 
-pub fn create_payload_reader(path: &PathBuf) -> Result<Box<dyn ReadSeek>> {
+/// Check the extracted image of `partition` against its expected hash, using the hash computed
+/// while writing it when its operations wrote it in order and re-reading it otherwise.
+fn check_output_hash(
+    partition: &PartitionUpdate,
+    out_file: &File,
+    hasher: OutputHasher,
+    verify: bool,
+    progress: &dyn Progress,
+) -> Result<HashCheck> {
+    let Some(expected_hash) = partition
+        .new_partition_info
+        .as_ref()
+        .and_then(|info| info.hash.as_deref())
+        .filter(|hash| verify && !hash.is_empty())
+    else {
+        return Ok(HashCheck::Skipped);
+    };
+    let hash = if let Some(hash) = hasher.finish(out_file.metadata()?.len()) {
+        hash
+    } else {
+        progress.verification_started(&partition.partition_name);
+        let mut reader =
+            BufReader::with_capacity(STREAM_BUFFER_SIZE, PositionedFile::new(out_file));
+        let mut hasher = Sha256::new();
+        io::copy(&mut reader, &mut hasher)?;
+        hasher.finalize().into()
+    };
+    Ok(if hash.as_slice() == expected_hash {
+        HashCheck::Verified
+    } else {
+        HashCheck::Mismatch
+    })
+}
+
+pub fn create_payload_reader(path: &PathBuf) -> Result<Box<dyn ReadSeek + Send>> {
     let file = File::open(path)?;
 
     let file_size = file.metadata()?.len();
 
     if file_size > 10 * 1024 * 1024 {
         unsafe { memmap2::Mmap::map(&file) }.map_or_else(
-            |_| Ok(Box::new(file) as Box<dyn ReadSeek>),
-            |mmap| Ok(Box::new(MmapReader { mmap, position: 0 }) as Box<dyn ReadSeek>),
+            |_| Ok(Box::new(file) as Box<dyn ReadSeek + Send>),
+            |mmap| Ok(Box::new(MmapReader { mmap, position: 0 }) as Box<dyn ReadSeek + Send>),
         )
     } else {
-        Ok(Box::new(file) as Box<dyn ReadSeek>)
+        Ok(Box::new(file) as Box<dyn ReadSeek + Send>)
     }
 }
 
@@ -372,3 +1195,450 @@ impl Seek for MmapReader {
         Ok(self.position)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{progress::NoProgress, proto::PartitionInfo};
+
+    const BLOCK_SIZE: u64 = 4;
+
+    fn extent(start_block: u64, num_blocks: u64) -> Extent {
+        Extent {
+            start_block: Some(start_block),
+            num_blocks: Some(num_blocks),
+        }
+    }
+
+    fn operation(
+        op_type: install_operation::Type,
+        src_extents: Vec<Extent>,
+        dst_extents: Vec<Extent>,
+        data: &[u8],
+    ) -> (InstallOperation, Cursor<Vec<u8>>) {
+        let mut op = InstallOperation {
+            src_extents,
+            dst_extents,
+            data_offset: Some(0),
+            data_length: Some(data.len() as u64),
+            ..Default::default()
+        };
+        op.set_type(op_type);
+        (op, Cursor::new(data.to_vec()))
+    }
+
+    #[test]
+    fn test_move_overlapping_extents() {
+        let mut image = Cursor::new(b"aaaabbbbccccdddd".to_vec());
+        let (op, mut payload) = operation(
+            install_operation::Type::Move,
+            vec![extent(0, 3)],
+            vec![extent(1, 3)],
+            &[],
+        );
+        process_operation(
+            &op,
+            0,
+            BLOCK_SIZE,
+            FailurePolicy::Strict,
+            &mut payload,
+            &mut image,
+            None,
+        )
+        .unwrap();
+        assert_eq!(image.into_inner(), b"aaaaaaaabbbbcccc");
+    }
+
+    #[test]
+    fn test_in_place_bsdiff() {
+        let mut image = Cursor::new(b"0123456789abcdef".to_vec());
+        let mut patch = Vec::new();
+        bsdiff::diff(b"89abcdef", b"89ABC", &mut patch).unwrap();
+        let (op, mut payload) = operation(
+            install_operation::Type::Bsdiff,
+            vec![extent(2, 2)],
+            vec![extent(0, 2)],
+            &patch,
+        );
+        process_operation(
+            &op,
+            0,
+            BLOCK_SIZE,
+            FailurePolicy::Strict,
+            &mut payload,
+            &mut image,
+            None,
+        )
+        .unwrap();
+        assert_eq!(image.into_inner(), b"89ABC\0\0\089abcdef");
+
+        // Extracting an in-place partition needs the original image to patch.
+        let partition = PartitionUpdate {
+            partition_name: "in_place".to_string(),
+            operations: vec![op],
+            ..Default::default()
+        };
+        let out_dir = std::env::temp_dir().join(format!("in_place_{}", std::process::id()));
+        let options = DumpOptions {
+            data_offset: 0,
+            block_size: BLOCK_SIZE,
+            minor_version: IN_PLACE_MINOR_VERSION,
+            out_dir: &out_dir,
+            old_dir: None,
+            verify: false,
+            policy: FailurePolicy::Strict,
+            progress: &NoProgress,
+            cancel: &CancellationToken::new(),
+            journal: None,
+        };
+        let error = dump_partition(&partition, &options, &mut payload).unwrap_err();
+        let Error::Partition { source, .. } = error else {
+            panic!("unexpected error {error}");
+        };
+        assert!(matches!(
+            *source,
+            Error::OldImageRequired { op_type: "BSDIFF" }
+        ));
+        assert!(!out_dir.exists());
+    }
+
+    #[test]
+    fn test_replace_scatter() {
+        let (mut op, mut payload) = operation(
+            install_operation::Type::Replace,
+            vec![],
+            vec![extent(2, 1), extent(0, 1)],
+            b"abcdef",
+        );
+        let mut image = Cursor::new(vec![b'.'; 12]);
+        process_operation(
+            &op,
+            0,
+            BLOCK_SIZE,
+            FailurePolicy::Strict,
+            &mut payload,
+            &mut image,
+            None,
+        )
+        .unwrap();
+        assert_eq!(image.into_inner(), b"ef\0\0....abcd");
+
+        // Output not filling the destination blocks is rejected.
+        op.dst_extents.push(extent(1, 1));
+        let mut image = Cursor::new(vec![b'.'; 12]);
+        process_operation(
+            &op,
+            0,
+            BLOCK_SIZE,
+            FailurePolicy::Strict,
+            &mut payload,
+            &mut image,
+            None,
+        )
+        .unwrap_err();
+        let skipped = process_operation(
+            &op,
+            0,
+            BLOCK_SIZE,
+            FailurePolicy::Lenient,
+            &mut payload,
+            &mut image,
+            None,
+        )
+        .unwrap();
+        assert!(matches!(skipped, Some(Error::DstBlocksMismatch { .. })));
+        assert_eq!(image.into_inner(), b"............");
+    }
+
+    /// Reader returning corrupt data on its first `failures` reads, like a flaky connection.
+    struct FlakyReader {
+        inner: Cursor<Vec<u8>>,
+        failures: usize,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.inner.read(buf)?;
+            if count > 0 && self.failures > 0 {
+                self.failures -= 1;
+                buf[..count].fill(0xFF);
+            }
+            Ok(count)
+        }
+    }
+
+    impl Seek for FlakyReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_failure_policy() {
+        let (mut op, payload) = operation(
+            install_operation::Type::Replace,
+            vec![],
+            vec![extent(0, 1)],
+            b"abcd",
+        );
+        op.data_sha256_hash = Some(Sha256::digest(b"abcd").to_vec());
+        for (policy, failures, expected) in [
+            (FailurePolicy::Strict, 1, None),
+            (FailurePolicy::Retry(2), 2, Some(b"abcd")),
+            (FailurePolicy::Retry(2), 3, None),
+            // Corrupt data is zeroed again once detected.
+            (FailurePolicy::Lenient, 1, Some(b"\0\0\0\0")),
+        ] {
+            let mut payload = FlakyReader {
+                inner: payload.clone(),
+                failures,
+            };
+            let mut image = Cursor::new(b"wxyz".to_vec());
+            let result =
+                process_operation(&op, 0, BLOCK_SIZE, policy, &mut payload, &mut image, None);
+            assert_eq!(
+                result.ok().map(|_| image.into_inner()),
+                expected.map(|data| data.to_vec()),
+                "{policy:?} with {failures} failures"
+            );
+        }
+        assert_eq!("retry=5".parse(), Ok(FailurePolicy::Retry(5)));
+        assert!("retry=x".parse::<FailurePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_stream_compressed() {
+        let new_data = b"compressed".repeat(3);
+        let mut bz2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+        bz2.write_all(&new_data).unwrap();
+        let bz2 = bz2.finish().unwrap();
+        let zstd = zstd::encode_all(&new_data[..], 3).unwrap();
+
+        for (op_type, data) in [
+            (install_operation::Type::ReplaceBz, bz2),
+            (install_operation::Type::Zstd, zstd),
+        ] {
+            let (mut op, mut payload) = operation(op_type, vec![], vec![extent(3, 8)], &data);
+            op.data_sha256_hash = Some(Sha256::digest(&data).to_vec());
+            let mut image = Cursor::new(vec![b'.'; 44]);
+            process_operation(
+                &op,
+                0,
+                BLOCK_SIZE,
+                FailurePolicy::Strict,
+                &mut payload,
+                &mut image,
+                None,
+            )
+            .unwrap();
+            let image = image.into_inner();
+            assert_eq!(&image[..12], b"............");
+            assert_eq!(&image[12..42], &new_data[..]);
+            assert_eq!(&image[42..], b"\0\0", "{op_type:?}");
+        }
+    }
+
+    #[test]
+    fn test_source_copy_hash() {
+        let mut old = Cursor::new(b"aaaabbbbcccc".to_vec());
+        let (mut op, mut payload) = operation(
+            install_operation::Type::SourceCopy,
+            vec![extent(1, 2)],
+            vec![extent(0, 2)],
+            &[],
+        );
+
+        op.src_sha256_hash = Some(Sha256::digest(b"aaaabbbb").to_vec());
+        let mut image = Cursor::new(vec![0u8; 8]);
+        for policy in [FailurePolicy::Strict, FailurePolicy::Retry(2)] {
+            process_operation(
+                &op,
+                0,
+                BLOCK_SIZE,
+                policy,
+                &mut payload,
+                &mut image,
+                Some(&mut old),
+            )
+            .unwrap_err();
+        }
+        let skipped = process_operation(
+            &op,
+            0,
+            BLOCK_SIZE,
+            FailurePolicy::Lenient,
+            &mut payload,
+            &mut image,
+            Some(&mut old),
+        )
+        .unwrap();
+        assert!(
+            matches!(skipped, Some(Error::SourceHashMismatch { ranges }) if ranges.len() == 1 && ranges[0] == (4..12))
+        );
+        assert_eq!(image.into_inner(), [0u8; 8]);
+
+        op.src_sha256_hash = Some(Sha256::digest(b"bbbbcccc").to_vec());
+        let mut image = Cursor::new(vec![0u8; 8]);
+        process_operation(
+            &op,
+            0,
+            BLOCK_SIZE,
+            FailurePolicy::Strict,
+            &mut payload,
+            &mut image,
+            Some(&mut old),
+        )
+        .unwrap();
+        assert_eq!(image.into_inner(), b"bbbbcccc");
+    }
+
+    /// Progress tallying the completed operations of each partition, their bytes and whether
+    /// the partition was extracted.
+    #[derive(Default)]
+    struct CountingProgress {
+        partitions: Mutex<HashMap<String, (usize, u64, bool)>>,
+    }
+
+    impl Progress for CountingProgress {
+        fn partition_started(&self, partition: &PartitionUpdate) {
+            lock(&self.partitions).insert(partition.partition_name.clone(), (0, 0, false));
+        }
+
+        fn operation_completed(&self, partition: &PartitionUpdate, _index: usize, bytes: u64) {
+            lock(&self.partitions)
+                .entry(partition.partition_name.clone())
+                .and_modify(|counts| {
+                    counts.0 += 1;
+                    counts.1 += bytes;
+                });
+        }
+
+        fn partition_finished(
+            &self,
+            partition: &PartitionUpdate,
+            result: &Result<PartitionReport>,
+        ) {
+            lock(&self.partitions)
+                .get_mut(&partition.partition_name)
+                .unwrap()
+                .2 = result.is_ok();
+        }
+    }
+
+    #[test]
+    fn test_dump_partitions() {
+        // Two partitions of `count` REPLACE operations each, written in reverse block order.
+        let mut payload = Vec::new();
+        let mut partitions = Vec::new();
+        let mut images = Vec::new();
+        for (name, count) in [("big", 64u64), ("small", 3)] {
+            let mut image = Vec::new();
+            let mut operations = Vec::new();
+            for i in 0..count {
+                let data = [name.as_bytes()[0], i as u8].repeat(2);
+                let mut op = InstallOperation {
+                    data_offset: Some(payload.len() as u64),
+                    data_length: Some(BLOCK_SIZE),
+                    dst_extents: vec![extent(count - 1 - i, 1)],
+                    ..Default::default()
+                };
+                op.set_type(install_operation::Type::Replace);
+                payload.extend_from_slice(&data);
+                image.splice(0..0, data);
+                operations.push(op);
+            }
+            partitions.push(PartitionUpdate {
+                partition_name: name.to_string(),
+                new_partition_info: Some(PartitionInfo {
+                    size: Some(image.len() as u64),
+                    hash: Some(Sha256::digest(&image).to_vec()),
+                }),
+                operations,
+                ..Default::default()
+            });
+            images.push(image);
+        }
+
+        let progress = CountingProgress::default();
+        let out_dir = std::env::temp_dir().join(format!("dump_partitions_{}", std::process::id()));
+        let open_payload =
+            || Ok(Box::new(Cursor::new(payload.clone())) as Box<dyn ReadSeek + Send>);
+        let options = DumpOptions {
+            data_offset: 0,
+            block_size: BLOCK_SIZE,
+            minor_version: 0,
+            out_dir: &out_dir,
+            old_dir: None,
+            verify: true,
+            policy: FailurePolicy::Strict,
+            progress: &NoProgress,
+            cancel: &CancellationToken::new(),
+            journal: None,
+        };
+        let results = dump_partitions(
+            &partitions.iter().collect::<Vec<_>>(),
+            &DumpOptions {
+                cancel: &CancellationToken::new(),
+                progress: &progress,
+                ..options
+            },
+            &open_payload,
+        );
+        let counts = progress.partitions.into_inner().unwrap();
+        assert_eq!(counts["big"], (64, 64 * BLOCK_SIZE, true));
+        assert_eq!(counts["small"], (3, 3 * BLOCK_SIZE, true));
+        for ((partition, image), result) in partitions.iter().zip(&images).zip(results) {
+            assert_eq!(result.unwrap().hash, HashCheck::Verified);
+            let out_path = out_dir.join(format!("{}.img", partition.partition_name));
+            assert_eq!(&fs::read(out_path).unwrap(), image);
+        }
+        fs::remove_dir_all(&out_dir).unwrap();
+
+        // A cancelled extraction leaves no partial images behind.
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let results = dump_partitions(
+            &partitions.iter().collect::<Vec<_>>(),
+            &DumpOptions {
+                cancel: &cancel,
+                progress: &NoProgress,
+                ..options
+            },
+            &open_payload,
+        );
+        for (partition, result) in partitions.iter().zip(results) {
+            assert!(result.unwrap_err().is_cancelled());
+            let out_path = out_dir.join(format!("{}.img", partition.partition_name));
+            assert!(!out_path.exists());
+        }
+        let _ = fs::remove_dir_all(&out_dir);
+    }
+
+    #[test]
+    fn test_file_write_zeros() {
+        let path = std::env::temp_dir().join(format!("write_zeros_{}.img", std::process::id()));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[0xAA; 3 * 4096]).unwrap();
+        // A hole inside the file, and one extending it.
+        file.write_zeros(4096, 4096).unwrap();
+        file.write_zeros(4 * 4096, 4096).unwrap();
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 5 * 4096);
+        assert!(data[..4096].iter().all(|&b| b == 0xAA));
+        assert!(data[4096..2 * 4096].iter().all(|&b| b == 0));
+        assert!(data[2 * 4096..3 * 4096].iter().all(|&b| b == 0xAA));
+        assert!(data[3 * 4096..].iter().all(|&b| b == 0));
+    }
+}
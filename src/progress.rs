@@ -0,0 +1,521 @@
+//! Progress events of an extraction, for rendering progress without tying the library to a UI.
+
+#[cfg(feature = "cli")]
+use std::time::Duration;
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+#[cfg(feature = "cli")]
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde_json::{Value, json};
+
+use crate::{
+    error::{Error, Result},
+    payload_dumper::PartitionReport,
+    proto::PartitionUpdate,
+    verify::HashCheck,
+};
+
+/// Receiver of progress events. Events of different partitions may arrive concurrently from
+/// several threads. Every method does nothing by default.
+#[allow(unused_variables, reason = "Default implementations ignore the events")]
+pub trait Progress: Send + Sync {
+    /// Extraction of `partition` is starting, again if it is retried after failing.
+    fn partition_started(&self, partition: &PartitionUpdate) {}
+
+    /// The image of `partition` was already extracted with a matching hash and is kept instead
+    /// of being extracted again.
+    fn partition_reused(&self, partition: &PartitionUpdate) {}
+
+    /// `operations` operations of `partition` were completed by an interrupted extraction
+    /// and are not run again.
+    fn partition_resumed(&self, partition: &PartitionUpdate, operations: usize) {}
+
+    /// Operation `index` of `partition` completed, writing `bytes` to the image. Operations of a
+    /// partition may complete out of order.
+    fn operation_completed(&self, partition: &PartitionUpdate, index: usize, bytes: u64) {}
+
+    /// Operation `index` of `partition` read corrupt data, which is fetched again.
+    fn operation_retried(&self, partition: &PartitionUpdate, index: usize, error: &Error) {}
+
+    /// The image of `partition` is re-read to check its hash.
+    fn verification_started(&self, partition: &str) {}
+
+    /// Extraction of `partition` ended with `result`.
+    fn partition_finished(&self, partition: &PartitionUpdate, result: &Result<PartitionReport>) {}
+
+    /// The image of an extracted partition was checked by
+    /// [`verify_partitions_hash`](crate::verify::verify_partitions_hash).
+    fn partition_verified(&self, partition: &str, result: &Result<HashCheck>) {}
+}
+
+/// Progress ignoring every event.
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+impl<P: Progress + ?Sized> Progress for Arc<P> {
+    fn partition_started(&self, partition: &PartitionUpdate) {
+        (**self).partition_started(partition);
+    }
+
+    fn partition_reused(&self, partition: &PartitionUpdate) {
+        (**self).partition_reused(partition);
+    }
+
+    fn partition_resumed(&self, partition: &PartitionUpdate, operations: usize) {
+        (**self).partition_resumed(partition, operations);
+    }
+
+    fn operation_completed(&self, partition: &PartitionUpdate, index: usize, bytes: u64) {
+        (**self).operation_completed(partition, index, bytes);
+    }
+
+    fn operation_retried(&self, partition: &PartitionUpdate, index: usize, error: &Error) {
+        (**self).operation_retried(partition, index, error);
+    }
+
+    fn verification_started(&self, partition: &str) {
+        (**self).verification_started(partition);
+    }
+
+    fn partition_finished(&self, partition: &PartitionUpdate, result: &Result<PartitionReport>) {
+        (**self).partition_finished(partition, result);
+    }
+
+    fn partition_verified(&self, partition: &str, result: &Result<HashCheck>) {
+        (**self).partition_verified(partition, result);
+    }
+}
+
+/// Progress shown as one bar per partition in a [`MultiProgress`].
+#[cfg(feature = "cli")]
+pub struct IndicatifProgress {
+    multi_progress: MultiProgress,
+    bars: Mutex<HashMap<String, ProgressBar>>,
+}
+
+#[cfg(feature = "cli")]
+impl IndicatifProgress {
+    #[must_use]
+    pub fn new(multi_progress: MultiProgress) -> Self {
+        Self {
+            multi_progress,
+            bars: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn bar(&self, partition: &str) -> Option<ProgressBar> {
+        self.bars
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(partition)
+            .cloned()
+    }
+
+    /// Show `pb` as the bar of `partition`, in place of the bar of an earlier attempt.
+    fn add_bar(&self, partition: &str, pb: ProgressBar) -> ProgressBar {
+        let mut bars = self.bars.lock().unwrap_or_else(PoisonError::into_inner);
+        let pb = match bars.get(partition) {
+            Some(old) => {
+                let pb = self.multi_progress.insert_after(old, pb);
+                self.multi_progress.remove(old);
+                pb
+            }
+            None => self.multi_progress.add(pb),
+        };
+        bars.insert(partition.to_string(), pb.clone());
+        pb
+    }
+}
+
+#[cfg(feature = "cli")]
+impl Progress for IndicatifProgress {
+    fn partition_started(&self, partition: &PartitionUpdate) {
+        let partition_name = &partition.partition_name;
+        let total_ops = partition.operations.len();
+        let pb = self.add_bar(partition_name, ProgressBar::new(total_ops as u64));
+        pb.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/white}] {percent}% - {msg}")
+            .unwrap()
+            .progress_chars("▰▱"));
+        pb.enable_steady_tick(Duration::from_millis(500));
+        pb.set_message(format!("Processing {partition_name} ({total_ops} ops)"));
+    }
+
+    fn partition_reused(&self, partition: &PartitionUpdate) {
+        let pb = self.add_bar(&partition.partition_name, ProgressBar::new_spinner());
+        pb.set_style(ProgressStyle::default_spinner().template("{msg}").unwrap());
+        pb.finish_with_message(format!(
+            "✓ Reused {}, hash verified",
+            partition.partition_name
+        ));
+    }
+
+    fn partition_resumed(&self, partition: &PartitionUpdate, operations: usize) {
+        if let Some(pb) = self.bar(&partition.partition_name) {
+            pb.set_position(operations as u64);
+            pb.set_message(format!(
+                "Resuming {} ({operations}/{} ops done)",
+                partition.partition_name,
+                partition.operations.len()
+            ));
+        }
+    }
+
+    fn operation_completed(&self, partition: &PartitionUpdate, _index: usize, _bytes: u64) {
+        if let Some(pb) = self.bar(&partition.partition_name) {
+            pb.inc(1);
+        }
+    }
+
+    fn verification_started(&self, partition: &str) {
+        let pb = self.bar(partition).unwrap_or_else(|| {
+            let pb = self.add_bar(partition, ProgressBar::new_spinner());
+            pb.set_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner:.green} {msg}")
+                    .unwrap(),
+            );
+            pb.enable_steady_tick(Duration::from_millis(100));
+            pb
+        });
+        pb.set_message(format!("Verifying hash for {partition}"));
+    }
+
+    fn partition_finished(&self, partition: &PartitionUpdate, result: &Result<PartitionReport>) {
+        let Some(pb) = self.bar(&partition.partition_name) else {
+            return;
+        };
+        let partition_name = &partition.partition_name;
+        let total_ops = partition.operations.len();
+        match result.as_ref().map(|report| report.hash) {
+            Ok(HashCheck::Verified) => pb.finish_with_message(format!(
+                "✓ Completed {partition_name} ({total_ops} ops), hash verified"
+            )),
+            Ok(HashCheck::Mismatch) => {
+                pb.abandon_with_message(format!("✕ Hash mismatch for {partition_name}"));
+            }
+            Ok(HashCheck::Skipped) => {
+                pb.finish_with_message(format!("✓ Completed {partition_name} ({total_ops} ops)"));
+            }
+            Err(_) => pb.abandon_with_message(format!("✕ Failed {partition_name}")),
+        }
+    }
+
+    fn partition_verified(&self, partition: &str, result: &Result<HashCheck>) {
+        let Some(pb) = self.bar(partition) else {
+            return;
+        };
+        pb.finish_with_message(match result {
+            Ok(HashCheck::Verified) => format!("✓ {partition} verified"),
+            Ok(HashCheck::Mismatch) => format!("✕ {partition} mismatch"),
+            Ok(HashCheck::Skipped) => format!("No hash for {partition}"),
+            Err(_) => format!("✕ Failed to verify {partition}"),
+        });
+    }
+}
+
+/// Progress written as one JSON object per line, each with an `event` field naming it.
+///
+/// Operation progress is reported at most once per percent of the operations of a partition,
+/// along with the bytes written so far and the throughput.
+pub struct JsonProgress<W> {
+    out: Mutex<W>,
+    partitions: Mutex<HashMap<String, PartitionProgress>>,
+}
+
+struct PartitionProgress {
+    started: Instant,
+    operations_done: usize,
+    bytes_written: u64,
+}
+
+impl<W: Write + Send> JsonProgress<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+            partitions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Write `event` with `fields`, which must be an object, as a line of its own.
+    pub fn emit(&self, event: &str, fields: Value) {
+        let mut object = json!({ "event": event });
+        if let (Some(object), Value::Object(fields)) = (object.as_object_mut(), fields) {
+            object.extend(fields);
+        }
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        // Progress is best effort, a closed pipe must not fail the extraction.
+        let _ = writeln!(out, "{object}").and_then(|()| out.flush());
+        drop(out);
+    }
+
+    fn partitions(&self) -> std::sync::MutexGuard<'_, HashMap<String, PartitionProgress>> {
+        self.partitions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<W: Write + Send> Progress for JsonProgress<W> {
+    fn partition_started(&self, partition: &PartitionUpdate) {
+        self.partitions().insert(
+            partition.partition_name.clone(),
+            PartitionProgress {
+                started: Instant::now(),
+                operations_done: 0,
+                bytes_written: 0,
+            },
+        );
+        self.emit(
+            "partition_started",
+            json!({
+                "partition": partition.partition_name,
+                "operations": partition.operations.len(),
+                "size": partition.new_partition_info.as_ref().and_then(|info| info.size),
+            }),
+        );
+    }
+
+    fn partition_reused(&self, partition: &PartitionUpdate) {
+        self.emit(
+            "partition_reused",
+            json!({ "partition": partition.partition_name, "status": "verified" }),
+        );
+    }
+
+    fn partition_resumed(&self, partition: &PartitionUpdate, operations: usize) {
+        if let Some(progress) = self.partitions().get_mut(&partition.partition_name) {
+            progress.operations_done = operations;
+        }
+        self.emit(
+            "partition_resumed",
+            json!({
+                "partition": partition.partition_name,
+                "operations_done": operations,
+                "operations": partition.operations.len(),
+            }),
+        );
+    }
+
+    fn operation_completed(&self, partition: &PartitionUpdate, _index: usize, bytes: u64) {
+        let total = partition.operations.len();
+        let mut partitions = self.partitions();
+        let Some(progress) = partitions.get_mut(&partition.partition_name) else {
+            return;
+        };
+        let percent = |done: usize| done * 100 / total.max(1);
+        let previous = percent(progress.operations_done);
+        progress.operations_done += 1;
+        progress.bytes_written += bytes;
+        if percent(progress.operations_done) == previous && progress.operations_done < total {
+            return;
+        }
+        let (operations_done, bytes_written) = (progress.operations_done, progress.bytes_written);
+        let elapsed = progress.started.elapsed().as_secs_f64();
+        drop(partitions);
+        let fields = json!({
+            "partition": partition.partition_name,
+            "operations_done": operations_done,
+            "operations": total,
+            "bytes_written": bytes_written,
+            "bytes_per_second": if elapsed > 0.0 {
+                (bytes_written as f64 / elapsed) as u64
+            } else {
+                0
+            },
+        });
+        self.emit("progress", fields);
+    }
+
+    fn operation_retried(&self, partition: &PartitionUpdate, index: usize, error: &Error) {
+        self.emit(
+            "operation_retried",
+            json!({
+                "partition": partition.partition_name,
+                "operation": index,
+                "error": error_chain(error),
+            }),
+        );
+    }
+
+    fn verification_started(&self, partition: &str) {
+        self.emit("verification_started", json!({ "partition": partition }));
+    }
+
+    fn partition_finished(&self, partition: &PartitionUpdate, result: &Result<PartitionReport>) {
+        let elapsed_ms = self
+            .partitions()
+            .remove(&partition.partition_name)
+            .map(|progress| progress.started.elapsed().as_millis());
+        let mut fields = json!({
+            "partition": partition.partition_name,
+            "elapsed_ms": elapsed_ms,
+        });
+        let details = match result {
+            Ok(report) => json!({
+                "status": hash_status(report.hash),
+                "warnings": report.warnings.iter().map(error_chain).collect::<Vec<_>>(),
+            }),
+            Err(e) => json!({ "status": "failed", "error": error_chain(e) }),
+        };
+        if let (Some(fields), Value::Object(details)) = (fields.as_object_mut(), details) {
+            fields.extend(details);
+        }
+        self.emit("partition_finished", fields);
+    }
+
+    fn partition_verified(&self, partition: &str, result: &Result<HashCheck>) {
+        self.emit(
+            "partition_verified",
+            match result {
+                Ok(check) => json!({ "partition": partition, "status": hash_status(*check) }),
+                Err(e) => json!({
+                    "partition": partition,
+                    "status": "failed",
+                    "error": error_chain(e),
+                }),
+            },
+        );
+    }
+}
+
+const fn hash_status(check: HashCheck) -> &'static str {
+    match check {
+        HashCheck::Verified => "verified",
+        HashCheck::Mismatch => "mismatch",
+        HashCheck::Skipped => "skipped",
+    }
+}
+
+/// `error` followed by its sources, the way `anyhow` shows them with `{:#}`.
+#[must_use]
+pub fn error_chain(error: &Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        message.push_str(": ");
+        message.push_str(&error.to_string());
+        source = error.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "cli")]
+    use std::io;
+
+    #[cfg(feature = "cli")]
+    use indicatif::{ProgressDrawTarget, TermLike};
+
+    use super::*;
+    use crate::proto::InstallOperation;
+
+    #[test]
+    fn test_json_progress() {
+        let partition = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![InstallOperation::default(); 2],
+            ..Default::default()
+        };
+        let progress = JsonProgress::new(Vec::new());
+        progress.partition_started(&partition);
+        progress.operation_completed(&partition, 1, 4096);
+        progress.operation_completed(&partition, 0, 4096);
+        progress.partition_finished(
+            &partition,
+            &Err(Error::Partition {
+                partition: "boot".to_string(),
+                source: Box::new(Error::ImageHashMismatch),
+            }),
+        );
+
+        let out = progress.out.into_inner().unwrap();
+        let events: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0]["event"], "partition_started");
+        assert_eq!(events[0]["operations"], 2);
+        assert_eq!(events[2]["event"], "progress");
+        assert_eq!(events[2]["operations_done"], 2);
+        assert_eq!(events[2]["bytes_written"], 8192);
+        assert_eq!(events[3]["status"], "failed");
+        assert_eq!(
+            events[3]["error"],
+            "Partition boot: Output image hash mismatch"
+        );
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_indicatif_retried_partition() {
+        let partition = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            ..Default::default()
+        };
+        let progress = IndicatifProgress::new(MultiProgress::with_draw_target(
+            ProgressDrawTarget::term_like(Box::new(NullTerm)),
+        ));
+        progress.partition_started(&partition);
+        progress.partition_finished(&partition, &Err(Error::Cancelled));
+        let failed = progress.bar("boot").unwrap();
+        assert!(!failed.is_hidden());
+        // The bar of the failed attempt is taken off the display.
+        progress.partition_started(&partition);
+        assert!(failed.is_hidden());
+        assert!(!progress.bar("boot").unwrap().is_finished());
+    }
+
+    /// Terminal discarding everything drawn on it.
+    #[cfg(feature = "cli")]
+    #[derive(Debug)]
+    struct NullTerm;
+
+    #[cfg(feature = "cli")]
+    impl TermLike for NullTerm {
+        fn width(&self) -> u16 {
+            80
+        }
+
+        fn move_cursor_up(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_down(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_right(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn move_cursor_left(&self, _n: usize) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_line(&self, _s: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_str(&self, _s: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn clear_line(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -262,12 +262,30 @@ pub struct ApexMetadata {
 }
 #[derive(Clone, PartialEq, Eq, ::prost::Message)]
 pub struct DeltaArchiveManifest {
+    #[deprecated]
+    #[prost(message, repeated, tag = "1")]
+    pub install_operations: ::prost::alloc::vec::Vec<InstallOperation>,
+    #[deprecated]
+    #[prost(message, repeated, tag = "2")]
+    pub kernel_install_operations: ::prost::alloc::vec::Vec<InstallOperation>,
     #[prost(uint32, optional, tag = "3", default = "4096")]
     pub block_size: ::core::option::Option<u32>,
     #[prost(uint64, optional, tag = "4")]
     pub signatures_offset: ::core::option::Option<u64>,
     #[prost(uint64, optional, tag = "5")]
     pub signatures_size: ::core::option::Option<u64>,
+    #[deprecated]
+    #[prost(message, optional, tag = "6")]
+    pub old_kernel_info: ::core::option::Option<PartitionInfo>,
+    #[deprecated]
+    #[prost(message, optional, tag = "7")]
+    pub new_kernel_info: ::core::option::Option<PartitionInfo>,
+    #[deprecated]
+    #[prost(message, optional, tag = "8")]
+    pub old_rootfs_info: ::core::option::Option<PartitionInfo>,
+    #[deprecated]
+    #[prost(message, optional, tag = "9")]
+    pub new_rootfs_info: ::core::option::Option<PartitionInfo>,
     #[prost(uint32, optional, tag = "12", default = "0")]
     pub minor_version: ::core::option::Option<u32>,
     #[prost(message, repeated, tag = "13")]
@@ -0,0 +1,139 @@
+use anyhow::{Result, bail};
+
+/// LSB-first bit reader over a byte slice, bounded to a bit window.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: u64,
+    end: u64,
+}
+
+impl<'a> BitReader<'a> {
+    /// Create a reader positioned at bit `start`, refusing to read past bit `end`.
+    pub fn new(data: &'a [u8], start: u64, end: u64) -> Result<Self> {
+        if start > end || end > data.len() as u64 * 8 {
+            bail!("Bit window {start}..{end} is out of bounds");
+        }
+        Ok(Self {
+            data,
+            position: start,
+            end,
+        })
+    }
+
+    /// Current absolute bit position.
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Number of bits left before the end of the window.
+    pub const fn remaining(&self) -> u64 {
+        self.end - self.position
+    }
+
+    /// Look at the next `count` (<= 32) bits without consuming them. Bits past the end of the
+    /// window read as zero.
+    pub fn peek_bits(&self, count: u32) -> u32 {
+        let available = self.remaining().min(u64::from(count)) as u32;
+        if available == 0 {
+            return 0;
+        }
+        let byte = (self.position / 8) as usize;
+        let shift = self.position % 8;
+        let mut word = 0u64;
+        for (i, b) in self.data[byte..].iter().take(5).enumerate() {
+            word |= u64::from(*b) << (8 * i);
+        }
+        ((word >> shift) & ((1u64 << available) - 1)) as u32
+    }
+
+    /// Consume `count` bits.
+    pub fn skip_bits(&mut self, count: u32) -> Result<()> {
+        if u64::from(count) > self.remaining() {
+            bail!("Unexpected end of deflate stream");
+        }
+        self.position += u64::from(count);
+        Ok(())
+    }
+
+    /// Read and consume the next `count` (<= 32) bits.
+    pub fn read_bits(&mut self, count: u32) -> Result<u32> {
+        let value = self.peek_bits(count);
+        self.skip_bits(count)?;
+        Ok(value)
+    }
+
+    /// Read `count` whole bytes; the reader must be byte aligned.
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        if !self.position.is_multiple_of(8) {
+            bail!("Unaligned raw read from deflate stream");
+        }
+        let start = (self.position / 8) as usize;
+        self.skip_bits(count as u32 * 8)?;
+        Ok(&self.data[start..start + count])
+    }
+
+    /// Number of bits until the next byte boundary.
+    pub const fn bits_to_boundary(&self) -> u32 {
+        ((8 - self.position % 8) % 8) as u32
+    }
+}
+
+/// LSB-first bit writer producing a byte vector.
+#[derive(Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    cache: u64,
+    cached_bits: u32,
+}
+
+impl BitWriter {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(capacity),
+            cache: 0,
+            cached_bits: 0,
+        }
+    }
+
+    /// Absolute number of bits written so far.
+    pub fn position(&self) -> u64 {
+        self.data.len() as u64 * 8 + u64::from(self.cached_bits)
+    }
+
+    /// Append the low `count` (<= 32) bits of `value`.
+    pub fn write_bits(&mut self, count: u32, value: u32) {
+        if count == 0 {
+            return;
+        }
+        let value = u64::from(value) & ((1u64 << count) - 1);
+        self.cache |= value << self.cached_bits;
+        self.cached_bits += count;
+        while self.cached_bits >= 8 {
+            self.data.push(self.cache as u8);
+            self.cache >>= 8;
+            self.cached_bits -= 8;
+        }
+    }
+
+    /// Number of bits until the next byte boundary.
+    pub const fn bits_to_boundary(&self) -> u32 {
+        (8 - self.cached_bits) % 8
+    }
+
+    /// Append raw bytes; the writer must be byte aligned.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.cached_bits != 0 {
+            bail!("Unaligned raw write into deflate stream");
+        }
+        self.data.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Pad the last partial byte with zeros and return the written bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.cached_bits > 0 {
+            self.data.push(self.cache as u8);
+        }
+        self.data
+    }
+}
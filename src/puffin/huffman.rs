@@ -0,0 +1,142 @@
+use anyhow::{Result, bail};
+
+use super::bit_io::{BitReader, BitWriter};
+
+const MAX_CODE_BITS: u32 = 15;
+
+/// Base values of the length symbols 257..=285.
+pub const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+/// Extra bits of the length symbols 257..=285.
+pub const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base values of the distance symbols 0..=29.
+pub const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+/// Extra bits of the distance symbols 0..=29.
+pub const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which the code length code lengths are stored (RFC 1951, 3.2.7).
+pub const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// A canonical Huffman code usable in both directions.
+pub struct Huffman {
+    /// `(symbol, length)` indexed by the next `max_bits` input bits; length 0 marks an invalid code.
+    lookup: Vec<(u16, u8)>,
+    max_bits: u32,
+    /// `(bit-reversed code, length)` indexed by symbol.
+    codes: Vec<(u16, u8)>,
+}
+
+impl Huffman {
+    /// Build a canonical code from per-symbol code lengths.
+    pub fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; MAX_CODE_BITS as usize + 1];
+        for &len in lengths {
+            if u32::from(len) > MAX_CODE_BITS {
+                bail!("Invalid Huffman code length {len}");
+            }
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Reject over-subscribed codes; incomplete ones are legal (e.g. a single distance code).
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                bail!("Over-subscribed Huffman code");
+            }
+        }
+
+        let mut next_code = [0u32; MAX_CODE_BITS as usize + 2];
+        for bits in 1..=MAX_CODE_BITS as usize {
+            next_code[bits + 1] = (next_code[bits] + u32::from(counts[bits])) << 1;
+        }
+
+        let max_bits = lengths.iter().copied().max().unwrap_or(0);
+        let mut lookup = vec![(0u16, 0u8); 1 << max_bits];
+        let mut codes = vec![(0u16, 0u8); lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let code = next_code[len as usize];
+            next_code[len as usize] += 1;
+            let reversed = (code as u16).reverse_bits() >> (16 - u32::from(len));
+            codes[symbol] = (reversed, len);
+            let mut index = reversed as usize;
+            while index < lookup.len() {
+                lookup[index] = (symbol as u16, len);
+                index += 1 << len;
+            }
+        }
+
+        Ok(Self {
+            lookup,
+            max_bits: u32::from(max_bits),
+            codes,
+        })
+    }
+
+    /// The fixed literal/length code (RFC 1951, 3.2.6).
+    pub fn fixed_lit_len() -> Result<Self> {
+        let mut lengths = [0u8; 288];
+        lengths[..144].fill(8);
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        lengths[280..].fill(8);
+        Self::new(&lengths)
+    }
+
+    /// The fixed distance code (RFC 1951, 3.2.6).
+    pub fn fixed_distance() -> Result<Self> {
+        Self::new(&[5u8; 32])
+    }
+
+    /// Decode one symbol.
+    pub fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        if self.max_bits == 0 {
+            bail!("Symbol read from an empty Huffman code");
+        }
+        let (symbol, len) = self.lookup[reader.peek_bits(self.max_bits) as usize];
+        if len == 0 {
+            bail!("Invalid Huffman code in deflate stream");
+        }
+        reader.skip_bits(u32::from(len))?;
+        Ok(symbol)
+    }
+
+    /// Encode one symbol.
+    pub fn encode(&self, writer: &mut BitWriter, symbol: u16) -> Result<()> {
+        match self.codes.get(symbol as usize) {
+            Some(&(code, len)) if len > 0 => {
+                writer.write_bits(u32::from(len), u32::from(code));
+                Ok(())
+            }
+            _ => bail!("Symbol {symbol} has no Huffman code"),
+        }
+    }
+}
+
+/// Index of the largest base not exceeding `value`, matching how puffin re-encodes lengths and
+/// distances.
+pub fn base_index(bases: &[u16], value: u16) -> usize {
+    let mut index = 0;
+    while index + 1 < bases.len() && value > bases[index] {
+        index += 1;
+    }
+    if value < bases[index] {
+        index -= 1;
+    }
+    index
+}
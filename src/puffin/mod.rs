@@ -0,0 +1,329 @@
+//! Native implementation of AOSP puffin patches, used by `PUFFDIFF` operations.
+//!
+//! A puffin patch describes the deflate streams of the source and destination data. Source
+//! deflates are "puffed" into a byte-oriented representation, the embedded bsdiff patch is
+//! applied to the puffed source, and the result is "huffed" back into bit-exact deflate streams.
+
+mod bit_io;
+mod huffman;
+mod puff;
+
+use anyhow::{Context, Result, bail};
+use prost::Message;
+
+use crate::{patch::bspatch, zucchini};
+use bit_io::{BitReader, BitWriter};
+use puff::{huff_deflate, puff_deflate};
+
+/// Magic at the start of every puffin patch.
+pub const PUFFIN_MAGIC: &[u8; 4] = b"PUF1";
+
+#[derive(Clone, Copy, PartialEq, Eq, ::prost::Message)]
+pub struct BitExtent {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    #[prost(uint64, tag = "2")]
+    pub length: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, ::prost::Message)]
+pub struct StreamInfo {
+    /// Deflate streams, in bits.
+    #[prost(message, repeated, tag = "1")]
+    pub deflates: ::prost::alloc::vec::Vec<BitExtent>,
+    /// Puffed streams in the puffed representation, in bytes.
+    #[prost(message, repeated, tag = "2")]
+    pub puffs: ::prost::alloc::vec::Vec<BitExtent>,
+    #[prost(uint64, tag = "3")]
+    pub puff_length: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, ::prost::Message)]
+pub struct PatchHeader {
+    #[prost(int32, tag = "1")]
+    pub version: i32,
+    #[prost(message, optional, tag = "2")]
+    pub src: ::core::option::Option<StreamInfo>,
+    #[prost(message, optional, tag = "3")]
+    pub dst: ::core::option::Option<StreamInfo>,
+    #[prost(enumeration = "patch_header::PatchType", tag = "4")]
+    pub r#type: i32,
+}
+
+/// Nested message and enum types in `PatchHeader`.
+pub mod patch_header {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum PatchType {
+        Bsdiff = 0,
+        Zucchini = 1,
+    }
+}
+
+/// Apply a puffin patch to `src`, producing the destination data.
+pub fn puffpatch(src: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let (header, raw_patch) = parse_patch(patch)?;
+    let patch_type = header.r#type();
+    let src_info = header.src.unwrap_or_default();
+    let dst_info = header.dst.unwrap_or_default();
+
+    let puffed_src = puff_stream(src, &src_info).context("Failed to puff source data")?;
+    let puffed_dst = match patch_type {
+        patch_header::PatchType::Bsdiff => bspatch(&puffed_src, raw_patch)?,
+        patch_header::PatchType::Zucchini => zucchini::apply(&puffed_src, raw_patch)?,
+    };
+    if puffed_dst.len() as u64 != dst_info.puff_length {
+        bail!(
+            "Patched puff stream has {} bytes, expected {}",
+            puffed_dst.len(),
+            dst_info.puff_length
+        );
+    }
+    huff_stream(&puffed_dst, &dst_info).context("Failed to huff destination data")
+}
+
+/// Split a puffin patch into its header and the embedded raw patch.
+fn parse_patch(patch: &[u8]) -> Result<(PatchHeader, &[u8])> {
+    if patch.len() < 8 || &patch[..4] != PUFFIN_MAGIC {
+        bail!("Invalid puffin patch: magic 'PUF1' not found");
+    }
+    let header_size = u32::from_be_bytes([patch[4], patch[5], patch[6], patch[7]]) as usize;
+    let Some(header) = patch.get(8..8 + header_size) else {
+        bail!("Invalid puffin patch: truncated header");
+    };
+    let header = PatchHeader::decode(header).context("Invalid puffin patch header")?;
+    Ok((header, &patch[8 + header_size..]))
+}
+
+/// Validate that deflates and puffs pair up and are sorted.
+fn check_stream_info(info: &StreamInfo) -> Result<()> {
+    if info.deflates.len() != info.puffs.len() {
+        bail!(
+            "Mismatched deflate ({}) and puff ({}) counts",
+            info.deflates.len(),
+            info.puffs.len()
+        );
+    }
+    for pair in info.deflates.windows(2) {
+        if pair[0].offset + pair[0].length > pair[1].offset {
+            bail!("Overlapping or unsorted deflate extents");
+        }
+    }
+    Ok(())
+}
+
+/// Produce the puffed representation of `src`. Bits between deflates are copied as raw bytes,
+/// one puff byte per (possibly partial) source byte, with deflate bits shifted or masked out.
+pub fn puff_stream(src: &[u8], info: &StreamInfo) -> Result<Vec<u8>> {
+    check_stream_info(info)?;
+    let src_bits = src.len() as u64 * 8;
+    let mut out = Vec::with_capacity(info.puff_length as usize);
+    let mut position = 0;
+
+    for (deflate, puff) in info.deflates.iter().zip(&info.puffs) {
+        let end = deflate.offset + deflate.length;
+        if end > src_bits {
+            bail!(
+                "Deflate extent {}+{} out of bounds",
+                deflate.offset,
+                deflate.length
+            );
+        }
+        copy_raw_bits(src, position, deflate.offset, &mut out)?;
+        if out.len() as u64 != puff.offset {
+            bail!(
+                "Puff offset mismatch: at {}, expected {}",
+                out.len(),
+                puff.offset
+            );
+        }
+
+        let window_end = end.div_ceil(8) * 8;
+        let mut reader = BitReader::new(src, deflate.offset, window_end)?;
+        puff_deflate(&mut reader, &mut out)?;
+        if reader.position().div_ceil(8) != end.div_ceil(8) {
+            bail!("Deflate at bit {} has an unexpected length", deflate.offset);
+        }
+        if out.len() as u64 != puff.offset + puff.length {
+            bail!("Puff length mismatch for deflate at bit {}", deflate.offset);
+        }
+        position = end;
+    }
+    copy_raw_bits(src, position, src_bits, &mut out)?;
+
+    if out.len() as u64 != info.puff_length {
+        bail!(
+            "Puffed stream has {} bytes, expected {}",
+            out.len(),
+            info.puff_length
+        );
+    }
+    Ok(out)
+}
+
+/// Rebuild deflate data from its puffed representation.
+pub fn huff_stream(puffed: &[u8], info: &StreamInfo) -> Result<Vec<u8>> {
+    check_stream_info(info)?;
+    let mut writer = BitWriter::with_capacity(puffed.len());
+    let mut puff_position = 0;
+
+    for (deflate, puff) in info.deflates.iter().zip(&info.puffs) {
+        let puff_end = (puff.offset + puff.length) as usize;
+        if puff.offset < puff_position as u64 || puff_end > puffed.len() {
+            bail!("Puff extent {}+{} out of bounds", puff.offset, puff.length);
+        }
+        write_raw_bits(
+            &puffed[puff_position..puff.offset as usize],
+            deflate.offset,
+            &mut writer,
+        )?;
+
+        huff_deflate(&puffed[puff.offset as usize..puff_end], &mut writer)?;
+        if writer.position() != deflate.offset + deflate.length {
+            bail!("Deflate at bit {} has an unexpected length", deflate.offset);
+        }
+        puff_position = puff_end;
+    }
+    // Each trailing puff byte completes the current byte or fills a new one.
+    let tail = &puffed[puff_position..];
+    let end = if tail.is_empty() {
+        writer.position()
+    } else {
+        (writer.position() / 8 + tail.len() as u64) * 8
+    };
+    write_raw_bits(tail, end, &mut writer)?;
+    Ok(writer.finish())
+}
+
+/// Copy the source bits in `start..end` as puff bytes, split on source byte boundaries.
+fn copy_raw_bits(src: &[u8], mut start: u64, end: u64, out: &mut Vec<u8>) -> Result<()> {
+    if start > end {
+        bail!("Deflate extents overlap");
+    }
+    let mut reader = BitReader::new(src, start, end)?;
+    while start < end {
+        let count = (8 - start % 8).min(end - start) as u32;
+        out.push(reader.read_bits(count)? as u8);
+        start += u64::from(count);
+    }
+    Ok(())
+}
+
+/// Write puff bytes as raw bits up to the absolute bit position `end`, inverting
+/// [`copy_raw_bits`].
+fn write_raw_bits(bytes: &[u8], end: u64, writer: &mut BitWriter) -> Result<()> {
+    let mut bytes = bytes.iter();
+    while writer.position() < end {
+        let count = (8 - writer.position() % 8).min(end - writer.position()) as u32;
+        let Some(&byte) = bytes.next() else {
+            bail!("Not enough raw bytes in puff stream");
+        };
+        writer.write_bits(count, u32::from(byte));
+    }
+    if bytes.next().is_some() {
+        bail!("Too many raw bytes in puff stream");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{Compression, write::DeflateEncoder};
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Wrap a deflate stream in raw bytes, returning the data and its stream info.
+    fn container(data: &[u8], level: u32) -> (Vec<u8>, StreamInfo) {
+        let mut src = b"head".to_vec();
+        src.extend_from_slice(&deflate(data, level));
+        src.extend_from_slice(b"tail");
+
+        let offset = 4 * 8;
+        let mut reader = BitReader::new(&src, offset, (src.len() as u64 - 4) * 8).unwrap();
+        let mut puff = Vec::new();
+        puff_deflate(&mut reader, &mut puff).unwrap();
+        // The stream was padded to a byte boundary; the trailing zero bits stay raw.
+        let length = reader.position() - offset;
+        let mut raw = Vec::new();
+        copy_raw_bits(&src, offset + length, src.len() as u64 * 8, &mut raw).unwrap();
+        let info = StreamInfo {
+            deflates: vec![BitExtent { offset, length }],
+            puffs: vec![BitExtent {
+                offset: 4,
+                length: puff.len() as u64,
+            }],
+            puff_length: (4 + puff.len() + raw.len()) as u64,
+        };
+        (src, info)
+    }
+
+    fn sample(seed: u8) -> Vec<u8> {
+        (0..20_000u32)
+            .map(|i| match i % 7 {
+                0 => seed,
+                1 => (i % 251) as u8,
+                _ => b"puffin patches "[(i % 15) as usize],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_puff_huff_roundtrip() {
+        for level in [0, 1, 6, 9] {
+            let (src, info) = container(&sample(7), level);
+            let puffed = puff_stream(&src, &info).unwrap();
+            assert_eq!(huff_stream(&puffed, &info).unwrap(), src, "level {level}");
+        }
+    }
+
+    #[test]
+    fn test_puffpatch() {
+        let (src, src_info) = container(&sample(1), 6);
+        let (dst, dst_info) = container(&sample(2), 9);
+        let mut raw_patch = Vec::new();
+        bsdiff::diff(
+            &puff_stream(&src, &src_info).unwrap(),
+            &puff_stream(&dst, &dst_info).unwrap(),
+            &mut raw_patch,
+        )
+        .unwrap();
+
+        let header = PatchHeader {
+            version: 1,
+            src: Some(src_info),
+            dst: Some(dst_info),
+            r#type: patch_header::PatchType::Bsdiff as i32,
+        }
+        .encode_to_vec();
+        let mut patch = PUFFIN_MAGIC.to_vec();
+        patch.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        patch.extend_from_slice(&header);
+        patch.extend_from_slice(&raw_patch);
+
+        assert_eq!(puffpatch(&src, &patch).unwrap(), dst);
+    }
+
+    /// A patch made by the independent puffer in `scripts/gen_fixtures.py`, covering stored,
+    /// fixed and dynamic blocks.
+    #[test]
+    fn test_puffpatch_fixture() {
+        const SOURCE: &[u8] = include_bytes!("../../tests/fixtures/puffin/source.bin");
+        const TARGET: &[u8] = include_bytes!("../../tests/fixtures/puffin/target.bin");
+        const PATCH: &[u8] = include_bytes!("../../tests/fixtures/puffin/patch.puffin");
+        const TARGET_SHA256: &str =
+            "876a212cf7135fce27cb4b39d996009f14b1053432c8d048ef44c3c368db6320";
+
+        let target = puffpatch(SOURCE, PATCH).unwrap();
+        assert_eq!(hex::encode(Sha256::digest(&target)), TARGET_SHA256);
+        assert_eq!(target, TARGET);
+    }
+}
@@ -0,0 +1,480 @@
+//! Conversion between deflate bit streams and puffin's "puff" byte representation.
+//!
+//! A puff stream is a sequence of blocks, each made of:
+//! - block metadata: big-endian `u16` (length - 1) followed by the metadata bytes. The first
+//!   metadata byte is `F TT SSSSS` (final bit, block type, skipped boundary bits of stored
+//!   blocks); dynamic blocks append their Huffman table description;
+//! - literal runs: one byte `0LLLLLLL` holding `length - 1`, or `0x7F` followed by a big-endian
+//!   `u16` holding `length - 128`, then the literal bytes;
+//! - length/distance pairs: one byte `1LLLLLLL` holding `length - 3` (or `0xFF` followed by one
+//!   byte holding `length - 130`) and a big-endian `u16` holding `distance - 1`;
+//! - an end-of-block marker, encoded as the length 259 without a distance.
+
+use anyhow::{Result, bail};
+
+use super::{
+    bit_io::{BitReader, BitWriter},
+    huffman::{
+        CODE_LENGTH_ORDER, DISTANCE_BASES, DISTANCE_EXTRA_BITS, Huffman, LENGTH_BASES,
+        LENGTH_EXTRA_BITS, base_index,
+    },
+};
+
+/// Maximum number of literals puffin stores in a single run.
+const MAX_LITERALS_LENGTH: usize = (1 << 16) + 127;
+/// Size of puffin's block metadata buffer.
+const MAX_METADATA_LENGTH: usize = 400;
+/// Length value used to mark the end of a block.
+const END_OF_BLOCK_LENGTH: u16 = 259;
+
+const BLOCK_STORED: u8 = 0;
+const BLOCK_FIXED: u8 = 1;
+const BLOCK_DYNAMIC: u8 = 2;
+
+/// Serializes puff items, coalescing consecutive literals into runs.
+struct PuffWriter<'a> {
+    out: &'a mut Vec<u8>,
+    literals: Vec<u8>,
+}
+
+impl<'a> PuffWriter<'a> {
+    const fn new(out: &'a mut Vec<u8>) -> Self {
+        Self {
+            out,
+            literals: Vec::new(),
+        }
+    }
+
+    fn literal(&mut self, byte: u8) {
+        self.literals.push(byte);
+        if self.literals.len() == MAX_LITERALS_LENGTH {
+            self.flush_literals();
+        }
+    }
+
+    fn literals(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.literal(byte);
+        }
+    }
+
+    fn flush_literals(&mut self) {
+        let len = self.literals.len();
+        if len == 0 {
+            return;
+        }
+        if len <= 127 {
+            self.out.push((len - 1) as u8);
+        } else {
+            self.out.push(127);
+            self.out
+                .extend_from_slice(&((len - 128) as u16).to_be_bytes());
+        }
+        self.out.append(&mut self.literals);
+    }
+
+    fn metadata(&mut self, metadata: &[u8]) {
+        self.flush_literals();
+        self.out
+            .extend_from_slice(&((metadata.len() - 1) as u16).to_be_bytes());
+        self.out.extend_from_slice(metadata);
+    }
+
+    fn length(&mut self, length: u16) {
+        if length < 130 {
+            self.out.push(0x80 | (length - 3) as u8);
+        } else {
+            self.out.push(0xFF);
+            self.out.push((length - 130) as u8);
+        }
+    }
+
+    fn len_dist(&mut self, length: u16, distance: u16) {
+        self.flush_literals();
+        self.length(length);
+        self.out.extend_from_slice(&(distance - 1).to_be_bytes());
+    }
+
+    fn end_of_block(&mut self) {
+        self.flush_literals();
+        self.length(END_OF_BLOCK_LENGTH);
+    }
+}
+
+/// One decoded puff item.
+enum PuffItem<'a> {
+    Metadata(&'a [u8]),
+    Literals(&'a [u8]),
+    LenDist(u16, u16),
+    EndOfBlock,
+}
+
+/// Parses a puff stream back into items.
+struct PuffReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    in_block: bool,
+}
+
+impl<'a> PuffReader<'a> {
+    const fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+            in_block: false,
+        }
+    }
+
+    const fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.position + count > self.data.len() {
+            bail!("Truncated puff stream");
+        }
+        let bytes = &self.data[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn take_u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn next_item(&mut self) -> Result<PuffItem<'a>> {
+        if !self.in_block {
+            let length = self.take_u16()? as usize + 1;
+            if length > MAX_METADATA_LENGTH {
+                bail!("Puff block metadata too long: {length}");
+            }
+            self.in_block = true;
+            return Ok(PuffItem::Metadata(self.take(length)?));
+        }
+
+        let head = self.take(1)?[0];
+        if head & 0x80 == 0 {
+            let length = if head < 127 {
+                head as usize + 1
+            } else {
+                self.take_u16()? as usize + 128
+            };
+            return Ok(PuffItem::Literals(self.take(length)?));
+        }
+
+        let length = if head & 0x7F < 127 {
+            u16::from(head & 0x7F) + 3
+        } else {
+            u16::from(self.take(1)?[0]) + 130
+        };
+        if length == END_OF_BLOCK_LENGTH {
+            self.in_block = false;
+            return Ok(PuffItem::EndOfBlock);
+        }
+        if length > 258 {
+            bail!("Invalid length {length} in puff stream");
+        }
+        let distance = self.take_u16()?;
+        if distance >= 1 << 15 {
+            bail!("Invalid distance {distance} in puff stream");
+        }
+        Ok(PuffItem::LenDist(length, distance + 1))
+    }
+}
+
+/// Read a dynamic block's Huffman tables from the deflate stream, recording them as puff
+/// metadata after the block header byte.
+fn puff_dynamic_tables(
+    reader: &mut BitReader,
+    metadata: &mut Vec<u8>,
+) -> Result<(Huffman, Huffman)> {
+    let num_lit_len = reader.read_bits(5)? as usize + 257;
+    let num_distance = reader.read_bits(5)? as usize + 1;
+    let num_codes = reader.read_bits(4)? as usize + 4;
+    if num_lit_len > 286 || num_distance > 30 {
+        bail!("Invalid dynamic Huffman table sizes");
+    }
+    metadata.push((num_lit_len - 257) as u8);
+    metadata.push((num_distance - 1) as u8);
+    metadata.push((num_codes - 4) as u8);
+
+    // Code length code lengths are packed two per byte, high nibble first.
+    let mut code_lengths = [0u8; 19];
+    for (i, &symbol) in CODE_LENGTH_ORDER.iter().take(num_codes).enumerate() {
+        let len = reader.read_bits(3)? as u8;
+        code_lengths[symbol] = len;
+        if i % 2 == 0 {
+            metadata.push(len << 4);
+        } else {
+            *metadata.last_mut().unwrap() |= len;
+        }
+    }
+    let code_huffman = Huffman::new(&code_lengths)?;
+
+    // Each code length symbol is stored as one byte: 0..=15 as is, 16 + 2 extra bits as
+    // 16..=19, 17 + 3 extra bits as 20..=27 and 18 + 7 extra bits as 28..=155.
+    let total = num_lit_len + num_distance;
+    let mut lengths = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let symbol = code_huffman.decode(reader)?;
+        let (repeat, value) = match symbol {
+            0..=15 => {
+                metadata.push(symbol as u8);
+                lengths.push(symbol as u8);
+                continue;
+            }
+            16 => {
+                let Some(&previous) = lengths.last() else {
+                    bail!("Code length repeat without a previous length");
+                };
+                let extra = reader.read_bits(2)?;
+                metadata.push(16 + extra as u8);
+                (3 + extra as usize, previous)
+            }
+            17 => {
+                let extra = reader.read_bits(3)?;
+                metadata.push(20 + extra as u8);
+                (3 + extra as usize, 0)
+            }
+            18 => {
+                let extra = reader.read_bits(7)?;
+                metadata.push(28 + extra as u8);
+                (11 + extra as usize, 0)
+            }
+            _ => bail!("Invalid code length symbol {symbol}"),
+        };
+        lengths.resize(lengths.len() + repeat, value);
+    }
+    if lengths.len() != total {
+        bail!("Code lengths overflow the dynamic Huffman tables");
+    }
+
+    Ok((
+        Huffman::new(&lengths[..num_lit_len])?,
+        Huffman::new(&lengths[num_lit_len..])?,
+    ))
+}
+
+/// Write a dynamic block's Huffman tables described by puff metadata (without the header byte)
+/// into the deflate stream.
+fn huff_dynamic_tables(metadata: &[u8], writer: &mut BitWriter) -> Result<(Huffman, Huffman)> {
+    if metadata.len() < 3 {
+        bail!("Truncated dynamic block metadata");
+    }
+    let num_lit_len = metadata[0] as usize + 257;
+    let num_distance = metadata[1] as usize + 1;
+    let num_codes = metadata[2] as usize + 4;
+    if num_lit_len > 286 || num_distance > 30 || num_codes > 19 {
+        bail!("Invalid dynamic Huffman table sizes");
+    }
+    writer.write_bits(5, u32::from(metadata[0]));
+    writer.write_bits(5, u32::from(metadata[1]));
+    writer.write_bits(4, u32::from(metadata[2]));
+
+    let mut index = 3 + num_codes.div_ceil(2);
+    if metadata.len() < index {
+        bail!("Truncated dynamic block metadata");
+    }
+    let mut code_lengths = [0u8; 19];
+    for (i, &symbol) in CODE_LENGTH_ORDER.iter().take(num_codes).enumerate() {
+        let byte = metadata[3 + i / 2];
+        let len = if i % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+        if len > 7 {
+            bail!("Invalid code length code length {len}");
+        }
+        code_lengths[symbol] = len;
+        writer.write_bits(3, u32::from(len));
+    }
+    let code_huffman = Huffman::new(&code_lengths)?;
+
+    let total = num_lit_len + num_distance;
+    let mut lengths = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let Some(&byte) = metadata.get(index) else {
+            bail!("Truncated dynamic block metadata");
+        };
+        index += 1;
+        let (repeat, value) = match byte {
+            0..=15 => {
+                code_huffman.encode(writer, u16::from(byte))?;
+                lengths.push(byte);
+                continue;
+            }
+            16..=19 => {
+                let Some(&previous) = lengths.last() else {
+                    bail!("Code length repeat without a previous length");
+                };
+                code_huffman.encode(writer, 16)?;
+                writer.write_bits(2, u32::from(byte - 16));
+                (byte as usize - 16 + 3, previous)
+            }
+            20..=27 => {
+                code_huffman.encode(writer, 17)?;
+                writer.write_bits(3, u32::from(byte - 20));
+                (byte as usize - 20 + 3, 0)
+            }
+            28..=155 => {
+                code_huffman.encode(writer, 18)?;
+                writer.write_bits(7, u32::from(byte - 28));
+                (byte as usize - 28 + 11, 0)
+            }
+            _ => bail!("Invalid code length byte {byte} in puff metadata"),
+        };
+        lengths.resize(lengths.len() + repeat, value);
+    }
+    if lengths.len() != total || index != metadata.len() {
+        bail!("Dynamic block metadata does not match its table sizes");
+    }
+
+    Ok((
+        Huffman::new(&lengths[..num_lit_len])?,
+        Huffman::new(&lengths[num_lit_len..])?,
+    ))
+}
+
+/// Convert the deflate blocks found in `reader` into puff form, appending to `out`. Like puffin,
+/// blocks are read as long as at least eight bits remain in the reader's window.
+pub fn puff_deflate(reader: &mut BitReader, out: &mut Vec<u8>) -> Result<()> {
+    let mut writer = PuffWriter::new(out);
+    let fixed = (Huffman::fixed_lit_len()?, Huffman::fixed_distance()?);
+
+    while reader.remaining() >= 8 {
+        let final_bit = reader.read_bits(1)? as u8;
+        let block_type = reader.read_bits(2)? as u8;
+        let header = (final_bit << 7) | (block_type << 5);
+
+        let dynamic;
+        let (lit_len, distance) = match block_type {
+            BLOCK_STORED => {
+                let skip = reader.bits_to_boundary();
+                let skipped = reader.read_bits(skip)? as u8;
+                let len = reader.read_bits(16)?;
+                let nlen = reader.read_bits(16)?;
+                if len ^ nlen != 0xFFFF {
+                    bail!("Invalid stored block length: LEN({len}) NLEN({nlen})");
+                }
+                writer.metadata(&[header | (skipped & 0x1F)]);
+                writer.literals(reader.read_bytes(len as usize)?);
+                writer.end_of_block();
+                continue;
+            }
+            BLOCK_FIXED => {
+                writer.metadata(&[header]);
+                (&fixed.0, &fixed.1)
+            }
+            BLOCK_DYNAMIC => {
+                let mut metadata = vec![header];
+                dynamic = puff_dynamic_tables(reader, &mut metadata)?;
+                if metadata.len() > MAX_METADATA_LENGTH {
+                    bail!("Dynamic block metadata too long");
+                }
+                writer.metadata(&metadata);
+                (&dynamic.0, &dynamic.1)
+            }
+            _ => bail!("Invalid deflate block type {block_type}"),
+        };
+
+        loop {
+            let symbol = lit_len.decode(reader)?;
+            match symbol {
+                0..=255 => writer.literal(symbol as u8),
+                256 => {
+                    writer.end_of_block();
+                    break;
+                }
+                257..=285 => {
+                    let index = symbol as usize - 257;
+                    let extra = reader.read_bits(u32::from(LENGTH_EXTRA_BITS[index]))?;
+                    let length = LENGTH_BASES[index] + extra as u16;
+                    let index = distance.decode(reader)? as usize;
+                    if index >= DISTANCE_BASES.len() {
+                        bail!("Invalid distance symbol {index}");
+                    }
+                    let extra = reader.read_bits(u32::from(DISTANCE_EXTRA_BITS[index]))?;
+                    writer.len_dist(length, DISTANCE_BASES[index] + extra as u16);
+                }
+                _ => bail!("Invalid literal/length symbol {symbol}"),
+            }
+        }
+    }
+    writer.flush_literals();
+    Ok(())
+}
+
+/// Convert a puff stream back into deflate blocks written to `writer`.
+pub fn huff_deflate(puff: &[u8], writer: &mut BitWriter) -> Result<()> {
+    let mut reader = PuffReader::new(puff);
+    let fixed = (Huffman::fixed_lit_len()?, Huffman::fixed_distance()?);
+
+    while !reader.is_empty() {
+        let PuffItem::Metadata(metadata) = reader.next_item()? else {
+            bail!("Puff block does not start with metadata");
+        };
+        let header = metadata[0];
+        let block_type = (header >> 5) & 0x03;
+        writer.write_bits(1, u32::from(header >> 7));
+        writer.write_bits(2, u32::from(block_type));
+
+        let dynamic;
+        let (lit_len, distance) = match block_type {
+            BLOCK_STORED => {
+                let skip = writer.bits_to_boundary();
+                writer.write_bits(skip, u32::from(header & 0x1F));
+                match reader.next_item()? {
+                    PuffItem::Literals(bytes) => {
+                        let len = bytes.len() as u32;
+                        writer.write_bits(16, len);
+                        writer.write_bits(16, !len);
+                        writer.write_bytes(bytes)?;
+                        if !matches!(reader.next_item()?, PuffItem::EndOfBlock) {
+                            bail!("Stored puff block did not end properly");
+                        }
+                    }
+                    PuffItem::EndOfBlock => {
+                        writer.write_bits(16, 0);
+                        writer.write_bits(16, 0xFFFF);
+                    }
+                    _ => bail!("Stored puff block did not end properly"),
+                }
+                continue;
+            }
+            BLOCK_FIXED => (&fixed.0, &fixed.1),
+            BLOCK_DYNAMIC => {
+                dynamic = huff_dynamic_tables(&metadata[1..], writer)?;
+                (&dynamic.0, &dynamic.1)
+            }
+            _ => bail!("Invalid deflate block type {block_type}"),
+        };
+
+        loop {
+            match reader.next_item()? {
+                PuffItem::Literals(bytes) => {
+                    for &byte in bytes {
+                        lit_len.encode(writer, u16::from(byte))?;
+                    }
+                }
+                PuffItem::LenDist(length, dist) => {
+                    let index = base_index(&LENGTH_BASES, length);
+                    lit_len.encode(writer, index as u16 + 257)?;
+                    writer.write_bits(
+                        u32::from(LENGTH_EXTRA_BITS[index]),
+                        u32::from(length - LENGTH_BASES[index]),
+                    );
+                    let index = base_index(&DISTANCE_BASES, dist);
+                    distance.encode(writer, index as u16)?;
+                    writer.write_bits(
+                        u32::from(DISTANCE_EXTRA_BITS[index]),
+                        u32::from(dist - DISTANCE_BASES[index]),
+                    );
+                }
+                PuffItem::EndOfBlock => {
+                    lit_len.encode(writer, 256)?;
+                    break;
+                }
+                PuffItem::Metadata(_) => bail!("Unexpected block metadata inside a puff block"),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,234 @@
+//! Re-signing payloads with another key.
+//!
+//! The signatures of the payload are replaced rather than added to: the re-signed payload keeps
+//! the header, manifest and operation data, with one metadata signature and one payload
+//! signature by the new key. A signature is as long as the key modulus, so the size of both
+//! signature blobs is known before signing and the manifest can point at them up front.
+
+use std::{
+    io::{SeekFrom, Write},
+    path::Path,
+};
+
+use prost::Message;
+use rsa::{
+    Pkcs1v15Sign, RsaPrivateKey, pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey,
+    traits::PublicKeyParts,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ReadSeek,
+    error::{Error, Result},
+    payload::{BRILLO_MAJOR_VERSION, PAYLOAD_MAGIC, Payload},
+    proto::{Signatures, signatures::Signature},
+    signature::{HASH_BUFFER_SIZE, PublicKey},
+};
+
+/// RSA private key to sign payloads with.
+pub struct SigningKey {
+    /// Where the key comes from, such as its file name.
+    name: String,
+    key: RsaPrivateKey,
+}
+
+impl SigningKey {
+    /// Read a key named `name` from `data`: a PEM or DER `PRIVATE KEY` or `RSA PRIVATE KEY`,
+    /// not encrypted.
+    pub fn from_bytes(name: impl Into<String>, data: &[u8]) -> Result<Self> {
+        let name = name.into();
+        let key = std::str::from_utf8(data).map_or_else(
+            |_| {
+                RsaPrivateKey::from_pkcs8_der(data)
+                    .or_else(|_| RsaPrivateKey::from_pkcs1_der(data))
+                    .ok()
+            },
+            |pem| {
+                RsaPrivateKey::from_pkcs8_pem(pem)
+                    .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+                    .ok()
+            },
+        );
+        let key = key.ok_or_else(|| Error::InvalidPrivateKey { name: name.clone() })?;
+        Ok(Self { name, key })
+    }
+
+    /// Read the key at `path`, named after it.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_bytes(path.display().to_string(), &std::fs::read(path)?)
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The public half of the key, to verify its signatures with.
+    #[must_use]
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_rsa(self.name.clone(), self.key.to_public_key())
+    }
+
+    /// Serialized [`Signatures`] holding the signature of `digest`, a SHA-256 hash, by this
+    /// key.
+    pub fn sign(&self, digest: &[u8]) -> Result<Vec<u8>> {
+        let data = self
+            .key
+            .sign(Pkcs1v15Sign::new::<Sha256>(), digest)
+            .map_err(|source| Error::Sign {
+                name: self.name.clone(),
+                source,
+            })?;
+        Ok(self.signatures(data).encode_to_vec())
+    }
+
+    /// Serialized [`Signatures`] as long as those [`SigningKey::sign`] returns.
+    fn placeholder(&self) -> Vec<u8> {
+        self.signatures(vec![0; self.key.size()]).encode_to_vec()
+    }
+
+    fn signatures(&self, data: Vec<u8>) -> Signatures {
+        Signatures {
+            signatures: vec![Signature {
+                unpadded_signature_size: Some(self.key.size() as u32),
+                data: Some(data),
+                ..Default::default()
+            }],
+        }
+    }
+}
+
+/// Sizes and hashes of a re-signed payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResignedPayload {
+    pub size: u64,
+    /// SHA-256 of the whole payload.
+    pub hash: [u8; 32],
+    /// Size of the header and manifest.
+    pub metadata_size: u64,
+    /// SHA-256 of the header and manifest, which the metadata signature is over.
+    pub metadata_hash: [u8; 32],
+}
+
+/// Write `payload` to `out` with its signatures replaced by signatures by `key`.
+///
+/// The operation data is copied as is, leaving out the old payload signatures. Only version 2
+/// payloads can be re-signed, as version 1 has no metadata signature.
+pub fn resign_payload<R: ReadSeek>(
+    payload: &mut Payload<R>,
+    key: &SigningKey,
+    out: &mut impl Write,
+) -> Result<ResignedPayload> {
+    if payload.version() != BRILLO_MAJOR_VERSION {
+        return Err(Error::UnsupportedVersion(payload.version()));
+    }
+    let data_offset = payload.data_offset();
+    let data_size = match payload.manifest().signatures_offset {
+        Some(signatures_offset) => signatures_offset,
+        None => payload.reader_mut().seek(SeekFrom::End(0))? - data_offset,
+    };
+    let placeholder = key.placeholder();
+    let mut manifest = payload.manifest().clone();
+    manifest.signatures_offset = Some(data_size);
+    manifest.signatures_size = Some(placeholder.len() as u64);
+    let manifest = manifest.encode_to_vec();
+
+    let mut metadata = PAYLOAD_MAGIC.to_vec();
+    metadata.extend_from_slice(&payload.version().to_be_bytes());
+    metadata.extend_from_slice(&(manifest.len() as u64).to_be_bytes());
+    metadata.extend_from_slice(&(placeholder.len() as u32).to_be_bytes());
+    metadata.extend_from_slice(&manifest);
+    let metadata_hash: [u8; 32] = Sha256::digest(&metadata).into();
+    let metadata_signature = key.sign(&metadata_hash)?;
+    debug_assert_eq!(metadata_signature.len(), placeholder.len());
+
+    let mut hasher = Sha256::new();
+    for chunk in [&metadata, &metadata_signature] {
+        out.write_all(chunk)?;
+        hasher.update(chunk);
+    }
+    let reader = payload.reader_mut();
+    reader.seek(SeekFrom::Start(data_offset))?;
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE.min(data_size as usize)];
+    let mut remaining = data_size;
+    while remaining > 0 {
+        let chunk = &mut buffer[..HASH_BUFFER_SIZE.min(remaining as usize)];
+        reader.read_exact(chunk)?;
+        out.write_all(chunk)?;
+        hasher.update(&*chunk);
+        remaining -= chunk.len() as u64;
+    }
+    let payload_signature = key.sign(&hasher.clone().finalize())?;
+    debug_assert_eq!(payload_signature.len(), placeholder.len());
+    out.write_all(&payload_signature)?;
+    hasher.update(&payload_signature);
+    out.flush()?;
+
+    let metadata_size = metadata.len() as u64;
+    Ok(ResignedPayload {
+        size: metadata_size + 2 * placeholder.len() as u64 + data_size,
+        hash: hasher.finalize().into(),
+        metadata_size,
+        metadata_hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+
+    use super::*;
+    use crate::{
+        payload::tests::build_payload,
+        proto::DeltaArchiveManifest,
+        signature::{
+            SignatureCheck,
+            tests::{signed_payload, test_key},
+            verify_payload_signatures,
+        },
+    };
+
+    #[test]
+    fn test_resign_payload() {
+        let pem = test_key().to_pkcs8_pem(LineEnding::LF).unwrap();
+        let key = SigningKey::from_bytes("new.pem", pem.as_bytes()).unwrap();
+        assert!(SigningKey::from_bytes("junk", b"junk").is_err());
+
+        let unsigned = build_payload(
+            &DeltaArchiveManifest {
+                block_size: Some(4096),
+                ..Default::default()
+            },
+            &[],
+            b"data",
+        );
+        for original in [signed_payload(&test_key(), b"data"), unsigned] {
+            let mut resigned = Vec::new();
+            let report = resign_payload(
+                &mut Payload::open(Cursor::new(&original)).unwrap(),
+                &key,
+                &mut resigned,
+            )
+            .unwrap();
+            assert_eq!(report.size, resigned.len() as u64);
+            assert_eq!(report.hash, <[u8; 32]>::from(Sha256::digest(&resigned)));
+
+            let mut payload = Payload::open(Cursor::new(&resigned)).unwrap();
+            assert_eq!(payload.metadata_size(), report.metadata_size);
+            assert_eq!(payload.manifest().signatures_offset, Some(4));
+            let data_offset = payload.data_offset() as usize;
+            assert_eq!(&resigned[data_offset..data_offset + 4], b"data");
+            let report = verify_payload_signatures(&mut payload, &[key.public_key()]).unwrap();
+            assert_eq!(
+                report.payload,
+                SignatureCheck::Verified {
+                    key: "new.pem".to_string(),
+                    signature: 0
+                }
+            );
+            assert!(report.is_verified());
+        }
+    }
+}
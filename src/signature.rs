@@ -0,0 +1,624 @@
+//! Verification of the signatures of a payload against trusted keys.
+//!
+//! A payload carries two sets of RSA signatures, PKCS#1 v1.5 over SHA-256:
+//! - the metadata signature, over the header and manifest, which lets a device reject a
+//!   tampered payload before reading its data;
+//! - the payload signature, stored at `signatures_offset` in the data, over everything from the
+//!   header up to that blob, metadata signature included.
+
+use std::{
+    fmt, fs,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use rsa::{
+    BigUint, Pkcs1v15Sign, RsaPublicKey, pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey,
+    traits::PublicKeyParts,
+};
+use sha2::{Digest, Sha256};
+use x509_cert::{
+    Certificate,
+    der::{Decode, DecodePem, Reader, SliceReader, asn1::OctetString, oid::ObjectIdentifier},
+    spki::AlgorithmIdentifierOwned,
+};
+
+use crate::{
+    ReadSeek,
+    error::{Error, Result},
+    payload::{Payload, decode_signatures},
+    proto::Signatures,
+};
+
+/// Size of the reads hashing the payload, large enough for remote sources.
+pub(crate) const HASH_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+pub(crate) const ID_SHA_1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.14.3.2.26");
+pub(crate) const ID_SHA_256: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const ID_SHA_384: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.2");
+const ID_SHA_512: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.3");
+
+/// RSA public key trusted to sign payloads, read from a key or a certificate.
+#[derive(Clone, Debug)]
+pub struct PublicKey {
+    /// Where the key comes from, such as its file name.
+    name: String,
+    /// Subject of the certificate the key was read from.
+    subject: Option<String>,
+    key: RsaPublicKey,
+}
+
+impl PublicKey {
+    /// Read a key named `name` from `data`: a PEM or DER X.509 certificate, `PUBLIC KEY` or
+    /// `RSA PUBLIC KEY`.
+    pub fn from_bytes(name: impl Into<String>, data: &[u8]) -> Result<Self> {
+        let name = name.into();
+        let certificate = Certificate::from_pem(data).or_else(|_| Certificate::from_der(data));
+        let (key, subject) = if let Ok(certificate) = certificate {
+            let spki = &certificate.tbs_certificate.subject_public_key_info;
+            let key = x509_cert::der::Encode::to_der(spki)
+                .ok()
+                .and_then(|der| RsaPublicKey::from_public_key_der(&der).ok());
+            (key, Some(certificate.tbs_certificate.subject.to_string()))
+        } else {
+            let key = std::str::from_utf8(data).map_or_else(
+                |_| {
+                    RsaPublicKey::from_public_key_der(data)
+                        .or_else(|_| RsaPublicKey::from_pkcs1_der(data))
+                        .ok()
+                },
+                |pem| {
+                    RsaPublicKey::from_public_key_pem(pem)
+                        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+                        .ok()
+                },
+            );
+            (key, None)
+        };
+        let key = key.ok_or_else(|| Error::InvalidKey { name: name.clone() })?;
+        Ok(Self { name, subject, key })
+    }
+
+    /// Wrap `key`, named `name`.
+    #[must_use]
+    pub fn from_rsa(name: impl Into<String>, key: RsaPublicKey) -> Self {
+        Self {
+            name: name.into(),
+            subject: None,
+            key,
+        }
+    }
+
+    /// Read the key or certificate at `path`, named after it.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_bytes(path.display().to_string(), &std::fs::read(path)?)
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Subject of the certificate the key was read from, if it was.
+    #[must_use]
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    #[must_use]
+    pub const fn key(&self) -> &RsaPublicKey {
+        &self.key
+    }
+
+    /// Whether `signature` is the signature of `digest`, a SHA-256 hash, by this key.
+    #[must_use]
+    pub fn verify(&self, digest: &[u8], signature: &[u8]) -> bool {
+        self.verify_with(Pkcs1v15Sign::new::<Sha256>(), digest, signature)
+    }
+
+    /// Whether `signature` is the signature of `digest` by this key, with the digest algorithm
+    /// of `scheme`.
+    pub(crate) fn verify_with(
+        &self,
+        scheme: Pkcs1v15Sign,
+        digest: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        self.key.verify(scheme, digest, signature).is_ok()
+    }
+
+    /// Recover the digest info `signature` was made over, if it is a PKCS#1 v1.5 signature by
+    /// this key, whatever the digest algorithm.
+    #[must_use]
+    pub fn recover_digest_info(&self, signature: &[u8]) -> Option<DigestInfo> {
+        let n = self.key.n();
+        let signature = BigUint::from_bytes_be(signature);
+        if &signature >= n {
+            return None;
+        }
+        // 0x00 0x01 0xff.. 0x00 DigestInfo, without the leading zero.
+        let encoded = signature.modpow(self.key.e(), n).to_bytes_be();
+        if encoded.len() != self.key.size() - 1 {
+            return None;
+        }
+        let encoded = encoded.strip_prefix(&[0x01])?;
+        let padding = encoded.iter().take_while(|&&byte| byte == 0xff).count();
+        if padding < 8 {
+            return None;
+        }
+        DigestInfo::from_der(encoded[padding..].strip_prefix(&[0x00])?)
+    }
+}
+
+/// Digest a signature was made over, with its algorithm.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DigestInfo {
+    pub algorithm: ObjectIdentifier,
+    pub digest: Vec<u8>,
+}
+
+impl DigestInfo {
+    fn from_der(der: &[u8]) -> Option<Self> {
+        let mut reader = SliceReader::new(der).ok()?;
+        let (algorithm, digest) = reader
+            .sequence(|reader| {
+                let algorithm = AlgorithmIdentifierOwned::decode(reader)?;
+                Ok((algorithm, OctetString::decode(reader)?))
+            })
+            .ok()?;
+        reader.finish(()).ok()?;
+        Some(Self {
+            algorithm: algorithm.oid,
+            digest: digest.into_bytes(),
+        })
+    }
+
+    /// Name of the digest algorithm, or its OID if unknown.
+    #[must_use]
+    pub fn algorithm_name(&self) -> String {
+        match self.algorithm {
+            ID_SHA_1 => "SHA-1".to_string(),
+            ID_SHA_256 => "SHA-256".to_string(),
+            ID_SHA_384 => "SHA-384".to_string(),
+            ID_SHA_512 => "SHA-512".to_string(),
+            oid => oid.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for DigestInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.algorithm_name(), hex::encode(&self.digest))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.subject {
+            Some(subject) => write!(f, "{} ({subject})", self.name),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+/// Outcome of checking one set of signatures.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SignatureCheck {
+    /// Signature `signature` of the set was made by the key named `key`.
+    Verified { key: String, signature: usize },
+    /// Signature `signature` was made by the key the signed file itself carries, and no trusted
+    /// key was given. Anyone can re-sign a file this way, so it is not [verified].
+    ///
+    /// [verified]: Self::is_verified
+    SelfSigned { signature: usize },
+    /// No signature of the set was made by any of the keys.
+    Mismatch,
+    /// The payload has no such signatures.
+    Unsigned,
+}
+
+impl SignatureCheck {
+    #[must_use]
+    pub const fn is_verified(&self) -> bool {
+        matches!(self, Self::Verified { .. })
+    }
+}
+
+impl fmt::Display for SignatureCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Verified { key, signature } => {
+                write!(f, "verified with {key} (signature {signature})")
+            }
+            Self::SelfSigned { signature } => write!(
+                f,
+                "only matches its own embedded key (signature {signature}), which is not trusted"
+            ),
+            Self::Mismatch => f.write_str("does not match any key"),
+            Self::Unsigned => f.write_str("not signed"),
+        }
+    }
+}
+
+/// Outcome of checking both signatures of a payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignatureReport {
+    pub metadata: SignatureCheck,
+    pub payload: SignatureCheck,
+}
+
+impl SignatureReport {
+    /// Whether both signatures were made by one of the keys.
+    #[must_use]
+    pub const fn is_verified(&self) -> bool {
+        self.metadata.is_verified() && self.payload.is_verified()
+    }
+}
+
+/// Check the metadata and payload signatures of `payload` against `keys`.
+///
+/// Checking the payload signature reads the whole payload.
+pub fn verify_payload_signatures<R: ReadSeek>(
+    payload: &mut Payload<R>,
+    keys: &[PublicKey],
+) -> Result<SignatureReport> {
+    let metadata = if payload.metadata_signature().is_empty() {
+        SignatureCheck::Unsigned
+    } else {
+        let digest = metadata_digest(payload)?;
+        check_signatures(&payload.metadata_signatures()?, &digest, keys)
+    };
+    let payload_check = match (payload.payload_signatures()?, payload_digest(payload)?) {
+        (Some(signatures), Some(digest)) => check_signatures(&signatures, &digest, keys),
+        _ => SignatureCheck::Unsigned,
+    };
+    Ok(SignatureReport {
+        metadata,
+        payload: payload_check,
+    })
+}
+
+/// Digest recovered from a signature, as dumped by [`dump_signatures`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecoveredDigest {
+    /// Key the digest info was recovered with.
+    pub key: String,
+    pub info: DigestInfo,
+    /// Whether the digest is that of the signed data.
+    pub matches: bool,
+}
+
+/// One signature of a set, as dumped by [`dump_signatures`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignatureSummary {
+    /// Size of the signature data, padding included.
+    pub size: usize,
+    pub unpadded_size: Option<u32>,
+    /// Where the signature data, without padding, was written.
+    pub path: Option<PathBuf>,
+    /// Digest info recovered with the first key the signature was made by.
+    pub recovered: Option<RecoveredDigest>,
+}
+
+/// The metadata or payload signatures of a payload, as dumped by [`dump_signatures`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignatureSetSummary {
+    /// `metadata` or `payload`.
+    pub name: &'static str,
+    /// SHA-256 of the signed data.
+    pub digest: [u8; 32],
+    /// Where the serialized [`Signatures`] were written.
+    pub path: Option<PathBuf>,
+    pub signatures: Vec<SignatureSummary>,
+}
+
+/// Summarize the metadata and payload signatures of `payload`, recovering their digest info
+/// with `keys`.
+///
+/// With `out_dir`, each serialized [`Signatures`] is written to `<name>_signatures.bin` and
+/// the data of each signature to `<name>_signature_<index>.sig`.
+/// Summarizing the payload signatures reads the whole payload.
+pub fn dump_signatures<R: ReadSeek>(
+    payload: &mut Payload<R>,
+    out_dir: Option<&Path>,
+    keys: &[PublicKey],
+) -> Result<Vec<SignatureSetSummary>> {
+    let mut sets = Vec::new();
+    if !payload.metadata_signature().is_empty() {
+        let raw = payload.metadata_signature().to_vec();
+        let digest = metadata_digest(payload)?;
+        sets.push(summarize_signatures(
+            "metadata", &raw, digest, out_dir, keys,
+        )?);
+    }
+    if let Some(raw) = payload.payload_signature()?
+        && let Some(digest) = payload_digest(payload)?
+    {
+        sets.push(summarize_signatures(
+            "payload", &raw, digest, out_dir, keys,
+        )?);
+    }
+    Ok(sets)
+}
+
+fn summarize_signatures(
+    name: &'static str,
+    raw: &[u8],
+    digest: [u8; 32],
+    out_dir: Option<&Path>,
+    keys: &[PublicKey],
+) -> Result<SignatureSetSummary> {
+    let signatures = decode_signatures(raw)?;
+    let path = out_dir.map(|dir| dir.join(format!("{name}_signatures.bin")));
+    if let Some(path) = &path {
+        fs::write(path, raw)?;
+    }
+    let signatures = signatures
+        .signatures
+        .iter()
+        .enumerate()
+        .map(|(index, signature)| {
+            let data = signature_data(signature);
+            let path = out_dir.map(|dir| dir.join(format!("{name}_signature_{index}.sig")));
+            if let Some(path) = &path {
+                fs::write(path, data)?;
+            }
+            let recovered = keys.iter().find_map(|key| {
+                key.recover_digest_info(data).map(|info| RecoveredDigest {
+                    key: key.to_string(),
+                    matches: info.digest == digest,
+                    info,
+                })
+            });
+            Ok(SignatureSummary {
+                size: signature.data.as_ref().map_or(0, Vec::len),
+                unpadded_size: signature.unpadded_signature_size,
+                path,
+                recovered,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(SignatureSetSummary {
+        name,
+        digest,
+        path,
+        signatures,
+    })
+}
+
+/// SHA-256 of the header and manifest of `payload`, which the metadata signature is over.
+fn metadata_digest<R: ReadSeek>(payload: &mut Payload<R>) -> Result<[u8; 32]> {
+    let (offset, size) = (payload.offset(), payload.metadata_size());
+    hash_range(payload.reader_mut(), offset, size)
+}
+
+/// SHA-256 of everything before the payload signatures, if the payload has them.
+fn payload_digest<R: ReadSeek>(payload: &mut Payload<R>) -> Result<Option<[u8; 32]>> {
+    let Some(signatures_offset) = payload.manifest().signatures_offset else {
+        return Ok(None);
+    };
+    let offset = payload.offset();
+    let signed_size = payload.data_offset() + signatures_offset - offset;
+    hash_range(payload.reader_mut(), offset, signed_size).map(Some)
+}
+
+/// Find the first of `signatures` of `digest` made by one of `keys`.
+#[must_use]
+pub fn check_signatures(
+    signatures: &Signatures,
+    digest: &[u8],
+    keys: &[PublicKey],
+) -> SignatureCheck {
+    if signatures.signatures.is_empty() {
+        return SignatureCheck::Unsigned;
+    }
+    for (index, signature) in signatures.signatures.iter().enumerate() {
+        let data = signature_data(signature);
+        if let Some(key) = keys.iter().find(|key| key.verify(digest, data)) {
+            return SignatureCheck::Verified {
+                key: key.to_string(),
+                signature: index,
+            };
+        }
+    }
+    SignatureCheck::Mismatch
+}
+
+/// Signature bytes of `signature`, without the padding some signers add to keep the size of
+/// the signature blob fixed.
+#[must_use]
+pub fn signature_data(signature: &crate::proto::signatures::Signature) -> &[u8] {
+    let data = signature.data.as_deref().unwrap_or_default();
+    signature
+        .unpadded_signature_size
+        .map_or(data, |size| &data[..(size as usize).min(data.len())])
+}
+
+/// SHA-256 of `len` bytes of `reader` from `offset`.
+pub fn hash_range(
+    reader: &mut (impl ReadSeek + ?Sized),
+    offset: u64,
+    len: u64,
+) -> Result<[u8; 32]> {
+    Ok(digest_range::<Sha256>(reader, offset, len)?.into())
+}
+
+/// Digest of `len` bytes of `reader` from `offset`, with any hash function.
+pub(crate) fn digest_range<D: Digest>(
+    reader: &mut (impl ReadSeek + ?Sized),
+    offset: u64,
+    len: u64,
+) -> Result<digest::Output<D>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE.min(len as usize)];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = &mut buffer[..HASH_BUFFER_SIZE.min(remaining as usize)];
+        reader.read_exact(chunk)?;
+        hasher.update(&*chunk);
+        remaining -= chunk.len() as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use prost::Message;
+    use rsa::{
+        RsaPrivateKey,
+        pkcs8::{EncodePublicKey, LineEnding},
+        rand_core::OsRng,
+    };
+
+    use super::*;
+    use crate::{
+        payload::tests::build_payload,
+        proto::{DeltaArchiveManifest, signatures::Signature},
+    };
+
+    pub fn test_key() -> RsaPrivateKey {
+        RsaPrivateKey::new(&mut OsRng, 1024).unwrap()
+    }
+
+    pub fn sign(key: &RsaPrivateKey, data: &[u8]) -> Signatures {
+        Signatures {
+            signatures: vec![Signature {
+                data: Some(
+                    key.sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(data))
+                        .unwrap(),
+                ),
+                ..Default::default()
+            }],
+        }
+    }
+
+    /// A payload with `data` signed by `key`.
+    pub fn signed_payload(key: &RsaPrivateKey, data: &[u8]) -> Vec<u8> {
+        // Signatures of a 1024-bit key have a fixed size, so sizes can be set up front.
+        let placeholder = sign(key, b"").encode_to_vec();
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: Some(data.len() as u64),
+            signatures_size: Some(placeholder.len() as u64),
+            ..Default::default()
+        };
+        let metadata_size = build_payload(&manifest, &[], &[]).len();
+        let metadata = &build_payload(&manifest, &placeholder, &[])[..metadata_size];
+        let metadata_signature = sign(key, metadata).encode_to_vec();
+        let unsigned = build_payload(&manifest, &metadata_signature, data);
+        build_payload(
+            &manifest,
+            &metadata_signature,
+            &[data, &sign(key, &unsigned).encode_to_vec()].concat(),
+        )
+    }
+
+    #[test]
+    fn test_verify_payload_signatures() {
+        let key = test_key();
+        let public_key = PublicKey {
+            name: "key.pem".to_string(),
+            subject: None,
+            key: key.to_public_key(),
+        };
+        let pem = public_key
+            .key
+            .to_public_key_pem(LineEnding::default())
+            .unwrap();
+        assert_eq!(
+            PublicKey::from_bytes("key.pem", pem.as_bytes())
+                .unwrap()
+                .key,
+            public_key.key
+        );
+        assert!(PublicKey::from_bytes("junk", b"junk").is_err());
+
+        let payload = signed_payload(&key, b"data");
+        let report = verify_payload_signatures(
+            &mut Payload::open(Cursor::new(&payload)).unwrap(),
+            std::slice::from_ref(&public_key),
+        )
+        .unwrap();
+        assert!(report.is_verified());
+        assert_eq!(
+            report.payload,
+            SignatureCheck::Verified {
+                key: "key.pem".to_string(),
+                signature: 0
+            }
+        );
+
+        // Tampered data only breaks the payload signature.
+        let mut tampered = payload.clone();
+        let data_offset = Payload::open(Cursor::new(&payload)).unwrap().data_offset() as usize;
+        tampered[data_offset] ^= 1;
+        let report = verify_payload_signatures(
+            &mut Payload::open(Cursor::new(&tampered)).unwrap(),
+            &[public_key],
+        )
+        .unwrap();
+        assert!(report.metadata.is_verified());
+        assert_eq!(report.payload, SignatureCheck::Mismatch);
+
+        let other_key = PublicKey {
+            name: "other.pem".to_string(),
+            subject: None,
+            key: test_key().to_public_key(),
+        };
+        let report = verify_payload_signatures(
+            &mut Payload::open(Cursor::new(&payload)).unwrap(),
+            &[other_key],
+        )
+        .unwrap();
+        assert_eq!(report.metadata, SignatureCheck::Mismatch);
+    }
+
+    #[test]
+    fn test_dump_signatures() {
+        let key = test_key();
+        let public_key = PublicKey {
+            name: "key.pem".to_string(),
+            subject: None,
+            key: key.to_public_key(),
+        };
+        let payload = signed_payload(&key, b"data");
+        let dir = std::env::temp_dir().join(format!("signatures_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut payload = Payload::open(Cursor::new(&payload)).unwrap();
+        let sets =
+            dump_signatures(&mut payload, Some(&dir), std::slice::from_ref(&public_key)).unwrap();
+        assert_eq!(
+            sets.iter().map(|set| set.name).collect::<Vec<_>>(),
+            ["metadata", "payload"]
+        );
+        for set in &sets {
+            let [signature] = &set.signatures[..] else {
+                panic!("expected one {} signature", set.name);
+            };
+            let recovered = signature.recovered.as_ref().unwrap();
+            assert!(recovered.matches);
+            assert_eq!(recovered.info.algorithm_name(), "SHA-256");
+            assert_eq!(recovered.info.digest, set.digest);
+            assert_eq!(
+                fs::read(signature.path.as_ref().unwrap()).unwrap().len(),
+                signature.size
+            );
+            decode_signatures(&fs::read(set.path.as_ref().unwrap()).unwrap()).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let other_key = PublicKey {
+            name: "other.pem".to_string(),
+            subject: None,
+            key: test_key().to_public_key(),
+        };
+        let sets = dump_signatures(&mut payload, None, &[other_key]).unwrap();
+        assert!(sets[0].signatures[0].recovered.is_none());
+        assert!(sets[0].signatures[0].path.is_none());
+    }
+}
@@ -0,0 +1,450 @@
+//! Building blocks for streaming operation data from the payload into the output image with
+//! bounded buffers.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use sha2::{Digest, Sha256};
+use xz4rust::{XzDecoder, XzNextBlockResult};
+
+use crate::proto::Extent;
+
+/// Size of the buffers used while streaming.
+pub const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Reader computing the SHA-256 of everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Consume the rest of the input and return its hash.
+    pub fn finish(mut self) -> io::Result<[u8; 32]> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(self.hasher.finalize().into())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Writer scattering a stream across `extents`, in order.
+pub struct ExtentWriter<'a, W> {
+    out: &'a mut W,
+    extents: &'a [Extent],
+    block_size: u64,
+    /// Index of the current extent.
+    index: usize,
+    /// Bytes already written into the current extent.
+    offset: u64,
+    written: u64,
+}
+
+impl<'a, W: Write + Seek> ExtentWriter<'a, W> {
+    pub const fn new(out: &'a mut W, extents: &'a [Extent], block_size: u64) -> Self {
+        Self {
+            out,
+            extents,
+            block_size,
+            index: 0,
+            offset: 0,
+            written: 0,
+        }
+    }
+
+    /// Total bytes written so far.
+    #[must_use]
+    pub const fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Zero-fill the rest of the current block.
+    pub fn pad_block(&mut self) -> io::Result<()> {
+        let padding = (self.block_size - self.written % self.block_size) % self.block_size;
+        io::copy(&mut io::repeat(0).take(padding), self)?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for ExtentWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let extent = loop {
+            let Some(extent) = self.extents.get(self.index) else {
+                return Err(io::Error::other(
+                    "Operation output exceeds its destination extents",
+                ));
+            };
+            if self.offset < extent.num_blocks.unwrap_or(0) * self.block_size {
+                break extent;
+            }
+            self.index += 1;
+            self.offset = 0;
+        };
+        if self.offset == 0 {
+            self.out.seek(SeekFrom::Start(
+                extent.start_block.unwrap_or(0) * self.block_size,
+            ))?;
+        }
+        let available = extent.num_blocks.unwrap_or(0) * self.block_size - self.offset;
+        let count = buf.len().min(available as usize);
+        self.out.write_all(&buf[..count])?;
+        self.offset += count as u64;
+        self.written += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Most bytes of out of order writes all [`OutputHasher`]s together hold on to.
+const REORDER_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Bytes of data currently held back by all [`OutputHasher`]s.
+static REORDER_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Incremental SHA-256 of an output image, fed with its writes.
+///
+/// Writes landing past the hashed position are held back until the writes before them arrive,
+/// so operations completing slightly out of order, as they do when extracted concurrently, are
+/// still hashed. Once more than [`REORDER_LIMIT`] bytes are held back across all hashers, the
+/// earliest gap of the hasher being written to is taken to be left unwritten and hashed as zeros, which is what it holds in a freshly truncated
+/// image. A write landing before the hashed position means the image was not produced in
+/// order; hashing stops, and the hash has to be computed by re-reading the image instead.
+pub struct OutputHasher {
+    hasher: Sha256,
+    position: u64,
+    in_order: bool,
+    /// Writes past `position`, by offset.
+    pending: BTreeMap<u64, Chunk>,
+    /// Bytes of data in `pending`, counted in [`REORDER_PENDING`].
+    pending_size: u64,
+}
+
+/// Write held back by an [`OutputHasher`].
+enum Chunk {
+    Data(Vec<u8>),
+    Zeros(u64),
+}
+
+impl Chunk {
+    const fn len(&self) -> u64 {
+        match self {
+            Self::Data(data) => data.len() as u64,
+            Self::Zeros(len) => *len,
+        }
+    }
+}
+
+impl OutputHasher {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            hasher: Sha256::new(),
+            position: 0,
+            in_order: true,
+            pending: BTreeMap::new(),
+            pending_size: 0,
+        }
+    }
+
+    /// A hasher that tracks nothing, for images not starting out zeroed.
+    #[must_use]
+    pub fn disabled() -> Self {
+        let mut hasher = Self::new();
+        hasher.in_order = false;
+        hasher
+    }
+
+    /// Record `data` written at `offset`.
+    pub fn update(&mut self, offset: u64, data: &[u8]) {
+        if !self.in_order || data.is_empty() {
+            return;
+        }
+        if offset == self.position && self.pending.is_empty() {
+            self.hasher.update(data);
+            self.position += data.len() as u64;
+            return;
+        }
+        // Consecutive writes of one operation extend the chunk they follow.
+        if let Some((&start, Chunk::Data(chunk))) = self.pending.iter_mut().next_back()
+            && start + chunk.len() as u64 == offset
+        {
+            chunk.extend_from_slice(data);
+        } else {
+            self.hold(offset, Chunk::Data(data.to_vec()));
+        }
+        self.pending_size += data.len() as u64;
+        REORDER_PENDING.fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.drain();
+    }
+
+    /// Record `len` zero bytes written at `offset`.
+    pub fn update_zeros(&mut self, offset: u64, len: u64) {
+        if !self.in_order || len == 0 {
+            return;
+        }
+        self.hold(offset, Chunk::Zeros(len));
+        self.drain();
+    }
+
+    fn hold(&mut self, offset: u64, chunk: Chunk) {
+        if self.pending.insert(offset, chunk).is_some() {
+            self.stop();
+        }
+    }
+
+    /// Hash the held back writes that follow on from the hashed position, and those past the
+    /// earliest gaps while too much is held back.
+    fn drain(&mut self) {
+        while self.in_order
+            && let Some(entry) = self.pending.first_entry()
+        {
+            let offset = *entry.key();
+            if offset > self.position && REORDER_PENDING.load(Ordering::Relaxed) <= REORDER_LIMIT {
+                break;
+            }
+            let chunk = entry.remove();
+            self.hash_chunk(offset, &chunk);
+        }
+    }
+
+    /// Hash `chunk` written at `offset`, with zeros for any gap before it.
+    fn hash_chunk(&mut self, offset: u64, chunk: &Chunk) {
+        if offset < self.position {
+            self.stop();
+            return;
+        }
+        hash_zeros(&mut self.hasher, offset - self.position);
+        match chunk {
+            Chunk::Data(data) => {
+                self.hasher.update(data);
+                self.release(data.len() as u64);
+            }
+            Chunk::Zeros(len) => hash_zeros(&mut self.hasher, *len),
+        }
+        self.position = offset + chunk.len();
+    }
+
+    /// Give up on hashing as the image is written.
+    fn stop(&mut self) {
+        self.in_order = false;
+        self.pending.clear();
+        self.release(self.pending_size);
+    }
+
+    /// Take `len` bytes of hashed or dropped data out of the held back count.
+    fn release(&mut self, len: u64) {
+        self.pending_size -= len;
+        REORDER_PENDING.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Hash of the image once it is `size` bytes long, or `None` if it was not written in order.
+    #[must_use]
+    pub fn finish(mut self, size: u64) -> Option<[u8; 32]> {
+        for (offset, chunk) in std::mem::take(&mut self.pending) {
+            if !self.in_order {
+                break;
+            }
+            self.hash_chunk(offset, &chunk);
+        }
+        if !self.in_order || self.position > size {
+            return None;
+        }
+        hash_zeros(&mut self.hasher, size - self.position);
+        Some(std::mem::take(&mut self.hasher).finalize().into())
+    }
+}
+
+impl Default for OutputHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for OutputHasher {
+    fn drop(&mut self) {
+        // Hashers of failed partitions are dropped with writes still held back.
+        self.release(self.pending_size);
+    }
+}
+
+fn hash_zeros(hasher: &mut Sha256, len: u64) {
+    // Hashing into a `Sha256` cannot fail.
+    let _ = io::copy(&mut io::repeat(0).take(len), hasher);
+}
+
+/// Output feeding everything written through it into a shared [`OutputHasher`].
+pub struct HashingWriter<'a, W> {
+    inner: W,
+    hasher: &'a Mutex<OutputHasher>,
+    position: u64,
+}
+
+impl<'a, W: Seek> HashingWriter<'a, W> {
+    pub fn new(mut inner: W, hasher: &'a Mutex<OutputHasher>) -> io::Result<Self> {
+        let position = inner.stream_position()?;
+        Ok(Self {
+            inner,
+            hasher,
+            position,
+        })
+    }
+
+    /// Record `len` zero bytes written at `offset` by other means, such as punching a hole.
+    pub fn record_zeros(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.lock().update_zeros(offset, len);
+        self.position = self.inner.stream_position()?;
+        Ok(())
+    }
+
+    #[must_use]
+    pub const fn inner_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, OutputHasher> {
+        self.hasher.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<W: Read> Read for HashingWriter<'_, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<W: Write + Seek> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.lock().update(self.position, &buf[..count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for HashingWriter<'_, W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+/// Decode an XZ stream from `reader` into `writer`.
+pub fn copy_decode_xz(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<()> {
+    let mut decoder =
+        XzDecoder::in_heap_with_alloc_dict_size(xz4rust::DICT_SIZE_MIN, xz4rust::DICT_SIZE_MAX);
+    let mut input = vec![0u8; STREAM_BUFFER_SIZE];
+    let mut output = vec![0u8; STREAM_BUFFER_SIZE];
+    let (mut start, mut end) = (0, 0);
+
+    loop {
+        if start == end {
+            end = reader.read(&mut input)?;
+            start = 0;
+            if end == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        match decoder.decode(&input[start..end], &mut output) {
+            Ok(XzNextBlockResult::NeedMoreData(consumed, produced)) => {
+                start += consumed;
+                writer.write_all(&output[..produced])?;
+            }
+            Ok(XzNextBlockResult::EndOfStream(_, produced)) => {
+                return writer.write_all(&output[..produced]);
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_extent_writer() {
+        let extents = [
+            Extent {
+                start_block: Some(2),
+                num_blocks: Some(1),
+            },
+            Extent {
+                start_block: Some(0),
+                num_blocks: Some(2),
+            },
+        ];
+        let mut out = Cursor::new(vec![b'.'; 12]);
+        let mut writer = ExtentWriter::new(&mut out, &extents, 4);
+        // Writes straddle extent boundaries.
+        for chunk in [&b"abc"[..], b"def", b"g"] {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.pad_block().unwrap();
+        assert_eq!(writer.written(), 8);
+        writer.write_all(b"ijkl").unwrap();
+        assert!(writer.write_all(b"m").is_err());
+        assert_eq!(out.into_inner(), b"efg\0ijklabcd");
+    }
+
+    #[test]
+    fn test_output_hasher() {
+        let image = b"\0\0abcd\0\0\0\0efgh\0\0";
+        let mut hasher = OutputHasher::new();
+        hasher.update(2, b"ab");
+        hasher.update(4, b"cd");
+        hasher.update_zeros(6, 4);
+        hasher.update(10, b"efgh");
+        assert_eq!(hasher.finish(16), Some(Sha256::digest(image).into()));
+
+        // Out of order writes are held back until the gaps before them are written.
+        let mut hasher = OutputHasher::new();
+        hasher.update(10, b"ef");
+        hasher.update(12, b"gh");
+        hasher.update(2, b"abcd");
+        hasher.update_zeros(6, 4);
+        assert_eq!(hasher.pending_size, 8);
+        hasher.update_zeros(0, 2);
+        assert_eq!(hasher.pending_size, 0);
+        assert_eq!(hasher.finish(16), Some(Sha256::digest(image).into()));
+
+        // Overwriting hashed data loses track of the image.
+        let mut hasher = OutputHasher::new();
+        hasher.update(2, b"abcd");
+        hasher.update(10, b"efgh");
+        hasher.update(0, b"\0\0ab");
+        assert_eq!(hasher.finish(16), None);
+    }
+}
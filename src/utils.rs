@@ -1,11 +1,8 @@
-use std::{io::SeekFrom, time::Duration};
-
-use anyhow::{Result, bail};
-use byteorder::{BigEndian, ReadBytesExt};
-use prost::Message;
+use std::{io::Write, time::Duration};
 
 use crate::{
-    ReadSeek,
+    error::Result,
+    payload::Payload,
     proto::{DeltaArchiveManifest, install_operation},
 };
 
@@ -15,9 +12,15 @@ pub fn is_differential_ota(manifest: &DeltaArchiveManifest) -> bool {
         partition.operations.iter().any(|op| {
             matches!(
                 op.r#type(),
-                install_operation::Type::SourceCopy
+                install_operation::Type::Move
+                    | install_operation::Type::Bsdiff
+                    | install_operation::Type::SourceCopy
                     | install_operation::Type::SourceBsdiff
                     | install_operation::Type::BrotliBsdiff
+                    | install_operation::Type::Puffdiff
+                    | install_operation::Type::Zucchini
+                    | install_operation::Type::Lz4diffBsdiff
+                    | install_operation::Type::Lz4diffPuffdiff
             )
         })
     })
@@ -57,53 +60,19 @@ pub fn format_size(size: u64) -> String {
     }
 }
 
-pub fn list_partitions(payload_reader: &mut Box<dyn ReadSeek>) -> Result<()> {
-    let mut magic = [0u8; 4];
-    payload_reader.read_exact(&mut magic)?;
-    if magic != *b"CrAU" {
-        payload_reader.seek(SeekFrom::Start(0))?;
-        let mut buffer = [0u8; 1024];
-        let mut offset = 0;
-        while offset < 1024 * 1024 {
-            let bytes_read = payload_reader.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
-            }
-            for i in 0..bytes_read - 3 {
-                if buffer[i] == b'C'
-                    && buffer[i + 1] == b'r'
-                    && buffer[i + 2] == b'A'
-                    && buffer[i + 3] == b'U'
-                {
-                    payload_reader.seek(SeekFrom::Start(offset + i as u64))?;
-                    return list_partitions(payload_reader);
-                }
-            }
-            offset += bytes_read as u64;
-        }
-        bail!("Invalid payload file: magic 'CrAU' not found");
-    }
-
-    let file_format_version = payload_reader.read_u64::<BigEndian>()?;
-    if file_format_version != 2 {
-        bail!("Unsupported payload version: {file_format_version}");
-    }
-    let manifest_size = payload_reader.read_u64::<BigEndian>()?;
-    let _metadata_signature_size = payload_reader.read_u32::<BigEndian>()?;
-
-    let mut manifest = vec![0u8; manifest_size as usize];
-    payload_reader.read_exact(&mut manifest)?;
-    let manifest = DeltaArchiveManifest::decode(&manifest[..])?;
-
-    println!("{:<20} {:<15}", "Partition Name", "Size");
-    println!("{}", "-".repeat(35));
+/// Write a table of the partitions in the payload to `out`.
+pub fn list_partitions<R>(payload: &Payload<R>, out: &mut impl Write) -> Result<()> {
+    let manifest = payload.manifest();
+    writeln!(out, "{:<20} {:<15}", "Partition Name", "Size")?;
+    writeln!(out, "{}", "-".repeat(35))?;
     for partition in &manifest.partitions {
         let size = partition
             .new_partition_info
             .as_ref()
             .and_then(|info| info.size)
             .unwrap_or(0);
-        println!(
+        writeln!(
+            out,
             "{:<20} {:<15}",
             partition.partition_name,
             if size > 0 {
@@ -111,7 +80,7 @@ pub fn list_partitions(payload_reader: &mut Box<dyn ReadSeek>) -> Result<()> {
             } else {
                 "Unknown".to_string()
             }
-        );
+        )?;
     }
     Ok(())
 }
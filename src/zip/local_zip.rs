@@ -1,10 +1,17 @@
 use std::{
     collections::HashMap,
-    io::{Error, ErrorKind, Read, Result as IoResult, Seek, SeekFrom},
+    io::{self, Read, Seek, SeekFrom},
     path::Path,
 };
 
-use crate::zip::zip_core::{ZipEntry, ZipParser};
+use crate::{
+    error::{Error, Result},
+    signature::PublicKey,
+    zip::{
+        signature::{ZipSignatureReport, verify_zip_signature},
+        zip_core::{ZipEntry, ZipParser},
+    },
+};
 
 pub struct ZipDecoder<R: Read + Seek> {
     reader: R,
@@ -21,7 +28,7 @@ pub struct ZipPayloadReader<R: Read + Seek> {
 pub type FileZipPayloadReader = ZipPayloadReader<std::fs::File>;
 
 impl<R: Read + Seek> ZipPayloadReader<R> {
-    pub fn new(reader: R) -> IoResult<Self> {
+    pub fn new(reader: R) -> Result<Self> {
         let decoder = ZipDecoder::new(reader)?;
         Ok(Self {
             decoder,
@@ -30,7 +37,7 @@ impl<R: Read + Seek> ZipPayloadReader<R> {
         })
     }
 
-    pub fn load_payload_entry(&mut self) -> IoResult<()> {
+    pub fn load_payload_entry(&mut self) -> Result<()> {
         if let Some(entry) = self.decoder.get_entry("payload.bin") {
             let mut entry = entry.clone();
             let data_offset = self.decoder.get_data_offset(&entry)?;
@@ -40,33 +47,30 @@ impl<R: Read + Seek> ZipPayloadReader<R> {
             self.current_position = 0;
             Ok(())
         } else {
-            Err(Error::new(
-                ErrorKind::NotFound,
-                "payload.bin not found in the zip",
-            ))
+            Err(Error::PayloadNotFound)
         }
     }
 }
 
 impl FileZipPayloadReader {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let file = std::fs::File::open(path)?;
         let mut reader = Self::new(file)?;
         reader.load_payload_entry()?;
         Ok(reader)
     }
 
-    pub fn new_for_parallel<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+    pub fn new_for_parallel<P: AsRef<Path>>(path: P) -> Result<Self> {
         Self::from_file(path)
     }
 }
 
 impl<R: Read + Seek> Read for ZipPayloadReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let entry = if let Some(entry) = &self.current_entry {
             entry
         } else {
-            self.load_payload_entry()?;
+            self.load_payload_entry().map_err(io::Error::from)?;
             self.current_entry.as_ref().unwrap()
         };
 
@@ -88,11 +92,11 @@ impl<R: Read + Seek> Read for ZipPayloadReader<R> {
 }
 
 impl<R: Read + Seek> Seek for ZipPayloadReader<R> {
-    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         let entry = if let Some(entry) = &self.current_entry {
             entry
         } else {
-            self.load_payload_entry()?;
+            self.load_payload_entry().map_err(io::Error::from)?;
             self.current_entry.as_ref().unwrap()
         };
 
@@ -115,8 +119,8 @@ impl<R: Read + Seek> Seek for ZipPayloadReader<R> {
         };
 
         if new_position > entry.uncompressed_size {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
                 "Seek beyond end of data",
             ));
         }
@@ -127,7 +131,7 @@ impl<R: Read + Seek> Seek for ZipPayloadReader<R> {
 }
 
 impl<R: Read + Seek> ZipDecoder<R> {
-    pub fn new(mut reader: R) -> IoResult<Self> {
+    pub fn new(mut reader: R) -> Result<Self> {
         let entries = Self::read_central_directory(&mut reader)?;
         Ok(Self { reader, entries })
     }
@@ -141,21 +145,32 @@ impl<R: Read + Seek> ZipDecoder<R> {
     }
 
     // Get the actual data offset for an entry (after local header)
-    pub fn get_data_offset(&mut self, entry: &ZipEntry) -> IoResult<u64> {
+    pub fn get_data_offset(&mut self, entry: &ZipEntry) -> Result<u64> {
         ZipParser::get_data_offset(&mut self.reader, entry)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
     }
 
-    fn read_central_directory(reader: &mut R) -> IoResult<HashMap<String, ZipEntry>> {
-        let (cd_offset, num_entries) = ZipParser::get_central_directory_info(reader)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    /// Read the contents of the entry `name`, if there is one.
+    pub fn read_entry(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.entries
+            .get(name)
+            .map(|entry| ZipParser::read_entry_data(&mut self.reader, entry))
+            .transpose()
+    }
+
+    /// Check the whole-file signature of the zip against `keys`, or its otacert if empty, which
+    /// is never trusted.
+    pub fn verify_signature(&mut self, keys: &[PublicKey]) -> Result<ZipSignatureReport> {
+        verify_zip_signature(&mut self.reader, keys)
+    }
+
+    fn read_central_directory(reader: &mut R) -> Result<HashMap<String, ZipEntry>> {
+        let (cd_offset, num_entries) = ZipParser::get_central_directory_info(reader)?;
 
         reader.seek(SeekFrom::Start(cd_offset))?;
         let mut entries = HashMap::new();
 
         for _ in 0..num_entries {
-            let entry = ZipParser::read_central_directory_entry(reader)
-                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            let entry = ZipParser::read_central_directory_entry(reader)?;
             entries.insert(entry.name.clone(), entry);
         }
 
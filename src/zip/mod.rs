@@ -1,3 +1,4 @@
 pub mod local_zip;
 pub mod remote_zip;
+pub mod signature;
 pub mod zip_core;
@@ -1,8 +1,15 @@
 use std::io::{self, Read, Seek, SeekFrom};
 
-use anyhow::{Result, anyhow};
-
-use crate::{http::HttpReader, zip::zip_core::ZipParser};
+use crate::{
+    cancel::CancellationToken,
+    error::{Error, Result},
+    http::HttpReader,
+    signature::PublicKey,
+    zip::{
+        signature::{ZipSignatureReport, verify_zip_signature},
+        zip_core::ZipParser,
+    },
+};
 
 pub struct RemoteZipReader {
     pub http_reader: HttpReader,
@@ -22,35 +29,34 @@ impl RemoteZipReader {
 
         let search_pattern = b"payload.bin";
         for i in 0..tail_buffer.len().saturating_sub(search_pattern.len()) {
-            if tail_buffer[i..].starts_with(search_pattern) {
-                if let Some(colon_pos) = tail_buffer[i..].iter().position(|&b| b == b':') {
-                    let start = i + colon_pos + 1;
-                    if let Some(end) = tail_buffer[start..]
-                        .iter()
-                        .position(|&b| b == b',' || b == b'\n' || b == b'\r')
+            if tail_buffer[i..].starts_with(search_pattern)
+                && let Some(colon_pos) = tail_buffer[i..].iter().position(|&b| b == b':')
+            {
+                let start = i + colon_pos + 1;
+                if let Some(end) = tail_buffer[start..]
+                    .iter()
+                    .position(|&b| b == b',' || b == b'\n' || b == b'\r')
+                {
+                    let metadata_str = std::str::from_utf8(&tail_buffer[start..start + end])
+                        .map_err(|_| Error::invalid_zip("Invalid UTF-8 in metadata"))?;
+                    let parts: Vec<&str> = metadata_str.split(':').collect();
+
+                    if parts.len() >= 2
+                        && let (Ok(offset), Ok(size)) =
+                            (parts[0].parse::<u64>(), parts[1].parse::<u64>())
                     {
-                        let metadata_str = std::str::from_utf8(&tail_buffer[start..start + end])
-                            .map_err(|_| anyhow!("Invalid UTF-8 in metadata"))?;
-                        let parts: Vec<&str> = metadata_str.split(':').collect();
-
-                        if parts.len() >= 2 {
-                            if let (Ok(offset), Ok(size)) =
-                                (parts[0].parse::<u64>(), parts[1].parse::<u64>())
-                            {
-                                http_reader.seek(SeekFrom::Start(offset))?;
-                                let mut header = [0u8; 4];
-                                http_reader.read_exact(&mut header)?;
-
-                                if header[0..2] == [0x50, 0x4B] {
-                                    return Ok((offset, size));
-                                }
-                            }
+                        http_reader.seek(SeekFrom::Start(offset))?;
+                        let mut header = [0u8; 4];
+                        http_reader.read_exact(&mut header)?;
+
+                        if header[0..2] == [0x50, 0x4B] {
+                            return Ok((offset, size));
                         }
                     }
                 }
             }
         }
-        Err(anyhow!("Could not find payload.bin metadata"))
+        Err(Error::PayloadNotFound)
     }
 
     fn find_payload_via_zip_structure(mut http_reader: HttpReader) -> Result<Self> {
@@ -75,7 +81,7 @@ impl RemoteZipReader {
     }
 
     pub fn new_for_parallel(url: String, user_agent: &str) -> Result<Self> {
-        let http_reader = HttpReader::new_silent(url, user_agent)?;
+        let http_reader = HttpReader::new(url, user_agent)?;
         if let Ok(payload_info) = Self::find_payload_via_metadata(&mut http_reader.clone()) {
             return Ok(Self {
                 http_reader,
@@ -86,6 +92,24 @@ impl RemoteZipReader {
         }
         Self::find_payload_via_zip_structure(http_reader)
     }
+
+    /// Fail reads with [`Error::Cancelled`] once `cancel` is cancelled.
+    #[must_use]
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.http_reader = self.http_reader.with_cancellation(cancel);
+        self
+    }
+
+    /// Read the contents of the entry `name` of the remote zip, if there is one.
+    pub fn read_entry(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+        ZipParser::read_entry(&mut self.http_reader, name)
+    }
+
+    /// Check the whole-file signature of the remote zip against `keys`, or its otacert if
+    /// empty, which is never trusted. This downloads the whole zip.
+    pub fn verify_signature(&mut self, keys: &[PublicKey]) -> Result<ZipSignatureReport> {
+        verify_zip_signature(&mut self.http_reader, keys)
+    }
 }
 
 impl Read for RemoteZipReader {
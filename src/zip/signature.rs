@@ -0,0 +1,431 @@
+//! Whole-file signature of OTA zips, as added by `signapk -w`.
+//!
+//! The signature is a PKCS#7 `SignedData` block at the end of the comment of the End of Central
+//! Directory record, located through the 6 bytes ending the comment: the distance of the block
+//! from the end of the file (u16), `0xffff`, and the size of the comment (u16). It covers the
+//! whole file up to the comment size field of that record.
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io::{Read, Seek, SeekFrom},
+};
+
+use cms::{
+    cert::CertificateChoices,
+    content_info::ContentInfo,
+    signed_data::{SignedData, SignerInfo},
+};
+use rsa::Pkcs1v15Sign;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use x509_cert::{
+    Certificate,
+    der::{Decode, Encode, asn1::OctetString, oid::ObjectIdentifier},
+};
+
+use crate::{
+    error::{Error, Result},
+    signature::{ID_SHA_1, ID_SHA_256, PublicKey, SignatureCheck, digest_range},
+    zip::zip_core::{EOCD_SIGNATURE, ZipParser},
+};
+
+/// Certificate of the key the OTA zip is expected to be signed with, in the zip itself.
+pub const OTACERT_PATH: &str = "META-INF/com/android/otacert";
+
+const FOOTER_SIZE: usize = 6;
+const EOCD_SIZE: usize = 22;
+const ID_MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+
+/// The whole-file signature of a zip.
+#[derive(Clone, Debug)]
+pub struct ZipSignature {
+    /// Number of leading bytes of the file that are signed.
+    signed_len: u64,
+    signed_data: SignedData,
+}
+
+impl ZipSignature {
+    /// Read the whole-file signature of the zip in `reader`, if it has one.
+    pub fn read(reader: &mut (impl Read + Seek)) -> Result<Option<Self>> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        if file_len < EOCD_SIZE as u64 {
+            return Err(Error::invalid_zip("File too small for a ZIP"));
+        }
+        let mut footer = [0u8; FOOTER_SIZE];
+        reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        reader.read_exact(&mut footer)?;
+        if footer[2..4] != [0xff, 0xff] {
+            return Ok(None);
+        }
+        let signature_start = usize::from(u16::from_le_bytes([footer[0], footer[1]]));
+        let comment_len = usize::from(u16::from_le_bytes([footer[4], footer[5]]));
+
+        let eocd_len = comment_len + EOCD_SIZE;
+        if eocd_len as u64 > file_len {
+            return Err(Error::invalid_zip(
+                "Signature footer past the start of the file",
+            ));
+        }
+        let mut eocd = vec![0u8; eocd_len];
+        reader.seek(SeekFrom::End(-(eocd_len as i64)))?;
+        reader.read_exact(&mut eocd)?;
+        if eocd[..4] != EOCD_SIGNATURE
+            || usize::from(u16::from_le_bytes([eocd[20], eocd[21]])) != comment_len
+        {
+            return Err(Error::invalid_zip(
+                "Signature footer does not match the End of Central Directory record",
+            ));
+        }
+        // A second record in the comment could make other tools read a different archive than
+        // the one signed.
+        if eocd[4..].windows(4).any(|window| window == EOCD_SIGNATURE) {
+            return Err(Error::invalid_zip(
+                "End of Central Directory signature found in the signed comment",
+            ));
+        }
+        if signature_start < FOOTER_SIZE || signature_start > comment_len {
+            return Err(Error::invalid_zip("Invalid signature block offset"));
+        }
+
+        let block = &eocd[eocd_len - signature_start..eocd_len - FOOTER_SIZE];
+        let signed_data = ContentInfo::from_der(block)
+            .and_then(|content| content.content.decode_as::<SignedData>())
+            .map_err(|e| Error::invalid_zip(format!("Invalid signature block: {e}")))?;
+        Ok(Some(Self {
+            signed_len: file_len - comment_len as u64 - 2,
+            signed_data,
+        }))
+    }
+
+    /// Number of leading bytes of the file covered by the signature.
+    #[must_use]
+    pub const fn signed_len(&self) -> u64 {
+        self.signed_len
+    }
+
+    #[must_use]
+    pub const fn signed_data(&self) -> &SignedData {
+        &self.signed_data
+    }
+
+    /// Certificates included in the signature block, usually that of the signer.
+    pub fn certificates(&self) -> impl Iterator<Item = &Certificate> {
+        self.signed_data
+            .certificates
+            .iter()
+            .flat_map(|certificates| certificates.0.iter())
+            .filter_map(|choice| match choice {
+                CertificateChoices::Certificate(certificate) => Some(certificate),
+                CertificateChoices::Other(_) => None,
+            })
+    }
+
+    /// Check the signature of the zip in `reader` against `keys`, reading the whole file.
+    pub fn verify(
+        &self,
+        reader: &mut (impl Read + Seek),
+        keys: &[PublicKey],
+    ) -> Result<SignatureCheck> {
+        let signers = &self.signed_data.signer_infos.0;
+        if signers.is_empty() {
+            return Ok(SignatureCheck::Unsigned);
+        }
+        let mut digests = HashMap::new();
+        for (index, signer) in signers.iter().enumerate() {
+            let Some(algorithm) = DigestAlgorithm::from_oid(signer.digest_alg.oid) else {
+                continue;
+            };
+            let digest = match digests.entry(algorithm) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(algorithm.digest_range(reader, self.signed_len)?)
+                }
+            };
+            let Some(signed_digest) = signed_digest(signer, algorithm, digest) else {
+                continue;
+            };
+            let signature = signer.signature.as_bytes();
+            if let Some(key) = keys
+                .iter()
+                .find(|key| key.verify_with(algorithm.scheme(), &signed_digest, signature))
+            {
+                return Ok(SignatureCheck::Verified {
+                    key: key.to_string(),
+                    signature: index,
+                });
+            }
+        }
+        Ok(SignatureCheck::Mismatch)
+    }
+}
+
+/// Outcome of checking the whole-file signature of an OTA zip.
+#[derive(Clone, Debug)]
+pub struct ZipSignatureReport {
+    pub check: SignatureCheck,
+    /// Subjects of the certificates included in the signature block.
+    pub signers: Vec<String>,
+    /// Contents of [`OTACERT_PATH`], if the zip has it.
+    pub otacert: Option<Vec<u8>>,
+}
+
+/// Check the whole-file signature of the OTA zip in `reader` against `keys`.
+///
+/// Without keys, it is checked against the zip's own otacert, which at best yields
+/// [`SignatureCheck::SelfSigned`]: that only shows the zip is consistent with itself, not who
+/// signed it.
+pub fn verify_zip_signature(
+    reader: &mut (impl Read + Seek),
+    keys: &[PublicKey],
+) -> Result<ZipSignatureReport> {
+    let otacert = ZipParser::read_entry(reader, OTACERT_PATH)?;
+    let Some(signature) = ZipSignature::read(reader)? else {
+        return Ok(ZipSignatureReport {
+            check: SignatureCheck::Unsigned,
+            signers: Vec::new(),
+            otacert,
+        });
+    };
+    let check = if keys.is_empty() {
+        let otacert = otacert.as_deref().ok_or(Error::NoTrustedKey)?;
+        let otacert_key = PublicKey::from_bytes(OTACERT_PATH, otacert)?;
+        match signature.verify(reader, std::slice::from_ref(&otacert_key))? {
+            SignatureCheck::Verified { signature, .. } => SignatureCheck::SelfSigned { signature },
+            check => check,
+        }
+    } else {
+        signature.verify(reader, keys)?
+    };
+    Ok(ZipSignatureReport {
+        check,
+        signers: signature
+            .certificates()
+            .map(|certificate| certificate.tbs_certificate.subject.to_string())
+            .collect(),
+        otacert,
+    })
+}
+
+/// Digest algorithms signapk signs with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum DigestAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    const fn from_oid(oid: ObjectIdentifier) -> Option<Self> {
+        match oid {
+            ID_SHA_1 => Some(Self::Sha1),
+            ID_SHA_256 => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    fn scheme(self) -> Pkcs1v15Sign {
+        match self {
+            Self::Sha1 => Pkcs1v15Sign::new::<Sha1>(),
+            Self::Sha256 => Pkcs1v15Sign::new::<Sha256>(),
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha1 => Sha1::digest(data).to_vec(),
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    fn digest_range(self, reader: &mut (impl Read + Seek), len: u64) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Sha1 => digest_range::<Sha1>(reader, 0, len)?.to_vec(),
+            Self::Sha256 => digest_range::<Sha256>(reader, 0, len)?.to_vec(),
+        })
+    }
+}
+
+/// Digest `signer` signed over `digest` of the content: the digest itself, or that of its
+/// signed attributes if they hold the content digest. `None` if they hold another one.
+fn signed_digest(
+    signer: &SignerInfo,
+    algorithm: DigestAlgorithm,
+    digest: &[u8],
+) -> Option<Vec<u8>> {
+    let Some(attributes) = &signer.signed_attrs else {
+        return Some(digest.to_vec());
+    };
+    let message_digest = attributes
+        .iter()
+        .find(|attribute| attribute.oid == ID_MESSAGE_DIGEST)?
+        .values
+        .get(0)?
+        .decode_as::<OctetString>()
+        .ok()?;
+    (message_digest.as_bytes() == digest)
+        .then(|| attributes.to_der().ok())
+        .flatten()
+        .map(|der| algorithm.digest(&der))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use cms::{
+        cert::IssuerAndSerialNumber,
+        content_info::CmsVersion,
+        signed_data::{EncapsulatedContentInfo, SignerIdentifier, SignerInfos},
+    };
+    use rsa::RsaPrivateKey;
+    use x509_cert::{
+        der::{Any, asn1::SetOfVec},
+        name::Name,
+        serial_number::SerialNumber,
+        spki::AlgorithmIdentifierOwned,
+    };
+
+    use super::*;
+    use crate::signature::tests::test_key;
+
+    const ID_SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+    const ID_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+    const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+
+    /// A detached PKCS#7 signature of `data` by `key`, without signed attributes like signapk.
+    pub fn pkcs7_sign(key: &RsaPrivateKey, data: &[u8]) -> Vec<u8> {
+        let signature = key
+            .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(data))
+            .unwrap();
+        let sha256 = AlgorithmIdentifierOwned {
+            oid: ID_SHA_256,
+            parameters: None,
+        };
+        let signed_data = SignedData {
+            version: CmsVersion::V1,
+            digest_algorithms: SetOfVec::try_from(vec![sha256.clone()]).unwrap(),
+            encap_content_info: EncapsulatedContentInfo {
+                econtent_type: ID_DATA,
+                econtent: None,
+            },
+            certificates: None,
+            crls: None,
+            signer_infos: SignerInfos(
+                SetOfVec::try_from(vec![SignerInfo {
+                    version: CmsVersion::V1,
+                    sid: SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
+                        issuer: Name::default(),
+                        serial_number: SerialNumber::new(&[1]).unwrap(),
+                    }),
+                    digest_alg: sha256,
+                    signed_attrs: None,
+                    signature_algorithm: AlgorithmIdentifierOwned {
+                        oid: RSA_ENCRYPTION,
+                        parameters: None,
+                    },
+                    signature: OctetString::new(signature).unwrap(),
+                    unsigned_attrs: None,
+                }])
+                .unwrap(),
+            ),
+        };
+        ContentInfo {
+            content_type: ID_SIGNED_DATA,
+            content: Any::encode_from(&signed_data).unwrap(),
+        }
+        .to_der()
+        .unwrap()
+    }
+
+    /// A zip holding `otacert`, signed by `key` the way signapk does.
+    pub fn signed_zip(key: &RsaPrivateKey, otacert: &[u8]) -> Vec<u8> {
+        let name = OTACERT_PATH.as_bytes();
+        let mut zip = Vec::new();
+        zip.extend_from_slice(b"PK\x03\x04");
+        zip.extend_from_slice(&[0; 22]);
+        zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&[0; 2]);
+        zip.extend_from_slice(name);
+        zip.extend_from_slice(otacert);
+        let cd_offset = zip.len() as u32;
+        zip.extend_from_slice(b"PK\x01\x02");
+        zip.extend_from_slice(&[0; 16]);
+        zip.extend_from_slice(&(otacert.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(otacert.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&[0; 16]);
+        zip.extend_from_slice(name);
+        let cd_size = zip.len() as u32 - cd_offset;
+        zip.extend_from_slice(b"PK\x05\x06");
+        zip.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+        zip.extend_from_slice(&cd_size.to_le_bytes());
+        zip.extend_from_slice(&cd_offset.to_le_bytes());
+
+        let block = pkcs7_sign(key, &zip);
+        let mut comment = b"signed by SignApk\0".to_vec();
+        comment.extend_from_slice(&block);
+        let signature_start = (block.len() + FOOTER_SIZE) as u16;
+        let comment_len = (comment.len() + FOOTER_SIZE) as u16;
+        comment.extend_from_slice(&signature_start.to_le_bytes());
+        comment.extend_from_slice(&[0xff, 0xff]);
+        comment.extend_from_slice(&comment_len.to_le_bytes());
+        zip.extend_from_slice(&comment_len.to_le_bytes());
+        zip.extend_from_slice(&comment);
+        zip
+    }
+
+    #[test]
+    fn test_verify_zip_signature() {
+        use rsa::pkcs8::{EncodePublicKey, LineEnding};
+
+        let key = test_key();
+        let otacert = key
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let zip = signed_zip(&key, otacert.as_bytes());
+
+        let report = verify_zip_signature(&mut Cursor::new(&zip), &[]).unwrap();
+        assert_eq!(report.otacert.as_deref(), Some(otacert.as_bytes()));
+        assert_eq!(report.check, SignatureCheck::SelfSigned { signature: 0 });
+        assert!(!report.check.is_verified());
+
+        let other_key = test_key()
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let other_key = PublicKey::from_bytes("other.pem", other_key.as_bytes()).unwrap();
+        let report = verify_zip_signature(&mut Cursor::new(&zip), &[other_key]).unwrap();
+        assert_eq!(report.check, SignatureCheck::Mismatch);
+
+        let mut tampered = zip.clone();
+        tampered[40] ^= 1;
+        let report = verify_zip_signature(&mut Cursor::new(&tampered), &[]).unwrap();
+        assert_eq!(report.check, SignatureCheck::Mismatch);
+
+        let unsigned = &zip[..zip.len() - 6];
+        assert!(
+            ZipSignature::read(&mut Cursor::new(unsigned))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_verify_zip_signature_fixture() {
+        const ZIP: &[u8] = include_bytes!("../../tests/fixtures/zipsig/ota.zip");
+        const SIGNER: &[u8] = include_bytes!("../../tests/fixtures/zipsig/signer.pem");
+        const OTHER: &[u8] = include_bytes!("../../tests/fixtures/zipsig/other.pem");
+
+        let report = verify_zip_signature(&mut Cursor::new(ZIP), &[]).unwrap();
+        assert_eq!(report.otacert.as_deref(), Some(SIGNER));
+        assert_eq!(report.check, SignatureCheck::SelfSigned { signature: 0 });
+
+        let signer = PublicKey::from_bytes("signer.pem", SIGNER).unwrap();
+        let report = verify_zip_signature(&mut Cursor::new(ZIP), &[signer]).unwrap();
+        assert!(report.check.is_verified());
+
+        let other = PublicKey::from_bytes("other.pem", OTHER).unwrap();
+        let report = verify_zip_signature(&mut Cursor::new(ZIP), &[other]).unwrap();
+        assert_eq!(report.check, SignatureCheck::Mismatch);
+    }
+}
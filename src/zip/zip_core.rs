@@ -1,6 +1,8 @@
 use std::io::{Read, Seek, SeekFrom};
 
-use anyhow::{Result, anyhow, bail};
+use flate2::read::DeflateDecoder;
+
+use crate::error::{Error, Result};
 
 // ZIP signatures
 pub const LOCAL_FILE_HEADER_SIGNATURE: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
@@ -64,8 +66,8 @@ impl ZipParser {
             }
         }
 
-        let eocd_offset =
-            eocd_pos.ok_or_else(|| anyhow!("Could not find End of Central Directory record"))?;
+        let eocd_offset = eocd_pos
+            .ok_or_else(|| Error::invalid_zip("Could not find End of Central Directory record"))?;
 
         // Read number of entries
         reader.seek(SeekFrom::Start(eocd_offset + 10))?;
@@ -80,7 +82,7 @@ impl ZipParser {
     pub fn read_zip64_eocd<R: Read + Seek>(reader: &mut R, eocd_offset: u64) -> Result<(u64, u64)> {
         // Look for ZIP64 EOCD locator
         if eocd_offset < 20 {
-            bail!("Invalid ZIP64 structure");
+            return Err(Error::invalid_zip("Invalid ZIP64 structure"));
         }
 
         let search_start = eocd_offset.saturating_sub(20);
@@ -114,7 +116,9 @@ impl ZipParser {
         }
 
         if !found_locator {
-            bail!("ZIP64 format indicated but ZIP64 EOCD locator not found");
+            return Err(Error::invalid_zip(
+                "ZIP64 format indicated but ZIP64 EOCD locator not found",
+            ));
         }
 
         // Read ZIP64 EOCD
@@ -123,7 +127,7 @@ impl ZipParser {
         reader.read_exact(&mut zip64_eocd)?;
 
         if zip64_eocd[0..4] != ZIP64_EOCD_SIGNATURE {
-            bail!("Invalid ZIP64 EOCD signature");
+            return Err(Error::invalid_zip("Invalid ZIP64 EOCD signature"));
         }
 
         let cd_offset = u64::from_le_bytes([
@@ -174,7 +178,9 @@ impl ZipParser {
         reader.read_exact(&mut entry_header)?;
 
         if entry_header[0..4] != CENTRAL_DIR_HEADER_SIGNATURE {
-            bail!("Invalid central directory header signature");
+            return Err(Error::invalid_zip(
+                "Invalid central directory header signature",
+            ));
         }
 
         let compression_method = u16::from_le_bytes([entry_header[10], entry_header[11]]);
@@ -304,31 +310,73 @@ impl ZipParser {
             }
         }
 
-        Err(anyhow!("Could not find payload.bin in ZIP file"))
+        Err(Error::PayloadNotFound)
     }
 
     /// Calculate the actual data offset for a ZIP entry (after local header)
     pub fn get_data_offset<R: Read + Seek>(reader: &mut R, entry: &ZipEntry) -> Result<u64> {
+        let (data_offset, local_compression) = Self::read_local_header(reader, entry)?;
+        // Double-check compression method in local header
+        if local_compression != 0 {
+            return Err(Error::CompressedPayload);
+        }
+        Ok(data_offset)
+    }
+
+    /// Read the local header of an entry, returning its data offset and compression method
+    pub fn read_local_header<R: Read + Seek>(
+        reader: &mut R,
+        entry: &ZipEntry,
+    ) -> Result<(u64, u16)> {
         reader.seek(SeekFrom::Start(entry.offset))?;
         let mut local_header = [0u8; 30];
         reader.read_exact(&mut local_header)?;
 
         if local_header[0..4] != LOCAL_FILE_HEADER_SIGNATURE {
-            bail!("Invalid local file header signature");
+            return Err(Error::invalid_zip("Invalid local file header signature"));
         }
 
-        // Double-check compression method in local header
         let local_compression = u16::from_le_bytes([local_header[8], local_header[9]]);
-        if local_compression != 0 {
-            bail!("payload.bin is compressed, expected uncompressed");
-        }
-
         let local_filename_len =
             u64::from(u16::from_le_bytes([local_header[26], local_header[27]]));
         let local_extra_len = u64::from(u16::from_le_bytes([local_header[28], local_header[29]]));
 
         let data_offset = entry.offset + 30 + local_filename_len + local_extra_len;
-        Ok(data_offset)
+        Ok((data_offset, local_compression))
+    }
+
+    /// Read the contents of the entry `name`, stored or deflated, if the ZIP has one
+    pub fn read_entry<R: Read + Seek>(reader: &mut R, name: &str) -> Result<Option<Vec<u8>>> {
+        let (cd_offset, num_entries) = Self::get_central_directory_info(reader)?;
+        reader.seek(SeekFrom::Start(cd_offset))?;
+
+        for _ in 0..num_entries {
+            let entry = Self::read_central_directory_entry(reader)?;
+            if entry.name == name {
+                return Self::read_entry_data(reader, &entry).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the contents of a stored or deflated entry
+    pub fn read_entry_data<R: Read + Seek>(reader: &mut R, entry: &ZipEntry) -> Result<Vec<u8>> {
+        let (data_offset, compression) = Self::read_local_header(reader, entry)?;
+        reader.seek(SeekFrom::Start(data_offset))?;
+        let mut data = vec![0u8; entry.compressed_size as usize];
+        reader.read_exact(&mut data)?;
+        match compression {
+            0 => Ok(data),
+            8 => {
+                let mut inflated = Vec::with_capacity(entry.uncompressed_size as usize);
+                DeflateDecoder::new(&data[..]).read_to_end(&mut inflated)?;
+                Ok(inflated)
+            }
+            method => Err(Error::invalid_zip(format!(
+                "Unsupported compression method {method} for {}",
+                entry.name
+            ))),
+        }
     }
 
     /// Verify payload magic at given offset
@@ -338,7 +386,7 @@ impl ZipParser {
         reader.read_exact(&mut magic)?;
 
         if &magic != b"CrAU" {
-            bail!("Invalid payload file: magic 'CrAU' not found at calculated offset");
+            return Err(Error::BadMagic { offset });
         }
 
         Ok(())
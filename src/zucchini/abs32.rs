@@ -0,0 +1,136 @@
+//! Absolute address references, following Zucchini's `abs32_utils`. Their locations come from
+//! relocation tables; their bodies are 32 or 64-bit virtual addresses.
+
+use super::{
+    address::{AddressTranslator, INVALID_OFFSET, INVALID_RVA, RVA_BOUND},
+    reference::Reference,
+};
+
+/// Reads and writes absolute addresses of one width relative to an image base.
+#[derive(Clone, Copy, Debug)]
+pub struct AbsoluteAddress {
+    pub is_64_bit: bool,
+    pub image_base: u64,
+}
+
+impl AbsoluteAddress {
+    pub const fn width(self) -> u32 {
+        if self.is_64_bit { 8 } else { 4 }
+    }
+
+    /// RVA of the address at `location`, if readable and valid.
+    fn read_rva(self, image: &[u8], location: u32) -> Option<u32> {
+        let location = location as usize;
+        let value = if self.is_64_bit {
+            u64::from_le_bytes(image.get(location..location + 8)?.try_into().ok()?)
+        } else {
+            u64::from(u32::from_le_bytes(
+                image.get(location..location + 4)?.try_into().ok()?,
+            ))
+        };
+        let rva = value.checked_sub(self.image_base)?;
+        (rva < u64::from(RVA_BOUND)).then_some(rva as u32)
+    }
+
+    /// Write the address of `rva` at `location`, if it can be represented.
+    fn write_rva(self, image: &mut [u8], location: u32, rva: u32) {
+        if rva >= RVA_BOUND {
+            return;
+        }
+        let Some(value) = self.image_base.checked_add(u64::from(rva)) else {
+            return;
+        };
+        let location = location as usize;
+        if self.is_64_bit {
+            if let Some(body) = image.get_mut(location..location + 8) {
+                body.copy_from_slice(&value.to_le_bytes());
+            }
+        } else if let (Ok(value), Some(body)) =
+            (u32::try_from(value), image.get_mut(location..location + 4))
+        {
+            body.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+/// References at the sorted `locations` within `lo..hi` whose targets translate to offsets.
+pub fn read_abs32(
+    image: &[u8],
+    address: AbsoluteAddress,
+    translator: &AddressTranslator,
+    locations: &[u32],
+    lo: u32,
+    hi: u32,
+) -> Vec<Reference> {
+    let start = locations.partition_point(|&location| location < lo);
+    let end = locations.partition_point(|&location| location < hi);
+    locations[start..end.max(start)]
+        .iter()
+        .filter_map(|&location| {
+            let target = translator.rva_to_offset(address.read_rva(image, location)?);
+            (target != INVALID_OFFSET).then_some(Reference { location, target })
+        })
+        .collect()
+}
+
+pub fn write_abs32(
+    image: &mut [u8],
+    address: AbsoluteAddress,
+    translator: &AddressTranslator,
+    reference: Reference,
+) {
+    let rva = translator.offset_to_rva(reference.target);
+    if rva != INVALID_RVA {
+        address.write_rva(image, reference.location, rva);
+    }
+}
+
+/// Turn sorted relocation targets into abs32 locations: drop those whose addresses do not
+/// translate to offsets, then those overlapping the previous location.
+pub fn abs32_locations(
+    image: &[u8],
+    address: AbsoluteAddress,
+    translator: &AddressTranslator,
+    mut locations: Vec<u32>,
+) -> Vec<u32> {
+    locations.sort_unstable();
+    let mut locations = read_abs32(image, address, translator, &locations, 0, u32::MAX)
+        .into_iter()
+        .map(|reference| reference.location)
+        .collect::<Vec<_>>();
+
+    let width = address.width();
+    let mut kept = 0;
+    for index in 1..locations.len() {
+        if locations[index] - locations[kept] >= width {
+            kept += 1;
+            locations[kept] = locations[index];
+        }
+    }
+    locations.truncate((kept + 1).min(locations.len()));
+    locations
+}
+
+/// Splits a region into the gaps between abs32 reference bodies, where rel32 references are
+/// searched, so the two kinds never collide.
+pub fn abs32_gaps(locations: &[u32], width: u32, begin: u32, end: u32) -> Vec<(u32, u32)> {
+    let first = locations.partition_point(|&location| location < begin);
+    let mut lo = begin;
+    if first > 0 {
+        lo = lo.max(locations[first - 1] + width);
+    }
+    let mut gaps = Vec::new();
+    for &location in locations[first..]
+        .iter()
+        .take_while(|&&location| location < end)
+    {
+        if lo < location {
+            gaps.push((lo, location));
+        }
+        lo = location + width;
+    }
+    if lo < end {
+        gaps.push((lo, end));
+    }
+    gaps
+}
@@ -0,0 +1,230 @@
+//! Translation between file offsets and RVAs, following Zucchini's `AddressTranslator`.
+//!
+//! Executables map file regions ("units") to RVA regions. A unit may cover more RVAs than file
+//! bytes (e.g. `.bss`); such "dangling" RVAs translate to fake offsets past every real offset,
+//! so references to them survive patching like any other.
+
+use anyhow::{Result, bail};
+
+/// Exclusive upper bound of offsets, and the marker of an invalid offset.
+pub const OFFSET_BOUND: u32 = 0x7FFF_FFFF;
+pub const INVALID_OFFSET: u32 = u32::MAX;
+/// Exclusive upper bound of RVAs, and the marker of an invalid RVA.
+pub const RVA_BOUND: u32 = 0x7FFF_FFFF;
+pub const INVALID_RVA: u32 = u32::MAX - 1;
+
+/// Whether `[begin, begin + size)` lies within `[0, bound)`.
+pub const fn range_is_bounded(begin: u64, size: u64, bound: u64) -> bool {
+    begin < bound && size <= bound - begin
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unit {
+    pub offset_begin: u32,
+    pub offset_size: u32,
+    pub rva_begin: u32,
+    pub rva_size: u32,
+}
+
+impl Unit {
+    const fn offset_end(&self) -> u32 {
+        self.offset_begin + self.offset_size
+    }
+
+    const fn rva_end(&self) -> u32 {
+        self.rva_begin + self.rva_size
+    }
+
+    const fn covers_offset(&self, offset: u32) -> bool {
+        offset >= self.offset_begin && offset - self.offset_begin < self.offset_size
+    }
+
+    const fn covers_rva(&self, rva: u32) -> bool {
+        rva >= self.rva_begin && rva - self.rva_begin < self.rva_size
+    }
+
+    const fn covers_dangling_rva(&self, rva: u32) -> bool {
+        self.covers_rva(rva) && rva - self.rva_begin >= self.offset_size
+    }
+
+    const fn has_dangling_rva(&self) -> bool {
+        self.rva_size > self.offset_size
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AddressTranslator {
+    units_by_offset: Vec<Unit>,
+    units_by_rva: Vec<Unit>,
+    fake_offset_begin: u32,
+}
+
+impl AddressTranslator {
+    /// Validate `units` and merge those with overlapping RVAs. Inconsistent units, such as two
+    /// offsets mapping to the same RVA, are an error.
+    pub fn new(mut units: Vec<Unit>) -> Result<Self> {
+        for unit in &mut units {
+            if !range_is_bounded(
+                unit.offset_begin.into(),
+                unit.offset_size.into(),
+                OFFSET_BOUND.into(),
+            ) || !range_is_bounded(
+                unit.rva_begin.into(),
+                unit.rva_size.into(),
+                RVA_BOUND.into(),
+            ) {
+                bail!("Address translation unit overflows");
+            }
+            unit.offset_size = unit.offset_size.min(unit.rva_size);
+        }
+        units.retain(|unit| unit.rva_size > 0);
+        units.sort_by_key(|unit| (unit.rva_begin, unit.rva_size));
+        units.dedup();
+
+        // Merge units with overlapping (or touching, when consistent) RVA ranges.
+        let mut merged: Vec<Unit> = Vec::with_capacity(units.len());
+        for fast in units {
+            let Some(slow) = merged.last_mut() else {
+                merged.push(fast);
+                continue;
+            };
+            if slow.rva_end() < fast.rva_begin {
+                merged.push(fast);
+                continue;
+            }
+            let merge_is_optional = slow.rva_end() == fast.rva_begin;
+            if fast.offset_begin < slow.offset_begin
+                || fast.offset_begin - slow.offset_begin != fast.rva_begin - slow.rva_begin
+                || (fast.has_dangling_rva() && fast.offset_end() < slow.offset_end())
+                || (slow.has_dangling_rva() && slow.offset_end() < fast.offset_end())
+            {
+                if merge_is_optional {
+                    merged.push(fast);
+                    continue;
+                }
+                bail!("Inconsistent address translation units");
+            }
+            slow.rva_size = slow.rva_size.max(fast.rva_end() - slow.rva_begin);
+            slow.offset_size = slow.offset_size.max(fast.offset_end() - slow.offset_begin);
+        }
+
+        // With RVA overlaps resolved, any offset overlap is an error.
+        let mut units_by_offset = merged.clone();
+        units_by_offset.sort_by_key(|unit| unit.offset_begin);
+        if units_by_offset
+            .windows(2)
+            .any(|pair| pair[0].offset_end() > pair[1].offset_begin)
+        {
+            bail!("Overlapping address translation units");
+        }
+
+        let offset_bound = merged.iter().map(Unit::offset_end).max().unwrap_or(0);
+        let rva_bound = merged.iter().map(Unit::rva_end).max().unwrap_or(0);
+        if !range_is_bounded(offset_bound.into(), rva_bound.into(), OFFSET_BOUND.into()) {
+            bail!("Image is too large for fake offsets");
+        }
+
+        Ok(Self {
+            units_by_offset,
+            units_by_rva: merged,
+            fake_offset_begin: offset_bound,
+        })
+    }
+
+    fn offset_to_unit(&self, offset: u32) -> Option<&Unit> {
+        let index = self
+            .units_by_offset
+            .partition_point(|unit| unit.offset_begin <= offset);
+        let unit = self.units_by_offset.get(index.checked_sub(1)?)?;
+        unit.covers_offset(offset).then_some(unit)
+    }
+
+    fn rva_to_unit(&self, rva: u32) -> Option<&Unit> {
+        let index = self
+            .units_by_rva
+            .partition_point(|unit| unit.rva_begin <= rva);
+        let unit = self.units_by_rva.get(index.checked_sub(1)?)?;
+        unit.covers_rva(rva).then_some(unit)
+    }
+
+    /// Whether `rva` belongs to any unit, including as a dangling RVA.
+    pub fn is_valid_rva(&self, rva: u32) -> bool {
+        rva != INVALID_RVA && self.rva_to_unit(rva).is_some()
+    }
+
+    pub fn offset_to_rva(&self, offset: u32) -> u32 {
+        if offset >= self.fake_offset_begin {
+            let rva = offset - self.fake_offset_begin;
+            return match self.rva_to_unit(rva) {
+                Some(unit) if unit.covers_dangling_rva(rva) => rva,
+                _ => INVALID_RVA,
+            };
+        }
+        self.offset_to_unit(offset).map_or(INVALID_RVA, |unit| {
+            offset - unit.offset_begin + unit.rva_begin
+        })
+    }
+
+    pub fn rva_to_offset(&self, rva: u32) -> u32 {
+        self.rva_to_unit(rva).map_or(INVALID_OFFSET, |unit| {
+            let delta = rva - unit.rva_begin;
+            if delta < unit.offset_size {
+                delta + unit.offset_begin
+            } else {
+                self.fake_offset_begin + rva
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_translator() {
+        let translator = AddressTranslator::new(vec![
+            Unit {
+                offset_begin: 0x100,
+                offset_size: 0x100,
+                rva_begin: 0x1100,
+                rva_size: 0x100,
+            },
+            // Overlaps the first unit consistently, and has dangling RVAs.
+            Unit {
+                offset_begin: 0x180,
+                offset_size: 0x80,
+                rva_begin: 0x1180,
+                rva_size: 0x200,
+            },
+        ])
+        .unwrap();
+        assert_eq!(translator.offset_to_rva(0x150), 0x1150);
+        assert_eq!(translator.rva_to_offset(0x11FF), 0x1FF);
+        assert_eq!(translator.offset_to_rva(0x50), INVALID_RVA);
+        // Dangling RVAs map to fake offsets past the end of the units, and back.
+        let fake = translator.rva_to_offset(0x1300);
+        assert_eq!(fake, 0x200 + 0x1300);
+        assert_eq!(translator.offset_to_rva(fake), 0x1300);
+        assert_eq!(translator.rva_to_offset(0x1400), INVALID_OFFSET);
+
+        // Two offsets for the same RVA are inconsistent.
+        assert!(
+            AddressTranslator::new(vec![
+                Unit {
+                    offset_begin: 0,
+                    offset_size: 0x10,
+                    rva_begin: 0x1000,
+                    rva_size: 0x10,
+                },
+                Unit {
+                    offset_begin: 0x20,
+                    offset_size: 0x10,
+                    rva_begin: 0x1008,
+                    rva_size: 0x10,
+                },
+            ])
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! ARM relative branch references, following Zucchini's `arm_utils` and `Rel32FinderArm`.
+//!
+//! Unlike x86, ARM references live inside the instruction word, so their location is the
+//! instruction itself and their target is decoded from (and encoded back into) its immediate.
+
+use super::{
+    address::{AddressTranslator, INVALID_OFFSET, INVALID_RVA},
+    reference::Reference,
+};
+
+/// Branch encodings carrying a PC-relative displacement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArmAddr {
+    /// A32 B, BL and BLX (immediate).
+    A24,
+    /// Thumb2 16-bit conditional B.
+    T8,
+    /// Thumb2 16-bit B.
+    T11,
+    /// Thumb2 32-bit conditional B.
+    T20,
+    /// Thumb2 32-bit B, BL and BLX.
+    T24,
+    /// `AArch64` TBZ and TBNZ.
+    Immd14,
+    /// `AArch64` B.cond, CBZ and CBNZ.
+    Immd19,
+    /// `AArch64` B and BL.
+    Immd26,
+}
+
+/// Bits `lo..=hi` of `value`, sign-extended.
+const fn signed_bits(value: u32, lo: u32, hi: u32) -> i32 {
+    ((value << (31 - hi)) as i32) >> (31 - hi + lo)
+}
+
+/// Bits `lo..=hi` of `value`.
+const fn unsigned_bits(value: u32, lo: u32, hi: u32) -> u32 {
+    (value << (31 - hi)) >> (31 - hi + lo)
+}
+
+const fn bit(value: u32, index: u32) -> u32 {
+    (value >> index) & 1
+}
+
+/// Whether `value` fits in a signed integer of `bits` bits.
+const fn signed_fit(value: i32, bits: u32) -> bool {
+    let bound = 1i64 << (bits - 1);
+    -bound <= value as i64 && (value as i64) < bound
+}
+
+impl ArmAddr {
+    /// Size of the instruction word, which is also its minimum distance to the next one.
+    pub const fn size(self) -> u32 {
+        match self {
+            Self::T8 | Self::T11 => 2,
+            _ => 4,
+        }
+    }
+
+    const fn instruction_align(self) -> u32 {
+        match self {
+            Self::T8 | Self::T11 | Self::T20 | Self::T24 => 2,
+            _ => 4,
+        }
+    }
+
+    /// Offset from the instruction to the PC its displacement is relative to.
+    const fn pc_offset(self) -> u32 {
+        match self {
+            Self::A24 => 8,
+            Self::T8 | Self::T11 | Self::T20 | Self::T24 => 4,
+            Self::Immd14 | Self::Immd19 | Self::Immd26 => 0,
+        }
+    }
+
+    /// Read the instruction word at `offset`. 32-bit Thumb2 instructions are two little-endian
+    /// halfwords, the first one in the high bits.
+    pub fn fetch(self, image: &[u8], offset: u32) -> Option<u32> {
+        let offset = offset as usize;
+        let bytes = image.get(offset..offset + self.size() as usize)?;
+        Some(match self {
+            Self::T8 | Self::T11 => u32::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+            Self::T20 | Self::T24 => {
+                (u32::from(u16::from_le_bytes([bytes[0], bytes[1]])) << 16)
+                    | u32::from(u16::from_le_bytes([bytes[2], bytes[3]]))
+            }
+            _ => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        })
+    }
+
+    fn store(self, image: &mut [u8], offset: u32, code: u32) {
+        let offset = offset as usize;
+        let Some(bytes) = image.get_mut(offset..offset + self.size() as usize) else {
+            return;
+        };
+        match self {
+            Self::T8 | Self::T11 => bytes.copy_from_slice(&(code as u16).to_le_bytes()),
+            Self::T20 | Self::T24 => {
+                bytes[..2].copy_from_slice(&((code >> 16) as u16).to_le_bytes());
+                bytes[2..].copy_from_slice(&(code as u16).to_le_bytes());
+            }
+            _ => bytes.copy_from_slice(&code.to_le_bytes()),
+        }
+    }
+
+    /// The displacement of `code` and the alignment of its target, if `code` is a branch of
+    /// this kind.
+    fn decode(self, code: u32) -> Option<(i32, u32)> {
+        match self {
+            Self::A24 => {
+                if !matches!(unsigned_bits(code, 24, 27), 0xA | 0xB) {
+                    return None;
+                }
+                let disp = signed_bits(code, 0, 23) << 2;
+                if unsigned_bits(code, 28, 31) == 0xF {
+                    // BLX switches to Thumb2, with the halfword bit in H.
+                    return Some((disp | (bit(code, 24) << 1) as i32, 2));
+                }
+                Some((disp, 4))
+            }
+            Self::T8 => (code & 0xF000 == 0xD000 && code & 0x0F00 != 0x0F00)
+                .then(|| (signed_bits(code, 0, 7) << 1, 2)),
+            Self::T11 => (code & 0xF800 == 0xE000).then(|| (signed_bits(code, 0, 10) << 1, 2)),
+            Self::T20 => {
+                if code & 0xF800_D000 != 0xF000_8000 || code & 0x03C0_0000 == 0x03C0_0000 {
+                    return None;
+                }
+                let t = (unsigned_bits(code, 16, 21) << 12)
+                    | (unsigned_bits(code, 0, 10) << 1)
+                    | (bit(code, 26) << 20)
+                    | (bit(code, 11) << 19)
+                    | (bit(code, 13) << 18);
+                Some((signed_bits(t, 0, 20), 2))
+            }
+            Self::T24 => {
+                let bits = code & 0xF800_D000;
+                if !matches!(bits, 0xF000_9000 | 0xF000_D000 | 0xF000_C000) {
+                    return None;
+                }
+                let s = bit(code, 26);
+                let t = (unsigned_bits(code, 16, 25) << 12)
+                    | (unsigned_bits(code, 0, 10) << 1)
+                    | (s << 24)
+                    | ((bit(code, 13) ^ s ^ 1) << 23)
+                    | ((bit(code, 11) ^ s ^ 1) << 22);
+                let disp = signed_bits(t, 0, 24);
+                if bits == 0xF000_C000 {
+                    // BLX requires H to be 0, and targets 4-byte aligned A32 code.
+                    return (bit(code, 0) == 0).then_some((disp, 4));
+                }
+                Some((disp, 2))
+            }
+            Self::Immd14 => {
+                (code & 0x7E00_0000 == 0x3600_0000).then(|| (signed_bits(code, 5, 18) << 2, 4))
+            }
+            Self::Immd19 => (code & 0xFF00_0010 == 0x5400_0000
+                || code & 0x7E00_0000 == 0x3400_0000)
+                .then(|| (signed_bits(code, 5, 23) << 2, 4)),
+            Self::Immd26 => {
+                (code & 0x7C00_0000 == 0x1400_0000).then(|| (signed_bits(code, 0, 25) << 2, 4))
+            }
+        }
+    }
+
+    /// `code` with its displacement replaced by `disp`, if it can be encoded.
+    fn encode(self, disp: i32, code: u32) -> Option<u32> {
+        let d = disp as u32;
+        match self {
+            Self::A24 => {
+                let mut t = code;
+                if unsigned_bits(t, 28, 31) == 0xF {
+                    if disp % 2 != 0 {
+                        return None;
+                    }
+                    t = (t & 0xFEFF_FFFF) | (bit(d, 1) << 24);
+                } else if disp % 4 != 0 {
+                    return None;
+                }
+                signed_fit(disp, 26).then_some((t & 0xFF00_0000) | ((d >> 2) & 0x00FF_FFFF))
+            }
+            Self::T8 => (disp % 2 == 0 && signed_fit(disp, 9))
+                .then_some((code & 0xFF00) | ((d >> 1) & 0xFF)),
+            Self::T11 => (disp % 2 == 0 && signed_fit(disp, 12))
+                .then_some((code & 0xF800) | ((d >> 1) & 0x07FF)),
+            Self::T20 => (disp % 2 == 0 && signed_fit(disp, 21)).then_some(
+                (code & 0xFBC0_D000)
+                    | (bit(d, 20) << 26)
+                    | (unsigned_bits(d, 12, 17) << 16)
+                    | (bit(d, 18) << 13)
+                    | (bit(d, 19) << 11)
+                    | unsigned_bits(d, 1, 11),
+            ),
+            Self::T24 => {
+                let bits = code & 0xF800_D000;
+                if disp % 2 != 0 || (bits == 0xF000_C000 && bit(d, 1) != 0) {
+                    return None;
+                }
+                let s = bit(d, 24);
+                signed_fit(disp, 25).then_some(
+                    bits | (s << 26)
+                        | (unsigned_bits(d, 12, 21) << 16)
+                        | ((bit(d, 23) ^ s ^ 1) << 13)
+                        | ((bit(d, 22) ^ s ^ 1) << 11)
+                        | unsigned_bits(d, 1, 11),
+                )
+            }
+            Self::Immd14 => (disp % 4 == 0 && signed_fit(disp, 16))
+                .then_some((code & 0xFFF8_001F) | (unsigned_bits(d, 2, 15) << 5)),
+            Self::Immd19 => (disp % 4 == 0 && signed_fit(disp, 21))
+                .then_some((code & 0xFF00_001F) | (unsigned_bits(d, 2, 20) << 5)),
+            Self::Immd26 => (disp % 4 == 0 && signed_fit(disp, 28))
+                .then_some((code & 0xFC00_0000) | unsigned_bits(d, 2, 27)),
+        }
+    }
+
+    /// Target RVA of the branch `code` at `instruction_rva`.
+    pub fn read_target(self, instruction_rva: u32, code: u32) -> Option<u32> {
+        if !instruction_rva.is_multiple_of(self.instruction_align()) {
+            return None;
+        }
+        let (disp, align) = self.decode(code)?;
+        let target = instruction_rva
+            .wrapping_add(self.pc_offset())
+            .wrapping_add_signed(disp);
+        Some(target & !(align - 1))
+    }
+
+    /// `code` at `instruction_rva` rewritten to branch to `target_rva`, if possible.
+    fn write_target(self, instruction_rva: u32, target_rva: u32, code: u32) -> Option<u32> {
+        if !instruction_rva.is_multiple_of(self.instruction_align()) {
+            return None;
+        }
+        let (_, align) = self.decode(code)?;
+        if !target_rva.is_multiple_of(align) {
+            return None;
+        }
+        let mut disp =
+            target_rva.wrapping_sub(instruction_rva.wrapping_add(self.pc_offset())) as i32;
+        // Thumb2 BLX is relative to the PC aligned down, so round the displacement up.
+        disp += disp.wrapping_neg() & (align as i32 - 1);
+        self.encode(disp, code)
+    }
+}
+
+/// The size of the Thumb2 instruction starting with the halfword `code`.
+const fn thumb2_instruction_size(code: u32) -> u32 {
+    if code & 0xF000 == 0xF000 || code & 0xF800 == 0xE800 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Scan `image[lo..hi]` for the next branch, returning its location, target RVA and kind,
+/// and the offset to continue from.
+fn scan(
+    image: &[u8],
+    translator: &AddressTranslator,
+    modes: &[ArmAddr],
+    lo: u32,
+    hi: u32,
+) -> Option<(u32, u32, ArmAddr, u32)> {
+    let thumb2 = modes.contains(&ArmAddr::T8);
+    let mut cursor = if thumb2 {
+        lo.next_multiple_of(2)
+    } else {
+        lo.next_multiple_of(4)
+    };
+    while cursor.checked_add(if thumb2 { 2 } else { 4 })? <= hi {
+        let instruction_rva = translator.offset_to_rva(cursor);
+        let size = if thumb2 {
+            thumb2_instruction_size(ArmAddr::T8.fetch(image, cursor)?)
+        } else {
+            4
+        };
+        if size == 4 && cursor + 4 > hi {
+            cursor += size;
+            continue;
+        }
+        for &addr in modes.iter().filter(|addr| addr.size() == size) {
+            let code = addr.fetch(image, cursor)?;
+            if let Some(target_rva) = addr.read_target(instruction_rva, code) {
+                return Some((cursor, target_rva, addr, cursor + size));
+            }
+        }
+        cursor += size;
+    }
+    None
+}
+
+/// Find branch locations of each kind in `modes` within `gaps` of a code section, keeping
+/// those whose targets lie in `code_sections`, sorted `(offset, size)` pairs.
+pub fn find_rel32_arm(
+    image: &[u8],
+    translator: &AddressTranslator,
+    modes: &[ArmAddr],
+    gaps: Vec<(u32, u32)>,
+    code_sections: &[(u32, u32)],
+    locations: &mut [Vec<u32>],
+) {
+    for (mut lo, hi) in gaps {
+        while let Some((location, target_rva, addr, next)) = scan(image, translator, modes, lo, hi)
+        {
+            lo = next;
+            let target = translator.rva_to_offset(target_rva);
+            if target == INVALID_OFFSET {
+                continue;
+            }
+            let index = code_sections.partition_point(|&(offset, _)| offset <= target);
+            if index > 0 && target - code_sections[index - 1].0 < code_sections[index - 1].1 {
+                let kind = modes.iter().position(|&mode| mode == addr).unwrap();
+                locations[kind].push(location);
+            }
+        }
+    }
+}
+
+/// Whether an A32 section is more likely Thumb2: most A32 instructions are unconditional,
+/// with 0xE in their top nibble.
+pub fn is_thumb2(code: &[u8], rva: u32) -> bool {
+    if !rva.is_multiple_of(4) || !code.len().is_multiple_of(4) {
+        return true;
+    }
+    let den = code.len() / 4;
+    let num = code
+        .chunks_exact(4)
+        .filter(|word| word[3] & 0xF0 == 0xE0)
+        .count();
+    (num as f64) < den as f64 * 0.4
+}
+
+/// References of kind `addr` at the sorted `locations` within `lo..hi`.
+pub fn read_rel32_arm(
+    image: &[u8],
+    translator: &AddressTranslator,
+    addr: ArmAddr,
+    locations: &[u32],
+    lo: u32,
+    hi: u32,
+) -> Vec<Reference> {
+    let start = locations.partition_point(|&location| location < lo);
+    locations[start..]
+        .iter()
+        .take_while(|&&location| location < hi)
+        .filter_map(|&location| {
+            let code = addr.fetch(image, location)?;
+            let target_rva = addr.read_target(translator.offset_to_rva(location), code)?;
+            let target = translator.rva_to_offset(target_rva);
+            (target != INVALID_OFFSET).then_some(Reference { location, target })
+        })
+        .collect()
+}
+
+pub fn write_rel32_arm(
+    image: &mut [u8],
+    translator: &AddressTranslator,
+    addr: ArmAddr,
+    reference: Reference,
+) {
+    let instruction_rva = translator.offset_to_rva(reference.location);
+    let target_rva = translator.offset_to_rva(reference.target);
+    if instruction_rva == INVALID_RVA || target_rva == INVALID_RVA {
+        return;
+    }
+    let Some(code) = addr.fetch(image, reference.location) else {
+        return;
+    };
+    if let Some(code) = addr.write_target(instruction_rva, target_rva, code) {
+        addr.store(image, reference.location, code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arm_branches() {
+        // (kind, instruction, its RVA, its target RVA)
+        let cases = [
+            (ArmAddr::A24, 0xEB00_0010, 0x1000, 0x1048), // BL
+            (ArmAddr::A24, 0xFBFF_FFFE, 0x1000, 0x1002), // BLX with H set
+            (ArmAddr::T8, 0xD0FE, 0x2002, 0x2002),       // BEQ to itself
+            (ArmAddr::T11, 0xE400, 0x2000, 0x1804),      // B backwards
+            (ArmAddr::T20, 0xF040_8022, 0x3000, 0x3048), // BNE.W
+            (ArmAddr::T24, 0xF000_F802, 0x3002, 0x300A), // BL
+            (ArmAddr::T24, 0xF000_E802, 0x3002, 0x3008), // BLX, aligned down
+            (ArmAddr::Immd14, 0x3600_0040, 0x4000, 0x4008),
+            (ArmAddr::Immd19, 0x5400_0041, 0x4000, 0x4008),
+            (ArmAddr::Immd26, 0x97FF_FFFF, 0x4000, 0x3FFC),
+        ];
+        for (addr, code, rva, target) in cases {
+            assert_eq!(
+                addr.read_target(rva, code),
+                Some(target),
+                "{addr:?} {code:#x}"
+            );
+            assert_eq!(addr.write_target(rva, target, code), Some(code), "{addr:?}");
+            // Moving the target moves it back when decoded.
+            let moved = addr.write_target(rva, target + 0x100, code).unwrap();
+            assert_eq!(
+                addr.read_target(rva, moved),
+                Some(target + 0x100),
+                "{addr:?}"
+            );
+        }
+        // Out of range and misaligned targets are rejected.
+        assert_eq!(ArmAddr::T8.write_target(0x2000, 0x3000, 0xD0FE), None);
+        assert_eq!(ArmAddr::A24.write_target(0x1000, 0x1002, 0xEB00_0010), None);
+    }
+}
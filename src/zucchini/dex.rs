@@ -0,0 +1,896 @@
+//! DEX references, following Zucchini's `DisassemblerDex`.
+//!
+//! DEX items refer to each other by index (strings, types, protos, fields, methods, ...) and by
+//! file offset (type lists, class data, annotations, ...), from fixed-size id items, from
+//! variable-length lists and from Dalvik instructions, which also branch by relative
+//! displacements. Every such field is a reference type; types referring to the same kind of
+//! item share a pool.
+
+use anyhow::{Result, anyhow, bail};
+
+use super::reference::{Disassembler, Reference, ReferenceGroup};
+
+const HEADER_SIZE: u32 = 0x70;
+const ENDIAN_CONSTANT: u32 = 0x1234_5678;
+/// Index meaning "none" in 32-bit index fields.
+const NO_INDEX: u32 = u32::MAX;
+/// Size of a Dalvik code unit.
+const CODE_UNIT: u32 = 2;
+
+const TYPE_STRING_ID: u16 = 0x0001;
+const TYPE_TYPE_ID: u16 = 0x0002;
+const TYPE_PROTO_ID: u16 = 0x0003;
+const TYPE_FIELD_ID: u16 = 0x0004;
+const TYPE_METHOD_ID: u16 = 0x0005;
+const TYPE_CLASS_DEF: u16 = 0x0006;
+const TYPE_CALL_SITE_ID: u16 = 0x0007;
+const TYPE_METHOD_HANDLE: u16 = 0x0008;
+const TYPE_TYPE_LIST: u16 = 0x1001;
+const TYPE_ANNOTATION_SET_REF_LIST: u16 = 0x1002;
+const TYPE_ANNOTATION_SET: u16 = 0x1003;
+const TYPE_CODE: u16 = 0x2001;
+const TYPE_ANNOTATIONS_DIRECTORY: u16 = 0x2006;
+
+/// Size of the fixed part of items of each map item type.
+const fn item_size(item_type: u16) -> u32 {
+    match item_type {
+        0x0000 => HEADER_SIZE,
+        TYPE_STRING_ID
+        | TYPE_TYPE_ID
+        | TYPE_CALL_SITE_ID
+        | TYPE_TYPE_LIST
+        | TYPE_ANNOTATION_SET_REF_LIST
+        | TYPE_ANNOTATION_SET => 4,
+        TYPE_PROTO_ID => 12,
+        TYPE_FIELD_ID | TYPE_METHOD_ID | TYPE_METHOD_HANDLE => 8,
+        TYPE_CLASS_DEF => 32,
+        TYPE_CODE | TYPE_ANNOTATIONS_DIRECTORY => 16,
+        _ => 1,
+    }
+}
+
+/// Offsets of the entries of variable-length items, parsed up front.
+#[derive(Clone, Copy)]
+enum Entries {
+    TypeList,
+    AnnotationSetRefList,
+    AnnotationSet,
+    AnnotationsDirectory,
+    FieldAnnotation,
+    MethodAnnotation,
+    ParameterAnnotation,
+}
+
+/// Where references of a type are.
+enum Location {
+    /// A field at this offset in every item of a map item type.
+    Item(u16, u32),
+    /// The field or method id of method handles of field (or method) kinds.
+    MethodHandle { field: bool },
+    /// A field at this offset in every entry of a list.
+    List(Entries, u32),
+    /// An operand at this offset in instructions with opcodes within these ranges.
+    Code(&'static [(u8, u8, u32)]),
+}
+
+/// What references of a type hold.
+enum Target {
+    /// An index of this width into the items of a map item type.
+    Index(u16, u32),
+    /// A 32-bit file offset, 0 meaning none.
+    Offset,
+    /// A signed displacement of this width, in code units from the instruction.
+    RelCode(u32),
+}
+
+struct ReferenceType {
+    pool: u8,
+    location: Location,
+    target: Target,
+}
+
+const fn reference(pool: u8, location: Location, target: Target) -> ReferenceType {
+    ReferenceType {
+        pool,
+        location,
+        target,
+    }
+}
+
+const STRING_ID: u8 = 0;
+const TYPE_ID: u8 = 1;
+const PROTO_ID: u8 = 2;
+const FIELD_ID: u8 = 3;
+const METHOD_ID: u8 = 4;
+const CALL_SITE_ID: u8 = 5;
+const METHOD_HANDLE: u8 = 6;
+const TYPE_LIST: u8 = 7;
+const ANNOTATION_SET_REF_LIST: u8 = 8;
+const ANNOTATION_SET: u8 = 9;
+const CLASS_DATA: u8 = 10;
+const CODE: u8 = 11;
+const STRING_DATA: u8 = 12;
+const ANNOTATION: u8 = 13;
+const ENCODED_ARRAY: u8 = 14;
+const ANNOTATIONS_DIRECTORY: u8 = 15;
+const CALL_SITE: u8 = 16;
+
+/// Reference types, indexed by type tag and grouped by pool.
+const TYPES: &[ReferenceType] = &[
+    // Type id descriptor, proto id shorty, field and method id names, class source file.
+    reference(
+        STRING_ID,
+        Location::Item(TYPE_TYPE_ID, 0),
+        Target::Index(TYPE_STRING_ID, 4),
+    ),
+    reference(
+        STRING_ID,
+        Location::Item(TYPE_PROTO_ID, 0),
+        Target::Index(TYPE_STRING_ID, 4),
+    ),
+    reference(
+        STRING_ID,
+        Location::Item(TYPE_FIELD_ID, 4),
+        Target::Index(TYPE_STRING_ID, 4),
+    ),
+    reference(
+        STRING_ID,
+        Location::Item(TYPE_METHOD_ID, 4),
+        Target::Index(TYPE_STRING_ID, 4),
+    ),
+    reference(
+        STRING_ID,
+        Location::Item(TYPE_CLASS_DEF, 16),
+        Target::Index(TYPE_STRING_ID, 4),
+    ),
+    // const-string, const-string/jumbo.
+    reference(
+        STRING_ID,
+        Location::Code(&[(0x1A, 0x1A, 2)]),
+        Target::Index(TYPE_STRING_ID, 2),
+    ),
+    reference(
+        STRING_ID,
+        Location::Code(&[(0x1B, 0x1B, 2)]),
+        Target::Index(TYPE_STRING_ID, 4),
+    ),
+    // Proto return type, field class and type, method class, class and superclass, type lists.
+    reference(
+        TYPE_ID,
+        Location::Item(TYPE_PROTO_ID, 4),
+        Target::Index(TYPE_TYPE_ID, 4),
+    ),
+    reference(
+        TYPE_ID,
+        Location::Item(TYPE_FIELD_ID, 0),
+        Target::Index(TYPE_TYPE_ID, 2),
+    ),
+    reference(
+        TYPE_ID,
+        Location::Item(TYPE_FIELD_ID, 2),
+        Target::Index(TYPE_TYPE_ID, 2),
+    ),
+    reference(
+        TYPE_ID,
+        Location::Item(TYPE_METHOD_ID, 0),
+        Target::Index(TYPE_TYPE_ID, 2),
+    ),
+    reference(
+        TYPE_ID,
+        Location::Item(TYPE_CLASS_DEF, 0),
+        Target::Index(TYPE_TYPE_ID, 4),
+    ),
+    reference(
+        TYPE_ID,
+        Location::Item(TYPE_CLASS_DEF, 8),
+        Target::Index(TYPE_TYPE_ID, 4),
+    ),
+    reference(
+        TYPE_ID,
+        Location::List(Entries::TypeList, 0),
+        Target::Index(TYPE_TYPE_ID, 2),
+    ),
+    // const-class, check-cast, instance-of, new-instance, new-array, filled-new-array.
+    reference(
+        TYPE_ID,
+        Location::Code(&[(0x1C, 0x1C, 2), (0x1F, 0x20, 2), (0x22, 0x25, 2)]),
+        Target::Index(TYPE_TYPE_ID, 2),
+    ),
+    // invoke-polymorphic, const-method-type; method id protos.
+    reference(
+        PROTO_ID,
+        Location::Code(&[(0xFA, 0xFB, 6), (0xFF, 0xFF, 2)]),
+        Target::Index(TYPE_PROTO_ID, 2),
+    ),
+    reference(
+        PROTO_ID,
+        Location::Item(TYPE_METHOD_ID, 2),
+        Target::Index(TYPE_PROTO_ID, 2),
+    ),
+    // iget, iput, sget, sput; method handles; field annotations.
+    reference(
+        FIELD_ID,
+        Location::Code(&[(0x52, 0x6D, 2)]),
+        Target::Index(TYPE_FIELD_ID, 2),
+    ),
+    reference(
+        FIELD_ID,
+        Location::MethodHandle { field: true },
+        Target::Index(TYPE_FIELD_ID, 2),
+    ),
+    reference(
+        FIELD_ID,
+        Location::List(Entries::FieldAnnotation, 0),
+        Target::Index(TYPE_FIELD_ID, 4),
+    ),
+    // invoke-kind, invoke-kind/range, invoke-polymorphic; method handles; method and parameter
+    // annotations.
+    reference(
+        METHOD_ID,
+        Location::Code(&[(0x6E, 0x72, 2), (0x74, 0x78, 2), (0xFA, 0xFB, 2)]),
+        Target::Index(TYPE_METHOD_ID, 2),
+    ),
+    reference(
+        METHOD_ID,
+        Location::MethodHandle { field: false },
+        Target::Index(TYPE_METHOD_ID, 2),
+    ),
+    reference(
+        METHOD_ID,
+        Location::List(Entries::MethodAnnotation, 0),
+        Target::Index(TYPE_METHOD_ID, 4),
+    ),
+    reference(
+        METHOD_ID,
+        Location::List(Entries::ParameterAnnotation, 0),
+        Target::Index(TYPE_METHOD_ID, 4),
+    ),
+    // invoke-custom; const-method-handle.
+    reference(
+        CALL_SITE_ID,
+        Location::Code(&[(0xFC, 0xFD, 2)]),
+        Target::Index(TYPE_CALL_SITE_ID, 2),
+    ),
+    reference(
+        METHOD_HANDLE,
+        Location::Code(&[(0xFE, 0xFE, 2)]),
+        Target::Index(TYPE_METHOD_HANDLE, 2),
+    ),
+    // Proto parameters, class interfaces.
+    reference(TYPE_LIST, Location::Item(TYPE_PROTO_ID, 8), Target::Offset),
+    reference(
+        TYPE_LIST,
+        Location::Item(TYPE_CLASS_DEF, 12),
+        Target::Offset,
+    ),
+    reference(
+        ANNOTATION_SET_REF_LIST,
+        Location::List(Entries::ParameterAnnotation, 4),
+        Target::Offset,
+    ),
+    // Annotation set ref lists, class, field and method annotations.
+    reference(
+        ANNOTATION_SET,
+        Location::List(Entries::AnnotationSetRefList, 0),
+        Target::Offset,
+    ),
+    reference(
+        ANNOTATION_SET,
+        Location::List(Entries::AnnotationsDirectory, 0),
+        Target::Offset,
+    ),
+    reference(
+        ANNOTATION_SET,
+        Location::List(Entries::FieldAnnotation, 4),
+        Target::Offset,
+    ),
+    reference(
+        ANNOTATION_SET,
+        Location::List(Entries::MethodAnnotation, 4),
+        Target::Offset,
+    ),
+    reference(
+        CLASS_DATA,
+        Location::Item(TYPE_CLASS_DEF, 24),
+        Target::Offset,
+    ),
+    // goto; goto/16, if-test, if-testz; fill-array-data, goto/32, packed-switch, sparse-switch.
+    reference(CODE, Location::Code(&[(0x28, 0x28, 1)]), Target::RelCode(1)),
+    reference(
+        CODE,
+        Location::Code(&[(0x29, 0x29, 2), (0x32, 0x3D, 2)]),
+        Target::RelCode(2),
+    ),
+    reference(
+        CODE,
+        Location::Code(&[(0x26, 0x26, 2), (0x2A, 0x2C, 2)]),
+        Target::RelCode(4),
+    ),
+    reference(
+        STRING_DATA,
+        Location::Item(TYPE_STRING_ID, 0),
+        Target::Offset,
+    ),
+    reference(
+        ANNOTATION,
+        Location::List(Entries::AnnotationSet, 0),
+        Target::Offset,
+    ),
+    reference(
+        ENCODED_ARRAY,
+        Location::Item(TYPE_CLASS_DEF, 28),
+        Target::Offset,
+    ),
+    reference(
+        ANNOTATIONS_DIRECTORY,
+        Location::Item(TYPE_CLASS_DEF, 20),
+        Target::Offset,
+    ),
+    reference(
+        CALL_SITE,
+        Location::Item(TYPE_CALL_SITE_ID, 0),
+        Target::Offset,
+    ),
+];
+
+const GROUPS: &[ReferenceGroup] = &{
+    let mut groups = [ReferenceGroup {
+        type_tag: 0,
+        pool_tag: 0,
+    }; TYPES.len()];
+    let mut index = 0;
+    while index < TYPES.len() {
+        groups[index] = ReferenceGroup {
+            type_tag: index as u8,
+            pool_tag: TYPES[index].pool,
+        };
+        index += 1;
+    }
+    groups
+};
+
+/// Dalvik instruction sizes in code units by first opcode, and how many opcodes follow with
+/// the same size. Unused opcodes are absent.
+const INSTRUCTIONS: &[(u8, u32, u8)] = &[
+    (0x00, 1, 1),
+    (0x01, 1, 1),
+    (0x02, 2, 1),
+    (0x03, 3, 1),
+    (0x04, 1, 1),
+    (0x05, 2, 1),
+    (0x06, 3, 1),
+    (0x07, 1, 1),
+    (0x08, 2, 1),
+    (0x09, 3, 1),
+    (0x0A, 1, 4),
+    (0x0E, 1, 1),
+    (0x0F, 1, 3),
+    (0x12, 1, 1),
+    (0x13, 2, 1),
+    (0x14, 3, 1),
+    (0x15, 2, 1),
+    (0x16, 2, 1),
+    (0x17, 3, 1),
+    (0x18, 5, 1),
+    (0x19, 2, 1),
+    (0x1A, 2, 1),
+    (0x1B, 3, 1),
+    (0x1C, 2, 1),
+    (0x1D, 1, 2),
+    (0x1F, 2, 1),
+    (0x20, 2, 1),
+    (0x21, 1, 1),
+    (0x22, 2, 1),
+    (0x23, 2, 1),
+    (0x24, 3, 1),
+    (0x25, 3, 1),
+    (0x26, 3, 1),
+    (0x27, 1, 1),
+    (0x28, 1, 1),
+    (0x29, 2, 1),
+    (0x2A, 3, 1),
+    (0x2B, 3, 2),
+    (0x2D, 2, 5),
+    (0x32, 2, 6),
+    (0x38, 2, 6),
+    (0x44, 2, 14),
+    (0x52, 2, 14),
+    (0x60, 2, 14),
+    (0x6E, 3, 5),
+    (0x74, 3, 5),
+    (0x7B, 1, 21),
+    (0x90, 2, 32),
+    (0xB0, 1, 32),
+    (0xD0, 2, 8),
+    (0xD8, 2, 11),
+    (0xFA, 4, 2),
+    (0xFC, 3, 2),
+    (0xFE, 2, 2),
+];
+
+fn u8_at(image: &[u8], offset: u32) -> Option<u8> {
+    image.get(offset as usize).copied()
+}
+
+fn u16_at(image: &[u8], offset: u32) -> Option<u16> {
+    let offset = offset as usize;
+    Some(u16::from_le_bytes(
+        image.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(image: &[u8], offset: u32) -> Option<u32> {
+    let offset = offset as usize;
+    Some(u32::from_le_bytes(
+        image.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Whether `image` holds `count` items of `size` bytes at `offset`.
+const fn covers_array(image_size: u32, offset: u32, count: u32, size: u32) -> bool {
+    count <= image_size / size && offset <= image_size - count * size
+}
+
+/// A cursor over the image, for the structures parsed up front.
+struct Cursor<'a> {
+    image: &'a [u8],
+    offset: u32,
+}
+
+impl Cursor<'_> {
+    const fn remaining(&self) -> u32 {
+        self.image.len() as u32 - self.offset
+    }
+
+    fn align(&mut self) -> Option<()> {
+        self.offset = self.offset.checked_next_multiple_of(4)?;
+        (self.offset as usize <= self.image.len()).then_some(())
+    }
+
+    fn skip(&mut self, count: u32, size: u32) -> Option<()> {
+        covers_array(self.remaining(), 0, count, size).then(|| self.offset += count * size)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let value = u32_at(self.image, self.offset)?;
+        self.offset += 4;
+        Some(value)
+    }
+
+    fn uleb128(&mut self) -> Option<u32> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = u8_at(self.image, self.offset)?;
+            self.offset += 1;
+            value |= u32::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn sleb128(&mut self) -> Option<i32> {
+        let start = self.offset;
+        let value = self.uleb128()?;
+        let bits = 7 * (self.offset - start);
+        if bits < 32 && value & (1 << (bits - 1)) != 0 {
+            return Some((value | (u32::MAX << bits)) as i32);
+        }
+        Some(value as i32)
+    }
+
+    /// Skip `count` entries of `size` bytes, appending their offsets to `offsets`.
+    fn entries(&mut self, count: u32, size: u32, offsets: &mut Vec<u32>) -> Option<()> {
+        let begin = self.offset;
+        self.skip(count, size)?;
+        offsets.extend((0..count).map(|index| begin + index * size));
+        Some(())
+    }
+
+    /// Skip a code item, returning its offset.
+    fn code_item(&mut self) -> Option<u32> {
+        self.align()?;
+        let offset = self.offset;
+        let tries = u16_at(self.image, offset + 6)?;
+        let instructions = u32_at(self.image, offset + 12)?;
+        self.skip(1, 16)?;
+        self.skip(instructions, CODE_UNIT)?;
+        if tries > 0 {
+            self.align()?;
+            self.skip(tries.into(), 8)?;
+            let handlers = self.uleb128()?;
+            if self.remaining() < handlers {
+                return None;
+            }
+            for _ in 0..handlers {
+                let size = self.sleb128()?;
+                for _ in 0..size.unsigned_abs() {
+                    self.uleb128()?;
+                    self.uleb128()?;
+                }
+                if size <= 0 {
+                    self.uleb128()?;
+                }
+            }
+        }
+        Some(offset)
+    }
+}
+
+/// Size in code units of the instruction at `offset`, including switch and array payloads.
+fn instruction_size(image: &[u8], offset: u32) -> Option<u32> {
+    let opcode = u8_at(image, offset)?;
+    let &(_, size, _) = INSTRUCTIONS
+        .iter()
+        .find(|&&(first, _, count)| first <= opcode && opcode - first < count)?;
+    if opcode != 0x00 {
+        return Some(size);
+    }
+    Some(match u8_at(image, offset + 1)? {
+        0x00 => size,
+        // packed-switch-payload, sparse-switch-payload, fill-array-data-payload.
+        0x01 => u32::from(u16_at(image, offset + 2)?) * 2 + 4,
+        0x02 => u32::from(u16_at(image, offset + 2)?) * 4 + 2,
+        0x03 => {
+            let width = u64::from(u16_at(image, offset + 2)?);
+            let count = u64::from(u32_at(image, offset + 4)?);
+            u32::try_from((count * width).div_ceil(2) + 4).ok()?
+        }
+        _ => return None,
+    })
+}
+
+pub struct DexDisassembler {
+    size: u32,
+    map: Vec<(u16, u32, u32)>,
+    lists: [Vec<u32>; 7],
+    code_items: Vec<u32>,
+}
+
+impl DexDisassembler {
+    pub fn parse(image: &[u8]) -> Result<Self> {
+        if image.len() < HEADER_SIZE as usize
+            || image[..4] != *b"dex\n"
+            || !matches!(&image[4..8], b"035\0" | b"037\0" | b"038\0" | b"039\0")
+        {
+            bail!("Zucchini element is not a DEX file");
+        }
+        let header = |offset| u32_at(image, offset).unwrap_or_default();
+        let size = header(32);
+        let map_offset = header(52);
+        if header(40) != ENDIAN_CONSTANT
+            || size < HEADER_SIZE
+            || size as usize > image.len()
+            || map_offset < HEADER_SIZE
+        {
+            bail!("Malformed DEX header");
+        }
+        let image = &image[..size as usize];
+        Self::parse_items(image, map_offset).ok_or_else(|| anyhow!("Malformed DEX items"))
+    }
+
+    fn parse_items(image: &[u8], map_offset: u32) -> Option<Self> {
+        let size = image.len() as u32;
+        let mut cursor = Cursor {
+            image,
+            offset: map_offset,
+        };
+        let count = cursor.u32()?;
+        let mut map: Vec<(u16, u32, u32)> = Vec::new();
+        for index in 0..count {
+            let entry = map_offset + 4 + index * 12;
+            let item_type = u16_at(image, entry)?;
+            let item_count = u32_at(image, entry + 4)?;
+            let offset = u32_at(image, entry + 8)?;
+            if !covers_array(size, offset, item_count, item_size(item_type))
+                || map.iter().any(|&(other, _, _)| other == item_type)
+            {
+                return None;
+            }
+            map.push((item_type, offset, item_count));
+        }
+        let required = [
+            TYPE_STRING_ID,
+            TYPE_TYPE_ID,
+            TYPE_PROTO_ID,
+            TYPE_FIELD_ID,
+            TYPE_METHOD_ID,
+            TYPE_CLASS_DEF,
+            TYPE_TYPE_LIST,
+            TYPE_CODE,
+        ];
+        if !required
+            .iter()
+            .all(|&item_type| map.iter().any(|&(other, _, _)| other == item_type))
+        {
+            return None;
+        }
+
+        let mut disassembler = Self {
+            size,
+            map,
+            lists: Default::default(),
+            code_items: Vec::new(),
+        };
+        for (item_type, list, entry_size) in [
+            (TYPE_TYPE_LIST, Entries::TypeList, 2),
+            (
+                TYPE_ANNOTATION_SET_REF_LIST,
+                Entries::AnnotationSetRefList,
+                4,
+            ),
+            (TYPE_ANNOTATION_SET, Entries::AnnotationSet, 4),
+        ] {
+            let (offset, count) = disassembler.map_item(item_type);
+            let mut cursor = Cursor { image, offset };
+            let mut offsets = Vec::new();
+            for _ in 0..count {
+                cursor.align()?;
+                let entries = cursor.u32()?;
+                cursor.entries(entries, entry_size, &mut offsets)?;
+            }
+            disassembler.lists[list as usize] = offsets;
+        }
+
+        // Annotations directories, each followed by field, method and parameter annotations.
+        let (offset, count) = disassembler.map_item(TYPE_ANNOTATIONS_DIRECTORY);
+        let mut cursor = Cursor { image, offset };
+        for _ in 0..count {
+            cursor.align()?;
+            disassembler.lists[Entries::AnnotationsDirectory as usize].push(cursor.offset);
+            cursor.u32()?;
+            let fields = cursor.u32()?;
+            let methods = cursor.u32()?;
+            let parameters = cursor.u32()?;
+            for (list, count) in [
+                (Entries::FieldAnnotation, fields),
+                (Entries::MethodAnnotation, methods),
+                (Entries::ParameterAnnotation, parameters),
+            ] {
+                cursor.entries(count, 8, &mut disassembler.lists[list as usize])?;
+            }
+        }
+
+        let (offset, count) = disassembler.map_item(TYPE_CODE);
+        let mut cursor = Cursor { image, offset };
+        for _ in 0..count {
+            disassembler.code_items.push(cursor.code_item()?);
+        }
+        (!disassembler.code_items.is_empty()).then_some(disassembler)
+    }
+
+    /// Offset and item count of a map item type, empty if absent.
+    fn map_item(&self, item_type: u16) -> (u32, u32) {
+        self.map
+            .iter()
+            .find(|&&(other, _, _)| other == item_type)
+            .map_or((0, 0), |&(_, offset, count)| (offset, count))
+    }
+
+    /// Target of the reference at `location`: `Ok(None)` for "none", `Err(())` if invalid.
+    fn read_target(&self, image: &[u8], target: &Target, location: u32) -> Result<Option<u32>, ()> {
+        match *target {
+            Target::Index(item_type, width) => {
+                let index = if width == 2 {
+                    u16_at(image, location).map(u32::from)
+                } else {
+                    u32_at(image, location)
+                }
+                .ok_or(())?;
+                if index == NO_INDEX {
+                    return Ok(None);
+                }
+                let (offset, count) = self.map_item(item_type);
+                if index >= count {
+                    return Err(());
+                }
+                Ok(Some(offset + index * item_size(item_type)))
+            }
+            Target::Offset => match u32_at(image, location).ok_or(())? {
+                0 => Ok(None),
+                target if target >= self.size => Err(()),
+                target => Ok(Some(target)),
+            },
+            Target::RelCode(width) => {
+                let displacement = match width {
+                    1 => u8_at(image, location).map(|value| i32::from(value as i8)),
+                    2 => u16_at(image, location).map(|value| i32::from(value as i16)),
+                    _ => u32_at(image, location).map(|value| value as i32),
+                }
+                .ok_or(())?;
+                let instruction = location - width.min(2);
+                Ok(Some(instruction.wrapping_add_signed(
+                    displacement.wrapping_mul(CODE_UNIT as i32),
+                )))
+            }
+        }
+    }
+
+    fn read_items(
+        &self,
+        image: &[u8],
+        reference_type: &ReferenceType,
+        (item_type, field): (u16, u32),
+        handle_kind: Option<bool>,
+        lo: u32,
+        hi: u32,
+    ) -> Vec<Reference> {
+        let (offset, count) = self.map_item(item_type);
+        let item_size = item_size(item_type);
+        let first = offset + field;
+        let start = if lo > first {
+            (lo - first).div_ceil(item_size)
+        } else {
+            0
+        };
+        let mut references = Vec::new();
+        for index in start..count {
+            let location = first + index * item_size;
+            if location >= hi {
+                break;
+            }
+            // Method handles refer to fields or methods depending on their kind.
+            if let Some(field_kind) = handle_kind {
+                let kind = u16_at(image, location - field).unwrap_or(u16::MAX);
+                if (kind <= 0x03) != field_kind || kind > 0x08 {
+                    continue;
+                }
+            }
+            match self.read_target(image, &reference_type.target, location) {
+                Ok(Some(target)) => references.push(Reference { location, target }),
+                Ok(None) => {}
+                Err(()) => break,
+            }
+        }
+        references
+    }
+
+    fn read_list(
+        &self,
+        image: &[u8],
+        reference_type: &ReferenceType,
+        (list, field): (Entries, u32),
+        lo: u32,
+        hi: u32,
+    ) -> Vec<Reference> {
+        let offsets = &self.lists[list as usize];
+        let start = offsets.partition_point(|&offset| offset + field < lo);
+        let mut references = Vec::new();
+        for &offset in &offsets[start..] {
+            let location = offset + field;
+            if location >= hi {
+                break;
+            }
+            match self.read_target(image, &reference_type.target, location) {
+                Ok(Some(target)) => references.push(Reference { location, target }),
+                Ok(None) => {}
+                Err(()) => break,
+            }
+        }
+        references
+    }
+
+    fn read_code(
+        &self,
+        image: &[u8],
+        reference_type: &ReferenceType,
+        operands: &[(u8, u8, u32)],
+        lo: u32,
+        hi: u32,
+    ) -> Vec<Reference> {
+        let start = self
+            .code_items
+            .partition_point(|&offset| offset <= lo)
+            .saturating_sub(1);
+        let mut references = Vec::new();
+        for &code_item in &self.code_items[start..] {
+            let Some(units) = u32_at(image, code_item + 12) else {
+                break;
+            };
+            let mut instruction = code_item + 16;
+            let end = instruction + units * CODE_UNIT;
+            while instruction < end {
+                if instruction >= hi {
+                    return references;
+                }
+                let Some(size) = instruction_size(image, instruction)
+                    .and_then(|size| size.checked_mul(CODE_UNIT))
+                    .filter(|&size| size <= end - instruction)
+                else {
+                    break;
+                };
+                let opcode = image[instruction as usize];
+                let operand = operands
+                    .iter()
+                    .find(|&&(first, last, _)| (first..=last).contains(&opcode));
+                let current = instruction;
+                instruction += size;
+                let Some(&(_, _, operand)) = operand else {
+                    continue;
+                };
+                let location = current + operand;
+                if location < lo {
+                    continue;
+                }
+                if location >= hi {
+                    return references;
+                }
+                if let Ok(Some(target)) = self.read_target(image, &reference_type.target, location)
+                {
+                    references.push(Reference { location, target });
+                }
+            }
+        }
+        references
+    }
+}
+
+impl Disassembler for DexDisassembler {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn reference_groups(&self) -> &'static [ReferenceGroup] {
+        GROUPS
+    }
+
+    fn read(&self, image: &[u8], group: ReferenceGroup, lo: u32, hi: u32) -> Vec<Reference> {
+        let image = &image[..self.size as usize];
+        let reference_type = &TYPES[usize::from(group.type_tag)];
+        match reference_type.location {
+            Location::Item(item_type, field) => {
+                self.read_items(image, reference_type, (item_type, field), None, lo, hi)
+            }
+            Location::MethodHandle { field } => self.read_items(
+                image,
+                reference_type,
+                (TYPE_METHOD_HANDLE, 4),
+                Some(field),
+                lo,
+                hi,
+            ),
+            Location::List(list, field) => {
+                self.read_list(image, reference_type, (list, field), lo, hi)
+            }
+            Location::Code(operands) => self.read_code(image, reference_type, operands, lo, hi),
+        }
+    }
+
+    fn write(&self, image: &mut [u8], group: ReferenceGroup, reference: Reference) {
+        let location = reference.location as usize;
+        let bytes = match TYPES[usize::from(group.type_tag)].target {
+            Target::Index(item_type, width) => {
+                let (offset, count) = self.map_item(item_type);
+                let index = reference.target.wrapping_sub(offset) / item_size(item_type);
+                if index >= count {
+                    return;
+                }
+                if width == 2 {
+                    (index as u16).to_le_bytes().to_vec()
+                } else {
+                    index.to_le_bytes().to_vec()
+                }
+            }
+            Target::Offset => reference.target.to_le_bytes().to_vec(),
+            Target::RelCode(width) => {
+                let instruction = reference.location - width.min(2);
+                let delta = reference.target.wrapping_sub(instruction) as i32 / CODE_UNIT as i32;
+                match width {
+                    1 => match i8::try_from(delta) {
+                        Ok(delta) => delta.to_le_bytes().to_vec(),
+                        Err(_) => return,
+                    },
+                    2 => match i16::try_from(delta) {
+                        Ok(delta) => delta.to_le_bytes().to_vec(),
+                        Err(_) => return,
+                    },
+                    _ => delta.to_le_bytes().to_vec(),
+                }
+            }
+        };
+        if let Some(body) = image.get_mut(location..location + bytes.len()) {
+            body.copy_from_slice(&bytes);
+        }
+    }
+}
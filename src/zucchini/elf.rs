@@ -0,0 +1,503 @@
+//! ELF references, following Zucchini's `DisassemblerElf`.
+//!
+//! Relocation sections give relative relocations (type 0), whose targets are the locations of
+//! absolute addresses (type 1). Executable sections are scanned for relative branches between
+//! those addresses (type 2 and up, one per branch encoding on ARM).
+
+use anyhow::{Result, anyhow, bail};
+
+use super::{
+    abs32::{AbsoluteAddress, abs32_gaps, abs32_locations, read_abs32, write_abs32},
+    address::{
+        AddressTranslator, INVALID_OFFSET, INVALID_RVA, OFFSET_BOUND, Unit, range_is_bounded,
+    },
+    arm::{ArmAddr, find_rel32_arm, is_thumb2, read_rel32_arm, write_rel32_arm},
+    reference::{Disassembler, Reference, ReferenceGroup},
+    rel32::{find_rel32_x86, read_rel32_x86, write_rel32_x86},
+};
+
+const ET_EXEC: u64 = 2;
+const ET_DYN: u64 = 3;
+const SHT_PROGBITS: u32 = 1;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_TLS: u64 = 0x400;
+
+const RELOC: u8 = 0;
+const ABS32: u8 = 1;
+const REL32: u8 = 2;
+
+const fn group(type_tag: u8, pool_tag: u8) -> ReferenceGroup {
+    ReferenceGroup { type_tag, pool_tag }
+}
+
+/// An ELF machine supported by Zucchini.
+pub trait ElfArch {
+    const MACHINE: u64;
+    const IS_64_BIT: bool;
+    /// Type of relative relocations, whose targets hold absolute addresses.
+    const RELATIVE_RELOC: u64;
+    /// Branch encodings of each rel32 type, or none for x86 and x64.
+    const REL32_MODES: &'static [ArmAddr];
+    const GROUPS: &'static [ReferenceGroup];
+}
+
+pub enum X86 {}
+pub enum X64 {}
+pub enum AArch32 {}
+pub enum AArch64 {}
+
+impl ElfArch for X86 {
+    const MACHINE: u64 = 3;
+    const IS_64_BIT: bool = false;
+    const RELATIVE_RELOC: u64 = 8;
+    const REL32_MODES: &'static [ArmAddr] = &[];
+    const GROUPS: &'static [ReferenceGroup] = &[
+        group(RELOC, RELOC),
+        group(ABS32, ABS32),
+        group(REL32, REL32),
+    ];
+}
+
+impl ElfArch for X64 {
+    const MACHINE: u64 = 62;
+    const IS_64_BIT: bool = true;
+    const RELATIVE_RELOC: u64 = 8;
+    const REL32_MODES: &'static [ArmAddr] = &[];
+    const GROUPS: &'static [ReferenceGroup] = &[
+        group(RELOC, RELOC),
+        group(ABS32, ABS32),
+        group(REL32, REL32),
+    ];
+}
+
+impl ElfArch for AArch32 {
+    const MACHINE: u64 = 40;
+    const IS_64_BIT: bool = false;
+    const RELATIVE_RELOC: u64 = 23;
+    const REL32_MODES: &'static [ArmAddr] = &[
+        ArmAddr::A24,
+        ArmAddr::T8,
+        ArmAddr::T11,
+        ArmAddr::T20,
+        ArmAddr::T24,
+    ];
+    const GROUPS: &'static [ReferenceGroup] = &[
+        group(RELOC, RELOC),
+        group(ABS32, ABS32),
+        group(REL32, REL32),
+        group(REL32 + 1, REL32),
+        group(REL32 + 2, REL32),
+        group(REL32 + 3, REL32),
+        group(REL32 + 4, REL32),
+    ];
+}
+
+impl ElfArch for AArch64 {
+    const MACHINE: u64 = 183;
+    const IS_64_BIT: bool = true;
+    const RELATIVE_RELOC: u64 = 1027;
+    const REL32_MODES: &'static [ArmAddr] = &[ArmAddr::Immd14, ArmAddr::Immd19, ArmAddr::Immd26];
+    const GROUPS: &'static [ReferenceGroup] = &[
+        group(RELOC, RELOC),
+        group(ABS32, ABS32),
+        group(REL32, REL32),
+        group(REL32 + 1, REL32),
+        group(REL32 + 2, REL32),
+    ];
+}
+
+/// Whether `[begin, begin + size)` lies within `[0, bound]`, allowing empty ranges at `bound`.
+const fn fits_in(begin: u64, size: u64, bound: u64) -> bool {
+    begin <= bound && size <= bound - begin
+}
+
+/// A little-endian field of `size` bytes at `offset`.
+fn field(image: &[u8], offset: u64, size: usize) -> Result<u64> {
+    let bytes = usize::try_from(offset)
+        .ok()
+        .and_then(|offset| image.get(offset..offset.checked_add(size)?))
+        .ok_or_else(|| anyhow!("Truncated ELF image"))?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| (value << 8) | u64::from(byte)))
+}
+
+/// Field layout of one ELF class: offsets of each field and the size of address-sized ones.
+struct Layout {
+    word: usize,
+    header_size: u64,
+    /// `e_phoff`, `e_shoff`, then the `e_phentsize` `e_phnum` `e_shentsize` `e_shnum` halves.
+    header: [u64; 6],
+    section_size: u64,
+    /// `sh_type`, `sh_flags`, `sh_addr`, `sh_offset`, `sh_size`, `sh_entsize`.
+    section: [u64; 6],
+    segment_size: u64,
+    /// `p_offset`, `p_filesz`.
+    segment: [u64; 2],
+}
+
+const ELF32: Layout = Layout {
+    word: 4,
+    header_size: 52,
+    header: [28, 32, 42, 44, 46, 48],
+    section_size: 40,
+    section: [4, 8, 12, 16, 20, 36],
+    segment_size: 32,
+    segment: [4, 16],
+};
+
+const ELF64: Layout = Layout {
+    word: 8,
+    header_size: 64,
+    header: [32, 40, 54, 56, 58, 60],
+    section_size: 64,
+    section: [4, 8, 16, 24, 32, 56],
+    segment_size: 56,
+    segment: [8, 32],
+};
+
+struct Section {
+    sh_type: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    entry_size: u64,
+}
+
+impl Section {
+    fn parse(image: &[u8], layout: &Layout, offset: u64) -> Result<Self> {
+        let [sh_type, flags, addr, sh_offset, size, entry_size] = layout.section;
+        Ok(Self {
+            sh_type: field(image, offset + sh_type, 4)? as u32,
+            flags: field(image, offset + flags, layout.word)?,
+            addr: field(image, offset + addr, layout.word)?,
+            offset: field(image, offset + sh_offset, layout.word)?,
+            size: field(image, offset + size, layout.word)?,
+            entry_size: field(image, offset + entry_size, layout.word)?,
+        })
+    }
+
+    /// Whether the section maps offsets to RVAs, rejecting malformed sections.
+    fn is_mapped(&self, image_size: u64) -> Result<bool> {
+        let offset_bound = if self.sh_type == SHT_NOBITS {
+            OFFSET_BOUND.into()
+        } else {
+            image_size
+        };
+        if !fits_in(self.addr, self.size, OFFSET_BOUND.into())
+            || !fits_in(self.offset, self.size, offset_bound)
+        {
+            bail!("Malformed ELF section");
+        }
+        // Unmapped and thread-local sections would only confuse translation.
+        Ok(self.size > 0
+            && self.addr > 0
+            && !(self.sh_type == SHT_NOBITS && self.flags & SHF_TLS != 0))
+    }
+}
+
+/// A relocation section: its offset, size and entry size.
+#[derive(Clone, Copy)]
+struct RelocSection {
+    offset: u32,
+    size: u32,
+    entry_size: u32,
+}
+
+pub struct ElfDisassembler {
+    size: u32,
+    is_64_bit: bool,
+    relative_reloc: u64,
+    groups: &'static [ReferenceGroup],
+    rel32_modes: &'static [ArmAddr],
+    translator: AddressTranslator,
+    /// Sorted by offset.
+    reloc_sections: Vec<RelocSection>,
+    abs32_locations: Vec<u32>,
+    /// Sorted locations of each rel32 type.
+    rel32_locations: Vec<Vec<u32>>,
+}
+
+impl ElfDisassembler {
+    pub fn parse<A: ElfArch>(image: &[u8]) -> Result<Self> {
+        let layout = if A::IS_64_BIT { &ELF64 } else { &ELF32 };
+        let image_size = image.len() as u64;
+        if image_size < layout.header_size
+            || image[..4] != *b"\x7FELF"
+            || image[4] != if A::IS_64_BIT { 2 } else { 1 }
+            || image[5] != 1
+        {
+            bail!("Zucchini element is not a little-endian ELF of its class");
+        }
+        if !matches!(field(image, 16, 2)?, ET_EXEC | ET_DYN) {
+            bail!("Zucchini element is not an ELF executable or shared object");
+        }
+        if field(image, 18, 2)? != A::MACHINE {
+            bail!("ELF machine does not match the Zucchini element type");
+        }
+
+        let [phoff, shoff, phentsize, phnum, shentsize, shnum] = layout.header;
+        let segments = field(image, phoff, layout.word)?;
+        let segment_count = field(image, phnum, 2)?;
+        let sections = field(image, shoff, layout.word)?;
+        let section_count = field(image, shnum, 2)?;
+        if (segment_count > 0 && field(image, phentsize, 2)? != layout.segment_size)
+            || (section_count > 0 && field(image, shentsize, 2)? != layout.section_size)
+            || !fits_in(segments, segment_count * layout.segment_size, image_size)
+            || !fits_in(sections, section_count * layout.section_size, image_size)
+        {
+            bail!("Malformed ELF header tables");
+        }
+        let mut offset_bound = layout
+            .header_size
+            .max(segments + segment_count * layout.segment_size)
+            .max(sections + section_count * layout.section_size);
+
+        let sections = (0..section_count)
+            .map(|index| Section::parse(image, layout, sections + index * layout.section_size))
+            .collect::<Result<Vec<_>>>()?;
+        let mut units = Vec::new();
+        let mut mapped = Vec::new();
+        for section in sections {
+            if !section.is_mapped(image_size)? {
+                continue;
+            }
+            // Bounded by `is_mapped`. `SHT_NOBITS` sections only have RVAs, which dangle.
+            units.push(Unit {
+                offset_begin: section.offset as u32,
+                offset_size: if section.sh_type == SHT_NOBITS {
+                    0
+                } else {
+                    section.size as u32
+                },
+                rva_begin: section.addr as u32,
+                rva_size: section.size as u32,
+            });
+            if section.sh_type != SHT_NOBITS {
+                offset_bound = offset_bound.max(section.offset + section.size);
+            }
+            mapped.push(section);
+        }
+        let translator = AddressTranslator::new(units)?;
+
+        let [p_offset, p_filesz] = layout.segment;
+        for index in 0..segment_count {
+            let segment = segments + index * layout.segment_size;
+            let offset = field(image, segment + p_offset, layout.word)?;
+            let size = field(image, segment + p_filesz, layout.word)?;
+            if !range_is_bounded(offset, size, OFFSET_BOUND.into()) {
+                bail!("Malformed ELF segment");
+            }
+            offset_bound = offset_bound.max(offset + size);
+        }
+        if offset_bound > image_size {
+            bail!("ELF image is truncated");
+        }
+        let image = &image[..offset_bound as usize];
+
+        let mut disassembler = Self {
+            size: offset_bound as u32,
+            is_64_bit: A::IS_64_BIT,
+            relative_reloc: A::RELATIVE_RELOC,
+            groups: A::GROUPS,
+            rel32_modes: A::REL32_MODES,
+            translator,
+            reloc_sections: Vec::new(),
+            abs32_locations: Vec::new(),
+            rel32_locations: vec![Vec::new(); A::GROUPS.len() - 2],
+        };
+
+        let mut code_sections = Vec::new();
+        for section in &mapped {
+            match section.sh_type {
+                SHT_REL | SHT_RELA => disassembler.reloc_sections.push(RelocSection {
+                    offset: section.offset as u32,
+                    size: section.size as u32,
+                    entry_size: u32::try_from(section.entry_size).unwrap_or(u32::MAX),
+                }),
+                SHT_PROGBITS if section.flags & SHF_EXECINSTR != 0 => {
+                    code_sections.push(section);
+                }
+                _ => {}
+            }
+        }
+        disassembler
+            .reloc_sections
+            .sort_by_key(|section| section.offset);
+        code_sections.sort_by_key(|section| section.offset);
+        // Entries too small to hold a relocation would make every reference unreliable.
+        let min_entry_size = 2 * layout.word as u32;
+        if disassembler
+            .reloc_sections
+            .iter()
+            .any(|section| section.entry_size < min_entry_size)
+        {
+            disassembler.reloc_sections.clear();
+        }
+
+        let address = disassembler.absolute_address();
+        let targets = disassembler
+            .read_relocs(image, 0, offset_bound as u32)
+            .into_iter()
+            .map(|reference| reference.target)
+            .collect();
+        disassembler.abs32_locations =
+            abs32_locations(image, address, &disassembler.translator, targets);
+
+        let code_ranges = code_sections
+            .iter()
+            .map(|section| (section.offset as u32, section.size as u32))
+            .collect::<Vec<_>>();
+        for section in code_sections {
+            let begin = section.offset as u32;
+            let end = begin + section.size as u32;
+            let gaps = abs32_gaps(&disassembler.abs32_locations, address.width(), begin, end);
+            let translator = &disassembler.translator;
+            let locations = &mut disassembler.rel32_locations;
+            match A::MACHINE {
+                X86::MACHINE | X64::MACHINE => {
+                    let start_rva = section.addr as u32;
+                    find_rel32_x86(
+                        image,
+                        translator,
+                        A::IS_64_BIT,
+                        gaps,
+                        (start_rva, start_rva + section.size as u32),
+                        &mut locations[0],
+                    );
+                }
+                AArch32::MACHINE => {
+                    let code = &image[begin as usize..end as usize];
+                    let (modes, locations) = if is_thumb2(code, section.addr as u32) {
+                        (&A::REL32_MODES[1..], &mut locations[1..])
+                    } else {
+                        (&A::REL32_MODES[..1], &mut locations[..1])
+                    };
+                    find_rel32_arm(image, translator, modes, gaps, &code_ranges, locations);
+                }
+                _ => find_rel32_arm(
+                    image,
+                    translator,
+                    A::REL32_MODES,
+                    gaps,
+                    &code_ranges,
+                    locations,
+                ),
+            }
+        }
+        for locations in &mut disassembler.rel32_locations {
+            locations.sort_unstable();
+        }
+        Ok(disassembler)
+    }
+
+    const fn absolute_address(&self) -> AbsoluteAddress {
+        AbsoluteAddress {
+            is_64_bit: self.is_64_bit,
+            image_base: 0,
+        }
+    }
+
+    /// Relative relocations whose entries start within `lo..hi`.
+    fn read_relocs(&self, image: &[u8], lo: u32, hi: u32) -> Vec<Reference> {
+        let word = if self.is_64_bit { 8 } else { 4 };
+        let mut references = Vec::new();
+        for section in &self.reloc_sections {
+            let end = section.offset + section.size;
+            let mut cursor = section.offset;
+            if lo > cursor {
+                cursor += (lo - cursor).div_ceil(section.entry_size) * section.entry_size;
+            }
+            while cursor < hi && cursor + section.entry_size <= end {
+                let location = cursor;
+                cursor += section.entry_size;
+                let (Ok(offset), Ok(info)) = (
+                    field(image, location.into(), word),
+                    field(image, u64::from(location) + word as u64, word),
+                ) else {
+                    break;
+                };
+                let reloc_type = if self.is_64_bit {
+                    info & 0xFFFF_FFFF
+                } else {
+                    info & 0xFF
+                };
+                if reloc_type != self.relative_reloc {
+                    continue;
+                }
+                let Ok(rva) = u32::try_from(offset) else {
+                    continue;
+                };
+                let target = self.translator.rva_to_offset(rva);
+                if target != INVALID_OFFSET {
+                    references.push(Reference { location, target });
+                }
+            }
+        }
+        references
+    }
+
+    fn write_reloc(&self, image: &mut [u8], reference: Reference) {
+        let rva = self.translator.offset_to_rva(reference.target);
+        if rva == INVALID_RVA {
+            return;
+        }
+        let location = reference.location as usize;
+        if self.is_64_bit {
+            if let Some(body) = image.get_mut(location..location + 8) {
+                body.copy_from_slice(&u64::from(rva).to_le_bytes());
+            }
+        } else if let Some(body) = image.get_mut(location..location + 4) {
+            body.copy_from_slice(&rva.to_le_bytes());
+        }
+    }
+}
+
+impl Disassembler for ElfDisassembler {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn reference_groups(&self) -> &'static [ReferenceGroup] {
+        self.groups
+    }
+
+    fn read(&self, image: &[u8], group: ReferenceGroup, lo: u32, hi: u32) -> Vec<Reference> {
+        let image = &image[..self.size as usize];
+        match group.type_tag {
+            RELOC => self.read_relocs(image, lo, hi),
+            ABS32 => read_abs32(
+                image,
+                self.absolute_address(),
+                &self.translator,
+                &self.abs32_locations,
+                lo,
+                hi,
+            ),
+            tag => {
+                let index = usize::from(tag - REL32);
+                let locations = &self.rel32_locations[index];
+                match self.rel32_modes.get(index) {
+                    Some(&addr) => read_rel32_arm(image, &self.translator, addr, locations, lo, hi),
+                    None => read_rel32_x86(image, &self.translator, locations, lo, hi),
+                }
+            }
+        }
+    }
+
+    fn write(&self, image: &mut [u8], group: ReferenceGroup, reference: Reference) {
+        match group.type_tag {
+            RELOC => self.write_reloc(image, reference),
+            ABS32 => write_abs32(image, self.absolute_address(), &self.translator, reference),
+            tag => match self.rel32_modes.get(usize::from(tag - REL32)) {
+                Some(&addr) => write_rel32_arm(image, &self.translator, addr, reference),
+                None => write_rel32_x86(image, &self.translator, reference),
+            },
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Zucchini patch applier, used by `ZUCCHINI` operations.
+//!
+//! A Zucchini patch splits the new image into elements, each patched from a region of the old
+//! image. Every element is rebuilt from equivalences (copies from the old region), extra data
+//! and raw byte deltas; executable elements additionally carry reference corrections, applied
+//! by disassembling the old and new elements (see [`reference`]).
+
+mod abs32;
+mod address;
+mod arm;
+mod dex;
+mod elf;
+mod reference;
+mod rel32;
+mod win32;
+
+use std::io::Read;
+
+use anyhow::{Context, Result, anyhow, bail};
+
+/// Magic at the start of every Zucchini patch.
+const ZUCCHINI_MAGIC: &[u8; 4] = b"Zucc";
+const MAJOR_VERSION: u16 = 1;
+const PATCH_HEADER_SIZE: usize = 24;
+const ELEMENT_HEADER_SIZE: usize = 22;
+
+/// Executable type of a patch element, as serialized in patches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutableType {
+    NoOp,
+    Win32X86,
+    Win32X64,
+    ElfX86,
+    ElfX64,
+    ElfAArch32,
+    ElfAArch64,
+    Dex,
+    Ztf,
+}
+
+impl ExecutableType {
+    fn from_raw(raw: u32) -> Result<Self> {
+        Ok(match raw {
+            0 => Self::NoOp,
+            1 => Self::Win32X86,
+            2 => Self::Win32X64,
+            3 => Self::ElfX86,
+            4 => Self::ElfX64,
+            5 => Self::ElfAArch32,
+            6 => Self::ElfAArch64,
+            7 => Self::Dex,
+            8 => Self::Ztf,
+            _ => bail!("Unknown Zucchini executable type {raw}"),
+        })
+    }
+}
+
+/// Little-endian cursor over patch data.
+#[derive(Clone, Copy)]
+struct Source<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Source<'a> {
+    const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    const fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if count > self.data.len() {
+            bail!("Truncated Zucchini patch");
+        }
+        let (head, tail) = self.data.split_at(count);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A `u32` size-prefixed buffer.
+    fn buffer(&mut self) -> Result<Self> {
+        let size = self.u32()? as usize;
+        Ok(Self::new(self.take(size)?))
+    }
+
+    /// An unsigned LEB128 value.
+    fn var_uint(&mut self) -> Result<u32> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            value |= u32::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Malformed varint in Zucchini patch")
+    }
+
+    /// A zigzag-encoded signed LEB128 value.
+    fn var_int(&mut self) -> Result<i32> {
+        let value = self.var_uint()?;
+        Ok(if value & 1 == 0 {
+            (value >> 1) as i32
+        } else {
+            !(value >> 1) as i32
+        })
+    }
+}
+
+/// A region of the old image copied to the new image.
+#[derive(Clone, Copy, Debug)]
+struct Equivalence {
+    src_offset: u32,
+    dst_offset: u32,
+    length: u32,
+}
+
+impl Equivalence {
+    const fn src_end(&self) -> u32 {
+        self.src_offset + self.length
+    }
+}
+
+/// Decodes equivalences from their three delta-encoded streams.
+struct EquivalenceSource<'a> {
+    src_skip: Source<'a>,
+    dst_skip: Source<'a>,
+    copy_count: Source<'a>,
+    previous_src_offset: u32,
+    previous_dst_offset: u32,
+}
+
+impl<'a> EquivalenceSource<'a> {
+    fn parse(source: &mut Source<'a>) -> Result<Self> {
+        Ok(Self {
+            src_skip: source.buffer()?,
+            dst_skip: source.buffer()?,
+            copy_count: source.buffer()?,
+            previous_src_offset: 0,
+            previous_dst_offset: 0,
+        })
+    }
+
+    fn next(&mut self) -> Result<Option<Equivalence>> {
+        if self.src_skip.is_empty() || self.dst_skip.is_empty() || self.copy_count.is_empty() {
+            return Ok(None);
+        }
+        let overflow = || anyhow!("Equivalence offset overflow in Zucchini patch");
+        let length = self.copy_count.var_uint()?;
+        let src_offset = self
+            .previous_src_offset
+            .checked_add_signed(self.src_skip.var_int()?)
+            .ok_or_else(overflow)?;
+        self.previous_src_offset = src_offset.checked_add(length).ok_or_else(overflow)?;
+        let dst_offset = self
+            .previous_dst_offset
+            .checked_add(self.dst_skip.var_uint()?)
+            .ok_or_else(overflow)?;
+        self.previous_dst_offset = dst_offset.checked_add(length).ok_or_else(overflow)?;
+        Ok(Some(Equivalence {
+            src_offset,
+            dst_offset,
+            length,
+        }))
+    }
+
+    const fn is_done(&self) -> bool {
+        self.src_skip.is_empty() && self.dst_skip.is_empty() && self.copy_count.is_empty()
+    }
+}
+
+/// Decodes raw byte deltas applied on top of equivalences.
+struct RawDeltaSource<'a> {
+    copy_offsets: Source<'a>,
+    diffs: Source<'a>,
+    compensation: u32,
+}
+
+impl<'a> RawDeltaSource<'a> {
+    fn parse(source: &mut Source<'a>) -> Result<Self> {
+        Ok(Self {
+            copy_offsets: source.buffer()?,
+            diffs: source.buffer()?,
+            compensation: 0,
+        })
+    }
+
+    /// Next `(copy_offset, diff)`, where `copy_offset` counts bytes across all equivalences.
+    fn next(&mut self) -> Result<Option<(u32, u8)>> {
+        if self.copy_offsets.is_empty() || self.diffs.is_empty() {
+            return Ok(None);
+        }
+        let copy_offset = self
+            .compensation
+            .checked_add(self.copy_offsets.var_uint()?)
+            .ok_or_else(|| anyhow!("Raw delta offset overflow in Zucchini patch"))?;
+        let diff = self.diffs.u8()?;
+        if diff == 0 {
+            bail!("Zero raw delta in Zucchini patch");
+        }
+        self.compensation = copy_offset + 1;
+        Ok(Some((copy_offset, diff)))
+    }
+}
+
+/// Patch data for one element.
+struct ElementPatch<'a> {
+    old_offset: u32,
+    old_length: u32,
+    new_offset: u32,
+    new_length: u32,
+    exe_type: ExecutableType,
+    equivalences: EquivalenceSource<'a>,
+    extra_data: Source<'a>,
+    raw_deltas: RawDeltaSource<'a>,
+    reference_deltas: Source<'a>,
+    /// Extra targets of each reference pool, by pool tag.
+    extra_targets: Vec<(u8, Source<'a>)>,
+}
+
+impl<'a> ElementPatch<'a> {
+    fn parse(source: &mut Source<'a>) -> Result<Self> {
+        let mut header = Source::new(source.take(ELEMENT_HEADER_SIZE)?);
+        let old_offset = header.u32()?;
+        let old_length = header.u32()?;
+        let new_offset = header.u32()?;
+        let new_length = header.u32()?;
+        let exe_type = ExecutableType::from_raw(header.u32()?)?;
+        let _version = header.u16()?;
+
+        let equivalences = EquivalenceSource::parse(source)?;
+        let extra_data = source.buffer()?;
+        let raw_deltas = RawDeltaSource::parse(source)?;
+        let reference_deltas = source.buffer()?;
+
+        let extra_target_pools = source.u32()?;
+        let mut extra_targets = Vec::new();
+        for _ in 0..extra_target_pools {
+            let tag = source.u8()?;
+            if tag == 0xFF || extra_targets.iter().any(|&(pool_tag, _)| pool_tag == tag) {
+                bail!("Invalid extra target pool tag {tag} in Zucchini patch");
+            }
+            extra_targets.push((tag, source.buffer()?));
+        }
+
+        Ok(Self {
+            old_offset,
+            old_length,
+            new_offset,
+            new_length,
+            exe_type,
+            equivalences,
+            extra_data,
+            raw_deltas,
+            reference_deltas,
+            extra_targets,
+        })
+    }
+
+    fn apply(self, old_image: &[u8], new_image: &mut [u8]) -> Result<()> {
+        let old_end = self.old_offset as usize + self.old_length as usize;
+        let new_end = self.new_offset as usize + self.new_length as usize;
+        let (Some(old), Some(new)) = (
+            old_image.get(self.old_offset as usize..old_end),
+            new_image.get_mut(self.new_offset as usize..new_end),
+        ) else {
+            bail!("Zucchini element is out of bounds");
+        };
+
+        // Equivalences are decoded twice: once to copy data, once to locate raw deltas.
+        let equivalences = {
+            let mut source = self.equivalences;
+            let mut equivalences = Vec::new();
+            while let Some(equivalence) = source.next()? {
+                equivalences.push(equivalence);
+            }
+            if !source.is_done() {
+                bail!("Trailing equivalence data in Zucchini patch");
+            }
+            equivalences
+        };
+
+        apply_equivalences_and_extra_data(old, &equivalences, self.extra_data, new)?;
+        apply_raw_deltas(&equivalences, self.raw_deltas, new)?;
+        reference::correct_references(
+            self.exe_type,
+            old,
+            new,
+            &equivalences,
+            self.reference_deltas,
+            &self.extra_targets,
+        )
+    }
+}
+
+/// Fill the new element with copies from the old element and extra data in between.
+fn apply_equivalences_and_extra_data(
+    old: &[u8],
+    equivalences: &[Equivalence],
+    mut extra_data: Source,
+    new: &mut [u8],
+) -> Result<()> {
+    let mut position = 0;
+    for equivalence in equivalences {
+        let src = equivalence.src_offset as usize;
+        let dst = equivalence.dst_offset as usize;
+        let length = equivalence.length as usize;
+        if dst < position || dst + length > new.len() || src + length > old.len() {
+            bail!("Invalid equivalence in Zucchini patch");
+        }
+        new[position..dst].copy_from_slice(extra_data.take(dst - position)?);
+        new[dst..dst + length].copy_from_slice(&old[src..src + length]);
+        position = dst + length;
+    }
+    let tail = new.len() - position;
+    new[position..].copy_from_slice(extra_data.take(tail)?);
+    if !extra_data.is_empty() {
+        bail!("Trailing extra data in Zucchini patch");
+    }
+    Ok(())
+}
+
+/// Add raw byte deltas to bytes copied by equivalences.
+fn apply_raw_deltas(
+    equivalences: &[Equivalence],
+    mut raw_deltas: RawDeltaSource,
+    new: &mut [u8],
+) -> Result<()> {
+    let mut equivalences = equivalences.iter();
+    let mut current = equivalences.next();
+    let mut base_copy_offset = 0u64;
+    while let Some((copy_offset, diff)) = raw_deltas.next()? {
+        let copy_offset = u64::from(copy_offset);
+        while let Some(equivalence) = current {
+            if base_copy_offset + u64::from(equivalence.length) > copy_offset {
+                break;
+            }
+            base_copy_offset += u64::from(equivalence.length);
+            current = equivalences.next();
+        }
+        let Some(equivalence) = current else {
+            bail!("Raw delta past the last equivalence in Zucchini patch");
+        };
+        let offset = u64::from(equivalence.dst_offset) + copy_offset - base_copy_offset;
+        let byte = &mut new[offset as usize];
+        *byte = byte.wrapping_add(diff);
+    }
+    Ok(())
+}
+
+/// Decompress brotli-wrapped patches, as emitted by payload generators.
+fn unwrap_patch(patch: &[u8]) -> Result<std::borrow::Cow<'_, [u8]>> {
+    if patch.starts_with(ZUCCHINI_MAGIC) {
+        return Ok(patch.into());
+    }
+    let mut decompressed = Vec::new();
+    brotli_decompressor::Decompressor::new(patch, 4096)
+        .read_to_end(&mut decompressed)
+        .context("Invalid Zucchini patch: magic 'Zucc' not found")?;
+    if !decompressed.starts_with(ZUCCHINI_MAGIC) {
+        bail!("Invalid Zucchini patch: magic 'Zucc' not found");
+    }
+    Ok(decompressed.into())
+}
+
+/// Apply a Zucchini patch to `old_image`, producing the new image.
+pub fn apply(old_image: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let patch = unwrap_patch(patch)?;
+    let mut source = Source::new(&patch);
+    let mut header = Source::new(source.take(PATCH_HEADER_SIZE)?);
+    header.take(4)?;
+    let major_version = header.u16()?;
+    let _minor_version = header.u16()?;
+    let old_size = header.u32()?;
+    let old_crc = header.u32()?;
+    let new_size = header.u32()?;
+    let new_crc = header.u32()?;
+
+    if major_version != MAJOR_VERSION {
+        bail!("Unsupported Zucchini patch version {major_version}");
+    }
+    if old_image.len() != old_size as usize {
+        bail!(
+            "Zucchini old image size mismatch: got {}, expected {old_size}",
+            old_image.len()
+        );
+    }
+    if crc32fast::hash(old_image) != old_crc {
+        bail!("Zucchini old image CRC mismatch");
+    }
+
+    let element_count = source.u32()?;
+    let mut new_image = vec![0u8; new_size as usize];
+    let mut covered = 0;
+    for _ in 0..element_count {
+        let element = ElementPatch::parse(&mut source)?;
+        if element.new_offset != covered {
+            bail!("Zucchini elements do not cover the new image contiguously");
+        }
+        covered += element.new_length;
+        element.apply(old_image, &mut new_image)?;
+    }
+    if !source.is_empty() {
+        bail!("Trailing data in Zucchini patch");
+    }
+    if covered != new_size {
+        bail!("Zucchini elements do not cover the whole new image");
+    }
+    if crc32fast::hash(&new_image) != new_crc {
+        bail!("Zucchini new image CRC mismatch");
+    }
+    Ok(new_image)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    fn var_uint(mut value: u32, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn buffer(data: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+    }
+
+    #[test]
+    fn test_apply_raw_element() {
+        let old = b"The quick brown fox jumps over the lazy dog".to_vec();
+        let new = b">> The quick brown cat jumps over the lazy dog!".to_vec();
+
+        // Copy "The quick brown " and " jumps over the lazy dog", turning "fox" into "cat" with
+        // raw deltas on a third equivalence.
+        let equivalences = [(0u32, 3u32, 16u32), (16, 19, 3), (19, 22, 24)];
+        let (mut src_skip, mut dst_skip, mut copy_count) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut previous_src, mut previous_dst) = (0i64, 0u32);
+        for (src, dst, len) in equivalences {
+            let diff = i64::from(src) - previous_src;
+            var_uint(((diff << 1) ^ (diff >> 63)) as u32, &mut src_skip);
+            var_uint(dst - previous_dst, &mut dst_skip);
+            var_uint(len, &mut copy_count);
+            previous_src = i64::from(src + len);
+            previous_dst = dst + len;
+        }
+        let mut raw_offsets = Vec::new();
+        let mut raw_diffs = Vec::new();
+        let mut compensation = 0;
+        for (copy_offset, (from, to)) in (16u32..19).zip(b"fox".iter().zip(b"cat")) {
+            var_uint(copy_offset - compensation, &mut raw_offsets);
+            raw_diffs.push(to.wrapping_sub(*from));
+            compensation = copy_offset + 1;
+        }
+
+        let mut patch = ZUCCHINI_MAGIC.to_vec();
+        patch.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        patch.extend_from_slice(&0u16.to_le_bytes());
+        for value in [
+            old.len() as u32,
+            crc32fast::hash(&old),
+            new.len() as u32,
+            crc32fast::hash(&new),
+            1,
+        ] {
+            patch.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [0, old.len() as u32, 0, new.len() as u32, 0] {
+            patch.extend_from_slice(&value.to_le_bytes());
+        }
+        patch.extend_from_slice(&1u16.to_le_bytes());
+        buffer(&src_skip, &mut patch);
+        buffer(&dst_skip, &mut patch);
+        buffer(&copy_count, &mut patch);
+        buffer(b">> !", &mut patch);
+        buffer(&raw_offsets, &mut patch);
+        buffer(&raw_diffs, &mut patch);
+        buffer(&[], &mut patch);
+        patch.extend_from_slice(&0u32.to_le_bytes());
+
+        assert_eq!(apply(&old, &patch).unwrap(), new);
+    }
+
+    #[test]
+    fn test_apply_elf_fixture() {
+        const OLD: &[u8] = include_bytes!("../../tests/fixtures/zucchini/old.elf");
+        const NEW: &[u8] = include_bytes!("../../tests/fixtures/zucchini/new.elf");
+        const PATCH: &[u8] = include_bytes!("../../tests/fixtures/zucchini/elf.zucchini");
+        const NEW_SHA256: &str = "d9bd21e85ec97ab29cb2198d74afd44e69bc69546e7c287409bfa1405ccab444";
+
+        let new = apply(OLD, PATCH).unwrap();
+        assert_eq!(hex::encode(Sha256::digest(&new)), NEW_SHA256);
+        assert_eq!(new, NEW);
+    }
+
+    #[test]
+    fn test_apply_dex_fixture() {
+        const OLD: &[u8] = include_bytes!("../../tests/fixtures/zucchini/old.dex");
+        const NEW: &[u8] = include_bytes!("../../tests/fixtures/zucchini/new.dex");
+        const PATCH: &[u8] = include_bytes!("../../tests/fixtures/zucchini/dex.zucchini");
+        const NEW_SHA256: &str = "5e25f73ce223b64c5d60f7b6fcbcce14a877c8d92e39ed56004ec96c1438c5a3";
+
+        let new = apply(OLD, PATCH).unwrap();
+        assert_eq!(hex::encode(Sha256::digest(&new)), NEW_SHA256);
+        assert_eq!(new, NEW);
+    }
+}
@@ -0,0 +1,297 @@
+//! Reference correction, following Zucchini's `ApplyReferencesCorrection`.
+//!
+//! Executable elements store references (pointers, branch displacements, relocations) whose
+//! targets move when code moves. Equivalences copy references with stale targets; the patch
+//! instead encodes each new target as a small delta between target "keys", indices into a sorted
+//! pool of known targets. The pool holds the old targets projected into the new image, plus
+//! extra targets shipped in the patch.
+
+use anyhow::{Result, anyhow, bail};
+
+use super::{Equivalence, ExecutableType, Source, address::OFFSET_BOUND, dex, elf, win32};
+
+/// A reference at `location` pointing to `target`, both file offsets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reference {
+    pub location: u32,
+    pub target: u32,
+}
+
+/// A type of references sharing a reader and writer. Types whose targets are interchangeable
+/// share a target pool.
+#[derive(Clone, Copy, Debug)]
+pub struct ReferenceGroup {
+    pub type_tag: u8,
+    pub pool_tag: u8,
+}
+
+/// Reference extraction and rewriting for one executable format. Disassemblers hold what they
+/// parsed from an image, and are handed the image itself on every call.
+pub trait Disassembler {
+    /// Size of the executable, which must match the element size.
+    fn size(&self) -> u32;
+
+    /// Reference groups, ordered by type tag.
+    fn reference_groups(&self) -> &'static [ReferenceGroup];
+
+    /// References of `group` whose location lies in `lo..hi`, ordered by location.
+    fn read(&self, image: &[u8], group: ReferenceGroup, lo: u32, hi: u32) -> Vec<Reference>;
+
+    /// Rewrite the reference of `group` at `reference.location` to point to its target.
+    /// References that cannot be encoded are left alone, as Zucchini does.
+    fn write(&self, image: &mut [u8], group: ReferenceGroup, reference: Reference);
+}
+
+/// Parse `image` as an executable of `exe_type`.
+fn make_disassembler(exe_type: ExecutableType, image: &[u8]) -> Result<Box<dyn Disassembler>> {
+    Ok(match exe_type {
+        ExecutableType::NoOp => Box::new(NoOpDisassembler(image.len() as u32)),
+        ExecutableType::ElfX86 => Box::new(elf::ElfDisassembler::parse::<elf::X86>(image)?),
+        ExecutableType::ElfX64 => Box::new(elf::ElfDisassembler::parse::<elf::X64>(image)?),
+        ExecutableType::ElfAArch32 => Box::new(elf::ElfDisassembler::parse::<elf::AArch32>(image)?),
+        ExecutableType::ElfAArch64 => Box::new(elf::ElfDisassembler::parse::<elf::AArch64>(image)?),
+        ExecutableType::Win32X86 => Box::new(win32::Win32Disassembler::parse::<win32::X86>(image)?),
+        ExecutableType::Win32X64 => Box::new(win32::Win32Disassembler::parse::<win32::X64>(image)?),
+        ExecutableType::Dex => Box::new(dex::DexDisassembler::parse(image)?),
+        // ZTF is Zucchini's text format for its own tests; update payloads never carry it.
+        ExecutableType::Ztf => {
+            bail!("Reference correction for {exe_type:?} Zucchini elements is not supported")
+        }
+    })
+}
+
+/// Raw elements have no references.
+struct NoOpDisassembler(u32);
+
+impl Disassembler for NoOpDisassembler {
+    fn size(&self) -> u32 {
+        self.0
+    }
+
+    fn reference_groups(&self) -> &'static [ReferenceGroup] {
+        &[]
+    }
+
+    fn read(&self, _: &[u8], _: ReferenceGroup, _: u32, _: u32) -> Vec<Reference> {
+        Vec::new()
+    }
+
+    fn write(&self, _: &mut [u8], _: ReferenceGroup, _: Reference) {}
+}
+
+/// Maps offsets of the old element to the new one through equivalences.
+struct OffsetMapper {
+    /// Equivalences sorted by source offset, with overlapping sources pruned.
+    equivalences: Vec<Equivalence>,
+    old_size: u32,
+    new_size: u32,
+}
+
+impl OffsetMapper {
+    fn new(equivalences: &[Equivalence], old_size: u32, new_size: u32) -> Self {
+        let mut equivalences = equivalences.to_vec();
+        equivalences.sort_by_key(|equivalence| equivalence.src_offset);
+
+        // Where sources overlap, the longer equivalence wins, and shorter ones are trimmed.
+        let mut current = 0;
+        while current < equivalences.len() {
+            let src_end = equivalences[current].src_end();
+            let mut next = current + 1;
+            let mut next_is_reaper = false;
+            while next < equivalences.len() && equivalences[next].src_offset < src_end {
+                if equivalences[current].length < equivalences[next].length {
+                    equivalences[current].length -= src_end - equivalences[next].src_offset;
+                    next_is_reaper = true;
+                    break;
+                }
+                next += 1;
+            }
+            if next_is_reaper {
+                for reduced in &mut equivalences[current + 1..next] {
+                    reduced.length = 0;
+                }
+                current = next;
+            } else {
+                let src_end = equivalences[current].src_end();
+                for reduced in &mut equivalences[current + 1..next] {
+                    let delta = src_end - reduced.src_offset;
+                    reduced.length -= reduced.length.min(delta);
+                    reduced.src_offset += delta;
+                    reduced.dst_offset += delta;
+                }
+                current += 1;
+            }
+        }
+        equivalences.retain(|equivalence| equivalence.length > 0);
+
+        Self {
+            equivalences,
+            old_size,
+            new_size,
+        }
+    }
+
+    /// Project sorted `offsets` covered by an equivalence, dropping the others.
+    fn forward_project_all(&self, offsets: &mut Vec<u32>) {
+        let mut current = 0;
+        offsets.retain_mut(|offset| {
+            while self
+                .equivalences
+                .get(current)
+                .is_some_and(|equivalence| equivalence.src_end() <= *offset)
+            {
+                current += 1;
+            }
+            match self.equivalences.get(current) {
+                Some(equivalence) if equivalence.src_offset <= *offset => {
+                    *offset = *offset - equivalence.src_offset + equivalence.dst_offset;
+                    true
+                }
+                _ => false,
+            }
+        });
+    }
+
+    /// Project any offset using the equivalence whose source is nearest to it. Fake offsets
+    /// past the old element move along with the end of the element.
+    fn extended_forward_project(&self, offset: u32) -> u32 {
+        if offset >= self.old_size {
+            let delta = offset - self.old_size;
+            return if delta < OFFSET_BOUND.saturating_sub(self.new_size) {
+                delta + self.new_size
+            } else {
+                OFFSET_BOUND - 1
+            };
+        }
+        let mut index = self
+            .equivalences
+            .partition_point(|equivalence| equivalence.src_offset <= offset);
+        if index > 0 {
+            let previous = &self.equivalences[index - 1];
+            if index == self.equivalences.len()
+                || offset < previous.src_end()
+                || offset - previous.src_end() < self.equivalences[index].src_offset - offset
+            {
+                index -= 1;
+            }
+        }
+        let Some(equivalence) = self.equivalences.get(index) else {
+            return offset;
+        };
+        offset
+            .wrapping_sub(equivalence.src_offset)
+            .wrapping_add(equivalence.dst_offset)
+    }
+}
+
+/// Sorted, unique targets of one pool, indexed by key.
+struct TargetPool(Vec<u32>);
+
+impl TargetPool {
+    fn insert(&mut self, targets: impl IntoIterator<Item = u32>) {
+        self.0.extend(targets);
+        self.0.sort_unstable();
+        self.0.dedup();
+    }
+
+    /// Key of the target nearest to `offset`, preferring the lower one on ties.
+    fn key_for_nearest_offset(&self, offset: u32) -> u32 {
+        let mut index = self.0.partition_point(|&target| target < offset);
+        if index > 0
+            && (index == self.0.len() || self.0[index] - offset >= offset - self.0[index - 1])
+        {
+            index -= 1;
+        }
+        index as u32
+    }
+}
+
+/// Decode the delta-encoded extra targets of a pool.
+fn extra_targets(mut source: Source) -> Result<Vec<u32>> {
+    let mut targets = Vec::new();
+    let mut compensation = 0u32;
+    while !source.is_empty() {
+        let target = compensation
+            .checked_add(source.var_uint()?)
+            .ok_or_else(|| anyhow!("Extra target overflow in Zucchini patch"))?;
+        targets.push(target);
+        compensation = target.wrapping_add(1);
+    }
+    Ok(targets)
+}
+
+/// Rewrite the references of `new`, which holds the element rebuilt from `old` by
+/// `equivalences`, extra data and raw deltas.
+pub fn correct_references(
+    exe_type: ExecutableType,
+    old: &[u8],
+    new: &mut [u8],
+    equivalences: &[Equivalence],
+    mut reference_deltas: Source,
+    extra_target_pools: &[(u8, Source)],
+) -> Result<()> {
+    let old_disassembler = make_disassembler(exe_type, old)?;
+    let new_disassembler = make_disassembler(exe_type, new)?;
+    if old_disassembler.size() as usize != old.len()
+        || new_disassembler.size() as usize != new.len()
+    {
+        bail!("{exe_type:?} Zucchini element size does not match its executable");
+    }
+
+    let groups = old_disassembler.reference_groups();
+    let mut pool_tags = groups
+        .iter()
+        .map(|group| group.pool_tag)
+        .collect::<Vec<_>>();
+    pool_tags.sort_unstable();
+    pool_tags.dedup();
+
+    let mapper = OffsetMapper::new(equivalences, old.len() as u32, new.len() as u32);
+    for pool_tag in pool_tags {
+        let pool_groups = groups
+            .iter()
+            .copied()
+            .filter(|group| group.pool_tag == pool_tag);
+
+        // Old targets that survive in the new element, and extra targets from the patch.
+        let mut targets = TargetPool(Vec::new());
+        for group in pool_groups.clone() {
+            let references = old_disassembler.read(old, group, 0, old.len() as u32);
+            targets.insert(references.iter().map(|reference| reference.target));
+        }
+        mapper.forward_project_all(&mut targets.0);
+        targets.0.sort_unstable();
+        if let Some((_, source)) = extra_target_pools.iter().find(|(tag, _)| *tag == pool_tag) {
+            targets.insert(extra_targets(*source)?);
+        }
+
+        for group in pool_groups {
+            for equivalence in equivalences {
+                let references = old_disassembler.read(
+                    old,
+                    group,
+                    equivalence.src_offset,
+                    equivalence.src_end(),
+                );
+                for reference in references {
+                    let projected = mapper.extended_forward_project(reference.target);
+                    let key = targets.key_for_nearest_offset(projected);
+                    if reference_deltas.is_empty() {
+                        bail!("Zucchini patch is missing reference deltas");
+                    }
+                    let key = key.wrapping_add_signed(reference_deltas.var_int()?);
+                    let Some(&target) = targets.0.get(key as usize) else {
+                        bail!("Invalid reference delta in Zucchini patch");
+                    };
+                    let location =
+                        reference.location - equivalence.src_offset + equivalence.dst_offset;
+                    new_disassembler.write(new, group, Reference { location, target });
+                }
+            }
+        }
+    }
+    if !reference_deltas.is_empty() {
+        bail!("Trailing reference deltas in Zucchini patch");
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! Heuristic detection of x86 and x64 relative branch references, following Zucchini's
+//! `Rel32FinderX86` and `Rel32FinderX64`, and their readers and writers.
+
+use super::{
+    address::{AddressTranslator, INVALID_OFFSET, INVALID_RVA},
+    reference::Reference,
+};
+
+/// A candidate rel32 reference: the location of its displacement and its target RVA.
+struct Rel32 {
+    location: u32,
+    target_rva: u32,
+    can_point_outside_section: bool,
+}
+
+/// Scan `image[lo..hi]` for the next instruction with a rel32 operand, returning it with the
+/// offset to continue from if it is rejected and the offset past it if accepted.
+fn scan(
+    image: &[u8],
+    translator: &AddressTranslator,
+    is_x64: bool,
+    lo: u32,
+    hi: u32,
+) -> Option<(Rel32, u32, u32)> {
+    let result = |cursor: u32, opcode_size: u32, can_point_outside_section: bool| {
+        let location = cursor + opcode_size;
+        let location_rva = translator.offset_to_rva(location);
+        let start = location as usize;
+        let displacement = u32::from_le_bytes(image[start..start + 4].try_into().unwrap());
+        let rel32 = Rel32 {
+            location,
+            target_rva: location_rva.wrapping_add(4).wrapping_add(displacement),
+            can_point_outside_section,
+        };
+        Some((rel32, cursor + 1, cursor + opcode_size + 4))
+    };
+
+    for cursor in lo..hi {
+        let code = &image[cursor as usize..hi as usize];
+        // CALL rel32 and JMP rel32.
+        if code.len() >= 5 && (code[0] == 0xE8 || code[0] == 0xE9) {
+            return result(cursor, 1, false);
+        }
+        if code.len() >= 6 {
+            // Jcc rel32.
+            if code[0] == 0x0F && code[1] & 0xF0 == 0x80 {
+                return result(cursor, 2, false);
+            }
+            // CALL and JMP [rip + disp32], and MOV and LEA with a [rip + disp32] operand.
+            if is_x64
+                && ((code[0] == 0xFF && (code[1] == 0x15 || code[1] == 0x25))
+                    || (matches!(code[0], 0x89 | 0x8B | 0x8D) && code[1] & 0xC7 == 0x05))
+            {
+                return result(cursor, 2, true);
+            }
+        }
+        // The same MOV and LEA after a REX or operand size prefix.
+        if is_x64
+            && code.len() >= 7
+            && (code[0] & 0xF2 == 0x40 || code[0] == 0x66)
+            && matches!(code[1], 0x89 | 0x8B | 0x8D)
+            && code[2] & 0xC7 == 0x05
+        {
+            return result(cursor, 3, true);
+        }
+    }
+    None
+}
+
+/// Find rel32 locations in `gaps` of a code section whose RVAs are `start_rva..end_rva`.
+pub fn find_rel32_x86(
+    image: &[u8],
+    translator: &AddressTranslator,
+    is_x64: bool,
+    gaps: Vec<(u32, u32)>,
+    (start_rva, end_rva): (u32, u32),
+    locations: &mut Vec<u32>,
+) {
+    for (mut lo, hi) in gaps {
+        while let Some((rel32, reject, accept)) = scan(image, translator, is_x64, lo, hi) {
+            lo = reject;
+            if translator.is_valid_rva(rel32.target_rva)
+                && (rel32.can_point_outside_section
+                    || (start_rva..end_rva).contains(&rel32.target_rva))
+            {
+                lo = accept;
+                locations.push(rel32.location);
+            }
+        }
+    }
+}
+
+/// References at the sorted rel32 `locations` within `lo..hi`.
+pub fn read_rel32_x86(
+    image: &[u8],
+    translator: &AddressTranslator,
+    locations: &[u32],
+    lo: u32,
+    hi: u32,
+) -> Vec<Reference> {
+    let start = locations.partition_point(|&location| location < lo);
+    locations[start..]
+        .iter()
+        .take_while(|&&location| location < hi)
+        .filter_map(|&location| {
+            let start = location as usize;
+            let displacement = u32::from_le_bytes(image.get(start..start + 4)?.try_into().ok()?);
+            let target_rva = translator
+                .offset_to_rva(location)
+                .wrapping_add(4)
+                .wrapping_add(displacement);
+            let target = translator.rva_to_offset(target_rva);
+            (target != INVALID_OFFSET).then_some(Reference { location, target })
+        })
+        .collect()
+}
+
+pub fn write_rel32_x86(image: &mut [u8], translator: &AddressTranslator, reference: Reference) {
+    let target_rva = translator.offset_to_rva(reference.target);
+    let location_rva = translator.offset_to_rva(reference.location);
+    if target_rva == INVALID_RVA || location_rva == INVALID_RVA {
+        return;
+    }
+    let displacement = target_rva.wrapping_sub(location_rva.wrapping_add(4));
+    let start = reference.location as usize;
+    if let Some(body) = image.get_mut(start..start + 4) {
+        body.copy_from_slice(&displacement.to_le_bytes());
+    }
+}
@@ -0,0 +1,391 @@
+//! PE references, following Zucchini's `DisassemblerWin32`.
+//!
+//! The base relocation table gives relocation units (type 0), whose targets are the locations
+//! of absolute addresses (type 1). Code sections are scanned for rel32 branches between those
+//! addresses (type 2).
+
+use anyhow::{Result, anyhow};
+
+use super::{
+    abs32::{AbsoluteAddress, abs32_gaps, abs32_locations, read_abs32, write_abs32},
+    address::{AddressTranslator, INVALID_OFFSET, INVALID_RVA, RVA_BOUND, Unit, range_is_bounded},
+    reference::{Disassembler, Reference, ReferenceGroup},
+    rel32::{find_rel32_x86, read_rel32_x86, write_rel32_x86},
+};
+
+const DOS_HEADER_SIZE: u32 = 64;
+const COFF_HEADER_SIZE: u32 = 20;
+const SECTION_HEADER_SIZE: u32 = 40;
+const RELOC_HEADER_SIZE: u32 = 8;
+const BASE_RELOCATION_TABLE: u32 = 5;
+/// `IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ`.
+const CODE_CHARACTERISTICS: u32 = 0x6000_0020;
+
+const RELOC: u8 = 0;
+const ABS32: u8 = 1;
+const REL32: u8 = 2;
+
+/// A PE flavor supported by Zucchini.
+pub trait Win32Arch {
+    const IS_64_BIT: bool;
+    /// Optional header magic.
+    const MAGIC: u16;
+    /// Type of the base relocations holding absolute addresses.
+    const RELOC_TYPE: u16;
+    const GROUPS: &'static [ReferenceGroup];
+}
+
+pub enum X86 {}
+pub enum X64 {}
+
+impl Win32Arch for X86 {
+    const IS_64_BIT: bool = false;
+    const MAGIC: u16 = 0x10B;
+    const RELOC_TYPE: u16 = 3;
+    const GROUPS: &'static [ReferenceGroup] = &[
+        ReferenceGroup {
+            type_tag: RELOC,
+            pool_tag: RELOC,
+        },
+        ReferenceGroup {
+            type_tag: ABS32,
+            pool_tag: ABS32,
+        },
+        ReferenceGroup {
+            type_tag: REL32,
+            pool_tag: REL32,
+        },
+    ];
+}
+
+impl Win32Arch for X64 {
+    const IS_64_BIT: bool = true;
+    const MAGIC: u16 = 0x20B;
+    const RELOC_TYPE: u16 = 10;
+    const GROUPS: &'static [ReferenceGroup] = &[
+        ReferenceGroup {
+            type_tag: RELOC,
+            pool_tag: RELOC,
+        },
+        ReferenceGroup {
+            type_tag: ABS32,
+            pool_tag: ABS32,
+        },
+        ReferenceGroup {
+            type_tag: REL32,
+            pool_tag: REL32,
+        },
+    ];
+}
+
+fn u16_at(image: &[u8], offset: u32) -> Option<u16> {
+    let offset = offset as usize;
+    Some(u16::from_le_bytes(
+        image.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(image: &[u8], offset: u32) -> Option<u32> {
+    let offset = offset as usize;
+    Some(u32::from_le_bytes(
+        image.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(image: &[u8], offset: u32) -> Option<u64> {
+    let offset = offset as usize;
+    Some(u64::from_le_bytes(
+        image.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+pub struct Win32Disassembler {
+    size: u32,
+    address: AbsoluteAddress,
+    reloc_type: u16,
+    groups: &'static [ReferenceGroup],
+    translator: AddressTranslator,
+    /// Offsets of the base relocation table and its blocks, empty without relocations.
+    reloc_region: (u32, u32),
+    reloc_blocks: Vec<u32>,
+    abs32_locations: Vec<u32>,
+    rel32_locations: Vec<u32>,
+}
+
+impl Win32Disassembler {
+    pub fn parse<A: Win32Arch>(image: &[u8]) -> Result<Self> {
+        Self::parse_headers::<A>(image)
+            .ok_or_else(|| anyhow!("Zucchini element is not a valid PE image of its type"))?
+    }
+
+    /// Parse headers, returning `None` where they are malformed.
+    fn parse_headers<A: Win32Arch>(image: &[u8]) -> Option<Result<Self>> {
+        if image.get(..2)? != b"MZ" || image.len() < DOS_HEADER_SIZE as usize {
+            return None;
+        }
+        let pe_offset = u32_at(image, 0x3C)?;
+        if pe_offset % 8 != 0 || pe_offset < DOS_HEADER_SIZE {
+            return None;
+        }
+        if image.get(pe_offset as usize..pe_offset as usize + 4)? != b"PE\0\0" {
+            return None;
+        }
+        let coff = pe_offset.checked_add(4)?;
+        let section_count = u32::from(u16_at(image, coff + 2)?);
+        let optional_size = u32::from(u16_at(image, coff + 16)?);
+        let optional = coff + COFF_HEADER_SIZE;
+        let data_directory = if A::IS_64_BIT { 112 } else { 96 };
+        if optional_size < data_directory
+            || image.len() < (optional + optional_size) as usize
+            || u16_at(image, optional)? != A::MAGIC
+        {
+            return None;
+        }
+        let image_base = if A::IS_64_BIT {
+            u64_at(image, optional + 24)?
+        } else {
+            u64::from(u32_at(image, optional + 28)?)
+        };
+        let directory_count = u32_at(image, optional + data_directory - 4)?;
+        if directory_count > (optional_size - data_directory) / 8 {
+            return None;
+        }
+        let base_relocation_table = (directory_count > BASE_RELOCATION_TABLE)
+            .then(|| {
+                let entry = optional + data_directory + BASE_RELOCATION_TABLE * 8;
+                Some((u32_at(image, entry)?, u32_at(image, entry + 4)?))
+            })
+            .flatten()
+            .filter(|&(_, size)| size > 0);
+
+        let sections = optional + optional_size;
+        let sections_end = sections + section_count * SECTION_HEADER_SIZE;
+        if image.len() < sections_end as usize {
+            return None;
+        }
+        let mut units = Vec::new();
+        let mut code_sections = Vec::new();
+        let mut offset_bound = sections_end;
+        for index in 0..section_count {
+            let header = sections + index * SECTION_HEADER_SIZE;
+            let virtual_size = u32_at(image, header + 8)?;
+            let virtual_address = u32_at(image, header + 12)?;
+            let raw_size = u32_at(image, header + 16)?;
+            let raw_offset = u32_at(image, header + 20)?;
+            let characteristics = u32_at(image, header + 36)?;
+            if !range_is_bounded(raw_offset.into(), raw_size.into(), image.len() as u64)
+                || !range_is_bounded(
+                    virtual_address.into(),
+                    virtual_size.into(),
+                    RVA_BOUND.into(),
+                )
+            {
+                return None;
+            }
+            let unit = Unit {
+                offset_begin: raw_offset,
+                offset_size: raw_size,
+                rva_begin: virtual_address,
+                rva_size: virtual_size,
+            };
+            units.push(unit);
+            offset_bound = offset_bound.max(raw_offset + raw_size);
+            if characteristics & CODE_CHARACTERISTICS == CODE_CHARACTERISTICS {
+                code_sections.push(unit);
+            }
+        }
+        if code_sections.is_empty() {
+            return None;
+        }
+        Some(Self::new::<A>(
+            &image[..offset_bound as usize],
+            image_base,
+            units,
+            base_relocation_table,
+            &code_sections,
+        ))
+    }
+
+    fn new<A: Win32Arch>(
+        image: &[u8],
+        image_base: u64,
+        units: Vec<Unit>,
+        base_relocation_table: Option<(u32, u32)>,
+        code_sections: &[Unit],
+    ) -> Result<Self> {
+        let mut disassembler = Self {
+            size: image.len() as u32,
+            address: AbsoluteAddress {
+                is_64_bit: A::IS_64_BIT,
+                image_base,
+            },
+            reloc_type: A::RELOC_TYPE,
+            groups: A::GROUPS,
+            translator: AddressTranslator::new(units)?,
+            reloc_region: (0, 0),
+            reloc_blocks: Vec::new(),
+            abs32_locations: Vec::new(),
+            rel32_locations: Vec::new(),
+        };
+
+        if let Some((rva, size)) = base_relocation_table {
+            let offset = disassembler.translator.rva_to_offset(rva);
+            if offset != INVALID_OFFSET
+                && range_is_bounded(offset.into(), size.into(), u64::from(disassembler.size) + 1)
+                && let Some(blocks) = find_reloc_blocks(image, offset, offset + size)
+            {
+                disassembler.reloc_region = (offset, offset + size);
+                disassembler.reloc_blocks = blocks;
+            }
+        }
+
+        let targets = disassembler
+            .read_relocs(image, 0, disassembler.size)
+            .into_iter()
+            .map(|reference| reference.target)
+            .collect();
+        let address = disassembler.address;
+        disassembler.abs32_locations =
+            abs32_locations(image, address, &disassembler.translator, targets);
+
+        for section in code_sections {
+            // Code past the virtual size is never loaded, so it holds no references.
+            let begin = section.offset_begin;
+            let end = begin + section.rva_size.min(section.offset_size);
+            let gaps = abs32_gaps(&disassembler.abs32_locations, address.width(), begin, end);
+            find_rel32_x86(
+                image,
+                &disassembler.translator,
+                A::IS_64_BIT,
+                gaps,
+                (section.rva_begin, section.rva_begin + section.rva_size),
+                &mut disassembler.rel32_locations,
+            );
+        }
+        disassembler.rel32_locations.sort_unstable();
+        Ok(disassembler)
+    }
+
+    /// Relocation units of the relevant type within `lo..hi`, whose targets fit an address.
+    fn read_relocs(&self, image: &[u8], lo: u32, hi: u32) -> Vec<Reference> {
+        let (region_begin, region_end) = self.reloc_region;
+        let lo = lo.clamp(region_begin, region_end);
+        let hi = hi.clamp(region_begin, region_end);
+        let block = self.reloc_blocks.partition_point(|&block| block <= lo);
+        let Some(&block) = block
+            .checked_sub(1)
+            .and_then(|index| self.reloc_blocks.get(index))
+        else {
+            return Vec::new();
+        };
+        let target_bound = (self.size + 1).saturating_sub(self.address.width());
+
+        let mut references = Vec::new();
+        let mut block = block;
+        let mut cursor = block + RELOC_HEADER_SIZE;
+        if lo > cursor {
+            cursor += (lo - cursor).next_multiple_of(2);
+        }
+        while cursor < hi {
+            let block_end = block + u32_at(image, block + 4).unwrap_or(0);
+            if cursor >= block_end {
+                block = block_end;
+                cursor = block + RELOC_HEADER_SIZE;
+                continue;
+            }
+            let (Some(rva_hi), Some(unit)) = (u32_at(image, block), u16_at(image, cursor)) else {
+                break;
+            };
+            let location = cursor;
+            cursor += 2;
+            if unit >> 12 != self.reloc_type {
+                continue;
+            }
+            let target = self
+                .translator
+                .rva_to_offset(rva_hi.wrapping_add(u32::from(unit & 0xFFF)));
+            if target != INVALID_OFFSET && target < target_bound {
+                references.push(Reference { location, target });
+            }
+        }
+        references
+    }
+
+    fn write_reloc(&self, image: &mut [u8], reference: Reference) {
+        let (region_begin, region_end) = self.reloc_region;
+        if !(region_begin..region_end).contains(&reference.location) {
+            return;
+        }
+        let block = self
+            .reloc_blocks
+            .partition_point(|&block| block <= reference.location);
+        let (Some(&block), Some(unit)) = (
+            block
+                .checked_sub(1)
+                .and_then(|index| self.reloc_blocks.get(index)),
+            u16_at(image, reference.location),
+        ) else {
+            return;
+        };
+        let Some(rva_hi) = u32_at(image, block) else {
+            return;
+        };
+        let target_rva = self.translator.offset_to_rva(reference.target);
+        let rva_lo = target_rva.wrapping_sub(rva_hi) & 0xFFF;
+        if target_rva == INVALID_RVA || target_rva != rva_hi.wrapping_add(rva_lo) {
+            return;
+        }
+        let unit = (unit & 0xF000) | rva_lo as u16;
+        let location = reference.location as usize;
+        image[location..location + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+}
+
+/// Offsets of the relocation blocks tiling `begin..end`, or `None` if they don't.
+fn find_reloc_blocks(image: &[u8], begin: u32, end: u32) -> Option<Vec<u32>> {
+    let mut blocks = Vec::new();
+    let mut block = begin;
+    while end - block >= RELOC_HEADER_SIZE {
+        blocks.push(block);
+        let size = u32_at(image, block + 4)?;
+        if size < RELOC_HEADER_SIZE || size % 4 != 0 || size > end - block {
+            return None;
+        }
+        block += size;
+    }
+    (block == end).then_some(blocks)
+}
+
+impl Disassembler for Win32Disassembler {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn reference_groups(&self) -> &'static [ReferenceGroup] {
+        self.groups
+    }
+
+    fn read(&self, image: &[u8], group: ReferenceGroup, lo: u32, hi: u32) -> Vec<Reference> {
+        let image = &image[..self.size as usize];
+        match group.type_tag {
+            RELOC => self.read_relocs(image, lo, hi),
+            ABS32 => read_abs32(
+                image,
+                self.address,
+                &self.translator,
+                &self.abs32_locations,
+                lo,
+                hi,
+            ),
+            _ => read_rel32_x86(image, &self.translator, &self.rel32_locations, lo, hi),
+        }
+    }
+
+    fn write(&self, image: &mut [u8], group: ReferenceGroup, reference: Reference) {
+        match group.type_tag {
+            RELOC => self.write_reloc(image, reference),
+            ABS32 => write_abs32(image, self.address, &self.translator, reference),
+            _ => write_rel32_x86(image, &self.translator, reference),
+        }
+    }
+}
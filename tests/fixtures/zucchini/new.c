@@ -0,0 +1,37 @@
+static int counter;
+static int calls;
+static char scratch[256];
+
+static const char *const names[] = {"alpha", "beta", "gamma", "delta", "epsilon"};
+
+int scale(int value) { return value * 5 + counter; }
+
+static int clamp(int value) {
+    calls++;
+    return value > 1000 ? 1000 : value < -1000 ? -1000 : value;
+}
+
+int bump(int value) {
+    counter += clamp(value);
+    scratch[value & 0xFF] = (char)value;
+    return scale(counter);
+}
+
+static int twice(int value) { return bump(value) + bump(value + 1); }
+
+int (*const handlers[])(int) = {scale, bump, twice, clamp};
+
+__attribute__((visibility("default"))) int dispatch(int index, int value) {
+    if (index < 0 || index > 3)
+        return -1;
+    return handlers[index](value) + names[index][0];
+}
+
+__attribute__((visibility("default"))) const char *name(int index) { return names[index % 5]; }
+
+__attribute__((visibility("default"))) int checksum(const char *text) {
+    int sum = calls;
+    while (*text)
+        sum = sum * 31 + *text++;
+    return sum + scratch[sum & 0xFF];
+}
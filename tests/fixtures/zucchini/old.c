@@ -0,0 +1,31 @@
+static int counter;
+static char scratch[256];
+
+static const char *const names[] = {"alpha", "beta", "gamma", "delta"};
+
+int scale(int value) { return value * 3 + counter; }
+
+int bump(int value) {
+    counter += value;
+    scratch[value & 0xFF] = (char)value;
+    return scale(counter);
+}
+
+static int twice(int value) { return bump(value) + bump(value + 1); }
+
+int (*const handlers[])(int) = {scale, bump, twice};
+
+__attribute__((visibility("default"))) int dispatch(int index, int value) {
+    if (index < 0 || index > 2)
+        return -1;
+    return handlers[index](value) + names[index][0];
+}
+
+__attribute__((visibility("default"))) const char *name(int index) { return names[index & 3]; }
+
+__attribute__((visibility("default"))) int checksum(const char *text) {
+    int sum = 0;
+    while (*text)
+        sum = sum * 31 + *text++;
+    return sum + scratch[sum & 0xFF];
+}